@@ -0,0 +1,38 @@
+confirmation-subject = Willkommen
+confirmation-greeting = Willkommen bei unserem Newsletter, { $name }!
+confirmation-instructions = Bitte folge dem Link unten, um deine Anmeldung zu bestätigen.
+confirmation-button = Anmeldung bestätigen
+
+welcome-subject = Du bist angemeldet
+welcome-greeting = Danke für die Bestätigung deiner Anmeldung, { $name }!
+welcome-body = Ab jetzt bekommst du jede neue Ausgabe.
+
+already-subscribed-subject = Du bist schon angemeldet
+already-subscribed-greeting = Hallo { $name },
+already-subscribed-body = gerade hat sich jemand mit dieser Adresse angemeldet, sie bekommt unseren Newsletter aber schon. Du musst nichts tun.
+
+email-change-subject = Bestätige deine neue E-Mail-Adresse
+email-change-greeting = Hallo { $name },
+email-change-instructions = Bitte folge dem Link unten, um unseren Newsletter ab jetzt an diese Adresse zu bekommen.
+email-change-button = Neue Adresse bestätigen
+
+email-changed-subject = Deine E-Mail-Adresse wurde geändert
+email-changed-body = { $name }, ab jetzt schicken wir unseren Newsletter an { $new_email } statt an diese Adresse.
+email-changed-warning = Falls du diese Änderung nicht angefordert hast, antworte bitte auf diese E-Mail.
+
+personal-data-access-subject = Deine Daten
+personal-data-access-greeting = Hallo { $name },
+personal-data-access-instructions = Folge innerhalb der nächsten Stunde dem Link unten, um alles herunterzuladen oder zu löschen, was wir über dich speichern.
+personal-data-access-button = Meine Daten verwalten
+
+preferences-access-subject = Deine Einstellungen
+preferences-access-greeting = Hallo { $name },
+preferences-access-instructions = Folge innerhalb der nächsten Stunde dem Link unten, um deine Einstellungen oder deine E-Mail-Adresse zu ändern.
+preferences-access-button = Meine Einstellungen ändern
+
+digest-subject = Dein Wochenüberblick
+digest-intro = Das haben wir diese Woche veröffentlicht.
+
+newsletter-unsubscribe = Abmelden
+newsletter-preferences = Deine Einstellungen
+newsletter-personal-data = Deine Daten
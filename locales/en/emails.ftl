@@ -0,0 +1,38 @@
+confirmation-subject = Welcome
+confirmation-greeting = Welcome to our newsletter, { $name }!
+confirmation-instructions = Please follow the link below to confirm your registration.
+confirmation-button = Confirm my subscription
+
+welcome-subject = You are subscribed
+welcome-greeting = Thanks for confirming your subscription, { $name }!
+welcome-body = You will receive every new issue from now on.
+
+already-subscribed-subject = You are already subscribed
+already-subscribed-greeting = Hello { $name },
+already-subscribed-body = someone just signed up with this address, but it already receives our newsletter. There is nothing you need to do.
+
+email-change-subject = Confirm your new email address
+email-change-greeting = Hello { $name },
+email-change-instructions = Please follow the link below to receive our newsletter at this address from now on.
+email-change-button = Confirm my new address
+
+email-changed-subject = Your email address was changed
+email-changed-body = { $name }, from now on we send our newsletter to { $new_email } instead of this address.
+email-changed-warning = If you did not ask for this change, please reply to this email.
+
+personal-data-access-subject = Your data
+personal-data-access-greeting = Hello { $name },
+personal-data-access-instructions = Follow the link below within the next hour to download or erase everything we store about you.
+personal-data-access-button = Manage my data
+
+preferences-access-subject = Your preferences
+preferences-access-greeting = Hello { $name },
+preferences-access-instructions = Follow the link below within the next hour to change your preferences or your email address.
+preferences-access-button = Change my preferences
+
+digest-subject = Your weekly digest
+digest-intro = Here is what we published this week.
+
+newsletter-unsubscribe = Unsubscribe
+newsletter-preferences = Your preferences
+newsletter-personal-data = Your data
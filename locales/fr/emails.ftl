@@ -0,0 +1,38 @@
+confirmation-subject = Bienvenue
+confirmation-greeting = Bienvenue dans notre newsletter, { $name } !
+confirmation-instructions = Veuillez suivre le lien ci-dessous pour confirmer votre inscription.
+confirmation-button = Confirmer mon inscription
+
+welcome-subject = Votre inscription est confirmée
+welcome-greeting = Merci d’avoir confirmé votre inscription, { $name } !
+welcome-body = Vous recevrez désormais chaque nouveau numéro.
+
+already-subscribed-subject = Vous êtes déjà inscrit
+already-subscribed-greeting = Bonjour { $name },
+already-subscribed-body = quelqu’un vient de s’inscrire avec cette adresse, mais elle reçoit déjà notre newsletter. Vous n’avez rien à faire.
+
+email-change-subject = Confirmez votre nouvelle adresse e-mail
+email-change-greeting = Bonjour { $name },
+email-change-instructions = Veuillez suivre le lien ci-dessous pour recevoir désormais notre newsletter à cette adresse.
+email-change-button = Confirmer ma nouvelle adresse
+
+email-changed-subject = Votre adresse e-mail a été modifiée
+email-changed-body = { $name }, nous enverrons désormais notre newsletter à { $new_email } au lieu de cette adresse.
+email-changed-warning = Si vous n’avez pas demandé cette modification, veuillez répondre à cet e-mail.
+
+personal-data-access-subject = Vos données
+personal-data-access-greeting = Bonjour { $name },
+personal-data-access-instructions = Suivez le lien ci-dessous dans l’heure qui vient pour télécharger ou effacer tout ce que nous conservons à votre sujet.
+personal-data-access-button = Gérer mes données
+
+preferences-access-subject = Vos préférences
+preferences-access-greeting = Bonjour { $name },
+preferences-access-instructions = Suivez le lien ci-dessous dans l’heure qui vient pour modifier vos préférences ou votre adresse e-mail.
+preferences-access-button = Modifier mes préférences
+
+digest-subject = Votre résumé de la semaine
+digest-intro = Voici ce que nous avons publié cette semaine.
+
+newsletter-unsubscribe = Se désabonner
+newsletter-preferences = Vos préférences
+newsletter-personal-data = Vos données
@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE users
+(
+    user_id       uuid NOT NULL,
+    PRIMARY KEY (user_id),
+    username      TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL
+);
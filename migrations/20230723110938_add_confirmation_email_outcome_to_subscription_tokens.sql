@@ -0,0 +1,5 @@
+-- Add migration script here
+-- Record whether the confirmation email carrying the token ever left
+ALTER TABLE subscription_tokens
+    ADD COLUMN confirmation_email_sent_at timestamptz NULL,
+    ADD COLUMN confirmation_email_error   TEXT        NULL;
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- Existing tokens count as issued now so they get a full lifetime after the upgrade
+ALTER TABLE subscription_tokens
+    ADD COLUMN issued_at   timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN consumed_at timestamptz NULL;
+CREATE INDEX subscription_tokens_subscriber_id_idx ON subscription_tokens (subscriber_id);
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Subscribers leaving the list move to status 'unsubscribed', this records when
+ALTER TABLE subscriptions ADD COLUMN unsubscribed_at timestamptz NULL;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- NULL means the subscriber did not tell us, emails then use the default language.
+ALTER TABLE subscriptions ADD COLUMN language TEXT NULL;
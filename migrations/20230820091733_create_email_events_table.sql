@@ -0,0 +1,13 @@
+-- Add migration script here
+-- Raw bounce and spam complaint notifications of the email provider, kept for auditing.
+CREATE TABLE email_events
+(
+    id               uuid        NOT NULL,
+    record_type      TEXT        NOT NULL,
+    subscriber_email TEXT        NOT NULL,
+    payload          JSONB       NOT NULL,
+    received_at      timestamptz NOT NULL,
+    PRIMARY KEY (id)
+);
+-- Looked up case-insensitively, like the subscriptions they belong to.
+CREATE INDEX email_events_lower_subscriber_email_idx ON email_events (lower(subscriber_email));
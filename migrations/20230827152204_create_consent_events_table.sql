@@ -0,0 +1,17 @@
+-- Add migration script here
+-- Proof of the double opt-in: one row when the form is submitted, one when the
+-- confirmation link is followed.
+CREATE TABLE consent_events
+(
+    id                 uuid        NOT NULL,
+    subscriber_id      uuid        NOT NULL
+        REFERENCES subscriptions (id),
+    event_type         TEXT        NOT NULL,
+    occurred_at        timestamptz NOT NULL,
+    ip_address         TEXT        NULL,
+    user_agent         TEXT        NULL,
+    form_version       TEXT        NOT NULL,
+    subscription_token TEXT        NOT NULL,
+    PRIMARY KEY (id)
+);
+CREATE INDEX consent_events_subscriber_id_idx ON consent_events (subscriber_id);
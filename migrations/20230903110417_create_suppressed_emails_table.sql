@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Keyed hashes of erased addresses, so they are not imported again without keeping
+-- the addresses themselves.
+CREATE TABLE suppressed_emails
+(
+    email_hash    TEXT        NOT NULL,
+    suppressed_at timestamptz NOT NULL,
+    PRIMARY KEY (email_hash)
+);
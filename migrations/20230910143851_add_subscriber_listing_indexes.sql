@@ -0,0 +1,10 @@
+-- Add migration script here
+-- Back the admin subscriber listing: keyset pagination over (subscribed_at, id),
+-- optionally within a single status, and case-insensitive substring search.
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE INDEX subscriptions_subscribed_at_id_idx
+    ON subscriptions (subscribed_at DESC, id DESC);
+CREATE INDEX subscriptions_status_subscribed_at_id_idx
+    ON subscriptions (status, subscribed_at DESC, id DESC);
+CREATE INDEX subscriptions_email_trgm_idx ON subscriptions USING gin (email gin_trgm_ops);
+CREATE INDEX subscriptions_name_trgm_idx ON subscriptions USING gin (name gin_trgm_ops);
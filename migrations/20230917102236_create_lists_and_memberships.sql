@@ -0,0 +1,56 @@
+-- Add migration script here
+-- `subscriptions` keeps one row per email address, what someone is subscribed to
+-- is tracked per list. Everything that existed before goes to the default list.
+CREATE TABLE lists
+(
+    id         uuid        NOT NULL,
+    slug       TEXT        NOT NULL UNIQUE,
+    name       TEXT        NOT NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY (id)
+);
+INSERT INTO lists (id, slug, name, created_at)
+VALUES (gen_random_uuid(), 'default', 'Newsletter', now());
+
+CREATE TABLE list_memberships
+(
+    subscriber_id   uuid        NOT NULL
+        REFERENCES subscriptions (id),
+    list_id         uuid        NOT NULL
+        REFERENCES lists (id),
+    status          TEXT        NOT NULL,
+    subscribed_at   timestamptz NOT NULL,
+    confirmed_at    timestamptz NULL,
+    unsubscribed_at timestamptz NULL,
+    PRIMARY KEY (subscriber_id, list_id)
+);
+CREATE INDEX list_memberships_list_id_status_idx ON list_memberships (list_id, status);
+
+-- Bounces and complaints are about the address, not the list: such subscribers had
+-- confirmed and the address status keeps them from receiving anything.
+INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at, unsubscribed_at)
+SELECT subscriptions.id,
+       lists.id,
+       CASE
+           WHEN subscriptions.status IN ('bounced', 'complained') THEN 'confirmed'
+           ELSE subscriptions.status
+       END,
+       subscriptions.subscribed_at,
+       subscriptions.unsubscribed_at
+FROM subscriptions, lists
+WHERE lists.slug = 'default';
+
+-- A confirmation token confirms one membership
+ALTER TABLE subscription_tokens ADD COLUMN list_id uuid NULL;
+UPDATE subscription_tokens SET list_id = (SELECT id FROM lists WHERE slug = 'default');
+ALTER TABLE subscription_tokens
+    ALTER COLUMN list_id SET NOT NULL,
+    ADD FOREIGN KEY (subscriber_id, list_id) REFERENCES list_memberships (subscriber_id, list_id);
+
+ALTER TABLE consent_events ADD COLUMN list_id uuid NULL REFERENCES lists (id);
+UPDATE consent_events SET list_id = (SELECT id FROM lists WHERE slug = 'default');
+ALTER TABLE consent_events ALTER COLUMN list_id SET NOT NULL;
+
+ALTER TABLE newsletter_issues ADD COLUMN list_id uuid NULL REFERENCES lists (id);
+UPDATE newsletter_issues SET list_id = (SELECT id FROM lists WHERE slug = 'default');
+ALTER TABLE newsletter_issues ALTER COLUMN list_id SET NOT NULL;
@@ -0,0 +1,18 @@
+-- Add migration script here
+ALTER TABLE subscriptions ADD COLUMN email_frequency TEXT NOT NULL DEFAULT 'immediate';
+
+-- Every change made on the preference page, with where it came from.
+CREATE TABLE preference_changes
+(
+    id            uuid        NOT NULL,
+    subscriber_id uuid        NOT NULL
+        REFERENCES subscriptions (id),
+    field         TEXT        NOT NULL,
+    old_value     TEXT        NULL,
+    new_value     TEXT        NULL,
+    changed_at    timestamptz NOT NULL,
+    ip_address    TEXT        NULL,
+    user_agent    TEXT        NULL,
+    PRIMARY KEY (id)
+);
+CREATE INDEX preference_changes_subscriber_id_idx ON preference_changes (subscriber_id);
@@ -0,0 +1,13 @@
+-- Add migration script here
+-- A new address only replaces the old one once its owner followed the link sent to it.
+CREATE TABLE email_change_requests
+(
+    token         TEXT        NOT NULL,
+    subscriber_id uuid        NOT NULL
+        REFERENCES subscriptions (id),
+    new_email     TEXT        NOT NULL,
+    requested_at  timestamptz NOT NULL,
+    confirmed_at  timestamptz NULL,
+    PRIMARY KEY (token)
+);
+CREATE INDEX email_change_requests_subscriber_id_idx ON email_change_requests (subscriber_id);
@@ -0,0 +1,14 @@
+-- Add migration script here
+-- An IANA name such as `Europe/Berlin`, used to deliver issues at a local time.
+ALTER TABLE subscriptions ADD COLUMN timezone TEXT NULL;
+
+-- `local_send_at` is the wall-clock time for issues sent in every subscriber's own
+-- timezone. An issue is queued for delivery once, `enqueued_at` records when.
+ALTER TABLE newsletter_issues
+    ADD COLUMN scheduled_at  timestamptz NULL,
+    ADD COLUMN local_send_at timestamp   NULL,
+    ADD COLUMN enqueued_at   timestamptz NULL,
+    ADD COLUMN cancelled_at  timestamptz NULL;
+UPDATE newsletter_issues SET enqueued_at = published_at;
+CREATE INDEX newsletter_issues_waiting_idx ON newsletter_issues (scheduled_at)
+    WHERE enqueued_at IS NULL AND cancelled_at IS NULL;
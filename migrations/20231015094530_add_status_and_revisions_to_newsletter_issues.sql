@@ -0,0 +1,43 @@
+-- Add migration script here
+-- Issues start as drafts. `sending` lasts until the last delivery of the issue has
+-- left the queue. A cancelled schedule turns the issue back into a draft, `cancelled_at`
+-- keeps when that last happened.
+ALTER TABLE newsletter_issues
+    ADD COLUMN status  TEXT        NOT NULL DEFAULT 'draft',
+    ADD COLUMN sent_at timestamptz NULL,
+    ADD CONSTRAINT newsletter_issues_status_check
+        CHECK (status IN ('draft', 'scheduled', 'sending', 'sent'));
+UPDATE newsletter_issues SET status = CASE
+    WHEN cancelled_at IS NOT NULL THEN 'draft'
+    WHEN enqueued_at IS NULL THEN 'scheduled'
+    WHEN EXISTS (
+        SELECT 1 FROM issue_delivery_queue
+        WHERE issue_delivery_queue.newsletter_issue_id = newsletter_issues.newsletter_issue_id
+    ) THEN 'sending'
+    ELSE 'sent'
+END;
+UPDATE newsletter_issues SET sent_at = enqueued_at WHERE status = 'sent';
+UPDATE newsletter_issues SET scheduled_at = NULL, local_send_at = NULL WHERE status = 'draft';
+DROP INDEX newsletter_issues_waiting_idx;
+-- Drafts are not published, the column only ever recorded when the row was written.
+ALTER TABLE newsletter_issues RENAME COLUMN published_at TO created_at;
+CREATE INDEX newsletter_issues_status_idx ON newsletter_issues (status)
+    WHERE status IN ('scheduled', 'sending');
+
+-- Every saved version of an issue, the first one included.
+CREATE TABLE newsletter_issue_revisions
+(
+    newsletter_issue_id uuid        NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    revision            INT         NOT NULL,
+    title               TEXT        NOT NULL,
+    text_content        TEXT        NOT NULL,
+    html_content        TEXT        NOT NULL,
+    edited_by           uuid        NULL REFERENCES users (user_id),
+    edited_at           timestamptz NOT NULL,
+    PRIMARY KEY (newsletter_issue_id, revision)
+);
+INSERT INTO newsletter_issue_revisions
+    (newsletter_issue_id, revision, title, text_content, html_content, edited_at)
+SELECT newsletter_issue_id, 1, title, text_content, html_content, created_at
+FROM newsletter_issues;
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Set while a worker sends the delivery, other workers skip it until the lease runs out.
+ALTER TABLE issue_delivery_queue
+    ADD COLUMN leased_until timestamptz NULL;
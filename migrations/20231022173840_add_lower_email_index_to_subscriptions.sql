@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Addresses are looked up case-insensitively, two subscriptions must not differ only
+-- in the casing of their address.
+CREATE UNIQUE INDEX subscriptions_lower_email_idx ON subscriptions (lower(email));
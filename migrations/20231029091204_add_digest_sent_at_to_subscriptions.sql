@@ -0,0 +1,4 @@
+-- Add migration script here
+-- When the last weekly digest went out, the next one covers the issues queued since.
+-- Set when a subscriber switches to the digest so it does not repeat earlier issues.
+ALTER TABLE subscriptions ADD COLUMN digest_sent_at timestamptz NULL;
@@ -0,0 +1,170 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::http::header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::web::Data;
+use actix_web::{FromRequest, HttpRequest, HttpResponse};
+use anyhow::Context;
+use base64::Engine;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::session_state::TypedSession;
+use crate::utils::see_other;
+
+/// An admin who presented valid credentials with the request.
+///
+/// Handlers taking this extractor are only reached by authenticated users, everyone
+/// else gets a `401` with a Basic authentication challenge.
+pub struct AuthenticatedUser {
+    pub user_id: Uuid,
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let credentials = basic_authentication(req.headers());
+        let db_pool = req.app_data::<Data<PgPool>>().cloned();
+
+        Box::pin(async move {
+            let db_pool = db_pool.ok_or_else(|| {
+                actix_web::error::ErrorInternalServerError("Database pool is not configured.")
+            })?;
+            let user_id = match credentials {
+                Ok(credentials) => validate_credentials(credentials, &db_pool).await,
+                Err(e) => Err(AuthError::InvalidCredentials(e)),
+            };
+            match user_id {
+                Ok(user_id) => Ok(AuthenticatedUser { user_id }),
+                Err(AuthError::InvalidCredentials(e)) => {
+                    tracing::info!("Rejected unauthenticated request cause: [{:?}]", e);
+                    let response = HttpResponse::Unauthorized()
+                        .insert_header((
+                            WWW_AUTHENTICATE,
+                            HeaderValue::from_static(r#"Basic realm="admin""#),
+                        ))
+                        .finish();
+                    Err(actix_web::error::InternalError::from_response(e, response).into())
+                }
+                Err(AuthError::UnexpectedError(e)) => {
+                    tracing::error!("Failed to authenticate request cause: [{:?}]", e);
+                    Err(actix_web::error::ErrorInternalServerError(e))
+                }
+            }
+        })
+    }
+}
+
+/// An admin with a logged in browser session.
+///
+/// Anonymous visitors of handlers taking this extractor are redirected to the login page.
+pub struct LoggedInUser {
+    pub user_id: Uuid,
+}
+
+impl FromRequest for LoggedInUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let session = TypedSession::from_request(req, payload);
+
+        Box::pin(async move {
+            let session = session.await?;
+            match session
+                .get_user_id()
+                .map_err(actix_web::error::ErrorInternalServerError)?
+            {
+                Some(user_id) => Ok(LoggedInUser { user_id }),
+                None => {
+                    let e = anyhow::anyhow!("The user has not logged in");
+                    Err(
+                        actix_web::error::InternalError::from_response(e, see_other("/login"))
+                            .into(),
+                    )
+                }
+            }
+        })
+    }
+}
+
+/// The credentials webhook callers have to present, registered as application data.
+pub struct WebhookCredentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+/// A webhook call from our email provider, authenticated with Basic auth against
+/// the configured `WebhookCredentials`.
+pub struct AuthenticatedWebhook;
+
+impl FromRequest for AuthenticatedWebhook {
+    type Error = actix_web::Error;
+    type Future = std::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let Some(expected) = req.app_data::<Data<WebhookCredentials>>() else {
+            return std::future::ready(Err(actix_web::error::ErrorInternalServerError(
+                "Webhook credentials are not configured.",
+            )));
+        };
+        let result = basic_authentication(req.headers()).and_then(|credentials| {
+            // Compare both parts in constant time, so response times do not help
+            // guessing the password.
+            let username_matches = credentials
+                .username
+                .as_bytes()
+                .ct_eq(expected.username.as_bytes());
+            let password_matches = credentials
+                .password
+                .expose_secret()
+                .as_bytes()
+                .ct_eq(expected.password.expose_secret().as_bytes());
+            match bool::from(username_matches & password_matches) {
+                true => Ok(AuthenticatedWebhook),
+                false => Err(anyhow::anyhow!("Invalid webhook credentials.")),
+            }
+        });
+        std::future::ready(result.map_err(|e| {
+            tracing::info!("Rejected unauthenticated webhook call cause: [{:?}]", e);
+            let response = HttpResponse::Unauthorized()
+                .insert_header((
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"Basic realm="webhooks""#),
+                ))
+                .finish();
+            actix_web::error::InternalError::from_response(e, response).into()
+        }))
+    }
+}
+
+fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+    let header_value = headers
+        .get(AUTHORIZATION)
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string.")?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'.")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64encoded_segment)
+        .context("Failed to base64-decode 'Basic' credentials.")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .context("The decoded credential string is not valid UTF8.")?;
+
+    let (username, password) = decoded_credentials
+        .split_once(':')
+        .context("A username and a password must be provided in 'Basic' auth.")?;
+
+    Ok(Credentials {
+        username: username.to_string(),
+        password: Secret::new(password.to_string()),
+    })
+}
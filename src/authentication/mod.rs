@@ -0,0 +1,5 @@
+pub use extractors::*;
+pub use password::*;
+
+mod extractors;
+mod password;
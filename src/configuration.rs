@@ -1,24 +1,119 @@
+use std::net::IpAddr;
 use std::time::Duration;
 
-use secrecy::Secret;
+use secrecy::{ExposeSecret, Secret};
 use serde::Deserialize;
 use sqlx::postgres::PgConnectOptions;
 
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    AppEmailTransport, EmailClient, FileSpoolTransport, PostmarkTransport, SmtpTransport,
+};
+use crate::email_templates::EmailTemplates;
+use crate::issue_delivery_worker::RetryPolicy;
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub issue_delivery: IssueDeliverySettings,
+    pub session: SessionSettings,
+    pub subscriptions: SubscriptionSettings,
+    pub idempotency: IdempotencySettings,
+    pub templates: TemplateSettings,
+    pub webhooks: WebhookSettings,
+    #[serde(default)]
+    pub admin: AdminSettings,
+}
+
+/// The first admin, created on startup while there are no users at all. Meant to be
+/// set through `APP_ADMIN__USERNAME` and `APP_ADMIN__PASSWORD_HASH`, the hash is a PHC
+/// string as stored in `users.password_hash`.
+#[derive(Deserialize, Clone, Default)]
+pub struct AdminSettings {
+    pub username: Option<String>,
+    pub password_hash: Option<Secret<String>>,
+    /// The only addresses test sends of newsletter issues go to.
+    #[serde(default)]
+    pub test_recipients: Vec<String>,
+}
+
+/// Basic auth credentials the email provider presents when calling our webhooks.
+#[derive(Deserialize, Clone)]
+pub struct WebhookSettings {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+/// The webhook password of `local.yaml`, good enough on a development machine only.
+const PLACEHOLDER_WEBHOOK_PASSWORD: &str = "change-me-webhook-password";
+
+impl WebhookSettings {
+    /// Outside `local` the password has to be set, e.g. through `APP_WEBHOOKS__PASSWORD`.
+    fn check_password(&self, environment: &Environment) -> Result<(), config::ConfigError> {
+        let password = self.password.expose_secret();
+        if *environment != Environment::Local
+            && (password.is_empty() || password == PLACEHOLDER_WEBHOOK_PASSWORD)
+        {
+            return Err(config::ConfigError::Message(format!(
+                "webhooks.password has to be set in the {} environment",
+                environment.as_str()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TemplateSettings {
+    pub directory: String,
+    /// Holds one directory of Fluent (`.ftl`) files per supported language.
+    pub locales_directory: String,
+    /// Used when a subscriber did not pick a language or picked one we do not support.
+    pub default_language: String,
+    /// Re-reads the templates and messages before every render, meant for editing them locally.
+    pub hot_reload: bool,
+}
+
+impl TemplateSettings {
+    pub fn email_templates(&self) -> Result<EmailTemplates, anyhow::Error> {
+        EmailTemplates::load(
+            &self.directory,
+            &self.locales_directory,
+            &self.default_language,
+            self.hot_reload,
+        )
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct EmailClientSettings {
+    pub transport: EmailTransportKind,
     pub base_url: String,
     pub sender_email: String,
     pub auth_token: Secret<String>,
     pub timeout_millis: u64,
+    pub smtp: SmtpSettings,
+    pub spool_directory: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailTransportKind {
+    Postmark,
+    Smtp,
+    File,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    pub starttls: bool,
+    /// Authentication is skipped when left empty.
+    pub username: String,
+    pub password: Secret<String>,
 }
 
 impl EmailClientSettings {
@@ -26,7 +121,89 @@ impl EmailClientSettings {
         SubscriberEmail::parse(self.sender_email.clone())
     }
     pub fn timeout(&self) -> Duration {
-        Duration::from_secs(self.timeout_millis)
+        Duration::from_millis(self.timeout_millis)
+    }
+    pub fn client(self) -> EmailClient {
+        let sender = self.sender().expect("Not a valid email address");
+        let timeout = self.timeout();
+        let transport =
+            match self.transport {
+                EmailTransportKind::Postmark => AppEmailTransport::Postmark(
+                    PostmarkTransport::new(self.base_url, self.auth_token, timeout),
+                ),
+                EmailTransportKind::Smtp => {
+                    let credentials = match self.smtp.username.is_empty() {
+                        true => None,
+                        false => Some((self.smtp.username, self.smtp.password)),
+                    };
+                    AppEmailTransport::Smtp(
+                        SmtpTransport::new(
+                            &self.smtp.host,
+                            self.smtp.port,
+                            self.smtp.starttls,
+                            credentials,
+                            timeout,
+                        )
+                        .expect("Failed to set up the SMTP transport"),
+                    )
+                }
+                EmailTransportKind::File => AppEmailTransport::FileSpool(
+                    FileSpoolTransport::new(self.spool_directory)
+                        .expect("Failed to create the email spool directory"),
+                ),
+            };
+        EmailClient::new(sender, transport)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct IssueDeliverySettings {
+    pub poll_interval_millis: u64,
+    pub max_attempts: u32,
+    pub backoff_base_millis: u64,
+    pub backoff_max_millis: u64,
+}
+
+impl IssueDeliverySettings {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_millis)
+    }
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            base_delay: Duration::from_millis(self.backoff_base_millis),
+            max_delay: Duration::from_millis(self.backoff_max_millis),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SubscriptionSettings {
+    pub token_ttl_secs: u64,
+    pub pending_retention_secs: u64,
+    pub cleanup_interval_secs: u64,
+}
+
+impl SubscriptionSettings {
+    pub fn token_ttl(&self) -> Duration {
+        Duration::from_secs(self.token_ttl_secs)
+    }
+    pub fn pending_retention(&self) -> Duration {
+        Duration::from_secs(self.pending_retention_secs)
+    }
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_secs(self.cleanup_interval_secs)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct IdempotencySettings {
+    pub retention_secs: u64,
+}
+
+impl IdempotencySettings {
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention_secs)
     }
 }
 
@@ -35,6 +212,81 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    pub hmac_secret: Secret<String>,
+    /// Keys the hashes of erased addresses. Unlike `hmac_secret` it must never be
+    /// rotated, a new key forgets every erased address.
+    pub suppression_key: Secret<String>,
+    /// The proxies whose `X-Forwarded-For` header tells the client address.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// The secret of `local.yaml`, it is in the repository and therefore no secret at all.
+const PLACEHOLDER_HMAC_SECRET: &str =
+    "long-and-very-secret-random-key-needed-to-verify-message-integrity-of-session-cookies";
+
+/// The suppression key of `local.yaml`, public as well.
+const PLACEHOLDER_SUPPRESSION_KEY: &str = "change-me-suppression-key";
+
+/// The session cookie key is derived from the secret, `cookie::Key::from` panics on
+/// anything shorter.
+const MIN_HMAC_SECRET_LENGTH: usize = 64;
+
+impl ApplicationSettings {
+    /// The secret signs the session cookies and the subscriber links. Outside `local` it
+    /// has to be set, e.g. through `APP_APPLICATION__HMAC_SECRET`.
+    fn check_hmac_secret(&self, environment: &Environment) -> Result<(), config::ConfigError> {
+        let secret = self.hmac_secret.expose_secret();
+        if *environment != Environment::Local
+            && (secret.is_empty() || secret == PLACEHOLDER_HMAC_SECRET)
+        {
+            return Err(config::ConfigError::Message(format!(
+                "application.hmac_secret has to be set in the {} environment",
+                environment.as_str()
+            )));
+        }
+        if secret.len() < MIN_HMAC_SECRET_LENGTH {
+            return Err(config::ConfigError::Message(format!(
+                "application.hmac_secret has to be at least {} bytes long",
+                MIN_HMAC_SECRET_LENGTH
+            )));
+        }
+        Ok(())
+    }
+
+    /// Outside `local` the key has to be set, e.g. through
+    /// `APP_APPLICATION__SUPPRESSION_KEY`. It cannot be the HMAC secret, or rotating
+    /// that secret would empty the suppression list.
+    fn check_suppression_key(&self, environment: &Environment) -> Result<(), config::ConfigError> {
+        let key = self.suppression_key.expose_secret();
+        if *environment != Environment::Local
+            && (key.is_empty() || key == PLACEHOLDER_SUPPRESSION_KEY)
+        {
+            return Err(config::ConfigError::Message(format!(
+                "application.suppression_key has to be set in the {} environment",
+                environment.as_str()
+            )));
+        }
+        if key.is_empty() || key == self.hmac_secret.expose_secret() {
+            return Err(config::ConfigError::Message(
+                "application.suppression_key has to be set and differ from application.hmac_secret"
+                    .into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SessionSettings {
+    pub store: SessionStoreKind,
+    pub cookie_secure: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreKind {
+    Memory,
+    Postgres,
 }
 
 #[derive(Deserialize, Clone)]
@@ -59,11 +311,22 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
         .add_source(config::File::from(
             configuration_path.join(environment_filename),
         ))
+        // E.g. `APP_ADMIN__USERNAME=ursula` sets `Settings.admin.username`.
+        .add_source(
+            config::Environment::with_prefix("APP")
+                .prefix_separator("_")
+                .separator("__"),
+        )
         .build()?;
 
-    settings.try_deserialize::<Settings>()
+    let settings = settings.try_deserialize::<Settings>()?;
+    settings.application.check_hmac_secret(&environment)?;
+    settings.application.check_suppression_key(&environment)?;
+    settings.webhooks.check_password(&environment)?;
+    Ok(settings)
 }
 
+#[derive(PartialEq, Eq)]
 enum Environment {
     Local,
     Development,
@@ -105,3 +368,130 @@ impl DatabaseSettings {
         self.without_db().database(&self.database_name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+
+    use crate::configuration::{
+        ApplicationSettings, Environment, WebhookSettings, MIN_HMAC_SECRET_LENGTH,
+        PLACEHOLDER_HMAC_SECRET, PLACEHOLDER_SUPPRESSION_KEY,
+    };
+
+    fn webhooks(password: &str) -> WebhookSettings {
+        WebhookSettings {
+            username: "postmark".into(),
+            password: Secret::new(password.into()),
+        }
+    }
+
+    fn application(hmac_secret: &str) -> ApplicationSettings {
+        ApplicationSettings {
+            port: 8000,
+            host: "127.0.0.1".into(),
+            base_url: "http://127.0.0.1".into(),
+            hmac_secret: Secret::new(hmac_secret.into()),
+            suppression_key: Secret::new(PLACEHOLDER_SUPPRESSION_KEY.into()),
+            trusted_proxies: vec![],
+        }
+    }
+
+    fn with_suppression_key(suppression_key: &str) -> ApplicationSettings {
+        ApplicationSettings {
+            suppression_key: Secret::new(suppression_key.into()),
+            ..application(&"a-real-secret".repeat(5))
+        }
+    }
+
+    #[test]
+    fn the_placeholder_webhook_password_is_fine_locally() {
+        assert!(webhooks("change-me-webhook-password")
+            .check_password(&Environment::Local)
+            .is_ok());
+    }
+
+    #[test]
+    fn a_missing_or_placeholder_webhook_password_is_rejected_outside_local() {
+        for environment in [Environment::Development, Environment::Production] {
+            assert!(webhooks("").check_password(&environment).is_err());
+            assert!(webhooks("change-me-webhook-password")
+                .check_password(&environment)
+                .is_err());
+            assert!(webhooks("a-real-secret")
+                .check_password(&environment)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn the_placeholder_hmac_secret_is_fine_locally() {
+        assert!(application(PLACEHOLDER_HMAC_SECRET)
+            .check_hmac_secret(&Environment::Local)
+            .is_ok());
+    }
+
+    #[test]
+    fn a_missing_or_placeholder_hmac_secret_is_rejected_outside_local() {
+        for environment in [Environment::Development, Environment::Production] {
+            assert!(application("").check_hmac_secret(&environment).is_err());
+            assert!(application(PLACEHOLDER_HMAC_SECRET)
+                .check_hmac_secret(&environment)
+                .is_err());
+            assert!(application(&"a-real-secret".repeat(5))
+                .check_hmac_secret(&environment)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn an_hmac_secret_too_short_for_the_cookie_key_is_rejected_everywhere() {
+        for environment in [
+            Environment::Local,
+            Environment::Development,
+            Environment::Production,
+        ] {
+            assert!(application(&"a".repeat(MIN_HMAC_SECRET_LENGTH - 1))
+                .check_hmac_secret(&environment)
+                .is_err());
+            assert!(application(&"a".repeat(MIN_HMAC_SECRET_LENGTH))
+                .check_hmac_secret(&environment)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn the_placeholder_suppression_key_is_fine_locally() {
+        assert!(with_suppression_key(PLACEHOLDER_SUPPRESSION_KEY)
+            .check_suppression_key(&Environment::Local)
+            .is_ok());
+    }
+
+    #[test]
+    fn a_missing_or_placeholder_suppression_key_is_rejected_outside_local() {
+        for environment in [Environment::Development, Environment::Production] {
+            assert!(with_suppression_key("")
+                .check_suppression_key(&environment)
+                .is_err());
+            assert!(with_suppression_key(PLACEHOLDER_SUPPRESSION_KEY)
+                .check_suppression_key(&environment)
+                .is_err());
+            assert!(with_suppression_key("a-real-suppression-key")
+                .check_suppression_key(&environment)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn the_suppression_key_cannot_be_the_hmac_secret() {
+        let hmac_secret = "a-real-secret".repeat(5);
+        for environment in [
+            Environment::Local,
+            Environment::Development,
+            Environment::Production,
+        ] {
+            assert!(with_suppression_key(&hmac_secret)
+                .check_suppression_key(&environment)
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::net::IpAddr;
+
+use actix_web::http::header::USER_AGENT;
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+/// The two steps of the double opt-in.
+#[derive(Clone, Copy, Debug)]
+pub enum ConsentEventKind {
+    /// The subscription form, or the preference page with a new topic, was submitted.
+    Subscribed,
+    /// The confirmation link sent by email was followed.
+    Confirmed,
+}
+
+impl ConsentEventKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConsentEventKind::Subscribed => "subscribed",
+            ConsentEventKind::Confirmed => "confirmed",
+        }
+    }
+}
+
+/// Where an event was recorded, and so which wording of the consent the subscriber
+/// saw. Bump the version of a form whenever its wording changes.
+#[derive(Clone, Copy, Debug)]
+pub enum ConsentForm {
+    /// The subscription endpoint.
+    Subscribe,
+    /// The topics of the preference page.
+    Preferences,
+    /// The confirmation link sent by email.
+    ConfirmationEmail,
+}
+
+impl ConsentForm {
+    fn version(&self) -> &'static str {
+        match self {
+            ConsentForm::Subscribe => "subscribe-v1",
+            ConsentForm::Preferences => "preferences-v1",
+            ConsentForm::ConfirmationEmail => "confirmation-email-v1",
+        }
+    }
+}
+
+/// The proxies in front of the application. Only their `X-Forwarded-For` headers
+/// are believed, anybody else could put any address in there.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+impl TrustedProxies {
+    /// The address that connected to the outermost trusted proxy. Proxies append to
+    /// `X-Forwarded-For`, so it is read from the right and the first address that is
+    /// not a trusted proxy is the client. Whatever the client sent is left of it.
+    fn client_ip(&self, request: &HttpRequest) -> Option<IpAddr> {
+        let mut client = request.peer_addr()?.ip();
+        let forwarded: Vec<&str> = request
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for hop in forwarded.into_iter().rev() {
+            if !self.0.contains(&client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+/// Who sent the request that gave or confirmed the consent.
+pub struct ConsentOrigin {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ConsentOrigin {
+    /// The client address is the peer address, unless that is one of the configured
+    /// `TrustedProxies`.
+    pub fn from_request(request: &HttpRequest) -> Self {
+        let ip_address = match request.app_data::<Data<TrustedProxies>>() {
+            Some(trusted_proxies) => trusted_proxies.client_ip(request),
+            None => request.peer_addr().map(|address| address.ip()),
+        }
+        .map(|ip| ip.to_string());
+        let user_agent = request
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        Self {
+            ip_address,
+            user_agent,
+        }
+    }
+}
+
+/// A recorded consent event, as exported for a subscriber.
+#[derive(Serialize)]
+pub struct ConsentEvent {
+    /// The slug of the list the consent was given for.
+    pub list: String,
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub form_version: String,
+    pub subscription_token: String,
+}
+
+#[tracing::instrument(
+    name = "Record a consent event",
+    skip(transaction, subscription_token, origin)
+)]
+pub async fn record_consent_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    kind: ConsentEventKind,
+    subscription_token: &str,
+    origin: &ConsentOrigin,
+    form: ConsentForm,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO consent_events (
+            id, subscriber_id, list_id, event_type, occurred_at,
+            ip_address, user_agent, form_version, subscription_token
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        list_id,
+        kind.as_str(),
+        Utc::now(),
+        origin.ip_address,
+        origin.user_agent,
+        form.version(),
+        subscription_token
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Every consent event of a subscriber, oldest first.
+#[tracing::instrument(name = "Get the consent events of a subscriber", skip(db_pool))]
+pub async fn get_consent_events(
+    subscriber_id: Uuid,
+    db_pool: &sqlx::PgPool,
+) -> Result<Vec<ConsentEvent>, sqlx::Error> {
+    sqlx::query_as!(
+        ConsentEvent,
+        r#"
+        SELECT lists.slug AS list, event_type, occurred_at,
+               ip_address, user_agent, form_version, subscription_token
+        FROM consent_events
+        JOIN lists ON lists.id = consent_events.list_id
+        WHERE subscriber_id = $1
+        ORDER BY occurred_at
+        "#,
+        subscriber_id
+    )
+    .fetch_all(db_pool)
+    .await
+}
@@ -0,0 +1,208 @@
+use anyhow::Context;
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_templates::{EmailTemplates, TemplateContext};
+use crate::subscriber_links::SubscriberLinkSigner;
+
+/// Digests sent per run of the delivery worker, the others wait for the next run.
+const MAX_DIGESTS_PER_RUN: usize = 50;
+
+/// A subscriber whose digest is due, claimed by moving `digest_sent_at` to now.
+struct DueDigest {
+    subscriber_id: Uuid,
+    email: String,
+    language: Option<String>,
+    /// `None` for the first digest, which covers the week before it.
+    previous_digest_sent_at: Option<DateTime<Utc>>,
+    digest_sent_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct DigestIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+/// Sends the weekly digest to the confirmed subscribers who chose it and did not get
+/// one for a week. A digest bundles the issues queued for their lists since the
+/// previous one, a week without issues sends nothing. A digest that fails to go out
+/// is tried again on the next run. Returns the number of digests sent.
+#[tracing::instrument(name = "Send the due weekly digests", skip_all, err)]
+pub async fn send_due_digests(
+    db_pool: &PgPool,
+    email_client: &EmailClient,
+    subscriber_links: &SubscriberLinkSigner,
+    email_templates: &EmailTemplates,
+) -> Result<usize, anyhow::Error> {
+    let mut n_sent = 0;
+    for _ in 0..MAX_DIGESTS_PER_RUN {
+        let Some(due) = claim_due_digest(db_pool).await? else {
+            break;
+        };
+        match send_digest(
+            db_pool,
+            email_client,
+            subscriber_links,
+            email_templates,
+            &due,
+        )
+        .await
+        {
+            Ok(true) => n_sent += 1,
+            Ok(false) => {}
+            Err(e) => {
+                release_digest(db_pool, &due).await?;
+                return Err(e);
+            }
+        }
+    }
+    Ok(n_sent)
+}
+
+/// Returns `false` when there was nothing to send.
+#[tracing::instrument(
+    name = "Send a weekly digest",
+    skip_all,
+    fields(subscriber_id = %due.subscriber_id)
+)]
+async fn send_digest(
+    db_pool: &PgPool,
+    email_client: &EmailClient,
+    subscriber_links: &SubscriberLinkSigner,
+    email_templates: &EmailTemplates,
+    due: &DueDigest,
+) -> Result<bool, anyhow::Error> {
+    let recipient = match SubscriberEmail::parse(due.email.clone()) {
+        Ok(recipient) => recipient,
+        // Trying again next run would not help, the week is skipped like a delivery.
+        Err(e) => {
+            tracing::warn!(
+                "Skipping the digest of a subscriber, the stored contact details are invalid: [{}]",
+                e
+            );
+            return Ok(false);
+        }
+    };
+    let issues = get_digest_issues(db_pool, due)
+        .await
+        .context("Failed to load the issues of a weekly digest.")?;
+    if issues.is_empty() {
+        return Ok(false);
+    }
+    // The digest spans lists, its link leaves all of them.
+    let unsubscribe_link = subscriber_links.unsubscribe_all_link(due.subscriber_id);
+    let mut context = TemplateContext::new();
+    context.insert("issues", &issues);
+    context.insert("unsubscribe_link", &unsubscribe_link);
+    context.insert(
+        "personal_data_link",
+        &subscriber_links.personal_data_link(due.subscriber_id),
+    );
+    context.insert(
+        "preferences_link",
+        &subscriber_links.preferences_link(due.subscriber_id),
+    );
+    let language = due.language.as_deref();
+    let subject = email_templates.message(language, "digest-subject")?;
+    let body = email_templates.render("digest", language, &context)?;
+    email_client
+        .send_mail(
+            &recipient,
+            &subject,
+            &body.html,
+            &body.text,
+            Some(&unsubscribe_link),
+        )
+        .await
+        .context("Failed to send a weekly digest.")?;
+    Ok(true)
+}
+
+#[tracing::instrument(name = "Claim a due weekly digest", skip(db_pool))]
+async fn claim_due_digest(db_pool: &PgPool) -> Result<Option<DueDigest>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        WITH due AS (
+            SELECT id, digest_sent_at
+            FROM subscriptions
+            WHERE status = 'confirmed'
+              AND email_frequency = 'weekly_digest'
+              AND (digest_sent_at IS NULL OR digest_sent_at <= now() - interval '7 days')
+            ORDER BY digest_sent_at NULLS FIRST
+            LIMIT 1
+            FOR UPDATE
+            SKIP LOCKED
+        )
+        UPDATE subscriptions SET digest_sent_at = now()
+        FROM due
+        WHERE subscriptions.id = due.id
+        RETURNING subscriptions.id AS "subscriber_id!", subscriptions.email AS "email!",
+                  subscriptions.language, due.digest_sent_at AS previous_digest_sent_at,
+                  subscriptions.digest_sent_at AS "digest_sent_at!"
+        "#
+    )
+    .fetch_optional(db_pool)
+    .await?;
+    Ok(row.map(|row| DueDigest {
+        subscriber_id: row.subscriber_id,
+        email: row.email,
+        language: row.language,
+        previous_digest_sent_at: row.previous_digest_sent_at,
+        digest_sent_at: row.digest_sent_at,
+    }))
+}
+
+/// Puts the claim back so that the digest is tried again, unless the subscriber has
+/// switched frequencies since.
+#[tracing::instrument(
+    name = "Release a weekly digest",
+    skip_all,
+    fields(subscriber_id = %due.subscriber_id)
+)]
+async fn release_digest(db_pool: &PgPool, due: &DueDigest) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions SET digest_sent_at = $3
+        WHERE id = $1 AND digest_sent_at = $2
+        "#,
+        due.subscriber_id,
+        due.digest_sent_at,
+        due.previous_digest_sent_at
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// The issues queued for the subscriber's confirmed lists during the week the digest
+/// covers, oldest first.
+async fn get_digest_issues(
+    db_pool: &PgPool,
+    due: &DueDigest,
+) -> Result<Vec<DigestIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        DigestIssue,
+        r#"
+        SELECT newsletter_issues.title, newsletter_issues.text_content,
+               newsletter_issues.html_content
+        FROM newsletter_issues
+        JOIN list_memberships ON list_memberships.list_id = newsletter_issues.list_id
+        WHERE list_memberships.subscriber_id = $1
+          AND list_memberships.status = 'confirmed'
+          AND newsletter_issues.enqueued_at > COALESCE($2::timestamptz, $3::timestamptz - interval '7 days')
+          AND newsletter_issues.enqueued_at <= $3
+        ORDER BY newsletter_issues.enqueued_at
+        "#,
+        due.subscriber_id,
+        due.previous_digest_sent_at,
+        due.digest_sent_at
+    )
+    .fetch_all(db_pool)
+    .await
+}
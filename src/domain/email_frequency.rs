@@ -0,0 +1,49 @@
+/// How often a subscriber wants to hear from us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailFrequency {
+    Immediate,
+    WeeklyDigest,
+}
+
+impl EmailFrequency {
+    pub const ALL: [EmailFrequency; 2] = [EmailFrequency::Immediate, EmailFrequency::WeeklyDigest];
+
+    pub fn parse(frequency: String) -> Result<EmailFrequency, String> {
+        match frequency.trim() {
+            "immediate" => Ok(Self::Immediate),
+            "weekly_digest" => Ok(Self::WeeklyDigest),
+            _ => Err(format!("{} is not a valid email frequency", frequency)),
+        }
+    }
+}
+
+impl AsRef<str> for EmailFrequency {
+    fn as_ref(&self) -> &str {
+        match self {
+            EmailFrequency::Immediate => "immediate",
+            EmailFrequency::WeeklyDigest => "weekly_digest",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok_eq};
+
+    use crate::domain::EmailFrequency;
+
+    #[test]
+    fn known_frequencies_round_trip() {
+        for frequency in EmailFrequency::ALL {
+            assert_ok_eq!(
+                EmailFrequency::parse(frequency.as_ref().to_string()),
+                frequency
+            );
+        }
+    }
+
+    #[test]
+    fn an_unknown_frequency_is_invalid() {
+        assert_err!(EmailFrequency::parse("daily".to_string()));
+    }
+}
@@ -0,0 +1,53 @@
+/// How a newsletter list is named in forms and API requests, e.g. `weekly-digest`:
+/// lowercase ASCII letters, digits and dashes.
+#[derive(Debug)]
+pub struct ListSlug(String);
+
+impl ListSlug {
+    pub fn parse(slug: String) -> Result<ListSlug, String> {
+        let is_valid = !slug.is_empty()
+            && slug.len() <= 64
+            && !slug.starts_with('-')
+            && !slug.ends_with('-')
+            && slug
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !is_valid {
+            return Err(format!("{} is not a valid list slug", slug));
+        }
+        Ok(Self(slug))
+    }
+}
+
+impl AsRef<str> for ListSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use crate::domain::ListSlug;
+
+    #[test]
+    fn lowercase_words_joined_by_dashes_are_valid() {
+        assert_ok!(ListSlug::parse("weekly-digest-2".to_string()));
+    }
+
+    #[test]
+    fn an_empty_slug_is_invalid() {
+        assert_err!(ListSlug::parse("".to_string()));
+    }
+
+    #[test]
+    fn uppercase_letters_and_spaces_are_invalid() {
+        assert_err!(ListSlug::parse("Weekly digest".to_string()));
+    }
+
+    #[test]
+    fn a_leading_dash_is_invalid() {
+        assert_err!(ListSlug::parse("-digest".to_string()));
+    }
+}
@@ -1,7 +1,15 @@
+pub use email_frequency::EmailFrequency;
+pub use list_slug::ListSlug;
 pub use subscriber::Subscriber;
 pub use subscriber_email::SubscriberEmail;
+pub use subscriber_language::SubscriberLanguage;
 pub use subscriber_name::SubscriberName;
+pub use subscriber_timezone::SubscriberTimezone;
 
+pub mod email_frequency;
+pub mod list_slug;
 pub mod subscriber;
 pub mod subscriber_email;
+pub mod subscriber_language;
 pub mod subscriber_name;
+pub mod subscriber_timezone;
@@ -1,10 +1,11 @@
-use crate::domain::{SubscriberEmail, SubscriberName};
+use crate::domain::{SubscriberEmail, SubscriberLanguage, SubscriberName};
 use crate::routes::SubscriberCreateRequest;
 
 #[derive(Debug)]
 pub struct Subscriber {
     pub name: SubscriberName,
     pub email: SubscriberEmail,
+    pub language: Option<SubscriberLanguage>,
 }
 
 impl TryFrom<SubscriberCreateRequest> for Subscriber {
@@ -14,6 +15,11 @@ impl TryFrom<SubscriberCreateRequest> for Subscriber {
         Ok(Subscriber {
             name: SubscriberName::parse(value.name)?,
             email: SubscriberEmail::parse(value.email)?,
+            language: value
+                .language
+                .filter(|language| !language.trim().is_empty())
+                .map(SubscriberLanguage::parse)
+                .transpose()?,
         })
     }
 }
@@ -0,0 +1,57 @@
+use unic_langid::LanguageIdentifier;
+
+/// A BCP 47 language tag such as `de` or `pt-BR`, stored in its canonical form.
+#[derive(Debug)]
+pub struct SubscriberLanguage(String);
+
+impl SubscriberLanguage {
+    pub fn parse(language: String) -> Result<SubscriberLanguage, String> {
+        let language: LanguageIdentifier = language
+            .trim()
+            .parse()
+            .map_err(|_| format!("{} is not a valid language", language))?;
+        if language.language.is_empty() {
+            return Err(format!("{} is not a valid language", language));
+        }
+        Ok(Self(language.to_string()))
+    }
+}
+
+impl AsRef<str> for SubscriberLanguage {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use claim::{assert_err, assert_ok};
+
+    use crate::domain::SubscriberLanguage;
+
+    #[test]
+    fn parse_empty_language_is_invalid() {
+        assert_err!(SubscriberLanguage::parse("".to_string()));
+    }
+
+    #[test]
+    fn parse_garbage_language_is_invalid() {
+        assert_err!(SubscriberLanguage::parse("not a language".to_string()));
+    }
+
+    #[test]
+    fn parse_undetermined_language_is_invalid() {
+        assert_err!(SubscriberLanguage::parse("und".to_string()));
+    }
+
+    #[test]
+    fn parse_language_with_region_is_valid() {
+        assert_ok!(SubscriberLanguage::parse("pt-BR".to_string()));
+    }
+
+    #[test]
+    fn parse_normalizes_the_case() {
+        let language = SubscriberLanguage::parse("pt-br".to_string()).unwrap();
+        assert_eq!(language.as_ref(), "pt-BR");
+    }
+}
@@ -0,0 +1,47 @@
+use chrono_tz::Tz;
+
+/// An IANA timezone such as `Europe/Berlin`.
+#[derive(Debug, Clone, Copy)]
+pub struct SubscriberTimezone(Tz);
+
+impl SubscriberTimezone {
+    pub fn parse(timezone: String) -> Result<SubscriberTimezone, String> {
+        timezone
+            .trim()
+            .parse::<Tz>()
+            .map(Self)
+            .map_err(|_| format!("{} is not a valid timezone", timezone))
+    }
+
+    pub fn tz(&self) -> Tz {
+        self.0
+    }
+}
+
+impl AsRef<str> for SubscriberTimezone {
+    fn as_ref(&self) -> &str {
+        self.0.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use crate::domain::SubscriberTimezone;
+
+    #[test]
+    fn an_iana_name_is_valid() {
+        assert_ok!(SubscriberTimezone::parse("Europe/Berlin".to_string()));
+    }
+
+    #[test]
+    fn an_empty_timezone_is_invalid() {
+        assert_err!(SubscriberTimezone::parse("".to_string()));
+    }
+
+    #[test]
+    fn an_unknown_name_is_invalid() {
+        assert_err!(SubscriberTimezone::parse("Europe/Atlantis".to_string()));
+    }
+}
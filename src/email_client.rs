@@ -1,184 +0,0 @@
-use std::time::Duration;
-
-use reqwest::{Client, Url};
-use secrecy::{ExposeSecret, Secret};
-use serde::Serialize;
-
-use crate::domain::SubscriberEmail;
-
-#[derive(Debug)]
-pub struct EmailClient {
-    sender: SubscriberEmail,
-    client: Client,
-    base_url: Url,
-    auth_token: Secret<String>,
-}
-
-impl EmailClient {
-    pub fn new(
-        base_url: String,
-        sender: SubscriberEmail,
-        auth_token: Secret<String>,
-        duration: Duration,
-    ) -> Self {
-        let client = Client::builder().timeout(duration).build().unwrap();
-
-        let base_url = Url::parse(&base_url).unwrap();
-
-        Self {
-            sender,
-            client,
-            base_url,
-            auth_token,
-        }
-    }
-    pub async fn send_mail(
-        &self,
-        recipient: SubscriberEmail,
-        subject: &str,
-        html_body: &str,
-        text_body: &str,
-    ) -> Result<(), reqwest::Error> {
-        let url = self
-            .base_url
-            .join("email")
-            .expect("Can not build email client base url");
-        let request = SendEmailRequest {
-            from: self.sender.as_ref(),
-            to: recipient.as_ref(),
-            subject,
-            html_body,
-            text_body,
-        };
-        self.client
-            .post(url)
-            .json(&request)
-            .header("X-Postmark-Server-Token", self.auth_token.expose_secret())
-            .send()
-            .await?
-            .error_for_status()?;
-        Ok(())
-    }
-}
-
-#[derive(Serialize)]
-#[serde(rename_all = "PascalCase")]
-struct SendEmailRequest<'a> {
-    from: &'a str,
-    to: &'a str,
-    subject: &'a str,
-    html_body: &'a str,
-    text_body: &'a str,
-}
-
-#[cfg(test)]
-mod tests {
-    use std::time::Duration;
-
-    use claim::{assert_err, assert_ok};
-    use fake::faker::internet::en::SafeEmail;
-    use fake::faker::lorem::en::{Paragraph, Sentence};
-    use fake::{Fake, Faker};
-    use secrecy::Secret;
-    use serde_json::Value;
-    use wiremock::matchers::{header, header_exists, method, path};
-    use wiremock::{Match, Mock, MockServer, Request, ResponseTemplate};
-
-    use crate::domain::SubscriberEmail;
-    use crate::email_client::EmailClient;
-
-    struct SendEmailBodyMatcher;
-
-    impl Match for SendEmailBodyMatcher {
-        fn matches(&self, request: &Request) -> bool {
-            let result: Result<Value, _> = serde_json::from_slice(&request.body);
-            if let Ok(body) = result {
-                dbg!(&body);
-                body.get("From").is_some()
-                    && body.get("To").is_some()
-                    && body.get("Subject").is_some()
-                    && body.get("HtmlBody").is_some()
-                    && body.get("TextBody").is_some()
-            } else {
-                false
-            }
-        }
-    }
-
-    fn subject() -> String {
-        Sentence(1..2).fake()
-    }
-
-    fn email() -> SubscriberEmail {
-        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
-    }
-
-    fn content() -> String {
-        Paragraph(1..10).fake()
-    }
-
-    fn email_client(base_url: String) -> EmailClient {
-        EmailClient::new(
-            base_url,
-            email(),
-            Secret::new(Faker.fake()),
-            Duration::from_millis(200),
-        )
-    }
-
-    #[tokio::test]
-    async fn send_email_fires_a_request() {
-        let mock_server = MockServer::start().await;
-
-        Mock::given(header_exists("X-Postmark-Server-Token"))
-            .and(header("Content-Type", "application/json"))
-            .and(path("/email"))
-            .and(method("POST"))
-            .and(SendEmailBodyMatcher)
-            .respond_with(ResponseTemplate::new(200))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        let result = email_client(mock_server.uri())
-            .send_mail(email(), &subject(), &content(), &content())
-            .await;
-
-        assert_ok!(result);
-    }
-
-    #[tokio::test]
-    async fn send_email_returns_err_if_server_timeouts() {
-        let mock_server = MockServer::start().await;
-
-        let response = ResponseTemplate::new(200).set_delay(Duration::from_secs(180));
-        Mock::given(header_exists("X-Postmark-Server-Token"))
-            .respond_with(response)
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        let result = email_client(mock_server.uri())
-            .send_mail(email(), &subject(), &content(), &content())
-            .await;
-
-        assert_err!(result);
-    }
-
-    #[tokio::test]
-    async fn send_email_returns_err_if_server_returns_500() {
-        let mock_server = MockServer::start().await;
-
-        Mock::given(header_exists("X-Postmark-Server-Token"))
-            .respond_with(ResponseTemplate::new(500))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        let result = email_client(mock_server.uri())
-            .send_mail(email(), &subject(), &content(), &content())
-            .await;
-
-        assert_err!(result);
-    }
-}
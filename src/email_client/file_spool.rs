@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+
+use crate::email_client::{build_message, Email, EmailError, EmailTransport};
+
+/// Writes every email as an `.eml` file into a local directory instead of sending it,
+/// handy during development to look at what would have gone out.
+#[derive(Clone, Debug)]
+pub struct FileSpoolTransport {
+    spool: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileSpoolTransport {
+    pub fn new(directory: impl Into<PathBuf>) -> Result<Self, std::io::Error> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            spool: AsyncFileTransport::new(directory),
+        })
+    }
+}
+
+impl EmailTransport for FileSpoolTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let message = build_message(email)?;
+        self.spool
+            .send(message)
+            .await
+            .map_err(|e| EmailError::Transient(anyhow!(e)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{AppEmailTransport, EmailClient, FileSpoolTransport};
+
+    #[tokio::test]
+    async fn send_email_writes_an_eml_file_into_the_spool_directory() {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let email_client = EmailClient::new(
+            SubscriberEmail::parse("sender@example.com".into()).unwrap(),
+            AppEmailTransport::FileSpool(FileSpoolTransport::new(&directory).unwrap()),
+        );
+
+        email_client
+            .send_mail(
+                &SubscriberEmail::parse("recipient@example.com".into()).unwrap(),
+                "Greetings",
+                "<p>Hello</p>",
+                "Hello",
+                None,
+            )
+            .await
+            .unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let content = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(content.contains("Subject: Greetings"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use std::future::Future;
+
+pub use file_spool::FileSpoolTransport;
+pub use postmark::PostmarkTransport;
+pub use smtp::SmtpTransport;
+
+use crate::domain::SubscriberEmail;
+use crate::utils::error_chain_fmt;
+
+mod file_spool;
+mod postmark;
+mod smtp;
+
+/// An email ready to be handed over to a transport.
+pub struct Email<'a> {
+    pub from: &'a SubscriberEmail,
+    pub to: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_body: &'a str,
+    pub text_body: &'a str,
+    /// Adds the `List-Unsubscribe` and `List-Unsubscribe-Post` headers for one-click
+    /// unsubscribing (RFC 8058).
+    pub unsubscribe_url: Option<&'a str>,
+}
+
+#[derive(thiserror::Error)]
+pub enum EmailError {
+    /// The message was refused, sending it again will not change the outcome.
+    #[error("The email was rejected: {0:#}")]
+    Rejected(anyhow::Error),
+    /// Timeouts, connection problems, throttling or an outage on the other side.
+    #[error("The email could not be sent: {0:#}")]
+    Transient(anyhow::Error),
+}
+
+impl std::fmt::Debug for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl EmailError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, EmailError::Transient(_))
+    }
+
+    /// A failure that hit a whole batch is reported once for each of its emails.
+    fn for_each_of(self, n_emails: usize) -> Vec<Result<(), EmailError>> {
+        (0..n_emails)
+            .map(|_| {
+                Err(match &self {
+                    EmailError::Rejected(e) => EmailError::Rejected(anyhow::anyhow!("{:#}", e)),
+                    EmailError::Transient(e) => EmailError::Transient(anyhow::anyhow!("{:#}", e)),
+                })
+            })
+            .collect()
+    }
+}
+
+pub trait EmailTransport: Sync {
+    fn send(&self, email: &Email<'_>) -> impl Future<Output = Result<(), EmailError>> + Send;
+
+    /// Returns one result per email, in order. Transports without a batch API send
+    /// the emails one after the other.
+    fn send_batch(
+        &self,
+        emails: &[Email<'_>],
+    ) -> impl Future<Output = Vec<Result<(), EmailError>>> + Send {
+        async move {
+            let mut results = Vec::with_capacity(emails.len());
+            for email in emails {
+                results.push(self.send(email).await);
+            }
+            results
+        }
+    }
+}
+
+/// The transport selected through `EmailClientSettings`.
+#[derive(Clone, Debug)]
+pub enum AppEmailTransport {
+    Postmark(PostmarkTransport),
+    Smtp(SmtpTransport),
+    FileSpool(FileSpoolTransport),
+}
+
+impl EmailTransport for AppEmailTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        match self {
+            Self::Postmark(transport) => transport.send(email).await,
+            Self::Smtp(transport) => transport.send(email).await,
+            Self::FileSpool(transport) => transport.send(email).await,
+        }
+    }
+
+    async fn send_batch(&self, emails: &[Email<'_>]) -> Vec<Result<(), EmailError>> {
+        match self {
+            Self::Postmark(transport) => transport.send_batch(emails).await,
+            Self::Smtp(transport) => transport.send_batch(emails).await,
+            Self::FileSpool(transport) => transport.send_batch(emails).await,
+        }
+    }
+}
+
+/// One email of a batch sent through `EmailClient::send_batch`.
+pub struct OutgoingEmail<'a> {
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_body: &'a str,
+    pub text_body: &'a str,
+    pub unsubscribe_url: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub struct EmailClient {
+    sender: SubscriberEmail,
+    transport: AppEmailTransport,
+}
+
+impl EmailClient {
+    pub fn new(sender: SubscriberEmail, transport: AppEmailTransport) -> Self {
+        Self { sender, transport }
+    }
+
+    /// Passing an `unsubscribe_url` adds the `List-Unsubscribe` and
+    /// `List-Unsubscribe-Post` headers for one-click unsubscribing (RFC 8058).
+    pub async fn send_mail(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_body: &str,
+        text_body: &str,
+        unsubscribe_url: Option<&str>,
+    ) -> Result<(), EmailError> {
+        let email = Email {
+            from: &self.sender,
+            to: recipient,
+            subject,
+            html_body,
+            text_body,
+            unsubscribe_url,
+        };
+        self.transport.send(&email).await
+    }
+
+    /// Sends many emails with as few requests as the transport allows and reports
+    /// the outcome of every single one, in the order they were given.
+    pub async fn send_batch(&self, emails: &[OutgoingEmail<'_>]) -> Vec<Result<(), EmailError>> {
+        let emails: Vec<Email<'_>> = emails
+            .iter()
+            .map(|email| Email {
+                from: &self.sender,
+                to: email.recipient,
+                subject: email.subject,
+                html_body: email.html_body,
+                text_body: email.text_body,
+                unsubscribe_url: email.unsubscribe_url,
+            })
+            .collect();
+        self.transport.send_batch(&emails).await
+    }
+}
+
+/// Builds the MIME message shared by the transports that do not talk to an HTTP API.
+fn build_message(email: &Email<'_>) -> Result<lettre::Message, EmailError> {
+    use lettre::message::header::{HeaderName, HeaderValue};
+    use lettre::message::MultiPart;
+
+    let mut builder = lettre::Message::builder()
+        .from(parse_mailbox(email.from)?)
+        .to(parse_mailbox(email.to)?)
+        .subject(email.subject);
+    if let Some(url) = email.unsubscribe_url {
+        builder = builder
+            .raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe"),
+                format!("<{}>", url),
+            ))
+            .raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+                "List-Unsubscribe=One-Click".into(),
+            ));
+    }
+    builder
+        .multipart(MultiPart::alternative_plain_html(
+            email.text_body.to_string(),
+            email.html_body.to_string(),
+        ))
+        .map_err(|e| EmailError::Rejected(e.into()))
+}
+
+fn parse_mailbox(email: &SubscriberEmail) -> Result<lettre::message::Mailbox, EmailError> {
+    email
+        .as_ref()
+        .parse()
+        .map_err(|e: lettre::address::AddressError| EmailError::Rejected(e.into()))
+}
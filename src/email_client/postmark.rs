@@ -0,0 +1,340 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use reqwest::{Client, StatusCode, Url};
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+
+use crate::email_client::{Email, EmailError, EmailTransport};
+
+/// Postmark accepts at most this many emails in a single batch request.
+const MAX_BATCH_SIZE: usize = 500;
+
+/// Sends through Postmark's JSON API.
+#[derive(Clone, Debug)]
+pub struct PostmarkTransport {
+    client: Client,
+    base_url: Url,
+    auth_token: Secret<String>,
+}
+
+impl PostmarkTransport {
+    pub fn new(base_url: String, auth_token: Secret<String>, timeout: Duration) -> Self {
+        let client = Client::builder().timeout(timeout).build().unwrap();
+        let base_url = Url::parse(&base_url).unwrap();
+        Self {
+            client,
+            base_url,
+            auth_token,
+        }
+    }
+}
+
+impl EmailTransport for PostmarkTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        self.post("email", &SendEmailRequest::from(email)).await?;
+        Ok(())
+    }
+
+    async fn send_batch(&self, emails: &[Email<'_>]) -> Vec<Result<(), EmailError>> {
+        let mut results = Vec::with_capacity(emails.len());
+        for chunk in emails.chunks(MAX_BATCH_SIZE) {
+            let request: Vec<SendEmailRequest> = chunk.iter().map(SendEmailRequest::from).collect();
+            match self.send_chunk(&request).await {
+                Ok(chunk_results) => results.extend(chunk_results),
+                Err(e) => results.extend(e.for_each_of(chunk.len())),
+            }
+        }
+        results
+    }
+}
+
+impl PostmarkTransport {
+    async fn post<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        request: &T,
+    ) -> Result<reqwest::Response, EmailError> {
+        let url = self
+            .base_url
+            .join(path)
+            .expect("Can not build email client base url");
+        self.client
+            .post(url)
+            .json(request)
+            .header("X-Postmark-Server-Token", self.auth_token.expose_secret())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(classify)
+    }
+
+    async fn send_chunk(
+        &self,
+        request: &[SendEmailRequest<'_>],
+    ) -> Result<Vec<Result<(), EmailError>>, EmailError> {
+        let response: Vec<BatchResult> = self
+            .post("email/batch", request)
+            .await?
+            .json()
+            .await
+            .map_err(|e| EmailError::Transient(anyhow!(e)))?;
+        if response.len() != request.len() {
+            // Without a result per email we cannot tell which of them went out.
+            return Err(EmailError::Transient(anyhow!(
+                "Postmark returned [{}] results for a batch of [{}] emails",
+                response.len(),
+                request.len()
+            )));
+        }
+        Ok(response
+            .into_iter()
+            .map(|result| match result.error_code {
+                0 => Ok(()),
+                code => Err(classify_error_code(code, &result.message)),
+            })
+            .collect())
+    }
+}
+
+/// Timeouts, connection failures, throttling and 5xx responses are worth another
+/// attempt, any other rejection by Postmark will not change on its own.
+fn classify(e: reqwest::Error) -> EmailError {
+    match e.status() {
+        Some(status) if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS => {
+            EmailError::Transient(anyhow!(e))
+        }
+        Some(_) => EmailError::Rejected(anyhow!(e)),
+        None => EmailError::Transient(anyhow!(e)),
+    }
+}
+
+/// Maintenance, an account out of credits, throttling and errors on Postmark's side
+/// clear up by themselves. Any other code is about the email or the recipient, such
+/// as 300 for an invalid address or 406 for an inactive recipient.
+fn classify_error_code(code: i64, message: &str) -> EmailError {
+    let e = anyhow!("Postmark error [{}]: {}", code, message);
+    match code {
+        100 | 405 | 429 => EmailError::Transient(e),
+        code if code >= 500 => EmailError::Transient(e),
+        _ => EmailError::Rejected(e),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<EmailHeader<'a>>,
+}
+
+impl<'a> From<&Email<'a>> for SendEmailRequest<'a> {
+    fn from(email: &Email<'a>) -> Self {
+        Self {
+            from: email.from.as_ref(),
+            to: email.to.as_ref(),
+            subject: email.subject,
+            html_body: email.html_body,
+            text_body: email.text_body,
+            headers: email
+                .unsubscribe_url
+                .map(|url| {
+                    vec![
+                        EmailHeader {
+                            name: "List-Unsubscribe",
+                            value: format!("<{}>", url),
+                        },
+                        EmailHeader {
+                            name: "List-Unsubscribe-Post",
+                            value: "List-Unsubscribe=One-Click".into(),
+                        },
+                    ]
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailHeader<'a> {
+    name: &'a str,
+    value: String,
+}
+
+/// Postmark answers a batch with one of these per email, in request order.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResult {
+    error_code: i64,
+    #[serde(default)]
+    message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use claim::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use secrecy::Secret;
+    use serde_json::Value;
+    use wiremock::matchers::{header, header_exists, method, path};
+    use wiremock::{Match, Mock, MockServer, Request, ResponseTemplate};
+
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{AppEmailTransport, EmailClient, PostmarkTransport};
+
+    struct SendEmailBodyMatcher;
+
+    impl Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = result {
+                dbg!(&body);
+                body.get("From").is_some()
+                    && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    fn email_client(base_url: String) -> EmailClient {
+        EmailClient::new(
+            email(),
+            AppEmailTransport::Postmark(PostmarkTransport::new(
+                base_url,
+                Secret::new(Faker.fake()),
+                Duration::from_millis(200),
+            )),
+        )
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_request() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let result = email_client(mock_server.uri())
+            .send_mail(&email(), &subject(), &content(), &content(), None)
+            .await;
+
+        assert_ok!(result);
+    }
+
+    #[tokio::test]
+    async fn send_email_returns_err_if_server_timeouts() {
+        let mock_server = MockServer::start().await;
+
+        let response = ResponseTemplate::new(200).set_delay(Duration::from_secs(180));
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let result = email_client(mock_server.uri())
+            .send_mail(&email(), &subject(), &content(), &content(), None)
+            .await;
+
+        assert_err!(result);
+    }
+
+    #[tokio::test]
+    async fn send_email_returns_err_if_server_returns_500() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let result = email_client(mock_server.uri())
+            .send_mail(&email(), &subject(), &content(), &content(), None)
+            .await;
+
+        assert_err!(result);
+    }
+
+    #[tokio::test]
+    async fn a_4xx_response_is_not_retryable() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let result = email_client(mock_server.uri())
+            .send_mail(&email(), &subject(), &content(), &content(), None)
+            .await;
+
+        assert!(!result.unwrap_err().is_retryable());
+    }
+
+    #[tokio::test]
+    async fn send_email_adds_list_unsubscribe_headers_when_given_a_link() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        email_client(mock_server.uri())
+            .send_mail(
+                &email(),
+                &subject(),
+                &content(),
+                &content(),
+                Some("https://example.com/unsubscribe"),
+            )
+            .await
+            .unwrap();
+
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body["Headers"],
+            serde_json::json!([
+                {"Name": "List-Unsubscribe", "Value": "<https://example.com/unsubscribe>"},
+                {"Name": "List-Unsubscribe-Post", "Value": "List-Unsubscribe=One-Click"},
+            ])
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use secrecy::{ExposeSecret, Secret};
+
+use crate::email_client::{build_message, Email, EmailError, EmailTransport};
+
+/// Sends through a plain SMTP relay, upgrading the connection with STARTTLS when
+/// asked to and authenticating with AUTH PLAIN or LOGIN when credentials are given.
+#[derive(Clone, Debug)]
+pub struct SmtpTransport {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpTransport {
+    pub fn new(
+        host: &str,
+        port: u16,
+        starttls: bool,
+        credentials: Option<(String, Secret<String>)>,
+        timeout: Duration,
+    ) -> Result<Self, anyhow::Error> {
+        let tls = match starttls {
+            true => Tls::Required(TlsParameters::new(host.into())?),
+            false => Tls::None,
+        };
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            .port(port)
+            .tls(tls)
+            .timeout(Some(timeout));
+        if let Some((username, password)) = credentials {
+            builder = builder
+                .credentials(Credentials::new(
+                    username,
+                    password.expose_secret().to_string(),
+                ))
+                .authentication(vec![Mechanism::Plain, Mechanism::Login]);
+        }
+        Ok(Self {
+            mailer: builder.build(),
+        })
+    }
+}
+
+impl EmailTransport for SmtpTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let message = build_message(email)?;
+        self.mailer.send(message).await.map_err(|e| {
+            // 5xx replies are final, everything else (4xx, I/O, timeouts) may pass later.
+            match e.is_permanent() {
+                true => EmailError::Rejected(anyhow!(e)),
+                false => EmailError::Transient(anyhow!(e)),
+            }
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{AppEmailTransport, EmailClient, SmtpTransport};
+
+    /// Accepts a single SMTP session, answering every command with `rcpt_reply` for
+    /// RCPT TO and a success code otherwise, and returns the received DATA.
+    async fn fake_smtp_server(rcpt_reply: &'static str) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut data = String::new();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let command = line.to_uppercase();
+                let reply = if command.starts_with("EHLO") {
+                    "250 localhost\r\n"
+                } else if command.starts_with("RCPT") {
+                    rcpt_reply
+                } else if command.starts_with("DATA") {
+                    writer.write_all(b"354 go ahead\r\n").await.unwrap();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if line == "." {
+                            break;
+                        }
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    "250 queued\r\n"
+                } else if command.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    "250 ok\r\n"
+                };
+                writer.write_all(reply.as_bytes()).await.unwrap();
+            }
+            data
+        });
+        (port, handle)
+    }
+
+    fn email_client(port: u16) -> EmailClient {
+        EmailClient::new(
+            SubscriberEmail::parse("sender@example.com".into()).unwrap(),
+            AppEmailTransport::Smtp(
+                SmtpTransport::new("127.0.0.1", port, false, None, Duration::from_secs(2)).unwrap(),
+            ),
+        )
+    }
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("recipient@example.com".into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_email_transfers_the_message_over_smtp() {
+        let (port, server) = fake_smtp_server("250 ok\r\n").await;
+
+        email_client(port)
+            .send_mail(
+                &recipient(),
+                "Greetings",
+                "<p>Hello</p>",
+                "Hello",
+                Some("https://example.com/unsubscribe"),
+            )
+            .await
+            .unwrap();
+
+        let data = server.await.unwrap();
+        assert!(data.contains("Subject: Greetings"));
+        assert!(data.contains("List-Unsubscribe: <https://example.com/unsubscribe>"));
+        assert!(data.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+    }
+
+    #[tokio::test]
+    async fn a_permanent_smtp_failure_is_not_retryable() {
+        let (port, _server) = fake_smtp_server("550 no such user\r\n").await;
+
+        let result = email_client(port)
+            .send_mail(&recipient(), "Greetings", "<p>Hello</p>", "Hello", None)
+            .await;
+
+        assert!(!result.unwrap_err().is_retryable());
+    }
+
+    #[tokio::test]
+    async fn a_temporary_smtp_failure_is_retryable() {
+        let (port, _server) = fake_smtp_server("451 try again later\r\n").await;
+
+        let result = email_client(port)
+            .send_mail(&recipient(), "Greetings", "<p>Hello</p>", "Hello", None)
+            .await;
+
+        assert!(result.unwrap_err().is_retryable());
+    }
+}
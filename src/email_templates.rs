@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use anyhow::Context;
+use fluent_bundle::{FluentArgs, FluentValue};
+use tera::{Tera, Value};
+
+use crate::localization::MessageCatalog;
+
+pub use tera::Context as TemplateContext;
+
+/// The HTML and plain-text bodies of an email.
+pub struct RenderedEmail {
+    pub html: String,
+    pub text: String,
+}
+
+/// Email bodies rendered from `emails/<name>.html` and `emails/<name>.txt` in the
+/// templates directory. Values interpolated into the HTML variant are escaped.
+///
+/// Templates look up their wording with `{{ t(key="message-id", lang=lang) }}`,
+/// any other argument is passed on to the Fluent message.
+#[derive(Clone)]
+pub struct EmailTemplates {
+    tera: Arc<RwLock<Tera>>,
+    catalog: Arc<RwLock<MessageCatalog>>,
+    locales_directory: String,
+    default_language: String,
+    hot_reload: bool,
+}
+
+impl EmailTemplates {
+    /// Compiles every template and message up front so that a broken one stops the
+    /// application from starting rather than failing the first email that uses it.
+    pub fn load(
+        directory: &str,
+        locales_directory: &str,
+        default_language: &str,
+        hot_reload: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let catalog = Arc::new(RwLock::new(MessageCatalog::load(
+            locales_directory,
+            default_language,
+        )?));
+        let mut tera = Tera::new(&format!("{}/**/*", directory))
+            .with_context(|| format!("Failed to load the templates in [{}]", directory))?;
+        tera.set_escape_fn(escape_html);
+        tera.register_function(
+            "t",
+            Translate {
+                catalog: catalog.clone(),
+            },
+        );
+        Ok(Self {
+            tera: Arc::new(RwLock::new(tera)),
+            catalog,
+            locales_directory: locales_directory.into(),
+            default_language: default_language.into(),
+            hot_reload,
+        })
+    }
+
+    /// Renders both variants in the best supported match for `language`, see
+    /// `MessageCatalog::fallback_chain`.
+    pub fn render(
+        &self,
+        name: &str,
+        language: Option<&str>,
+        context: &TemplateContext,
+    ) -> Result<RenderedEmail, anyhow::Error> {
+        self.reload_if_enabled()?;
+        let mut context = context.clone();
+        context.insert("lang", &self.resolve_language(language));
+        let tera = self.tera.read().unwrap();
+        let html = tera
+            .render(&format!("emails/{}.html", name), &context)
+            .with_context(|| format!("Failed to render the [{}] HTML template", name))?;
+        let text = tera
+            .render(&format!("emails/{}.txt", name), &context)
+            .with_context(|| format!("Failed to render the [{}] text template", name))?;
+        Ok(RenderedEmail { html, text })
+    }
+
+    /// A single message from the catalog, for the parts of an email that are not
+    /// rendered from a template such as its subject.
+    pub fn message(&self, language: Option<&str>, id: &str) -> Result<String, anyhow::Error> {
+        self.reload_if_enabled()?;
+        self.catalog.read().unwrap().format(language, id, None)
+    }
+
+    fn resolve_language(&self, language: Option<&str>) -> String {
+        self.catalog.read().unwrap().fallback_chain(language)[0].to_string()
+    }
+
+    fn reload_if_enabled(&self) -> Result<(), anyhow::Error> {
+        if self.hot_reload {
+            self.tera
+                .write()
+                .unwrap()
+                .full_reload()
+                .context("Failed to reload the templates")?;
+            *self.catalog.write().unwrap() =
+                MessageCatalog::load(&self.locales_directory, &self.default_language)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `t` function available in every template.
+struct Translate {
+    catalog: Arc<RwLock<MessageCatalog>>,
+}
+
+impl tera::Function for Translate {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let id = args
+            .get("key")
+            .and_then(Value::as_str)
+            .ok_or("`t` needs a `key` argument")?;
+        let language = args.get("lang").and_then(Value::as_str);
+        let mut fluent_args = FluentArgs::new();
+        for (name, value) in args {
+            if name == "key" || name == "lang" {
+                continue;
+            }
+            let value = match value {
+                Value::Number(n) => FluentValue::from(n.as_f64().unwrap_or_default()),
+                Value::String(s) => FluentValue::from(s.as_str()),
+                other => FluentValue::from(other.to_string()),
+            };
+            fluent_args.set(name.as_str(), value);
+        }
+        self.catalog
+            .read()
+            .unwrap()
+            .format(language, id, Some(&fluent_args))
+            .map(Value::String)
+            .map_err(|e| tera::Error::msg(format!("{:#}", e)))
+    }
+}
+
+/// Tera's default escaper also encodes `/` as `&#x2F;`, which mangles every link
+/// interpolated into a template.
+fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::email_templates::{EmailTemplates, TemplateContext};
+
+    fn templates() -> EmailTemplates {
+        EmailTemplates::load("templates", "locales", "en", false).unwrap()
+    }
+
+    #[test]
+    fn interpolated_values_are_escaped_in_html_only() {
+        let mut context = TemplateContext::new();
+        context.insert("name", "<script>alert(1)</script>");
+        context.insert("confirmation_link", "http://127.0.0.1/confirm");
+
+        let email = templates().render("confirmation", None, &context).unwrap();
+
+        assert!(!email.html.contains("<script>"));
+        assert!(email.html.contains("&lt;script&gt;"));
+        assert!(email.text.contains("<script>alert(1)</script>"));
+    }
+
+    #[test]
+    fn newsletter_content_is_embedded_as_html() {
+        let mut context = TemplateContext::new();
+        context.insert("title", "Issue #1");
+        context.insert("html_content", "<p>Hello</p>");
+        context.insert("text_content", "Hello");
+        context.insert("unsubscribe_link", "http://127.0.0.1/unsubscribe");
+        context.insert("personal_data_link", "http://127.0.0.1/data");
+        context.insert("preferences_link", "http://127.0.0.1/preferences");
+
+        let email = templates().render("newsletter", None, &context).unwrap();
+
+        assert!(email.html.contains("<p>Hello</p>"));
+        assert!(email.text.starts_with("Hello"));
+        assert!(email.html.contains("http://127.0.0.1/unsubscribe"));
+        assert!(email
+            .text
+            .contains("Unsubscribe: http://127.0.0.1/unsubscribe"));
+    }
+
+    #[test]
+    fn emails_are_rendered_in_the_requested_language() {
+        let mut context = TemplateContext::new();
+        context.insert("name", "Ursula");
+
+        let email = templates()
+            .render("welcome", Some("de-AT"), &context)
+            .unwrap();
+
+        assert!(email.html.contains(r#"<html lang="de">"#));
+        assert!(email.html.contains("Danke"));
+        assert!(email.text.contains("Danke"));
+    }
+
+    #[test]
+    fn rendering_an_unknown_template_fails() {
+        assert!(templates()
+            .render("does-not-exist", None, &TemplateContext::new())
+            .is_err());
+    }
+}
@@ -0,0 +1,54 @@
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    const MAX_LENGTH: usize = 50;
+
+    pub fn parse(key: String) -> Result<Self, String> {
+        if key.trim().is_empty() {
+            return Err("The idempotency key cannot be empty".to_string());
+        }
+        if key.len() >= Self::MAX_LENGTH {
+            return Err(format!(
+                "The idempotency key must be shorter than {} characters",
+                Self::MAX_LENGTH
+            ));
+        }
+        Ok(Self(key))
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use crate::idempotency::IdempotencyKey;
+
+    #[test]
+    fn empty_key_is_invalid() {
+        assert_err!(IdempotencyKey::parse("".to_string()));
+    }
+
+    #[test]
+    fn whitespace_key_is_invalid() {
+        assert_err!(IdempotencyKey::parse("   ".to_string()));
+    }
+
+    #[test]
+    fn a_key_of_50_characters_is_invalid() {
+        assert_err!(IdempotencyKey::parse("a".repeat(50)));
+    }
+
+    #[test]
+    fn a_uuid_is_a_valid_key() {
+        assert_ok!(IdempotencyKey::parse(
+            "0a4ad7e4-4b2b-46e1-9a1e-0b1e5bc6d3a6".to_string()
+        ));
+    }
+}
@@ -0,0 +1,661 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{PgExecutor, PgPool};
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::digest::send_due_digests;
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, EmailError, OutgoingEmail};
+use crate::email_templates::EmailTemplates;
+use crate::issue_scheduler::enqueue_due_issues;
+use crate::newsletter_issues::{get_issue_content, render_issue, ListIssue};
+use crate::subscriber_links::SubscriberLinkSigner;
+
+pub struct IssueDeliveryWorker {
+    db_pool: PgPool,
+    email_client: EmailClient,
+    poll_interval: Duration,
+    retry_policy: RetryPolicy,
+    subscriber_links: SubscriberLinkSigner,
+    email_templates: EmailTemplates,
+}
+
+pub enum ExecutionOutcome {
+    TasksProcessed(DeliverySummary),
+    EmptyQueue,
+}
+
+/// What happened to the tasks of one batch.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DeliverySummary {
+    pub delivered: usize,
+    pub retry_scheduled: usize,
+    pub dead_lettered: usize,
+    /// Invalid or no longer confirmed recipients, dropped without sending.
+    pub skipped: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before the next attempt: doubles with every failed attempt up to
+    /// `max_delay`, the upper half is randomised so retries of one outage spread out.
+    pub fn retry_delay(&self, n_attempts: u32) -> Duration {
+        let exponent = n_attempts.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.pow(exponent))
+            .min(self.max_delay);
+        let half = delay / 2;
+        half + half.mul_f64(thread_rng().gen::<f64>())
+    }
+}
+
+struct DeliveryTask {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    n_attempts: i32,
+}
+
+impl IssueDeliveryWorker {
+    pub fn new(
+        db_pool: PgPool,
+        email_client: EmailClient,
+        poll_interval: Duration,
+        retry_policy: RetryPolicy,
+        subscriber_links: SubscriberLinkSigner,
+        email_templates: EmailTemplates,
+    ) -> Self {
+        Self {
+            db_pool,
+            email_client,
+            poll_interval,
+            retry_policy,
+            subscriber_links,
+            email_templates,
+        }
+    }
+
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        loop {
+            // Failures are logged by all three, the next iteration retries.
+            let _ = enqueue_due_issues(&self.db_pool).await;
+            let _ = mark_sent_issues(&self.db_pool).await;
+            let _ = send_due_digests(
+                &self.db_pool,
+                &self.email_client,
+                &self.subscriber_links,
+                &self.email_templates,
+            )
+            .await;
+            match try_execute_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.retry_policy,
+                &self.subscriber_links,
+                &self.email_templates,
+            )
+            .await
+            {
+                Ok(ExecutionOutcome::EmptyQueue) => tokio::time::sleep(self.poll_interval).await,
+                Ok(_) => {}
+                Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+            }
+        }
+    }
+}
+
+/// Deliveries picked up per run, the email client splits them further if the
+/// provider accepts fewer emails per request.
+const MAX_TASKS_PER_BATCH: i64 = 500;
+
+/// How long a claimed batch stays with its worker. The lease is renewed while the
+/// batch is being sent, deliveries of a worker that died are picked up again once
+/// their lease runs out.
+const DELIVERY_LEASE: Duration = Duration::from_secs(10 * 60);
+
+/// A delivery that passed all checks and is about to be handed to the email client.
+struct PreparedDelivery {
+    task: DeliveryTask,
+    recipient: SubscriberEmail,
+    subject: String,
+    unsubscribe_link: String,
+    html_body: String,
+    text_body: String,
+}
+
+struct ConfirmedSubscriber {
+    id: Uuid,
+    language: Option<String>,
+}
+
+/// Claims a batch of due deliveries, sends them and records the outcome of each in
+/// its own transaction. No lock is held while sending, so a database error after the
+/// batch went out only affects the deliveries whose outcome could not be recorded.
+#[tracing::instrument(
+    name = "Deliver a batch of queued newsletter issues",
+    skip_all,
+    fields(n_tasks = tracing::field::Empty),
+    err
+)]
+pub async fn try_execute_task(
+    db_pool: &PgPool,
+    email_client: &EmailClient,
+    retry_policy: &RetryPolicy,
+    subscriber_links: &SubscriberLinkSigner,
+    email_templates: &EmailTemplates,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let tasks = claim_tasks(db_pool, MAX_TASKS_PER_BATCH, DELIVERY_LEASE).await?;
+    if tasks.is_empty() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+    Span::current().record("n_tasks", tasks.len());
+    let mut summary = DeliverySummary::default();
+
+    let confirmed = get_confirmed_subscribers(db_pool, &tasks).await?;
+    let mut issues: HashMap<Uuid, Option<ListIssue>> = HashMap::new();
+    let mut deliveries = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        let recipient = match SubscriberEmail::parse(task.subscriber_email.clone()) {
+            Ok(recipient) => recipient,
+            Err(e) => {
+                tracing::warn!(
+                    "Skipping a confirmed subscriber, the stored contact details are invalid: [{}]",
+                    e
+                );
+                skip_task(db_pool, &task, &mut summary).await;
+                continue;
+            }
+        };
+        let key = (task.newsletter_issue_id, task.subscriber_email.clone());
+        let subscriber = match confirmed.get(&key) {
+            Some(subscriber) => subscriber,
+            None => {
+                tracing::info!("Skipping a subscriber who is no longer confirmed");
+                skip_task(db_pool, &task, &mut summary).await;
+                continue;
+            }
+        };
+        if let Entry::Vacant(entry) = issues.entry(task.newsletter_issue_id) {
+            match get_issue_content(db_pool, task.newsletter_issue_id).await {
+                Ok(issue) => entry.insert(issue),
+                // The delivery stays leased and is sent again once the lease runs out.
+                Err(e) => {
+                    tracing::error!(
+                        newsletter_issue_id = %task.newsletter_issue_id,
+                        "Failed to load the newsletter issue of a delivery cause: [{:?}]",
+                        e
+                    );
+                    continue;
+                }
+            };
+        }
+        let rendered = match &issues[&task.newsletter_issue_id] {
+            Some(issue) => render_issue(
+                email_templates,
+                subscriber_links,
+                issue,
+                subscriber.id,
+                subscriber.language.as_deref(),
+            )
+            .map(|body| (issue.content.title.clone(), issue.list_id, body)),
+            None => Err(anyhow::anyhow!(
+                "Newsletter issue [{}] does not exist",
+                task.newsletter_issue_id
+            )),
+        };
+        let (subject, list_id, body) = match rendered {
+            Ok(rendered) => rendered,
+            // Counted as an attempt, a broken template may be fixed before they run out.
+            Err(e) => {
+                let e = EmailError::Transient(e.context("Failed to render the newsletter issue"));
+                match record_failed_attempt(db_pool, &task, e, retry_policy).await {
+                    Ok(FailedAttempt::RetryScheduled) => summary.retry_scheduled += 1,
+                    Ok(FailedAttempt::DeadLettered) => summary.dead_lettered += 1,
+                    Err(e) => tracing::error!(
+                        newsletter_issue_id = %task.newsletter_issue_id,
+                        "Failed to record the outcome of a delivery cause: [{:?}]",
+                        e
+                    ),
+                }
+                continue;
+            }
+        };
+        let unsubscribe_link = subscriber_links.unsubscribe_link(subscriber.id, list_id);
+        deliveries.push(PreparedDelivery {
+            html_body: body.html,
+            text_body: body.text,
+            task,
+            recipient,
+            subject,
+            unsubscribe_link,
+        });
+    }
+
+    let outgoing: Vec<OutgoingEmail> = deliveries
+        .iter()
+        .map(|delivery| OutgoingEmail {
+            recipient: &delivery.recipient,
+            subject: &delivery.subject,
+            html_body: &delivery.html_body,
+            text_body: &delivery.text_body,
+            unsubscribe_url: Some(&delivery.unsubscribe_link),
+        })
+        .collect();
+    let results = send_with_lease_renewal(db_pool, email_client, &deliveries, &outgoing).await;
+    for (delivery, result) in deliveries.iter().zip(results) {
+        let recorded = match result {
+            Ok(()) => delete_task(db_pool, &delivery.task)
+                .await
+                .map(|_| summary.delivered += 1)
+                .map_err(anyhow::Error::from),
+            Err(e) => record_failed_attempt(db_pool, &delivery.task, e, retry_policy)
+                .await
+                .map(|attempt| match attempt {
+                    FailedAttempt::RetryScheduled => summary.retry_scheduled += 1,
+                    FailedAttempt::DeadLettered => summary.dead_lettered += 1,
+                }),
+        };
+        // The delivery stays leased and is sent again once the lease runs out.
+        if let Err(e) = recorded {
+            tracing::error!(
+                newsletter_issue_id = %delivery.task.newsletter_issue_id,
+                "Failed to record the outcome of a delivery cause: [{:?}]",
+                e
+            );
+        }
+    }
+    Ok(ExecutionOutcome::TasksProcessed(summary))
+}
+
+/// Sends the batch and renews the lease of its deliveries every half lease until it
+/// is done, so that no other worker picks them up while they are still being sent.
+async fn send_with_lease_renewal(
+    db_pool: &PgPool,
+    email_client: &EmailClient,
+    deliveries: &[PreparedDelivery],
+    outgoing: &[OutgoingEmail<'_>],
+) -> Vec<Result<(), EmailError>> {
+    let send = email_client.send_batch(outgoing);
+    tokio::pin!(send);
+    let mut renewal = tokio::time::interval_at(
+        tokio::time::Instant::now() + DELIVERY_LEASE / 2,
+        DELIVERY_LEASE / 2,
+    );
+    loop {
+        tokio::select! {
+            results = &mut send => return results,
+            _ = renewal.tick() => {
+                if let Err(e) = renew_lease(db_pool, deliveries, DELIVERY_LEASE).await {
+                    tracing::error!("Failed to renew the lease of a batch cause: [{:?}]", e);
+                }
+            }
+        }
+    }
+}
+
+#[tracing::instrument(name = "Renew the lease of a batch of delivery tasks", skip_all)]
+async fn renew_lease(
+    db_pool: &PgPool,
+    deliveries: &[PreparedDelivery],
+    lease: Duration,
+) -> Result<(), sqlx::Error> {
+    let issue_ids: Vec<Uuid> = deliveries
+        .iter()
+        .map(|d| d.task.newsletter_issue_id)
+        .collect();
+    let emails: Vec<String> = deliveries
+        .iter()
+        .map(|d| d.task.subscriber_email.clone())
+        .collect();
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET leased_until = now() + make_interval(secs => $3)
+        WHERE (newsletter_issue_id, subscriber_email) IN (
+            SELECT * FROM UNNEST($1::uuid[], $2::text[])
+        )
+        "#,
+        &issue_ids[..],
+        &emails[..],
+        lease.as_secs_f64()
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// Drops a delivery that will never be sent. When that fails the delivery stays
+/// leased and is looked at again once the lease runs out.
+async fn skip_task(db_pool: &PgPool, task: &DeliveryTask, summary: &mut DeliverySummary) {
+    match delete_task(db_pool, task).await {
+        Ok(()) => summary.skipped += 1,
+        Err(e) => tracing::error!(
+            newsletter_issue_id = %task.newsletter_issue_id,
+            "Failed to drop a delivery that will not be sent cause: [{:?}]",
+            e
+        ),
+    }
+}
+
+enum FailedAttempt {
+    RetryScheduled,
+    DeadLettered,
+}
+
+#[tracing::instrument(
+    name = "Record a failed delivery attempt",
+    skip_all,
+    fields(
+        newsletter_issue_id = %task.newsletter_issue_id,
+        subscriber_email = %task.subscriber_email
+    )
+)]
+async fn record_failed_attempt(
+    db_pool: &PgPool,
+    task: &DeliveryTask,
+    e: EmailError,
+    retry_policy: &RetryPolicy,
+) -> Result<FailedAttempt, anyhow::Error> {
+    let n_attempts = task.n_attempts + 1;
+    let last_error = e.to_string();
+
+    if !e.is_retryable() || n_attempts as u32 >= retry_policy.max_attempts {
+        tracing::error!(
+            "Giving up on delivery after [{}] attempts cause: [{:?}]",
+            n_attempts,
+            e
+        );
+        move_to_dead_letters(db_pool, task, n_attempts, &last_error).await?;
+        return Ok(FailedAttempt::DeadLettered);
+    }
+
+    let execute_after =
+        Utc::now() + chrono::Duration::from_std(retry_policy.retry_delay(n_attempts as u32))?;
+    tracing::warn!(
+        "Delivery attempt [{}] failed, retrying after [{}] cause: [{:?}]",
+        n_attempts,
+        execute_after,
+        e
+    );
+    schedule_retry(db_pool, task, n_attempts, execute_after, &last_error).await?;
+    Ok(FailedAttempt::RetryScheduled)
+}
+
+/// Leases a batch of due deliveries to this worker, committed right away so that no
+/// lock is held while they are sent.
+#[tracing::instrument(name = "Claim a batch of delivery tasks", skip_all)]
+async fn claim_tasks(
+    db_pool: &PgPool,
+    limit: i64,
+    lease: Duration,
+) -> Result<Vec<DeliveryTask>, sqlx::Error> {
+    sqlx::query_as!(
+        DeliveryTask,
+        r#"
+        UPDATE issue_delivery_queue
+        SET leased_until = now() + make_interval(secs => $2)
+        WHERE (newsletter_issue_id, subscriber_email) IN (
+            SELECT newsletter_issue_id, subscriber_email
+            FROM issue_delivery_queue
+            WHERE execute_after <= now()
+              AND (leased_until IS NULL OR leased_until <= now())
+            ORDER BY execute_after
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT $1
+        )
+        RETURNING newsletter_issue_id, subscriber_email, n_attempts
+        "#,
+        limit,
+        lease.as_secs_f64()
+    )
+    .fetch_all(db_pool)
+    .await
+}
+
+/// The recipients that are still confirmed, both as an address and as members of the
+/// list their issue was published to, keyed by issue and email.
+#[tracing::instrument(name = "Get confirmed subscribers by email", skip_all)]
+async fn get_confirmed_subscribers(
+    db_pool: &PgPool,
+    tasks: &[DeliveryTask],
+) -> Result<HashMap<(Uuid, String), ConfirmedSubscriber>, sqlx::Error> {
+    let issue_ids: Vec<Uuid> = tasks.iter().map(|t| t.newsletter_issue_id).collect();
+    let emails: Vec<String> = tasks.iter().map(|t| t.subscriber_email.clone()).collect();
+    let rows = sqlx::query!(
+        r#"
+        SELECT newsletter_issues.newsletter_issue_id, subscriptions.id,
+               subscriptions.email, subscriptions.language
+        FROM UNNEST($1::uuid[], $2::text[]) AS task(newsletter_issue_id, email)
+        JOIN newsletter_issues ON newsletter_issues.newsletter_issue_id = task.newsletter_issue_id
+        JOIN subscriptions ON subscriptions.email = task.email
+        JOIN list_memberships ON list_memberships.subscriber_id = subscriptions.id
+            AND list_memberships.list_id = newsletter_issues.list_id
+        WHERE subscriptions.status = 'confirmed' AND list_memberships.status = 'confirmed'
+        "#,
+        &issue_ids[..],
+        &emails[..]
+    )
+    .fetch_all(db_pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| {
+            let subscriber = ConfirmedSubscriber {
+                id: r.id,
+                language: r.language,
+            };
+            ((r.newsletter_issue_id, r.email), subscriber)
+        })
+        .collect())
+}
+
+#[tracing::instrument(name = "Delete a completed delivery task", skip_all)]
+async fn delete_task(
+    executor: impl PgExecutor<'_>,
+    task: &DeliveryTask,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Schedule a delivery retry", skip_all)]
+async fn schedule_retry(
+    db_pool: &PgPool,
+    task: &DeliveryTask,
+    n_attempts: i32,
+    execute_after: DateTime<Utc>,
+    last_error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET n_attempts = $3, execute_after = $4, last_error = $5, leased_until = NULL
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        n_attempts,
+        execute_after,
+        last_error
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// Only a delivery that is still queued is dead-lettered, one that was erased with
+/// the subscriber's personal data while it was being sent stays gone.
+#[tracing::instrument(name = "Move a delivery task to the dead letters", skip_all)]
+async fn move_to_dead_letters(
+    db_pool: &PgPool,
+    task: &DeliveryTask,
+    n_attempts: i32,
+    last_error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        WITH deleted AS (
+            DELETE FROM issue_delivery_queue
+            WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+            RETURNING newsletter_issue_id, subscriber_email
+        )
+        INSERT INTO issue_delivery_dead_letters
+            (newsletter_issue_id, subscriber_email, n_attempts, last_error, failed_at)
+        SELECT newsletter_issue_id, subscriber_email, $3, $4, now()
+        FROM deleted
+        ON CONFLICT (newsletter_issue_id, subscriber_email) DO UPDATE
+        SET n_attempts = EXCLUDED.n_attempts,
+            last_error = EXCLUDED.last_error,
+            failed_at  = EXCLUDED.failed_at
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        n_attempts,
+        last_error
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+/// A delivery that was given up on, as listed for the operators.
+#[derive(serde::Serialize)]
+pub struct DeadLetter {
+    pub newsletter_issue_id: Uuid,
+    pub subscriber_email: String,
+    pub n_attempts: i32,
+    pub last_error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// The dead-lettered deliveries of one newsletter issue or of all of them, most
+/// recent failures first.
+#[tracing::instrument(name = "Get dead-lettered deliveries", skip(db_pool))]
+pub async fn get_dead_letters(
+    db_pool: &PgPool,
+    newsletter_issue_id: Option<Uuid>,
+) -> Result<Vec<DeadLetter>, sqlx::Error> {
+    sqlx::query_as!(
+        DeadLetter,
+        r#"
+        SELECT newsletter_issue_id, subscriber_email, n_attempts, last_error, failed_at
+        FROM issue_delivery_dead_letters
+        WHERE $1::uuid IS NULL OR newsletter_issue_id = $1
+        ORDER BY failed_at DESC, subscriber_email
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_all(db_pool)
+    .await
+}
+
+/// Puts dead-lettered deliveries back on the queue with a fresh attempt budget,
+/// either for a single newsletter issue or for all of them.
+#[tracing::instrument(name = "Re-drive dead-lettered deliveries", skip(db_pool))]
+pub async fn redrive_dead_letters(
+    db_pool: &PgPool,
+    newsletter_issue_id: Option<Uuid>,
+) -> Result<u64, sqlx::Error> {
+    // The issues go back to sending until the redriven deliveries are done.
+    let redriven = sqlx::query_scalar!(
+        r#"
+        WITH redriven AS (
+            DELETE FROM issue_delivery_dead_letters
+            WHERE $1::uuid IS NULL OR newsletter_issue_id = $1
+            RETURNING newsletter_issue_id, subscriber_email
+        ), requeued AS (
+            INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+            SELECT newsletter_issue_id, subscriber_email FROM redriven
+            ON CONFLICT DO NOTHING
+            RETURNING newsletter_issue_id
+        ), reopened AS (
+            UPDATE newsletter_issues SET status = 'sending', sent_at = NULL
+            WHERE newsletter_issue_id IN (SELECT newsletter_issue_id FROM requeued)
+        )
+        SELECT COUNT(*) AS "count!" FROM requeued
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_one(db_pool)
+    .await?;
+    Ok(redriven as u64)
+}
+
+/// Issues are sent once the last of their deliveries has left the queue, delivered or
+/// dead-lettered.
+#[tracing::instrument(name = "Mark the sent newsletter issues", skip(db_pool), err)]
+async fn mark_sent_issues(db_pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET status = 'sent', sent_at = now()
+        WHERE status = 'sending'
+          AND NOT EXISTS (
+              SELECT 1 FROM issue_delivery_queue
+              WHERE issue_delivery_queue.newsletter_issue_id = newsletter_issues.newsletter_issue_id
+          )
+        "#
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::issue_delivery_worker::RetryPolicy;
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(300),
+        }
+    }
+
+    #[test]
+    fn first_retry_waits_between_half_and_full_base_delay() {
+        for _ in 0..100 {
+            let delay = retry_policy().retry_delay(1);
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn retry_delay_doubles_with_every_attempt() {
+        for _ in 0..100 {
+            let delay = retry_policy().retry_delay(3);
+            assert!(delay >= Duration::from_secs(20) && delay <= Duration::from_secs(40));
+        }
+    }
+
+    #[test]
+    fn retry_delay_is_capped_at_max_delay() {
+        for n_attempts in [6, 20, u32::MAX] {
+            let delay = retry_policy().retry_delay(n_attempts);
+            assert!(delay >= Duration::from_secs(150) && delay <= Duration::from_secs(300));
+        }
+    }
+}
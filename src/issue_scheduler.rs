@@ -0,0 +1,382 @@
+use chrono::{Duration, FixedOffset, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::domain::SubscriberTimezone;
+use crate::newsletter_issues::IssueChange;
+
+/// When a newsletter issue goes out. With `local_send_at` every subscriber who set a
+/// timezone gets the issue at that wall-clock time in their own timezone, everybody
+/// else at `scheduled_at`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    pub scheduled_at: DateTime<Utc>,
+    pub local_send_at: Option<NaiveDateTime>,
+}
+
+impl Schedule {
+    /// `scheduled_at` keeps the offset it was written with, its wall-clock time is the
+    /// one used in the subscribers' timezones.
+    pub fn new(scheduled_at: DateTime<FixedOffset>, in_subscriber_timezone: bool) -> Self {
+        Self {
+            scheduled_at: scheduled_at.with_timezone(&Utc),
+            local_send_at: in_subscriber_timezone.then(|| scheduled_at.naive_local()),
+        }
+    }
+
+    /// A wall-clock time in the editor's timezone, as entered in the admin form.
+    pub fn from_local(local: NaiveDateTime, timezone: Tz, in_subscriber_timezone: bool) -> Self {
+        Self {
+            scheduled_at: local_time_in(timezone, local),
+            local_send_at: in_subscriber_timezone.then_some(local),
+        }
+    }
+}
+
+/// The instant `local` happens in `timezone`. A wall-clock time skipped by a daylight
+/// saving change happens an hour later, a repeated one the first time round.
+pub fn local_time_in(timezone: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            timezone
+                .from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|instant| instant.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&local))
+}
+
+/// An issue waiting for its time, as listed for the editors.
+#[derive(Serialize)]
+pub struct ScheduledIssue {
+    pub newsletter_issue_id: Uuid,
+    pub title: String,
+    pub list: String,
+    pub scheduled_at: DateTime<Utc>,
+    pub local_send_at: Option<NaiveDateTime>,
+}
+
+#[tracing::instrument(name = "Get the scheduled newsletter issues", skip(db_pool))]
+pub async fn get_scheduled_issues(db_pool: &PgPool) -> Result<Vec<ScheduledIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        ScheduledIssue,
+        r#"
+        SELECT newsletter_issue_id, title, lists.slug AS list,
+               scheduled_at AS "scheduled_at!", local_send_at
+        FROM newsletter_issues
+        JOIN lists ON lists.id = newsletter_issues.list_id
+        WHERE status = 'scheduled'
+        ORDER BY scheduled_at
+        "#
+    )
+    .fetch_all(db_pool)
+    .await
+}
+
+#[tracing::instrument(name = "Schedule a newsletter issue", skip(transaction))]
+pub async fn schedule_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    issue_id: Uuid,
+    schedule: &Schedule,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET status = 'scheduled', scheduled_at = $2, local_send_at = $3
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id,
+        schedule.scheduled_at,
+        schedule.local_send_at
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Schedules a draft, or moves a scheduled issue to another time.
+#[tracing::instrument(name = "Reschedule a newsletter issue", skip(db_pool))]
+pub async fn reschedule_issue(
+    db_pool: &PgPool,
+    issue_id: Uuid,
+    schedule: &Schedule,
+) -> Result<IssueChange, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET status = 'scheduled', scheduled_at = $2, local_send_at = $3
+        WHERE newsletter_issue_id = $1 AND status IN ('draft', 'scheduled')
+        "#,
+        issue_id,
+        schedule.scheduled_at,
+        schedule.local_send_at
+    )
+    .execute(db_pool)
+    .await?;
+    schedule_change(db_pool, issue_id, result.rows_affected()).await
+}
+
+/// Turns a scheduled issue back into a draft and records when that happened.
+#[tracing::instrument(name = "Cancel a newsletter issue", skip(db_pool))]
+pub async fn cancel_issue(db_pool: &PgPool, issue_id: Uuid) -> Result<IssueChange, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'draft', scheduled_at = NULL, local_send_at = NULL, cancelled_at = now()
+        WHERE newsletter_issue_id = $1 AND status = 'scheduled'
+        "#,
+        issue_id
+    )
+    .execute(db_pool)
+    .await?;
+    schedule_change(db_pool, issue_id, result.rows_affected()).await
+}
+
+/// Queues the deliveries of a draft or scheduled issue right away.
+#[tracing::instrument(name = "Send a newsletter issue now", skip(db_pool))]
+pub async fn send_issue(db_pool: &PgPool, issue_id: Uuid) -> Result<IssueChange, sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+    let result = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET scheduled_at = NULL, local_send_at = NULL
+        WHERE newsletter_issue_id = $1 AND status IN ('draft', 'scheduled')
+        "#,
+        issue_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    if result.rows_affected() == 0 {
+        return schedule_change(db_pool, issue_id, 0).await;
+    }
+    enqueue_delivery_tasks(&mut transaction, issue_id).await?;
+    transaction.commit().await?;
+    Ok(IssueChange::Applied)
+}
+
+async fn schedule_change(
+    db_pool: &PgPool,
+    issue_id: Uuid,
+    rows_affected: u64,
+) -> Result<IssueChange, sqlx::Error> {
+    if rows_affected > 0 {
+        return Ok(IssueChange::Applied);
+    }
+    let exists = sqlx::query!(
+        r#"SELECT newsletter_issue_id FROM newsletter_issues WHERE newsletter_issue_id = $1"#,
+        issue_id
+    )
+    .fetch_optional(db_pool)
+    .await?
+    .is_some();
+    Ok(if exists {
+        IssueChange::NotAllowed
+    } else {
+        IssueChange::UnknownIssue
+    })
+}
+
+/// Queues the deliveries of every issue whose time has come. An issue sent in the
+/// subscribers' timezones is queued as soon as its time comes in the earliest
+/// timezone one of its recipients lives in, each delivery then waits for its own
+/// time in the queue. Returns the number of queued issues.
+#[tracing::instrument(name = "Enqueue the due newsletter issues", skip(db_pool), err)]
+pub async fn enqueue_due_issues(db_pool: &PgPool) -> Result<usize, anyhow::Error> {
+    let mut transaction = db_pool.begin().await?;
+    // UTC+14 is the first timezone to reach any wall-clock time, issues that are not
+    // due even there are not looked at.
+    let candidates = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id, scheduled_at AS "scheduled_at!", local_send_at
+        FROM newsletter_issues
+        WHERE status = 'scheduled'
+          AND LEAST(scheduled_at, (local_send_at - interval '14 hours') AT TIME ZONE 'UTC') <= now()
+        FOR UPDATE
+        SKIP LOCKED
+        "#
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let mut n_queued = 0;
+    for issue in candidates {
+        let now = Utc::now();
+        if issue.scheduled_at > now {
+            // Due in some timezone, but maybe not in one of the recipients'.
+            let Some(local_send_at) = issue.local_send_at else {
+                continue;
+            };
+            let (_, send_times) =
+                recipient_send_times(&mut transaction, issue.newsletter_issue_id, local_send_at)
+                    .await?;
+            if !send_times.iter().any(|send_at| *send_at <= now) {
+                continue;
+            }
+        }
+        enqueue_delivery_tasks(&mut transaction, issue.newsletter_issue_id).await?;
+        tracing::info!(
+            "Scheduled newsletter issue [{}] queued for delivery",
+            issue.newsletter_issue_id
+        );
+        n_queued += 1;
+    }
+    transaction.commit().await?;
+    Ok(n_queued)
+}
+
+/// Only queues the members who want every issue as it is published, the others get
+/// it with their next weekly digest, see `digest::send_due_digests`.
+#[tracing::instrument(
+    name = "Enqueue delivery tasks for the confirmed members of the list",
+    skip(transaction)
+)]
+pub async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let local_send_at = sqlx::query!(
+        r#"SELECT local_send_at FROM newsletter_issues WHERE newsletter_issue_id = $1"#,
+        issue_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .local_send_at;
+    let (timezones, send_times) = match local_send_at {
+        Some(local_send_at) => recipient_send_times(transaction, issue_id, local_send_at).await?,
+        None => (vec![], vec![]),
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email, execute_after)
+        SELECT $1, subscriptions.email,
+               GREATEST(now(), COALESCE(zone.send_at, newsletter_issues.scheduled_at, now()))
+        FROM subscriptions
+        JOIN list_memberships ON list_memberships.subscriber_id = subscriptions.id
+        JOIN newsletter_issues ON newsletter_issues.list_id = list_memberships.list_id
+        LEFT JOIN UNNEST($2::text[], $3::timestamptz[]) AS zone(timezone, send_at)
+               ON zone.timezone = subscriptions.timezone
+        WHERE newsletter_issues.newsletter_issue_id = $1
+          AND subscriptions.status = 'confirmed'
+          AND subscriptions.email_frequency = 'immediate'
+          AND list_memberships.status = 'confirmed'
+        "#,
+        issue_id,
+        &timezones[..],
+        &send_times[..]
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::info!("Failed to enqueue delivery tasks [{:?}]", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET status = 'sending', enqueued_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// When `local_send_at` happens in each timezone the confirmed recipients of the
+/// issue live in.
+async fn recipient_send_times(
+    transaction: &mut Transaction<'_, Postgres>,
+    issue_id: Uuid,
+    local_send_at: NaiveDateTime,
+) -> Result<(Vec<String>, Vec<DateTime<Utc>>), sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT DISTINCT subscriptions.timezone AS "timezone!"
+        FROM subscriptions
+        JOIN list_memberships ON list_memberships.subscriber_id = subscriptions.id
+        JOIN newsletter_issues ON newsletter_issues.list_id = list_memberships.list_id
+        WHERE newsletter_issues.newsletter_issue_id = $1
+          AND subscriptions.status = 'confirmed'
+          AND subscriptions.email_frequency = 'immediate'
+          AND list_memberships.status = 'confirmed'
+          AND subscriptions.timezone IS NOT NULL
+        "#,
+        issue_id
+    )
+    .fetch_all(transaction)
+    .await?;
+    let mut timezones = Vec::with_capacity(rows.len());
+    let mut send_times = Vec::with_capacity(rows.len());
+    for row in rows {
+        match SubscriberTimezone::parse(row.timezone.clone()) {
+            Ok(timezone) => {
+                send_times.push(local_time_in(timezone.tz(), local_send_at));
+                timezones.push(row.timezone);
+            }
+            // Those subscribers fall back to `scheduled_at`.
+            Err(e) => tracing::warn!("Ignoring a stored timezone: [{}]", e),
+        }
+    }
+    Ok((timezones, send_times))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+    use crate::issue_scheduler::{local_time_in, Schedule};
+
+    fn local(month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, month, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn local_times_follow_daylight_saving() {
+        let berlin = chrono_tz::Europe::Berlin;
+        assert_eq!(
+            local_time_in(berlin, local(1, 9, 8, 0)),
+            Utc.with_ymd_and_hms(2023, 1, 9, 7, 0, 0).unwrap()
+        );
+        assert_eq!(
+            local_time_in(berlin, local(7, 10, 8, 0)),
+            Utc.with_ymd_and_hms(2023, 7, 10, 6, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn a_skipped_local_time_happens_an_hour_later() {
+        // Clocks in Berlin jumped from 02:00 to 03:00 on 26 March 2023.
+        assert_eq!(
+            local_time_in(chrono_tz::Europe::Berlin, local(3, 26, 2, 30)),
+            Utc.with_ymd_and_hms(2023, 3, 26, 1, 30, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn a_repeated_local_time_happens_the_first_time_round() {
+        // Clocks in Berlin went back from 03:00 to 02:00 on 29 October 2023.
+        assert_eq!(
+            local_time_in(chrono_tz::Europe::Berlin, local(10, 29, 2, 30)),
+            Utc.with_ymd_and_hms(2023, 10, 29, 0, 30, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn a_schedule_in_subscriber_timezones_keeps_the_wall_clock_time() {
+        let scheduled_at =
+            chrono::DateTime::parse_from_rfc3339("2023-10-09T08:00:00+02:00").unwrap();
+
+        let schedule = Schedule::new(scheduled_at, true);
+
+        assert_eq!(
+            schedule.scheduled_at,
+            Utc.with_ymd_and_hms(2023, 10, 9, 6, 0, 0).unwrap()
+        );
+        assert_eq!(schedule.local_send_at, Some(local(10, 9, 8, 0)));
+        assert_eq!(Schedule::new(scheduled_at, false).local_send_at, None);
+    }
+}
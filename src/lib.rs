@@ -1,6 +1,23 @@
+pub mod authentication;
 pub mod configuration;
+pub mod consent;
+pub mod digest;
 pub mod domain;
 pub mod email_client;
+pub mod email_templates;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod issue_scheduler;
+pub mod lists;
+pub mod localization;
+pub mod newsletter_issues;
+pub mod personal_data;
+pub mod preferences;
 pub mod routes;
+pub mod session_state;
+pub mod session_store;
 pub mod startup;
+pub mod subscriber_links;
+pub mod subscription_cleanup_worker;
 pub mod telemetry;
+pub mod utils;
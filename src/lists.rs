@@ -0,0 +1,187 @@
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::domain::ListSlug;
+
+/// The list used when a request does not name one, it holds everything that was
+/// published before there were several lists.
+pub const DEFAULT_LIST: &str = "default";
+
+#[derive(Serialize)]
+pub struct NewsletterList {
+    pub id: Uuid,
+    pub slug: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The id of the list named `slug`, of the default list when `slug` is missing or
+/// empty.
+#[tracing::instrument(name = "Find a newsletter list", skip(transaction))]
+pub async fn find_list(
+    transaction: &mut Transaction<'_, Postgres>,
+    slug: Option<&str>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let slug = slug
+        .map(str::trim)
+        .filter(|slug| !slug.is_empty())
+        .unwrap_or(DEFAULT_LIST);
+    let row = sqlx::query!(r#"SELECT id FROM lists WHERE slug = $1"#, slug)
+        .fetch_optional(transaction)
+        .await?;
+    Ok(row.map(|row| row.id))
+}
+
+#[tracing::instrument(name = "Get the newsletter lists", skip(db_pool))]
+pub async fn get_lists(db_pool: &PgPool) -> Result<Vec<NewsletterList>, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterList,
+        r#"SELECT id, slug, name, created_at FROM lists ORDER BY created_at, slug"#
+    )
+    .fetch_all(db_pool)
+    .await
+}
+
+#[tracing::instrument(name = "Get a newsletter list", skip(db_pool))]
+pub async fn get_list(
+    db_pool: &PgPool,
+    list_id: Uuid,
+) -> Result<Option<NewsletterList>, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterList,
+        r#"SELECT id, slug, name, created_at FROM lists WHERE id = $1"#,
+        list_id
+    )
+    .fetch_optional(db_pool)
+    .await
+}
+
+/// Returns `None` when a list with the same slug exists already.
+#[tracing::instrument(name = "Create a newsletter list", skip(db_pool))]
+pub async fn insert_list(
+    db_pool: &PgPool,
+    slug: &ListSlug,
+    name: &str,
+) -> Result<Option<NewsletterList>, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterList,
+        r#"
+        INSERT INTO lists (id, slug, name, created_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (slug) DO NOTHING
+        RETURNING id, slug, name, created_at
+        "#,
+        Uuid::new_v4(),
+        slug.as_ref(),
+        name,
+        Utc::now()
+    )
+    .fetch_optional(db_pool)
+    .await
+}
+
+#[tracing::instrument(name = "Get the status of a list membership", skip(transaction))]
+pub async fn get_membership_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT status
+        FROM list_memberships
+        WHERE subscriber_id = $1 AND list_id = $2
+        FOR UPDATE
+        "#,
+        subscriber_id,
+        list_id
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(row.map(|row| row.status))
+}
+
+/// Makes the subscriber a pending member of the list. Someone subscribing again
+/// after leaving starts over, a confirmed membership is left alone.
+#[tracing::instrument(name = "Request a list membership", skip(transaction))]
+pub async fn request_membership(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at)
+        VALUES ($1, $2, 'pending_confirmation', $3)
+        ON CONFLICT (subscriber_id, list_id) DO UPDATE
+        SET status = 'pending_confirmation', subscribed_at = $3, unsubscribed_at = NULL
+        WHERE list_memberships.status = 'unsubscribed'
+        "#,
+        subscriber_id,
+        list_id,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Confirm a list membership", skip(transaction))]
+pub async fn confirm_membership(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE list_memberships
+        SET status = 'confirmed', confirmed_at = now(), unsubscribed_at = NULL
+        WHERE subscriber_id = $1 AND list_id = $2
+        "#,
+        subscriber_id,
+        list_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Leave every list", skip(transaction))]
+pub async fn leave_all_lists(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE list_memberships
+        SET status = 'unsubscribed', unsubscribed_at = now()
+        WHERE subscriber_id = $1 AND status <> 'unsubscribed'
+        "#,
+        subscriber_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Leave a list", skip(transaction))]
+pub async fn leave_list(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE list_memberships
+        SET status = 'unsubscribed', unsubscribed_at = now()
+        WHERE subscriber_id = $1 AND list_id = $2 AND status <> 'unsubscribed'
+        "#,
+        subscriber_id,
+        list_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
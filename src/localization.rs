@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use fluent_langneg::{negotiate_languages, NegotiationStrategy};
+use unic_langid::LanguageIdentifier;
+
+/// Fluent messages read from `<directory>/<language>/*.ftl`, one sub-directory per
+/// supported language.
+pub struct MessageCatalog {
+    bundles: HashMap<LanguageIdentifier, FluentBundle<FluentResource>>,
+    available: Vec<LanguageIdentifier>,
+    default_language: LanguageIdentifier,
+}
+
+impl MessageCatalog {
+    pub fn load(directory: &str, default_language: &str) -> Result<Self, anyhow::Error> {
+        let default_language: LanguageIdentifier = default_language
+            .parse()
+            .with_context(|| format!("[{}] is not a valid language", default_language))?;
+        let mut bundles = HashMap::new();
+        let entries = std::fs::read_dir(directory)
+            .with_context(|| format!("Failed to read the locales in [{}]", directory))?;
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let language: LanguageIdentifier = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .parse()
+                .with_context(|| format!("[{}] is not named after a language", path.display()))?;
+            bundles.insert(language.clone(), load_bundle(&path, language)?);
+        }
+        if !bundles.contains_key(&default_language) {
+            return Err(anyhow!(
+                "There are no messages for the default language [{}] in [{}]",
+                default_language,
+                directory
+            ));
+        }
+        let mut available: Vec<_> = bundles.keys().cloned().collect();
+        available.sort_by_key(|language| language.to_string());
+        Ok(Self {
+            bundles,
+            available,
+            default_language,
+        })
+    }
+
+    /// The supported languages to try for `requested`, best match first and always
+    /// ending with the default language. `de-AT` falls back to `de`, an unknown or
+    /// missing language to the default one.
+    pub fn fallback_chain(&self, requested: Option<&str>) -> Vec<&LanguageIdentifier> {
+        let requested: Vec<LanguageIdentifier> = requested
+            .and_then(|language| language.parse().ok())
+            .into_iter()
+            .collect();
+        negotiate_languages(
+            &requested,
+            &self.available,
+            Some(&self.default_language),
+            NegotiationStrategy::Filtering,
+        )
+    }
+
+    /// Formats the message `id` in the first language of the fallback chain that
+    /// defines it.
+    pub fn format(
+        &self,
+        language: Option<&str>,
+        id: &str,
+        args: Option<&FluentArgs>,
+    ) -> Result<String, anyhow::Error> {
+        for language in self.fallback_chain(language) {
+            let bundle = &self.bundles[language];
+            let Some(pattern) = bundle.get_message(id).and_then(|message| message.value()) else {
+                continue;
+            };
+            let mut errors = vec![];
+            let message = bundle.format_pattern(pattern, args, &mut errors);
+            if !errors.is_empty() {
+                return Err(anyhow!(
+                    "Failed to format the message [{}] in [{}]: {:?}",
+                    id,
+                    language,
+                    errors
+                ));
+            }
+            return Ok(message.into_owned());
+        }
+        Err(anyhow!("There is no message [{}] in any language", id))
+    }
+}
+
+fn load_bundle(
+    directory: &Path,
+    language: LanguageIdentifier,
+) -> Result<FluentBundle<FluentResource>, anyhow::Error> {
+    let mut bundle = FluentBundle::new_concurrent(vec![language]);
+    // The Unicode isolation marks around placeables end up verbatim in plain text
+    // emails, where they confuse more clients than they help.
+    bundle.set_use_isolating(false);
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("ftl") {
+            continue;
+        }
+        let source = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read [{}]", path.display()))?;
+        let resource = FluentResource::try_new(source)
+            .map_err(|(_, errors)| anyhow!("Failed to parse [{}]: {:?}", path.display(), errors))?;
+        bundle
+            .add_resource(resource)
+            .map_err(|errors| anyhow!("Failed to load [{}]: {:?}", path.display(), errors))?;
+    }
+    Ok(bundle)
+}
+
+#[cfg(test)]
+mod tests {
+    use fluent_bundle::FluentArgs;
+
+    use crate::localization::MessageCatalog;
+
+    fn catalog() -> MessageCatalog {
+        MessageCatalog::load("locales", "en").unwrap()
+    }
+
+    #[test]
+    fn a_regional_variant_falls_back_to_the_language() {
+        let catalog = catalog();
+        let chain: Vec<String> = catalog
+            .fallback_chain(Some("de-AT"))
+            .into_iter()
+            .map(|language| language.to_string())
+            .collect();
+
+        assert_eq!(chain, vec!["de", "en"]);
+    }
+
+    #[test]
+    fn an_unsupported_language_falls_back_to_the_default_one() {
+        let message = catalog()
+            .format(Some("xx"), "welcome-subject", None)
+            .unwrap();
+
+        assert_eq!(message, "You are subscribed");
+    }
+
+    #[test]
+    fn arguments_are_interpolated_without_isolation_marks() {
+        let mut args = FluentArgs::new();
+        args.set("name", "Ursula");
+
+        let message = catalog()
+            .format(Some("de"), "welcome-greeting", Some(&args))
+            .unwrap();
+
+        assert!(message.contains("Ursula"));
+        assert!(!message.contains('\u{2068}'));
+    }
+
+    #[test]
+    fn formatting_an_unknown_message_fails() {
+        assert!(catalog()
+            .format(Some("de"), "does-not-exist", None)
+            .is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::email_templates::{EmailTemplates, RenderedEmail, TemplateContext};
+use crate::subscriber_links::SubscriberLinkSigner;
+
+/// What an issue says, the same for every recipient.
+pub struct IssueContent {
+    pub title: String,
+    pub text_content: String,
+    pub html_content: String,
+}
+
+/// An issue as it goes out, along with the list it is sent to.
+pub struct ListIssue {
+    pub list_id: Uuid,
+    pub content: IssueContent,
+}
+
+/// An issue as shown to the editors. `status` is one of `draft`, `scheduled`,
+/// `sending` and `sent`, only drafts and scheduled issues can still be edited.
+#[derive(Serialize)]
+pub struct NewsletterIssue {
+    pub newsletter_issue_id: Uuid,
+    pub list: String,
+    pub status: String,
+    pub title: String,
+    pub text_content: String,
+    pub html_content: String,
+    pub revision: i32,
+    pub created_at: DateTime<Utc>,
+    pub scheduled_at: Option<DateTime<Utc>>,
+    pub local_send_at: Option<NaiveDateTime>,
+    pub enqueued_at: Option<DateTime<Utc>>,
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+/// A saved version of an issue, `edited_by` is the username of the editor.
+#[derive(Serialize)]
+pub struct IssueRevision {
+    pub revision: i32,
+    pub title: String,
+    pub text_content: String,
+    pub html_content: String,
+    pub edited_by: Option<String>,
+    pub edited_at: DateTime<Utc>,
+}
+
+/// What became of a request to change an issue.
+#[derive(Debug, PartialEq, Eq)]
+pub enum IssueChange {
+    Applied,
+    UnknownIssue,
+    /// The issue is not in a status that allows the change, e.g. it is being sent.
+    NotAllowed,
+}
+
+/// Saves a new draft along with its first revision.
+#[tracing::instrument(name = "Save newsletter issue details", skip_all)]
+pub async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    list_id: Uuid,
+    content: &IssueContent,
+    edited_by: Uuid,
+) -> Result<Uuid, sqlx::Error> {
+    let issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (newsletter_issue_id, list_id, title, text_content, html_content, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        issue_id,
+        list_id,
+        content.title,
+        content.text_content,
+        content.html_content,
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::info!("Failed to save newsletter issue [{:?}]", e);
+        e
+    })?;
+    record_revision(transaction, issue_id, edited_by).await?;
+    Ok(issue_id)
+}
+
+/// Replaces the content of a draft or scheduled issue, every actual change is kept
+/// as a new revision.
+#[tracing::instrument(name = "Edit a newsletter issue", skip(db_pool, content))]
+pub async fn edit_issue(
+    db_pool: &PgPool,
+    issue_id: Uuid,
+    content: &IssueContent,
+    edited_by: Uuid,
+) -> Result<IssueChange, sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+    let status = sqlx::query!(
+        r#"SELECT status FROM newsletter_issues WHERE newsletter_issue_id = $1 FOR UPDATE"#,
+        issue_id
+    )
+    .fetch_optional(&mut transaction)
+    .await?
+    .map(|row| row.status);
+    match status.as_deref() {
+        None => return Ok(IssueChange::UnknownIssue),
+        Some("draft" | "scheduled") => {}
+        Some(_) => return Ok(IssueChange::NotAllowed),
+    }
+    let result = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET title = $2, text_content = $3, html_content = $4
+        WHERE newsletter_issue_id = $1
+          AND (title, text_content, html_content) IS DISTINCT FROM ($2, $3, $4)
+        "#,
+        issue_id,
+        content.title,
+        content.text_content,
+        content.html_content
+    )
+    .execute(&mut transaction)
+    .await?;
+    if result.rows_affected() > 0 {
+        record_revision(&mut transaction, issue_id, edited_by).await?;
+    }
+    transaction.commit().await?;
+    Ok(IssueChange::Applied)
+}
+
+/// Copies the current content of the issue into its history.
+async fn record_revision(
+    transaction: &mut Transaction<'_, Postgres>,
+    issue_id: Uuid,
+    edited_by: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issue_revisions
+            (newsletter_issue_id, revision, title, text_content, html_content, edited_by, edited_at)
+        SELECT newsletter_issue_id,
+               COALESCE(
+                   (SELECT MAX(revision) FROM newsletter_issue_revisions WHERE newsletter_issue_id = $1),
+                   0
+               ) + 1,
+               title, text_content, html_content, $2, now()
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id,
+        edited_by
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get a newsletter issue", skip(db_pool))]
+pub async fn get_issue(
+    db_pool: &PgPool,
+    issue_id: Uuid,
+) -> Result<Option<NewsletterIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        SELECT newsletter_issue_id, lists.slug AS list, status, title, text_content,
+               html_content, newsletter_issues.created_at, scheduled_at, local_send_at,
+               enqueued_at, sent_at,
+               (SELECT MAX(revision) FROM newsletter_issue_revisions
+                WHERE newsletter_issue_revisions.newsletter_issue_id = newsletter_issues.newsletter_issue_id
+               ) AS "revision!"
+        FROM newsletter_issues
+        JOIN lists ON lists.id = newsletter_issues.list_id
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_optional(db_pool)
+    .await
+}
+
+#[tracing::instrument(name = "Get the content of a newsletter issue", skip(db_pool))]
+pub async fn get_issue_content(
+    db_pool: &PgPool,
+    issue_id: Uuid,
+) -> Result<Option<ListIssue>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT list_id, title, text_content, html_content
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_optional(db_pool)
+    .await?;
+    Ok(row.map(|row| ListIssue {
+        list_id: row.list_id,
+        content: IssueContent {
+            title: row.title,
+            text_content: row.text_content,
+            html_content: row.html_content,
+        },
+    }))
+}
+
+/// Every revision of the issue, oldest first.
+#[tracing::instrument(name = "Get the revisions of a newsletter issue", skip(db_pool))]
+pub async fn get_revisions(
+    db_pool: &PgPool,
+    issue_id: Uuid,
+) -> Result<Vec<IssueRevision>, sqlx::Error> {
+    sqlx::query_as!(
+        IssueRevision,
+        r#"
+        SELECT revision, title, text_content, html_content,
+               users.username AS "edited_by?", edited_at
+        FROM newsletter_issue_revisions
+        LEFT JOIN users ON users.user_id = newsletter_issue_revisions.edited_by
+        WHERE newsletter_issue_id = $1
+        ORDER BY revision
+        "#,
+        issue_id
+    )
+    .fetch_all(db_pool)
+    .await
+}
+
+/// The bodies of an issue exactly as the subscriber receives them, used by the
+/// delivery worker and by the previews.
+pub fn render_issue(
+    email_templates: &EmailTemplates,
+    subscriber_links: &SubscriberLinkSigner,
+    issue: &ListIssue,
+    subscriber_id: Uuid,
+    language: Option<&str>,
+) -> Result<RenderedEmail, anyhow::Error> {
+    let mut context = TemplateContext::new();
+    context.insert("title", &issue.content.title);
+    context.insert("html_content", &issue.content.html_content);
+    context.insert("text_content", &issue.content.text_content);
+    context.insert(
+        "unsubscribe_link",
+        &subscriber_links.unsubscribe_link(subscriber_id, issue.list_id),
+    );
+    context.insert(
+        "personal_data_link",
+        &subscriber_links.personal_data_link(subscriber_id),
+    );
+    context.insert(
+        "preferences_link",
+        &subscriber_links.preferences_link(subscriber_id),
+    );
+    email_templates.render("newsletter", language, &context)
+}
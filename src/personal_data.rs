@@ -0,0 +1,411 @@
+use std::collections::HashSet;
+
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use serde::Serialize;
+use sha2::Sha256;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::consent::ConsentEvent;
+use crate::preferences::PreferenceChange;
+
+/// Everything stored about an email address, as handed out to the data subject.
+#[derive(Serialize)]
+pub struct PersonalDataExport {
+    pub email: String,
+    /// Usually one, addresses differing in case only are separate subscriptions.
+    pub subscriptions: Vec<SubscriptionRecord>,
+    pub list_memberships: Vec<ListMembershipRecord>,
+    pub subscription_tokens: Vec<SubscriptionTokenRecord>,
+    pub email_change_requests: Vec<EmailChangeRecord>,
+    pub consent_events: Vec<ConsentEvent>,
+    pub preference_changes: Vec<PreferenceChange>,
+    pub pending_deliveries: Vec<DeliveryRecord>,
+    pub failed_deliveries: Vec<DeliveryRecord>,
+    pub email_events: Vec<EmailEventRecord>,
+}
+
+impl PersonalDataExport {
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty()
+            && self.email_change_requests.is_empty()
+            && self.pending_deliveries.is_empty()
+            && self.failed_deliveries.is_empty()
+            && self.email_events.is_empty()
+    }
+}
+
+#[derive(Serialize)]
+pub struct SubscriptionRecord {
+    pub id: Uuid,
+    pub name: String,
+    pub status: String,
+    pub language: Option<String>,
+    pub email_frequency: String,
+    pub timezone: Option<String>,
+    pub subscribed_at: DateTime<Utc>,
+    pub unsubscribed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct ListMembershipRecord {
+    pub list: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub unsubscribed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct SubscriptionTokenRecord {
+    pub subscription_token: String,
+    pub issued_at: DateTime<Utc>,
+    pub consumed_at: Option<DateTime<Utc>>,
+    pub confirmation_email_sent_at: Option<DateTime<Utc>>,
+    pub confirmation_email_error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct EmailChangeRecord {
+    pub new_email: String,
+    pub requested_at: DateTime<Utc>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct DeliveryRecord {
+    pub newsletter_issue_id: Uuid,
+    pub n_attempts: i32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct EmailEventRecord {
+    pub record_type: String,
+    pub payload: serde_json::Value,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Collects the rows of every table that refers to `email`, either directly or
+/// through the subscriber id. Addresses are compared case-insensitively, like on the
+/// suppression list.
+#[tracing::instrument(name = "Export personal data", skip(db_pool, email))]
+pub async fn export_personal_data(
+    db_pool: &PgPool,
+    email: &str,
+) -> Result<PersonalDataExport, sqlx::Error> {
+    let email = email.trim();
+    let subscriptions = sqlx::query_as!(
+        SubscriptionRecord,
+        r#"
+        SELECT id, name, status, language, email_frequency, timezone, subscribed_at,
+               unsubscribed_at
+        FROM subscriptions
+        WHERE lower(email) = lower($1)
+        ORDER BY subscribed_at
+        "#,
+        email
+    )
+    .fetch_all(db_pool)
+    .await?;
+    let subscriber_ids: Vec<Uuid> = subscriptions.iter().map(|s| s.id).collect();
+    let list_memberships = sqlx::query_as!(
+        ListMembershipRecord,
+        r#"
+        SELECT lists.slug AS list, status, subscribed_at, confirmed_at, unsubscribed_at
+        FROM list_memberships
+        JOIN lists ON lists.id = list_memberships.list_id
+        WHERE subscriber_id = ANY($1)
+        ORDER BY subscribed_at
+        "#,
+        &subscriber_ids[..]
+    )
+    .fetch_all(db_pool)
+    .await?;
+    let subscription_tokens = sqlx::query_as!(
+        SubscriptionTokenRecord,
+        r#"
+        SELECT subscription_token, issued_at, consumed_at,
+               confirmation_email_sent_at, confirmation_email_error
+        FROM subscription_tokens
+        WHERE subscriber_id = ANY($1)
+        ORDER BY issued_at
+        "#,
+        &subscriber_ids[..]
+    )
+    .fetch_all(db_pool)
+    .await?;
+    // Includes changes of somebody else's subscription to this address.
+    let email_change_requests = sqlx::query_as!(
+        EmailChangeRecord,
+        r#"
+        SELECT new_email, requested_at, confirmed_at
+        FROM email_change_requests
+        WHERE subscriber_id = ANY($1) OR lower(new_email) = lower($2)
+        ORDER BY requested_at
+        "#,
+        &subscriber_ids[..],
+        email
+    )
+    .fetch_all(db_pool)
+    .await?;
+    let mut consent_events = vec![];
+    let mut preference_changes = vec![];
+    for subscriber_id in &subscriber_ids {
+        consent_events.extend(crate::consent::get_consent_events(*subscriber_id, db_pool).await?);
+        preference_changes
+            .extend(crate::preferences::get_preference_changes(*subscriber_id, db_pool).await?);
+    }
+    let pending_deliveries = sqlx::query_as!(
+        DeliveryRecord,
+        r#"
+        SELECT newsletter_issue_id, n_attempts, last_error
+        FROM issue_delivery_queue
+        WHERE lower(subscriber_email) = lower($1)
+        "#,
+        email
+    )
+    .fetch_all(db_pool)
+    .await?;
+    let failed_deliveries = sqlx::query_as!(
+        DeliveryRecord,
+        r#"
+        SELECT newsletter_issue_id, n_attempts, last_error AS "last_error?"
+        FROM issue_delivery_dead_letters
+        WHERE lower(subscriber_email) = lower($1)
+        "#,
+        email
+    )
+    .fetch_all(db_pool)
+    .await?;
+    let email_events = sqlx::query_as!(
+        EmailEventRecord,
+        r#"
+        SELECT record_type, payload, received_at
+        FROM email_events
+        WHERE lower(subscriber_email) = lower($1)
+        ORDER BY received_at
+        "#,
+        email
+    )
+    .fetch_all(db_pool)
+    .await?;
+    Ok(PersonalDataExport {
+        email: email.into(),
+        subscriptions,
+        list_memberships,
+        subscription_tokens,
+        email_change_requests,
+        consent_events,
+        preference_changes,
+        pending_deliveries,
+        failed_deliveries,
+        email_events,
+    })
+}
+
+/// Deletes every row that refers to `email`, compared case-insensitively, and adds
+/// the address to the suppression list. Erasing an address we know nothing about
+/// still suppresses it. Returns the number of deleted rows.
+#[tracing::instrument(name = "Erase personal data", skip(db_pool, email, suppression_list))]
+pub async fn erase_personal_data(
+    db_pool: &PgPool,
+    email: &str,
+    suppression_list: &SuppressionList,
+) -> Result<u64, sqlx::Error> {
+    let email = email.trim();
+    let mut transaction = db_pool.begin().await?;
+    let subscriber_ids: Vec<Uuid> = sqlx::query_scalar!(
+        "SELECT id FROM subscriptions WHERE lower(email) = lower($1) FOR UPDATE",
+        email
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let mut n_deleted = 0;
+    n_deleted += sqlx::query!(
+        "DELETE FROM subscription_tokens WHERE subscriber_id = ANY($1)",
+        &subscriber_ids[..]
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    n_deleted += sqlx::query!(
+        "DELETE FROM consent_events WHERE subscriber_id = ANY($1)",
+        &subscriber_ids[..]
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    n_deleted += sqlx::query!(
+        r#"
+        DELETE FROM email_change_requests
+        WHERE subscriber_id = ANY($1) OR lower(new_email) = lower($2)
+        "#,
+        &subscriber_ids[..],
+        email
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    n_deleted += sqlx::query!(
+        "DELETE FROM preference_changes WHERE subscriber_id = ANY($1)",
+        &subscriber_ids[..]
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    n_deleted += sqlx::query!(
+        "DELETE FROM list_memberships WHERE subscriber_id = ANY($1)",
+        &subscriber_ids[..]
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    n_deleted += sqlx::query!(
+        "DELETE FROM issue_delivery_queue WHERE lower(subscriber_email) = lower($1)",
+        email
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    n_deleted += sqlx::query!(
+        "DELETE FROM issue_delivery_dead_letters WHERE lower(subscriber_email) = lower($1)",
+        email
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    n_deleted += sqlx::query!(
+        "DELETE FROM email_events WHERE lower(subscriber_email) = lower($1)",
+        email
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    n_deleted += sqlx::query!(
+        "DELETE FROM subscriptions WHERE id = ANY($1)",
+        &subscriber_ids[..]
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    suppression_list.suppress(&mut transaction, email).await?;
+    transaction.commit().await?;
+    Ok(n_deleted)
+}
+
+/// Addresses that were erased and must not be imported again. Only an HMAC of each
+/// address is kept, under a key of its own that is never rotated: a new key would
+/// empty the list.
+#[derive(Clone)]
+pub struct SuppressionList {
+    suppression_key: Secret<String>,
+}
+
+impl SuppressionList {
+    pub fn new(suppression_key: Secret<String>) -> Self {
+        Self { suppression_key }
+    }
+
+    /// Addresses are compared case-insensitively.
+    pub fn hash(&self, email: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(self.suppression_key.expose_secret().as_bytes())
+                .expect("HMAC can take a key of any size");
+        mac.update(b"suppressed-email:");
+        mac.update(email.trim().to_lowercase().as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[tracing::instrument(name = "Suppress an email address", skip_all)]
+    pub async fn suppress(
+        &self,
+        transaction: &mut Transaction<'_, Postgres>,
+        email: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO suppressed_emails (email_hash, suppressed_at)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            self.hash(email),
+            Utc::now()
+        )
+        .execute(transaction)
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Check whether an email address is suppressed", skip_all)]
+    pub async fn is_suppressed(&self, db_pool: &PgPool, email: &str) -> Result<bool, sqlx::Error> {
+        let row = sqlx::query!(
+            r#"SELECT email_hash FROM suppressed_emails WHERE email_hash = $1"#,
+            self.hash(email)
+        )
+        .fetch_optional(db_pool)
+        .await?;
+        Ok(row.is_some())
+    }
+
+    /// The addresses of `emails` that are suppressed, checked in a single query.
+    #[tracing::instrument(name = "Find the suppressed email addresses", skip_all)]
+    pub async fn suppressed_among(
+        &self,
+        transaction: &mut Transaction<'_, Postgres>,
+        emails: &[&str],
+    ) -> Result<HashSet<String>, sqlx::Error> {
+        let hashes: Vec<String> = emails.iter().map(|email| self.hash(email)).collect();
+        let suppressed: HashSet<String> = sqlx::query!(
+            r#"SELECT email_hash FROM suppressed_emails WHERE email_hash = ANY($1)"#,
+            &hashes[..]
+        )
+        .fetch_all(transaction)
+        .await?
+        .into_iter()
+        .map(|row| row.email_hash)
+        .collect();
+        Ok(emails
+            .iter()
+            .zip(hashes)
+            .filter(|(_, hash)| suppressed.contains(hash))
+            .map(|(email, _)| email.to_string())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+
+    use crate::personal_data::SuppressionList;
+
+    fn suppression_list(secret: &str) -> SuppressionList {
+        SuppressionList::new(Secret::new(secret.into()))
+    }
+
+    #[test]
+    fn the_hash_ignores_case_and_surrounding_whitespace() {
+        let list = suppression_list("secret");
+        assert_eq!(
+            list.hash("ursula@example.com"),
+            list.hash(" Ursula@Example.com ")
+        );
+    }
+
+    #[test]
+    fn the_hash_does_not_contain_the_address() {
+        let hash = suppression_list("secret").hash("ursula@example.com");
+        assert!(!hash.contains("ursula"));
+    }
+
+    #[test]
+    fn the_hash_depends_on_the_secret() {
+        assert_ne!(
+            suppression_list("secret").hash("ursula@example.com"),
+            suppression_list("another-secret").hash("ursula@example.com")
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::consent::ConsentOrigin;
+
+/// What a subscriber can change about themselves on the preference page, along with
+/// the address that confirmations of new topics go to.
+pub struct Preferences {
+    pub email: String,
+    pub name: String,
+    pub language: Option<String>,
+    pub email_frequency: String,
+    pub timezone: Option<String>,
+}
+
+/// A list as offered on the preference page, `subscribed` only for a confirmed
+/// membership.
+pub struct TopicChoice {
+    pub list_id: Uuid,
+    pub slug: String,
+    pub name: String,
+    pub subscribed: bool,
+}
+
+/// A recorded preference change, as exported for a subscriber.
+#[derive(Serialize)]
+pub struct PreferenceChange {
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub changed_at: DateTime<Utc>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[tracing::instrument(name = "Get the preferences of a subscriber", skip(transaction))]
+pub async fn get_preferences(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Option<Preferences>, sqlx::Error> {
+    sqlx::query_as!(
+        Preferences,
+        r#"
+        SELECT email, name, language, email_frequency, timezone
+        FROM subscriptions
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+/// Every list, in the order they were created.
+#[tracing::instrument(name = "Get the topics of a subscriber", skip(transaction))]
+pub async fn get_topic_choices(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Vec<TopicChoice>, sqlx::Error> {
+    sqlx::query_as!(
+        TopicChoice,
+        r#"
+        SELECT lists.id AS list_id, lists.slug, lists.name,
+               COALESCE(list_memberships.status = 'confirmed', false) AS "subscribed!"
+        FROM lists
+        LEFT JOIN list_memberships
+               ON list_memberships.list_id = lists.id
+              AND list_memberships.subscriber_id = $1
+        ORDER BY lists.created_at, lists.slug
+        "#,
+        subscriber_id
+    )
+    .fetch_all(transaction)
+    .await
+}
+
+/// Switching to the weekly digest starts its first week, the issues sent before are
+/// not repeated in it.
+#[tracing::instrument(name = "Update the preferences of a subscriber", skip(transaction))]
+pub async fn update_preferences(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    name: &str,
+    language: Option<&str>,
+    email_frequency: &str,
+    timezone: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET name = $2, language = $3, email_frequency = $4, timezone = $5,
+            digest_sent_at = CASE
+                WHEN email_frequency <> $4 AND $4 = 'weekly_digest' THEN now()
+                ELSE digest_sent_at
+            END
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        name,
+        language,
+        email_frequency,
+        timezone
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Record a preference change", skip(transaction, origin))]
+pub async fn record_preference_change(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    field: &str,
+    old_value: Option<&str>,
+    new_value: Option<&str>,
+    origin: &ConsentOrigin,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO preference_changes (
+            id, subscriber_id, field, old_value, new_value,
+            changed_at, ip_address, user_agent
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        field,
+        old_value,
+        new_value,
+        Utc::now(),
+        origin.ip_address,
+        origin.user_agent
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Every preference change of a subscriber, oldest first.
+#[tracing::instrument(name = "Get the preference changes of a subscriber", skip(db_pool))]
+pub async fn get_preference_changes(
+    subscriber_id: Uuid,
+    db_pool: &PgPool,
+) -> Result<Vec<PreferenceChange>, sqlx::Error> {
+    sqlx::query_as!(
+        PreferenceChange,
+        r#"
+        SELECT field, old_value, new_value, changed_at, ip_address, user_agent
+        FROM preference_changes
+        WHERE subscriber_id = $1
+        ORDER BY changed_at
+        "#,
+        subscriber_id
+    )
+    .fetch_all(db_pool)
+    .await
+}
@@ -0,0 +1,47 @@
+use actix_web::web::{Data, Json};
+use actix_web::HttpResponse;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::authentication::LoggedInUser;
+use crate::domain::ListSlug;
+use crate::lists::{get_lists, insert_list};
+use crate::utils::{e400, e500};
+
+#[derive(Deserialize)]
+pub struct CreateListRequest {
+    slug: String,
+    name: String,
+}
+
+#[tracing::instrument(name = "List the newsletter lists", skip(db_pool, _user))]
+pub async fn newsletter_lists(
+    db_pool: Data<PgPool>,
+    _user: LoggedInUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let lists = get_lists(&db_pool).await.map_err(e500)?;
+    Ok(HttpResponse::Ok().json(lists))
+}
+
+#[tracing::instrument(
+    name = "Create a newsletter list",
+    skip(body, db_pool, _user),
+    fields(slug = %body.slug)
+)]
+pub async fn create_newsletter_list(
+    body: Json<CreateListRequest>,
+    db_pool: Data<PgPool>,
+    _user: LoggedInUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let CreateListRequest { slug, name } = body.into_inner();
+    let slug = ListSlug::parse(slug).map_err(e400)?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(e400("The list needs a name."));
+    }
+    match insert_list(&db_pool, &slug, name).await.map_err(e500)? {
+        Some(list) => Ok(HttpResponse::Created().json(list)),
+        None => Ok(HttpResponse::Conflict()
+            .body(format!("There is a list named {} already.", slug.as_ref()))),
+    }
+}
@@ -0,0 +1,17 @@
+use actix_web::HttpResponse;
+
+use crate::authentication::LoggedInUser;
+use crate::session_state::TypedSession;
+use crate::utils::{e500, see_other};
+
+#[tracing::instrument(name = "Log out an admin", skip(user, session), fields(user_id = %user.user_id))]
+pub async fn log_out(
+    user: LoggedInUser,
+    session: TypedSession,
+) -> Result<HttpResponse, actix_web::Error> {
+    session.log_out();
+    session
+        .insert_flash("You have successfully logged out.")
+        .map_err(e500)?;
+    Ok(see_other("/login"))
+}
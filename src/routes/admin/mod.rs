@@ -0,0 +1,13 @@
+pub use dashboard::*;
+pub use lists::*;
+pub use logout::*;
+pub use newsletters::*;
+pub use password::*;
+pub use subscribers::*;
+
+mod dashboard;
+mod lists;
+mod logout;
+mod newsletters;
+mod password;
+mod subscribers;
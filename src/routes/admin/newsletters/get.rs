@@ -0,0 +1,86 @@
+use actix_web::http::header::ContentType;
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::LoggedInUser;
+use crate::lists::{get_lists, DEFAULT_LIST};
+use crate::session_state::TypedSession;
+use crate::utils::e500;
+
+pub async fn publish_newsletter_form(
+    _user: LoggedInUser,
+    session: TypedSession,
+    db_pool: Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let message_html = match session.take_flash() {
+        Some(message) => format!("<p><i>{}</i></p>", htmlescape::encode_minimal(&message)),
+        None => String::new(),
+    };
+    let list_options: String = get_lists(&db_pool)
+        .await
+        .map_err(e500)?
+        .into_iter()
+        .map(|list| {
+            format!(
+                r#"<option value="{}"{}>{}</option>"#,
+                htmlescape::encode_attribute(&list.slug),
+                if list.slug == DEFAULT_LIST {
+                    " selected"
+                } else {
+                    ""
+                },
+                htmlescape::encode_minimal(&list.name)
+            )
+        })
+        .collect();
+    let idempotency_key = Uuid::new_v4();
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Publish Newsletter Issue</title>
+</head>
+<body>
+    {message_html}
+    <form action="/admin/newsletters" method="post">
+        <label>List:<br>
+            <select name="list">{list_options}</select>
+        </label>
+        <br>
+        <label>Title:<br>
+            <input type="text" placeholder="Enter the issue title" name="title">
+        </label>
+        <br>
+        <label>Plain text content:<br>
+            <textarea placeholder="Enter the content in plain text" name="text_content" rows="20" cols="50"></textarea>
+        </label>
+        <br>
+        <label>HTML content:<br>
+            <textarea placeholder="Enter the content in HTML format" name="html_content" rows="20" cols="50"></textarea>
+        </label>
+        <br>
+        <label>Send at (leave empty to send now):<br>
+            <input type="datetime-local" name="scheduled_at">
+        </label>
+        <label>in the timezone
+            <input type="text" placeholder="UTC" name="timezone">
+        </label>
+        <br>
+        <label>
+            <input type="checkbox" name="in_subscriber_timezone">
+            at that time in each subscriber's own timezone
+        </label>
+        <br>
+        <input hidden type="text" name="idempotency_key" value="{idempotency_key}">
+        <button type="submit">Publish</button>
+    </form>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        )))
+}
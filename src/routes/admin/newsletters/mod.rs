@@ -0,0 +1,5 @@
+pub use get::*;
+pub use post::*;
+
+mod get;
+mod post;
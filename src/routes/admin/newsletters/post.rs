@@ -0,0 +1,135 @@
+use actix_web::web::{Data, Form};
+use actix_web::HttpResponse;
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::authentication::LoggedInUser;
+use crate::domain::SubscriberTimezone;
+use crate::idempotency::{save_response, try_processing, IdempotencyKey, NextAction};
+use crate::issue_scheduler::{enqueue_delivery_tasks, schedule_issue, Schedule};
+use crate::lists::find_list;
+use crate::newsletter_issues::{insert_newsletter_issue, IssueContent};
+use crate::session_state::TypedSession;
+use crate::utils::{e400, e500, see_other};
+
+#[derive(Deserialize)]
+pub struct PublishNewsletterForm {
+    title: String,
+    text_content: String,
+    html_content: String,
+    list: Option<String>,
+    /// A `datetime-local` value, empty to send right away.
+    scheduled_at: Option<String>,
+    /// The timezone `scheduled_at` was entered in, UTC when empty.
+    timezone: Option<String>,
+    /// A checkbox, present when checked.
+    in_subscriber_timezone: Option<String>,
+    idempotency_key: String,
+}
+
+fn parse_schedule(
+    scheduled_at: Option<String>,
+    timezone: Option<String>,
+    in_subscriber_timezone: bool,
+) -> Result<Option<Schedule>, String> {
+    let scheduled_at = match scheduled_at.as_deref().map(str::trim) {
+        None | Some("") => return Ok(None),
+        Some(scheduled_at) => scheduled_at,
+    };
+    let local = NaiveDateTime::parse_from_str(scheduled_at, "%Y-%m-%dT%H:%M")
+        .map_err(|_| format!("{} is not a valid date and time", scheduled_at))?;
+    let timezone = match timezone.as_deref().map(str::trim) {
+        None | Some("") => chrono_tz::UTC,
+        Some(timezone) => SubscriberTimezone::parse(timezone.into())?.tz(),
+    };
+    Ok(Some(Schedule::from_local(
+        local,
+        timezone,
+        in_subscriber_timezone,
+    )))
+}
+
+#[tracing::instrument(
+    name = "Publish a newsletter issue from the admin dashboard",
+    skip(form, db_pool, user, session),
+    fields(newsletter_title = %form.title, user_id = %user.user_id)
+)]
+pub async fn publish_newsletter_from_form(
+    form: Form<PublishNewsletterForm>,
+    db_pool: Data<PgPool>,
+    user: LoggedInUser,
+    session: TypedSession,
+) -> Result<HttpResponse, actix_web::Error> {
+    let PublishNewsletterForm {
+        title,
+        text_content,
+        html_content,
+        list,
+        scheduled_at,
+        timezone,
+        in_subscriber_timezone,
+        idempotency_key,
+    } = form.0;
+    let idempotency_key = IdempotencyKey::parse(idempotency_key).map_err(e400)?;
+    let schedule =
+        parse_schedule(scheduled_at, timezone, in_subscriber_timezone.is_some()).map_err(e400)?;
+
+    let mut transaction = match try_processing(&db_pool, &idempotency_key, user.user_id)
+        .await
+        .map_err(e500)?
+    {
+        NextAction::StartProcessing(transaction) => transaction,
+        NextAction::ReturnSavedResponse(saved_response) => {
+            insert_success_flash(&session, schedule.is_some())?;
+            return Ok(saved_response);
+        }
+    };
+
+    let list_id = find_list(&mut transaction, list.as_deref())
+        .await
+        .map_err(e500)?
+        .ok_or_else(|| e400("There is no such newsletter list."))?;
+    let content = IssueContent {
+        title,
+        text_content,
+        html_content,
+    };
+    let issue_id = insert_newsletter_issue(&mut transaction, list_id, &content, user.user_id)
+        .await
+        .map_err(e500)?;
+    match &schedule {
+        Some(schedule) => {
+            schedule_issue(&mut transaction, issue_id, schedule)
+                .await
+                .map_err(e500)?;
+            tracing::info!(
+                "Newsletter issue [{}] scheduled for [{}]",
+                issue_id,
+                schedule.scheduled_at
+            );
+        }
+        None => {
+            enqueue_delivery_tasks(&mut transaction, issue_id)
+                .await
+                .map_err(e500)?;
+            tracing::info!("Newsletter issue [{}] queued for delivery", issue_id);
+        }
+    }
+
+    let response = see_other("/admin/newsletters");
+    let response = save_response(transaction, &idempotency_key, user.user_id, response)
+        .await
+        .map_err(e500)?;
+    insert_success_flash(&session, schedule.is_some())?;
+    Ok(response)
+}
+
+fn insert_success_flash(session: &TypedSession, scheduled: bool) -> Result<(), actix_web::Error> {
+    let message = if scheduled {
+        "The newsletter issue has been scheduled."
+    } else {
+        "The newsletter issue has been accepted - emails will go out shortly."
+    };
+    session.insert_flash(message).map_err(e500)
+}
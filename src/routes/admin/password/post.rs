@@ -0,0 +1,77 @@
+use actix_web::web::{Data, Form};
+use actix_web::HttpResponse;
+use secrecy::{ExposeSecret, Secret};
+use serde::Deserialize;
+use sqlx::PgPool;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::authentication::{self, validate_credentials, AuthError, Credentials, LoggedInUser};
+use crate::routes::get_username;
+use crate::session_state::TypedSession;
+use crate::utils::{e500, see_other};
+
+const MIN_PASSWORD_LENGTH: usize = 12;
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: Secret<String>,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(
+    name = "Change the admin password",
+    skip(form, user, db_pool, session),
+    fields(user_id = %user.user_id)
+)]
+pub async fn change_password(
+    form: Form<ChangePasswordRequest>,
+    user: LoggedInUser,
+    db_pool: Data<PgPool>,
+    session: TypedSession,
+) -> Result<HttpResponse, actix_web::Error> {
+    let form = form.0;
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        session
+            .insert_flash("You entered two different new passwords - the field values must match.")
+            .map_err(e500)?;
+        return Ok(see_other("/admin/password"));
+    }
+
+    let password_length = form.new_password.expose_secret().graphemes(true).count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&password_length) {
+        session
+            .insert_flash(&format!(
+                "The new password must be between {} and {} characters long.",
+                MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+            ))
+            .map_err(e500)?;
+        return Ok(see_other("/admin/password"));
+    }
+
+    let username = get_username(user.user_id, &db_pool).await.map_err(e500)?;
+    let credentials = Credentials {
+        username,
+        password: form.current_password,
+    };
+    if let Err(e) = validate_credentials(credentials, &db_pool).await {
+        return match e {
+            AuthError::InvalidCredentials(_) => {
+                session
+                    .insert_flash("The current password is incorrect.")
+                    .map_err(e500)?;
+                Ok(see_other("/admin/password"))
+            }
+            AuthError::UnexpectedError(e) => Err(e500(e)),
+        };
+    }
+
+    authentication::change_password(user.user_id, form.new_password, &db_pool)
+        .await
+        .map_err(e500)?;
+    session
+        .insert_flash("Your password has been changed.")
+        .map_err(e500)?;
+    Ok(see_other("/admin/password"))
+}
@@ -0,0 +1,61 @@
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::{Data, Path};
+use actix_web::HttpResponse;
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::LoggedInUser;
+use crate::consent::{get_consent_events, ConsentEvent};
+use crate::utils::e500;
+
+#[derive(Serialize)]
+struct ConsentExport {
+    subscriber_id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    consent_events: Vec<ConsentEvent>,
+}
+
+/// Downloads everything we know about how a subscriber opted in, as JSON.
+#[tracing::instrument(
+    name = "Export the consent records of a subscriber",
+    skip(_user, db_pool)
+)]
+pub async fn export_consent_records(
+    _user: LoggedInUser,
+    subscriber_id: Path<Uuid>,
+    db_pool: Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let subscriber_id = subscriber_id.into_inner();
+    let Some(subscriber) = sqlx::query!(
+        r#"SELECT email, name, status FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(db_pool.get_ref())
+    .await
+    .map_err(e500)?
+    else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let consent_events = get_consent_events(subscriber_id, &db_pool)
+        .await
+        .map_err(e500)?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "consent-{}.json",
+                subscriber_id
+            ))],
+        })
+        .json(ConsentExport {
+            subscriber_id,
+            email: subscriber.email,
+            name: subscriber.name,
+            status: subscriber.status,
+            consent_events,
+        }))
+}
@@ -0,0 +1,153 @@
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::{Bytes, Data};
+use actix_web::HttpResponse;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::LoggedInUser;
+
+const PAGE_SIZE: i64 = 1000;
+
+const HEADER: [&str; 7] = [
+    "id",
+    "email",
+    "name",
+    "status",
+    "language",
+    "subscribed_at",
+    "unsubscribed_at",
+];
+
+struct ExportedSubscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    language: Option<String>,
+    subscribed_at: DateTime<Utc>,
+    unsubscribed_at: Option<DateTime<Utc>>,
+}
+
+/// What is left to send.
+enum ExportState {
+    Start,
+    After {
+        subscribed_at: DateTime<Utc>,
+        id: Uuid,
+    },
+    Done,
+}
+
+/// Downloads every subscriber as CSV, oldest first. The file is streamed a page at a
+/// time, the columns read by the import are a subset of the exported ones.
+#[tracing::instrument(name = "Export subscribers", skip(db_pool, _user))]
+pub async fn export_subscribers(db_pool: Data<PgPool>, _user: LoggedInUser) -> HttpResponse {
+    let db_pool = db_pool.into_inner();
+    let body = futures_util::stream::try_unfold(ExportState::Start, move |state| {
+        let db_pool = db_pool.clone();
+        async move { next_page(&db_pool, state).await }
+    });
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("subscribers.csv".into())],
+        })
+        .streaming(body)
+}
+
+async fn next_page(
+    db_pool: &PgPool,
+    state: ExportState,
+) -> Result<Option<(Bytes, ExportState)>, anyhow::Error> {
+    let mut csv = csv::Writer::from_writer(vec![]);
+    let after = match state {
+        ExportState::Done => return Ok(None),
+        ExportState::Start => {
+            csv.write_record(HEADER)?;
+            None
+        }
+        ExportState::After { subscribed_at, id } => Some((subscribed_at, id)),
+    };
+    let subscribers = get_subscribers_after(db_pool, after).await.map_err(|e| {
+        tracing::error!("Failed to read subscribers for the export: {:?}", e);
+        e
+    })?;
+    let next = match subscribers.last() {
+        Some(last) if subscribers.len() as i64 == PAGE_SIZE => ExportState::After {
+            subscribed_at: last.subscribed_at,
+            id: last.id,
+        },
+        _ => ExportState::Done,
+    };
+    for subscriber in subscribers {
+        csv.write_record([
+            subscriber.id.to_string(),
+            spreadsheet_safe(subscriber.email),
+            spreadsheet_safe(subscriber.name),
+            subscriber.status,
+            subscriber.language.unwrap_or_default(),
+            subscriber.subscribed_at.to_rfc3339(),
+            subscriber
+                .unsubscribed_at
+                .map(|at| at.to_rfc3339())
+                .unwrap_or_default(),
+        ])?;
+    }
+    Ok(Some((Bytes::from(csv.into_inner()?), next)))
+}
+
+/// Spreadsheets run a cell starting with one of these as a formula, the quote makes
+/// them show it as text instead. Names and addresses come from the sign-up form.
+fn spreadsheet_safe(field: String) -> String {
+    if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", field)
+    } else {
+        field
+    }
+}
+
+#[tracing::instrument(name = "Get a page of subscribers to export", skip(db_pool))]
+async fn get_subscribers_after(
+    db_pool: &PgPool,
+    after: Option<(DateTime<Utc>, Uuid)>,
+) -> Result<Vec<ExportedSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        ExportedSubscriber,
+        r#"
+        SELECT id, email, name, status, language, subscribed_at, unsubscribed_at
+        FROM subscriptions
+        WHERE ($1::timestamptz IS NULL OR (subscribed_at, id) > ($1, $2::uuid))
+        ORDER BY subscribed_at, id
+        LIMIT $3
+        "#,
+        after.map(|(subscribed_at, _)| subscribed_at),
+        after.map(|(_, id)| id),
+        PAGE_SIZE
+    )
+    .fetch_all(db_pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spreadsheet_safe;
+
+    #[test]
+    fn fields_starting_a_formula_are_quoted() {
+        for field in ["=1+1", "+1", "-1", "@SUM(A1)", "\t=1", "\r=1"] {
+            assert_eq!(spreadsheet_safe(field.into()), format!("'{}", field));
+        }
+    }
+
+    #[test]
+    fn other_fields_are_left_alone() {
+        assert_eq!(spreadsheet_safe("Ursula Le Guin".into()), "Ursula Le Guin");
+        assert_eq!(
+            spreadsheet_safe("a=b@example.com".into()),
+            "a=b@example.com"
+        );
+        assert_eq!(spreadsheet_safe("".into()), "");
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::HashSet;
+
+use actix_web::web::{Data, Payload, Query};
+use actix_web::HttpResponse;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::LoggedInUser;
+use crate::domain::Subscriber;
+use crate::lists::find_list;
+use crate::personal_data::SuppressionList;
+use crate::routes::SubscriberCreateRequest;
+use crate::utils::{e400, e500};
+
+const BATCH_SIZE: usize = 1000;
+
+/// Imported subscribers skip the double opt-in, `pending_confirmation` is therefore
+/// not offered: nobody would ever receive a confirmation link.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Confirmed,
+    Unsubscribed,
+}
+
+impl ImportStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ImportStatus::Confirmed => "confirmed",
+            ImportStatus::Unsubscribed => "unsubscribed",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    status: ImportStatus,
+    /// The slug of the list the subscribers join, the default list when missing.
+    list: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+struct ImportReport {
+    imported: u64,
+    errors: Vec<RowError>,
+}
+
+#[derive(Serialize)]
+struct RowError {
+    /// The position of the record in the file, the header being row 1.
+    row: u64,
+    error: String,
+}
+
+/// Imports subscribers from a CSV upload with an `email` and a `name` column and an
+/// optional `language` one, other columns are ignored. The upload is parsed as it
+/// arrives and inserted in batches; rows that are invalid, suppressed or already
+/// subscribed are reported and skipped. Nothing is imported if the upload fails.
+#[tracing::instrument(
+    name = "Import subscribers",
+    skip(query, payload, db_pool, suppression_list, _user)
+)]
+pub async fn import_subscribers(
+    query: Query<ImportQuery>,
+    mut payload: Payload,
+    db_pool: Data<PgPool>,
+    suppression_list: Data<SuppressionList>,
+    _user: LoggedInUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut transaction = db_pool.begin().await.map_err(e500)?;
+    let list_id = find_list(&mut transaction, query.list.as_deref())
+        .await
+        .map_err(e500)?
+        .ok_or_else(|| e400("There is no such newsletter list."))?;
+    let mut import = SubscriberImport::new(query.status, list_id);
+    let mut records = RecordBuffer::default();
+    while let Some(chunk) = payload.next().await {
+        let complete = records.push(&chunk?);
+        import
+            .process(&complete, &mut transaction, &suppression_list)
+            .await?;
+    }
+    import
+        .process(&records.finish(), &mut transaction, &suppression_list)
+        .await?;
+    let report = import.finish(&mut transaction, &suppression_list).await?;
+    transaction.commit().await.map_err(e500)?;
+    tracing::info!(
+        "Imported {} subscribers, skipped {} rows",
+        report.imported,
+        report.errors.len()
+    );
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Where the columns we read are in the file.
+struct Columns {
+    email: usize,
+    name: usize,
+    language: Option<usize>,
+}
+
+impl Columns {
+    fn from_header(header: &csv::StringRecord) -> Result<Self, String> {
+        // Spreadsheets tend to save UTF-8 with a byte order mark, it ends up in front
+        // of the first column name.
+        let position = |column: &str| {
+            header.iter().position(|field| {
+                field
+                    .trim_start_matches('\u{feff}')
+                    .trim()
+                    .eq_ignore_ascii_case(column)
+            })
+        };
+        Ok(Self {
+            email: position("email").ok_or("The header has no `email` column.")?,
+            name: position("name").ok_or("The header has no `name` column.")?,
+            language: position("language"),
+        })
+    }
+}
+
+struct ValidRow {
+    row: u64,
+    subscriber: Subscriber,
+}
+
+struct SubscriberImport {
+    status: ImportStatus,
+    list_id: Uuid,
+    columns: Option<Columns>,
+    rows_read: u64,
+    /// Catches an address listed twice, which the batch insert could not tell apart
+    /// from one that is already subscribed.
+    seen: HashSet<String>,
+    batch: Vec<ValidRow>,
+    report: ImportReport,
+}
+
+impl SubscriberImport {
+    fn new(status: ImportStatus, list_id: Uuid) -> Self {
+        Self {
+            status,
+            list_id,
+            columns: None,
+            rows_read: 0,
+            seen: HashSet::new(),
+            batch: Vec::with_capacity(BATCH_SIZE),
+            report: ImportReport::default(),
+        }
+    }
+
+    /// Validates the complete records in `data` and inserts a batch whenever one fills
+    /// up.
+    async fn process(
+        &mut self,
+        data: &[u8],
+        transaction: &mut Transaction<'_, Postgres>,
+        suppression_list: &SuppressionList,
+    ) -> Result<(), actix_web::Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(data);
+        for record in reader.records() {
+            self.rows_read += 1;
+            let row = self.rows_read;
+            let Some(columns) = &self.columns else {
+                let header = record.map_err(e400)?;
+                self.columns = Some(Columns::from_header(&header).map_err(e400)?);
+                continue;
+            };
+            match record {
+                Ok(record) => match validate(&record, columns, &mut self.seen) {
+                    Ok(subscriber) => self.batch.push(ValidRow { row, subscriber }),
+                    Err(error) => self.report.errors.push(RowError { row, error }),
+                },
+                Err(error) => self.report.errors.push(RowError {
+                    row,
+                    error: error.to_string(),
+                }),
+            }
+            if self.batch.len() >= BATCH_SIZE {
+                self.insert_batch(transaction, suppression_list)
+                    .await
+                    .map_err(e500)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish(
+        mut self,
+        transaction: &mut Transaction<'_, Postgres>,
+        suppression_list: &SuppressionList,
+    ) -> Result<ImportReport, actix_web::Error> {
+        if self.columns.is_none() {
+            return Err(e400("The upload is empty."));
+        }
+        self.insert_batch(transaction, suppression_list)
+            .await
+            .map_err(e500)?;
+        self.report.errors.sort_by_key(|error| error.row);
+        Ok(self.report)
+    }
+
+    #[tracing::instrument(name = "Insert a batch of imported subscribers", skip_all)]
+    async fn insert_batch(
+        &mut self,
+        transaction: &mut Transaction<'_, Postgres>,
+        suppression_list: &SuppressionList,
+    ) -> Result<(), sqlx::Error> {
+        let batch = std::mem::take(&mut self.batch);
+        let emails: Vec<&str> = batch
+            .iter()
+            .map(|row| row.subscriber.email.as_ref())
+            .collect();
+        let suppressed = suppression_list
+            .suppressed_among(transaction, &emails)
+            .await?;
+        let (suppressed, batch): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .partition(|row| suppressed.contains(row.subscriber.email.as_ref()));
+        for row in suppressed {
+            self.report.errors.push(RowError {
+                row: row.row,
+                error: format!(
+                    "{} asked for their personal data to be erased",
+                    row.subscriber.email.as_ref()
+                ),
+            });
+        }
+
+        let ids: Vec<Uuid> = batch.iter().map(|_| Uuid::new_v4()).collect();
+        let emails: Vec<String> = batch
+            .iter()
+            .map(|row| row.subscriber.email.as_ref().to_string())
+            .collect();
+        let names: Vec<String> = batch
+            .iter()
+            .map(|row| row.subscriber.name.as_ref().to_string())
+            .collect();
+        let languages: Vec<Option<String>> = batch
+            .iter()
+            .map(|row| {
+                row.subscriber
+                    .language
+                    .as_ref()
+                    .map(|language| language.as_ref().to_string())
+            })
+            .collect();
+        let now = Utc::now();
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO subscriptions (id, email, name, language, subscribed_at, status)
+            SELECT id, email, name, language, $5, $6
+            FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::text[])
+                AS imported(id, email, name, language)
+            ON CONFLICT (lower(email)) DO NOTHING
+            RETURNING id, email
+            "#,
+            &ids[..],
+            &emails[..],
+            &names[..],
+            &languages[..] as &[Option<String>],
+            now,
+            self.status.as_str()
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        let inserted_ids: Vec<Uuid> = inserted.iter().map(|row| row.id).collect();
+        sqlx::query!(
+            r#"
+            INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at, confirmed_at)
+            SELECT subscriber_id, $2, $3::text, $4::timestamptz,
+                   CASE WHEN $3 = 'confirmed' THEN $4 END
+            FROM UNNEST($1::uuid[]) AS imported(subscriber_id)
+            "#,
+            &inserted_ids[..],
+            self.list_id,
+            self.status.as_str(),
+            now
+        )
+        .execute(&mut *transaction)
+        .await?;
+        let inserted: HashSet<String> = inserted.into_iter().map(|row| row.email).collect();
+
+        self.report.imported += inserted.len() as u64;
+        for row in batch {
+            if !inserted.contains(row.subscriber.email.as_ref()) {
+                self.report.errors.push(RowError {
+                    row: row.row,
+                    error: format!("{} is already a subscriber", row.subscriber.email.as_ref()),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate(
+    record: &csv::StringRecord,
+    columns: &Columns,
+    seen: &mut HashSet<String>,
+) -> Result<Subscriber, String> {
+    let field = |index: usize| record.get(index).unwrap_or_default().trim().to_string();
+    let subscriber: Subscriber = SubscriberCreateRequest {
+        email: field(columns.email),
+        name: field(columns.name),
+        language: columns.language.map(field),
+        list: None,
+    }
+    .try_into()?;
+    if !seen.insert(subscriber.email.as_ref().to_lowercase()) {
+        return Err(format!(
+            "{} appears earlier in the file",
+            subscriber.email.as_ref()
+        ));
+    }
+    Ok(subscriber)
+}
+
+/// Cuts a CSV byte stream into complete records, so that each chunk of an upload can
+/// be parsed as soon as it arrives. A line break ends a record unless it is quoted.
+#[derive(Default)]
+struct RecordBuffer {
+    buffer: Vec<u8>,
+}
+
+impl RecordBuffer {
+    /// The records completed by `chunk`, the rest is kept for the next one.
+    fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.buffer.extend_from_slice(chunk);
+        let mut quoted = false;
+        let mut end = 0;
+        for (i, byte) in self.buffer.iter().enumerate() {
+            match byte {
+                // An escaped quote toggles twice, which leaves `quoted` unchanged.
+                b'"' => quoted = !quoted,
+                b'\n' if !quoted => end = i + 1,
+                _ => {}
+            }
+        }
+        let rest = self.buffer.split_off(end);
+        std::mem::replace(&mut self.buffer, rest)
+    }
+
+    /// The last record, which may lack a line break.
+    fn finish(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Columns, RecordBuffer};
+
+    #[test]
+    fn a_record_is_held_back_until_its_line_break_arrives() {
+        let mut buffer = RecordBuffer::default();
+
+        assert_eq!(buffer.push(b"email,name\nursula@exa"), b"email,name\n");
+        assert_eq!(
+            buffer.push(b"mple.com,Ursula\n"),
+            b"ursula@example.com,Ursula\n"
+        );
+        assert!(buffer.finish().is_empty());
+    }
+
+    #[test]
+    fn a_line_break_inside_quotes_does_not_end_the_record() {
+        let mut buffer = RecordBuffer::default();
+
+        assert!(buffer.push(b"a@example.com,\"Le\n").is_empty());
+        assert_eq!(
+            buffer.push(b" \"\"Guin\"\"\"\nb"),
+            b"a@example.com,\"Le\n \"\"Guin\"\"\"\n"
+        );
+        assert_eq!(buffer.finish(), b"b");
+    }
+
+    #[test]
+    fn a_byte_order_mark_before_the_header_is_ignored() {
+        let header = csv::StringRecord::from(vec!["\u{feff}email", "name"]);
+
+        let columns = Columns::from_header(&header).unwrap();
+
+        assert_eq!(columns.email, 0);
+        assert_eq!(columns.name, 1);
+    }
+}
@@ -0,0 +1,179 @@
+use actix_web::web::{Data, Query};
+use actix_web::HttpResponse;
+use anyhow::Context;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::LoggedInUser;
+use crate::utils::{e400, e500};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct SubscriberListQuery {
+    status: Option<String>,
+    /// Inclusive.
+    signed_up_after: Option<DateTime<Utc>>,
+    /// Exclusive.
+    signed_up_before: Option<DateTime<Utc>>,
+    /// Case-insensitive substring of the email or the name.
+    search: Option<String>,
+    limit: Option<i64>,
+    /// The `next_cursor` of the previous page.
+    cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SubscriberRecord {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    language: Option<String>,
+    subscribed_at: DateTime<Utc>,
+    unsubscribed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct SubscriberPage {
+    subscribers: Vec<SubscriberRecord>,
+    /// `None` on the last page.
+    next_cursor: Option<String>,
+}
+
+/// Where a page ends: the sort key of its last subscriber. Opaque to clients.
+#[derive(Debug, PartialEq)]
+struct Cursor {
+    subscribed_at: DateTime<Utc>,
+    id: Uuid,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(format!(
+            "{}|{}",
+            self.subscribed_at.to_rfc3339(),
+            self.id
+        ))
+    }
+
+    fn decode(cursor: &str) -> Result<Self, anyhow::Error> {
+        let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .context("The cursor is not valid base64.")?;
+        let decoded = String::from_utf8(decoded).context("The cursor is not valid UTF8.")?;
+        let (subscribed_at, id) = decoded
+            .split_once('|')
+            .context("The cursor is malformed.")?;
+        Ok(Self {
+            subscribed_at: DateTime::parse_from_rfc3339(subscribed_at)
+                .context("The cursor is malformed.")?
+                .with_timezone(&Utc),
+            id: id.parse().context("The cursor is malformed.")?,
+        })
+    }
+}
+
+/// Lists subscribers newest first. Pages are delimited by keyset rather than offset,
+/// so they stay stable while people keep signing up.
+#[tracing::instrument(name = "List subscribers", skip(query, db_pool, _user))]
+pub async fn list_subscribers(
+    query: Query<SubscriberListQuery>,
+    db_pool: Data<PgPool>,
+    _user: LoggedInUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(Cursor::decode)
+        .transpose()
+        .map_err(e400)?;
+    let search = query.search.as_deref().map(like_pattern);
+
+    // One row more than asked for tells whether there is a next page.
+    let mut subscribers = sqlx::query_as!(
+        SubscriberRecord,
+        r#"
+        SELECT id, email, name, status, language, subscribed_at, unsubscribed_at
+        FROM subscriptions
+        WHERE ($1::text IS NULL OR status = $1)
+          AND ($2::timestamptz IS NULL OR subscribed_at >= $2)
+          AND ($3::timestamptz IS NULL OR subscribed_at < $3)
+          AND ($4::text IS NULL OR email ILIKE $4 OR name ILIKE $4)
+          AND ($5::timestamptz IS NULL OR (subscribed_at, id) < ($5, $6::uuid))
+        ORDER BY subscribed_at DESC, id DESC
+        LIMIT $7
+        "#,
+        query.status,
+        query.signed_up_after,
+        query.signed_up_before,
+        search,
+        cursor.as_ref().map(|c| c.subscribed_at),
+        cursor.as_ref().map(|c| c.id),
+        limit + 1
+    )
+    .fetch_all(db_pool.get_ref())
+    .await
+    .map_err(e500)?;
+
+    let next_cursor = if subscribers.len() as i64 > limit {
+        subscribers.truncate(limit as usize);
+        subscribers.last().map(|last| {
+            Cursor {
+                subscribed_at: last.subscribed_at,
+                id: last.id,
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(SubscriberPage {
+        subscribers,
+        next_cursor,
+    }))
+}
+
+/// Matches `search` anywhere, taking `%`, `_` and `\` literally.
+fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::chrono::{TimeZone, Utc};
+    use uuid::Uuid;
+
+    use super::{like_pattern, Cursor};
+
+    #[test]
+    fn a_cursor_survives_a_round_trip() {
+        let cursor = Cursor {
+            subscribed_at: Utc.with_ymd_and_hms(2023, 9, 10, 14, 38, 51).unwrap(),
+            id: Uuid::new_v4(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+    }
+
+    #[test]
+    fn a_garbage_cursor_is_rejected() {
+        assert!(Cursor::decode("not a cursor").is_err());
+    }
+
+    #[test]
+    fn like_wildcards_in_the_search_are_escaped() {
+        assert_eq!(like_pattern("50%_off"), r"%50\%\_off%");
+    }
+}
@@ -0,0 +1,9 @@
+pub use consent::*;
+pub use export::*;
+pub use import::*;
+pub use list::*;
+
+mod consent;
+mod export;
+mod import;
+mod list;
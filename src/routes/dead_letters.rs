@@ -0,0 +1,44 @@
+use actix_web::web::{Data, Json, Query};
+use actix_web::HttpResponse;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::AuthenticatedUser;
+use crate::issue_delivery_worker::{get_dead_letters, redrive_dead_letters};
+use crate::utils::e500;
+
+#[derive(Debug, Deserialize)]
+pub struct DeadLetterFilter {
+    /// Limits the request to one newsletter issue, all of them when missing.
+    pub newsletter_issue_id: Option<Uuid>,
+}
+
+#[tracing::instrument(name = "List dead-lettered deliveries", skip(db_pool, _user))]
+pub async fn list_dead_letters(
+    filter: Query<DeadLetterFilter>,
+    db_pool: Data<PgPool>,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let dead_letters = get_dead_letters(&db_pool, filter.newsletter_issue_id)
+        .await
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok().json(dead_letters))
+}
+
+/// Puts the dead-lettered deliveries back on the queue with a fresh attempt budget.
+#[tracing::instrument(
+    name = "Re-drive dead-lettered deliveries",
+    skip(db_pool, user),
+    fields(user_id = %user.user_id)
+)]
+pub async fn requeue_dead_letters(
+    body: Json<DeadLetterFilter>,
+    db_pool: Data<PgPool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let redriven = redrive_dead_letters(&db_pool, body.newsletter_issue_id)
+        .await
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "redriven": redriven })))
+}
@@ -0,0 +1,113 @@
+use actix_web::web::{Data, Json};
+use actix_web::HttpResponse;
+use serde::Deserialize;
+use sqlx::types::chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::AuthenticatedWebhook;
+use crate::utils::{e400, e500};
+
+/// The fields we act upon, the whole payload is stored as it was received.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkEvent {
+    record_type: String,
+    email: String,
+    #[serde(rename = "Type", default)]
+    bounce_type: Option<String>,
+    /// Set when Postmark stopped sending to the address because of this event.
+    #[serde(default)]
+    inactive: bool,
+}
+
+impl PostmarkEvent {
+    /// The status the subscriber moves to, `None` for events that do not say
+    /// anything final about the address such as soft bounces.
+    fn subscriber_status(&self) -> Option<&'static str> {
+        match self.record_type.as_str() {
+            "SpamComplaint" => Some("complained"),
+            "Bounce" => {
+                let permanent = matches!(
+                    self.bounce_type.as_deref(),
+                    Some("HardBounce" | "BadEmailAddress")
+                );
+                (permanent || self.inactive).then_some("bounced")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Receives Postmark's bounce and spam complaint webhooks. Other record types are
+/// acknowledged and ignored, so that enabling more of them in Postmark is harmless.
+#[tracing::instrument(name = "Process a Postmark webhook", skip(payload, db_pool, _webhook))]
+pub async fn postmark_webhook(
+    payload: Json<serde_json::Value>,
+    db_pool: Data<PgPool>,
+    _webhook: AuthenticatedWebhook,
+) -> Result<HttpResponse, actix_web::Error> {
+    let payload = payload.into_inner();
+    let event: PostmarkEvent = serde_json::from_value(payload.clone()).map_err(e400)?;
+    if !matches!(event.record_type.as_str(), "Bounce" | "SpamComplaint") {
+        tracing::info!("Ignoring a [{}] webhook", event.record_type);
+        return Ok(HttpResponse::Ok().finish());
+    }
+
+    let mut transaction = db_pool.begin().await.map_err(e500)?;
+    store_email_event(&mut transaction, &event, &payload)
+        .await
+        .map_err(e500)?;
+    if let Some(status) = event.subscriber_status() {
+        tracing::info!("Marking the subscriber as [{}]", status);
+        update_subscriber_status(&mut transaction, &event.email, status)
+            .await
+            .map_err(e500)?;
+    }
+    transaction.commit().await.map_err(e500)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(name = "Store an email event", skip_all)]
+async fn store_email_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    event: &PostmarkEvent,
+    payload: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO email_events (id, record_type, subscriber_email, payload, received_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        Uuid::new_v4(),
+        event.record_type,
+        event.email,
+        payload,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// A spam complaint is final, a later bounce does not replace it. Providers do not
+/// keep the casing of the address we sent to, it is matched case-insensitively.
+#[tracing::instrument(name = "Update the subscriber status", skip(transaction, email))]
+async fn update_subscriber_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+    status: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET status = $1
+        WHERE lower(email) = lower($2) AND status <> 'complained'
+        "#,
+        status,
+        email
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use actix_web::web::{Data, Form};
+use actix_web::HttpResponse;
+use secrecy::Secret;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::session_state::TypedSession;
+use crate::utils::{e500, see_other};
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: Secret<String>,
+}
+
+#[tracing::instrument(
+    name = "Log in an admin",
+    skip(form, db_pool, session),
+    fields(username = %form.username, user_id = tracing::field::Empty)
+)]
+pub async fn login(
+    form: Form<LoginRequest>,
+    db_pool: Data<PgPool>,
+    session: TypedSession,
+) -> Result<HttpResponse, actix_web::Error> {
+    let form = form.0;
+    let credentials = Credentials {
+        username: form.username,
+        password: form.password,
+    };
+
+    match validate_credentials(credentials, &db_pool).await {
+        Ok(user_id) => {
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+            session.renew();
+            session.insert_user_id(user_id).map_err(e500)?;
+            Ok(see_other("/admin/dashboard"))
+        }
+        Err(AuthError::InvalidCredentials(e)) => {
+            tracing::info!("Rejected login attempt cause: [{:?}]", e);
+            session
+                .insert_flash("Authentication failed")
+                .map_err(e500)?;
+            Ok(see_other("/login"))
+        }
+        Err(AuthError::UnexpectedError(e)) => {
+            tracing::error!("Failed to log in cause: [{:?}]", e);
+            Err(e500(e))
+        }
+    }
+}
@@ -1,7 +1,29 @@
+pub use admin::*;
+pub use dead_letters::*;
+pub use email_webhooks::*;
 pub use health_check::*;
+pub use login::*;
+pub use newsletter_issues::*;
+pub use newsletters::*;
+pub use personal_data::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_email_change::*;
+pub use subscriptions_personal_data::*;
+pub use subscriptions_preferences::*;
+pub use subscriptions_unsubscribe::*;
 
+mod admin;
+mod dead_letters;
+mod email_webhooks;
 mod health_check;
+mod login;
+mod newsletter_issues;
+mod newsletters;
+mod personal_data;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_email_change;
+mod subscriptions_personal_data;
+mod subscriptions_preferences;
+mod subscriptions_unsubscribe;
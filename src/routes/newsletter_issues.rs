@@ -0,0 +1,260 @@
+use actix_web::web::{Data, Json, Path, Query};
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::AuthenticatedUser;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_templates::EmailTemplates;
+use crate::issue_scheduler::send_issue;
+use crate::lists::find_list;
+use crate::newsletter_issues::{
+    edit_issue, get_issue, get_issue_content, get_revisions, insert_newsletter_issue, render_issue,
+    IssueContent,
+};
+use crate::routes::{issue_change_response, NewsletterContent};
+use crate::startup::TestRecipients;
+use crate::subscriber_links::SubscriberLinkSigner;
+use crate::utils::{e400, e500};
+
+#[derive(Debug, Deserialize)]
+pub struct DraftRequest {
+    pub title: String,
+    pub content: NewsletterContent,
+    /// The slug of the list to publish to, the default list when missing.
+    pub list: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueEditRequest {
+    pub title: String,
+    pub content: NewsletterContent,
+}
+
+impl IssueEditRequest {
+    fn content(&self) -> IssueContent {
+        IssueContent {
+            title: self.title.clone(),
+            text_content: self.content.text.clone(),
+            html_content: self.content.html.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewParameters {
+    /// The subscriber to render the issue for.
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TestSendRequest {
+    /// The only address the test goes to, one of the `TestRecipients`.
+    pub email: String,
+}
+
+#[derive(Serialize)]
+struct IssuePreview {
+    subject: String,
+    html: String,
+    text: String,
+}
+
+struct PreviewSubscriber {
+    id: Uuid,
+    language: Option<String>,
+}
+
+/// Saves an issue without sending it, it can be edited, previewed and tested until
+/// it is scheduled or sent.
+#[tracing::instrument(
+    name = "Create a newsletter draft",
+    skip(body, db_pool, user),
+    fields(newsletter_title = %body.title, user_id = %user.user_id)
+)]
+pub async fn create_newsletter_draft(
+    body: Json<DraftRequest>,
+    db_pool: Data<PgPool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut transaction = db_pool.begin().await.map_err(e500)?;
+    let list_id = find_list(&mut transaction, body.list.as_deref())
+        .await
+        .map_err(e500)?
+        .ok_or_else(|| e400("There is no such newsletter list."))?;
+    let content = IssueContent {
+        title: body.title.clone(),
+        text_content: body.content.text.clone(),
+        html_content: body.content.html.clone(),
+    };
+    let issue_id = insert_newsletter_issue(&mut transaction, list_id, &content, user.user_id)
+        .await
+        .map_err(e500)?;
+    transaction.commit().await.map_err(e500)?;
+    Ok(HttpResponse::Created().json(serde_json::json!({ "newsletter_issue_id": issue_id })))
+}
+
+#[tracing::instrument(name = "Show a newsletter issue", skip(db_pool, _user))]
+pub async fn newsletter_issue(
+    issue_id: Path<Uuid>,
+    db_pool: Data<PgPool>,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    match get_issue(&db_pool, *issue_id).await.map_err(e500)? {
+        Some(issue) => Ok(HttpResponse::Ok().json(issue)),
+        None => Ok(HttpResponse::NotFound().body("There is no such newsletter issue.")),
+    }
+}
+
+#[tracing::instrument(name = "Edit a newsletter issue", skip(body, db_pool, user))]
+pub async fn edit_newsletter_issue(
+    issue_id: Path<Uuid>,
+    body: Json<IssueEditRequest>,
+    db_pool: Data<PgPool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let change = edit_issue(&db_pool, *issue_id, &body.content(), user.user_id)
+        .await
+        .map_err(e500)?;
+    Ok(issue_change_response(change))
+}
+
+#[tracing::instrument(
+    name = "List the revisions of a newsletter issue",
+    skip(db_pool, _user)
+)]
+pub async fn newsletter_issue_revisions(
+    issue_id: Path<Uuid>,
+    db_pool: Data<PgPool>,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let revisions = get_revisions(&db_pool, *issue_id).await.map_err(e500)?;
+    // Every issue has at least its first revision.
+    if revisions.is_empty() {
+        return Ok(HttpResponse::NotFound().body("There is no such newsletter issue."));
+    }
+    Ok(HttpResponse::Ok().json(revisions))
+}
+
+/// Renders the issue with the subscriber's language and links, exactly as the
+/// delivery worker would.
+#[tracing::instrument(
+    name = "Preview a newsletter issue",
+    skip(parameters, db_pool, subscriber_links, email_templates, _user)
+)]
+pub async fn preview_newsletter_issue(
+    issue_id: Path<Uuid>,
+    parameters: Query<PreviewParameters>,
+    db_pool: Data<PgPool>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+    email_templates: Data<EmailTemplates>,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let issue = match get_issue_content(&db_pool, *issue_id).await.map_err(e500)? {
+        Some(issue) => issue,
+        None => return Ok(HttpResponse::NotFound().body("There is no such newsletter issue.")),
+    };
+    let subscriber = match get_preview_subscriber(&db_pool, &parameters.email)
+        .await
+        .map_err(e500)?
+    {
+        Some(subscriber) => subscriber,
+        None => return Ok(HttpResponse::NotFound().body("There is no such subscriber.")),
+    };
+    let body = render_issue(
+        &email_templates,
+        &subscriber_links,
+        &issue,
+        subscriber.id,
+        subscriber.language.as_deref(),
+    )
+    .map_err(e500)?;
+    Ok(HttpResponse::Ok().json(IssuePreview {
+        subject: issue.content.title,
+        html: body.html,
+        text: body.text,
+    }))
+}
+
+/// Sends the issue to a single admin address out of `TestRecipients`, whatever its
+/// status. The test is rendered in the default language, its subscriber links belong
+/// to nobody.
+#[tracing::instrument(
+    name = "Send a test of a newsletter issue",
+    skip(
+        body,
+        db_pool,
+        email_client,
+        subscriber_links,
+        email_templates,
+        test_recipients,
+        _user
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn send_test_newsletter_issue(
+    issue_id: Path<Uuid>,
+    body: Json<TestSendRequest>,
+    db_pool: Data<PgPool>,
+    email_client: Data<EmailClient>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+    email_templates: Data<EmailTemplates>,
+    test_recipients: Data<TestRecipients>,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let recipient = SubscriberEmail::parse(body.0.email).map_err(e400)?;
+    if !test_recipients.contains(recipient.as_ref()) {
+        return Ok(HttpResponse::BadRequest()
+            .body("Tests are only sent to the addresses in `admin.test_recipients`."));
+    }
+    let issue = match get_issue_content(&db_pool, *issue_id).await.map_err(e500)? {
+        Some(issue) => issue,
+        None => return Ok(HttpResponse::NotFound().body("There is no such newsletter issue.")),
+    };
+    let rendered = render_issue(
+        &email_templates,
+        &subscriber_links,
+        &issue,
+        Uuid::nil(),
+        None,
+    )
+    .map_err(e500)?;
+    email_client
+        .send_mail(
+            &recipient,
+            &format!("[Test] {}", issue.content.title),
+            &rendered.html,
+            &rendered.text,
+            None,
+        )
+        .await
+        .map_err(e500)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Queues the deliveries of a draft or scheduled issue right away.
+#[tracing::instrument(name = "Send a newsletter issue", skip(db_pool, _user))]
+pub async fn send_newsletter_issue(
+    issue_id: Path<Uuid>,
+    db_pool: Data<PgPool>,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let change = send_issue(&db_pool, *issue_id).await.map_err(e500)?;
+    Ok(issue_change_response(change))
+}
+
+#[tracing::instrument(name = "Get the subscriber to preview for", skip(db_pool, email))]
+async fn get_preview_subscriber(
+    db_pool: &PgPool,
+    email: &str,
+) -> Result<Option<PreviewSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        PreviewSubscriber,
+        r#"SELECT id, language FROM subscriptions WHERE lower(email) = lower($1)"#,
+        email
+    )
+    .fetch_optional(db_pool)
+    .await
+}
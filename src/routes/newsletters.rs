@@ -0,0 +1,187 @@
+use actix_web::web::{Data, Json, Path};
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::FixedOffset;
+use serde::Deserialize;
+use sqlx::types::chrono::DateTime;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::authentication::AuthenticatedUser;
+use crate::idempotency::{save_response, try_processing, IdempotencyKey, NextAction};
+use crate::issue_scheduler::{
+    cancel_issue, enqueue_delivery_tasks, get_scheduled_issues, reschedule_issue, schedule_issue,
+    Schedule,
+};
+use crate::lists::find_list;
+use crate::newsletter_issues::{insert_newsletter_issue, IssueChange, IssueContent};
+use crate::utils::{e400, e500};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+#[derive(Debug, Deserialize)]
+pub struct NewsletterPublishRequest {
+    pub title: String,
+    pub content: NewsletterContent,
+    /// The slug of the list to publish to, the default list when missing.
+    pub list: Option<String>,
+    /// Sends the issue at this time rather than right away, see `ScheduleRequest`.
+    pub scheduled_at: Option<DateTime<FixedOffset>>,
+    #[serde(default)]
+    pub in_subscriber_timezone: bool,
+}
+
+impl NewsletterPublishRequest {
+    fn schedule(&self) -> Result<Option<Schedule>, actix_web::Error> {
+        match (self.scheduled_at, self.in_subscriber_timezone) {
+            (Some(scheduled_at), in_subscriber_timezone) => {
+                Ok(Some(Schedule::new(scheduled_at, in_subscriber_timezone)))
+            }
+            (None, true) => Err(e400(
+                "Sending in the subscribers' timezones needs a `scheduled_at`.",
+            )),
+            (None, false) => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleRequest {
+    /// An RFC 3339 timestamp, its offset tells the wall-clock time used with
+    /// `in_subscriber_timezone`.
+    pub scheduled_at: DateTime<FixedOffset>,
+    /// Delivers at the wall-clock time of `scheduled_at` in each subscriber's own
+    /// timezone, subscribers without one get the issue at `scheduled_at`.
+    #[serde(default)]
+    pub in_subscriber_timezone: bool,
+}
+
+impl ScheduleRequest {
+    fn schedule(&self) -> Schedule {
+        Schedule::new(self.scheduled_at, self.in_subscriber_timezone)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewsletterContent {
+    pub html: String,
+    pub text: String,
+}
+
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(body, db_pool, user, request),
+    fields(newsletter_title = %body.title, user_id = %user.user_id)
+)]
+pub async fn publish_newsletter(
+    body: Json<NewsletterPublishRequest>,
+    db_pool: Data<PgPool>,
+    user: AuthenticatedUser,
+    request: HttpRequest,
+) -> Result<HttpResponse, actix_web::Error> {
+    let schedule = body.schedule()?;
+    let idempotency_key = match request.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => {
+            let key = value.to_str().map_err(e400)?.to_string();
+            Some(IdempotencyKey::parse(key).map_err(e400)?)
+        }
+        None => None,
+    };
+
+    let mut transaction = match &idempotency_key {
+        Some(key) => match try_processing(&db_pool, key, user.user_id)
+            .await
+            .map_err(e500)?
+        {
+            NextAction::StartProcessing(transaction) => transaction,
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+        },
+        None => db_pool.begin().await.map_err(e500)?,
+    };
+
+    let list_id = find_list(&mut transaction, body.list.as_deref())
+        .await
+        .map_err(e500)?
+        .ok_or_else(|| e400("There is no such newsletter list."))?;
+    let content = IssueContent {
+        title: body.title.clone(),
+        text_content: body.content.text.clone(),
+        html_content: body.content.html.clone(),
+    };
+    let issue_id = insert_newsletter_issue(&mut transaction, list_id, &content, user.user_id)
+        .await
+        .map_err(e500)?;
+    match schedule {
+        Some(schedule) => {
+            schedule_issue(&mut transaction, issue_id, &schedule)
+                .await
+                .map_err(e500)?;
+            tracing::info!(
+                "Newsletter issue [{}] scheduled for [{}]",
+                issue_id,
+                schedule.scheduled_at
+            );
+        }
+        None => {
+            enqueue_delivery_tasks(&mut transaction, issue_id)
+                .await
+                .map_err(e500)?;
+            tracing::info!("Newsletter issue [{}] queued for delivery", issue_id);
+        }
+    }
+
+    let response =
+        HttpResponse::Accepted().json(serde_json::json!({ "newsletter_issue_id": issue_id }));
+    match idempotency_key {
+        Some(key) => save_response(transaction, &key, user.user_id, response)
+            .await
+            .map_err(e500),
+        None => {
+            transaction.commit().await.map_err(e500)?;
+            Ok(response)
+        }
+    }
+}
+
+#[tracing::instrument(name = "List the scheduled newsletter issues", skip(db_pool, _user))]
+pub async fn scheduled_newsletters(
+    db_pool: Data<PgPool>,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let issues = get_scheduled_issues(&db_pool).await.map_err(e500)?;
+    Ok(HttpResponse::Ok().json(issues))
+}
+
+/// Moves an issue to another time, as long as its deliveries are not queued yet.
+#[tracing::instrument(name = "Reschedule a newsletter issue", skip(body, db_pool, _user))]
+pub async fn reschedule_newsletter(
+    issue_id: Path<Uuid>,
+    body: Json<ScheduleRequest>,
+    db_pool: Data<PgPool>,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let change = reschedule_issue(&db_pool, *issue_id, &body.schedule())
+        .await
+        .map_err(e500)?;
+    Ok(issue_change_response(change))
+}
+
+#[tracing::instrument(name = "Cancel a newsletter issue", skip(db_pool, _user))]
+pub async fn cancel_newsletter(
+    issue_id: Path<Uuid>,
+    db_pool: Data<PgPool>,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let change = cancel_issue(&db_pool, *issue_id).await.map_err(e500)?;
+    Ok(issue_change_response(change))
+}
+
+pub(crate) fn issue_change_response(change: IssueChange) -> HttpResponse {
+    match change {
+        IssueChange::Applied => HttpResponse::NoContent().finish(),
+        IssueChange::UnknownIssue => {
+            HttpResponse::NotFound().body("There is no such newsletter issue.")
+        }
+        IssueChange::NotAllowed => HttpResponse::Conflict()
+            .body("The issue is not a draft or scheduled, it cannot be changed that way."),
+    }
+}
@@ -0,0 +1,53 @@
+use actix_web::web::{Data, Query};
+use actix_web::HttpResponse;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::authentication::AuthenticatedUser;
+use crate::personal_data::{erase_personal_data, export_personal_data, SuppressionList};
+use crate::utils::e500;
+
+#[derive(Deserialize)]
+pub struct PersonalDataQuery {
+    email: String,
+}
+
+/// Answers a data subject access request on behalf of the owner of `email`.
+#[tracing::instrument(
+    name = "Export the personal data of an email address",
+    skip(query, db_pool, user),
+    fields(user_id = %user.user_id)
+)]
+pub async fn personal_data_export(
+    query: Query<PersonalDataQuery>,
+    db_pool: Data<PgPool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let export = export_personal_data(&db_pool, &query.email)
+        .await
+        .map_err(e500)?;
+    if export.is_empty() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    Ok(HttpResponse::Ok().json(export))
+}
+
+/// Answers an erasure request with the number of deleted rows. Repeating it is
+/// harmless, so is erasing an address we never stored: it ends up on the suppression
+/// list either way.
+#[tracing::instrument(
+    name = "Erase the personal data of an email address",
+    skip(query, db_pool, suppression_list, user),
+    fields(user_id = %user.user_id)
+)]
+pub async fn personal_data_erasure(
+    query: Query<PersonalDataQuery>,
+    db_pool: Data<PgPool>,
+    suppression_list: Data<SuppressionList>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let erased_rows = erase_personal_data(&db_pool, &query.email, &suppression_list)
+        .await
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "erased_rows": erased_rows })))
+}
@@ -1,24 +1,70 @@
+use actix_web::http::header::ACCEPT_LANGUAGE;
+use actix_web::http::StatusCode;
 use actix_web::web::{Data, Form};
-use actix_web::{HttpResponse, Responder};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use sqlx::types::chrono::Utc;
+use sqlx::types::chrono::{DateTime, Utc};
 use sqlx::types::Uuid;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 
+use crate::consent::{record_consent_event, ConsentEventKind, ConsentForm, ConsentOrigin};
 use crate::domain::Subscriber;
-use crate::email_client::EmailClient;
-use crate::startup::ApplicationBaseUrl;
+use crate::email_client::{EmailClient, EmailError};
+use crate::email_templates::{EmailTemplates, RenderedEmail, TemplateContext};
+use crate::lists::{find_list, get_membership_status, request_membership};
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::utils::error_chain_fmt;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SubscriberCreateRequest {
     pub email: String,
     pub name: String,
+    /// Falls back to the `Accept-Language` header when missing.
+    pub language: Option<String>,
+    /// The slug of the list to join, the default list when missing.
+    pub list: Option<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SubscribeError::ValidationError(message) => {
+                HttpResponse::BadRequest().body(message.clone())
+            }
+            SubscribeError::UnexpectedError(_) => {
+                tracing::error!("Failed to add new subscriber cause: {:?}", self);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
 }
 
 #[tracing::instrument(
-name = "Adding a new subscriber", skip(subscriber_request, db_pool, base_url ),
+name = "Adding a new subscriber", skip(subscriber_request, request, db_pool, base_url, token_ttl, email_templates),
 fields(
 subscriber_email = % subscriber_request.email,
 subscriber_name = % subscriber_request.name
@@ -26,130 +72,327 @@ subscriber_name = % subscriber_request.name
 )]
 pub async fn subscriptions(
     subscriber_request: Form<SubscriberCreateRequest>,
+    request: HttpRequest,
     db_pool: Data<PgPool>,
     email_client: Data<EmailClient>,
     base_url: Data<ApplicationBaseUrl>,
-) -> impl Responder {
+    token_ttl: Data<SubscriptionTokenTtl>,
+    email_templates: Data<EmailTemplates>,
+) -> Result<HttpResponse, SubscribeError> {
     tracing::info!(
         "Adding new subscriber with email: [{}]",
         subscriber_request.email
     );
 
-    let subscriber_to_create = match subscriber_request.0.try_into() {
-        Ok(subscriber) => subscriber,
-        Err(_) => return HttpResponse::BadRequest().finish(),
-    };
+    let mut subscriber_request = subscriber_request.0;
+    if subscriber_request.language.is_none() {
+        subscriber_request.language = preferred_language(&request);
+    }
+    let list = subscriber_request.list.take();
+    let subscriber_to_create: Subscriber = subscriber_request
+        .try_into()
+        .map_err(SubscribeError::ValidationError)?;
 
-    let subscriber_id = match create_new_subscriber(&subscriber_to_create, db_pool.get_ref()).await
-    {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
+    let mut transaction = db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let list_id = find_list(&mut transaction, list.as_deref())
+        .await
+        .context("Failed to look up the newsletter list.")?
+        .ok_or_else(|| {
+            SubscribeError::ValidationError(format!(
+                "There is no newsletter list {}",
+                list.unwrap_or_default()
+            ))
+        })?;
+    let existing = get_existing_subscriber(&subscriber_to_create, &mut transaction)
+        .await
+        .context("Failed to look up an existing subscriber with the same email.")?;
+    let (subscriber_id, existing) = match existing {
+        Some(existing) => (existing.id, Some(existing)),
+        None => match create_new_subscriber(&subscriber_to_create, &mut transaction)
+            .await
+            .context("Failed to insert new subscriber in the database.")?
+        {
+            Some(subscriber_id) => (subscriber_id, None),
+            // A concurrent sign-up with the same email won the insert.
+            None => {
+                let existing = get_existing_subscriber(&subscriber_to_create, &mut transaction)
+                    .await
+                    .context("Failed to look up the concurrently added subscriber.")?
+                    .context("The concurrently added subscriber is gone.")?;
+                (existing.id, Some(existing))
+            }
+        },
     };
-
-    let token = generate_subscription_token();
-
-    if insert_subscription_token(&token, subscriber_id, db_pool.get_ref())
+    let membership_status = get_membership_status(&mut transaction, subscriber_id, list_id)
         .await
-        .is_err()
-    {
-        return HttpResponse::InternalServerError().finish();
+        .context("Failed to look up the list membership of the subscriber.")?;
+    // Answer exactly like a fresh sign-up, and just as slowly, so the response does
+    // not reveal whether the address is already on the list. Only the owner of the
+    // address learns about it from the notice.
+    let address_confirmed = existing.as_ref().map(|e| e.status.as_str()) == Some("confirmed");
+    if address_confirmed && membership_status.as_deref() == Some("confirmed") {
+        tracing::info!("Subscriber is already confirmed, sending a notice");
+        let notice = render_already_subscribed_email(&email_templates, &subscriber_to_create)?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit the transaction to look up a subscriber.")?;
+        send_subscription_email(&email_client, subscriber_to_create, &notice)
+            .await
+            .context("Failed to send an already subscribed notice.")?;
+        return Ok(HttpResponse::Ok().finish());
     }
-
-    tracing::info!("Sending confirmation mail to new subscriber");
-
-    if send_email_confirmation(
-        &email_client,
-        &base_url.0,
-        subscriber_to_create,
-        token.as_str(),
+    request_membership(&mut transaction, subscriber_id, list_id)
+        .await
+        .context("Failed to store the list membership of the subscriber.")?;
+    let token = confirmation_token(subscriber_id, list_id, &token_ttl, &mut transaction).await?;
+    record_consent_event(
+        &mut transaction,
+        subscriber_id,
+        list_id,
+        ConsentEventKind::Subscribed,
+        &token,
+        &ConsentOrigin::from_request(&request),
+        ConsentForm::Subscribe,
     )
     .await
-    .is_err()
-    {
-        return HttpResponse::InternalServerError().finish();
+    .context("Failed to record the consent given by the subscriber.")?;
+    // Rendered before committing so that a broken template does not leave behind a
+    // subscriber who never got the email.
+    let confirmation_email = render_confirmation_email(
+        &email_templates,
+        &base_url.0,
+        subscriber_to_create.name.as_ref(),
+        subscriber_to_create.language.as_ref().map(AsRef::as_ref),
+        token.as_str(),
+    )?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the transaction to store a new subscriber.")?;
+
+    tracing::info!("Sending confirmation mail to new subscriber");
+    let outcome =
+        send_subscription_email(&email_client, subscriber_to_create, &confirmation_email).await;
+    if let Err(e) = record_confirmation_email_outcome(&token, &outcome, db_pool.get_ref()).await {
+        tracing::warn!(
+            "Failed to record the confirmation email outcome cause: [{:?}]",
+            e
+        );
     }
+    outcome.context("Failed to send a confirmation email.")?;
 
     tracing::info!("Successfully added new subscriber");
-    HttpResponse::Ok().finish()
+    Ok(HttpResponse::Ok().finish())
 }
 
-#[tracing::instrument(
-    name = "Send a confirmation email to new subscriber",
-    skip(email_client, subscriber_to_create, base_url)
-)]
-async fn send_email_confirmation(
-    email_client: &EmailClient,
+/// The first usable language of the `Accept-Language` header. Weights are ignored,
+/// browsers list the languages in order of preference anyway.
+fn preferred_language(request: &HttpRequest) -> Option<String> {
+    let header = request.headers().get(ACCEPT_LANGUAGE)?.to_str().ok()?;
+    fluent_langneg::parse_accepted_languages(header)
+        .into_iter()
+        .find(|language| !language.language.is_empty())
+        .map(|language| language.to_string())
+}
+
+/// An email sent in answer to a sign-up.
+pub(crate) struct SubscriptionEmail {
+    pub(crate) subject: String,
+    pub(crate) body: RenderedEmail,
+}
+
+/// The token of the link confirming that the subscriber joins `list_id`. A resent
+/// confirmation email carries the same link as the one sent before.
+pub(crate) async fn confirmation_token(
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    token_ttl: &SubscriptionTokenTtl,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<String, anyhow::Error> {
+    let issued_after =
+        Utc::now() - chrono::Duration::from_std(token_ttl.0).context("Invalid token ttl.")?;
+    match get_live_subscription_token(subscriber_id, list_id, issued_after, transaction)
+        .await
+        .context("Failed to load the confirmation token of a pending subscriber.")?
+    {
+        Some(token) => {
+            tracing::info!("Subscriber is not confirmed, sending the confirmation mail again");
+            Ok(token)
+        }
+        None => {
+            let token = generate_subscription_token();
+            insert_subscription_token(&token, subscriber_id, list_id, transaction)
+                .await
+                .context("Failed to store the confirmation token for a subscriber.")?;
+            Ok(token)
+        }
+    }
+}
+
+pub(crate) fn render_confirmation_email(
+    email_templates: &EmailTemplates,
     base_url: &str,
-    subscriber_to_create: Subscriber,
+    name: &str,
+    language: Option<&str>,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
-    println!("base: {}", base_url);
+) -> Result<SubscriptionEmail, anyhow::Error> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, subscription_token
     );
-    let html_body = format!(
-        "Welcome to our newsletter! <br /> \
-                Please click <a href=\"{}\">here</a> to confirm your registration",
-        confirmation_link
-    );
-    let plain_body = format!(
-        "Welcome to our newsletter!\n Visit {} to confirm your registration",
-        confirmation_link
-    );
+    let mut context = TemplateContext::new();
+    context.insert("name", name);
+    context.insert("confirmation_link", &confirmation_link);
+    Ok(SubscriptionEmail {
+        subject: email_templates.message(language, "confirmation-subject")?,
+        body: email_templates.render("confirmation", language, &context)?,
+    })
+}
+
+/// Tells a confirmed subscriber who signed up again that nothing changed.
+fn render_already_subscribed_email(
+    email_templates: &EmailTemplates,
+    subscriber: &Subscriber,
+) -> Result<SubscriptionEmail, anyhow::Error> {
+    let mut context = TemplateContext::new();
+    context.insert("name", subscriber.name.as_ref());
+    let language = subscriber.language.as_ref().map(AsRef::as_ref);
+    Ok(SubscriptionEmail {
+        subject: email_templates.message(language, "already-subscribed-subject")?,
+        body: email_templates.render("already_subscribed", language, &context)?,
+    })
+}
 
+#[tracing::instrument(
+    name = "Send an email to a new subscriber",
+    skip(email_client, subscriber_to_create, email)
+)]
+async fn send_subscription_email(
+    email_client: &EmailClient,
+    subscriber_to_create: Subscriber,
+    email: &SubscriptionEmail,
+) -> Result<(), EmailError> {
     email_client
         .send_mail(
-            subscriber_to_create.email,
-            "Welcome",
-            &html_body,
-            &plain_body,
+            &subscriber_to_create.email,
+            &email.subject,
+            &email.body.html,
+            &email.body.text,
+            None,
         )
         .await
 }
 
+struct ExistingSubscriber {
+    id: Uuid,
+    status: String,
+}
+
+#[tracing::instrument(
+    name = "Look up an existing subscriber by email",
+    skip(subscriber, transaction)
+)]
+async fn get_existing_subscriber(
+    subscriber: &Subscriber,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        ExistingSubscriber,
+        r#"
+        SELECT id, status
+        FROM subscriptions
+        WHERE lower(email) = lower($1)
+        FOR UPDATE
+        "#,
+        subscriber.email.as_ref()
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+/// Finds a token for the membership that is neither consumed nor expired.
+#[tracing::instrument(
+    name = "Get the live subscription token of a subscriber",
+    skip(transaction)
+)]
+async fn get_live_subscription_token(
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    issued_after: DateTime<Utc>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT subscription_token
+        FROM subscription_tokens
+        WHERE subscriber_id = $1 AND list_id = $2 AND consumed_at IS NULL AND issued_at > $3
+        ORDER BY issued_at DESC
+        LIMIT 1
+        "#,
+        subscriber_id,
+        list_id,
+        issued_after
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(row.map(|r| r.subscription_token))
+}
+
+/// Returns `None` when there is a subscriber with the same email already.
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
-    skip(subscriber, db_pool)
+    skip(subscriber, transaction)
 )]
 async fn create_new_subscriber(
     subscriber: &Subscriber,
-    db_pool: &PgPool,
-) -> Result<Uuid, sqlx::Error> {
-    let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(
         r#"
-            INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-            VALUES($1, $2, $3, $4, 'pending_confirmation') 
+            INSERT INTO subscriptions (id, email, name, subscribed_at, status, language)
+            VALUES($1, $2, $3, $4, 'pending_confirmation', $5)
+            ON CONFLICT (lower(email)) DO NOTHING
+            RETURNING id
         "#,
-        subscriber_id,
+        Uuid::new_v4(),
         subscriber.email.as_ref(),
         subscriber.name.as_ref(),
-        Utc::now()
+        Utc::now(),
+        subscriber.language.as_ref().map(AsRef::as_ref)
     )
-    .execute(db_pool)
+    .fetch_optional(transaction)
     .await
     .map_err(|e| {
         tracing::info!("Failed to add subscriber cause: [{:?}]", e);
         e
     })?;
-    Ok(subscriber_id)
+    Ok(row.map(|r| r.id))
 }
 
+#[tracing::instrument(
+    name = "Store subscription token in the database",
+    skip(token, transaction)
+)]
 async fn insert_subscription_token(
     token: &str,
     subscriber_id: Uuid,
-    db_pool: &PgPool,
+    list_id: Uuid,
+    transaction: &mut Transaction<'_, Postgres>,
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"
-        INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-        VALUES($1, $2)
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, list_id)
+        VALUES($1, $2, $3)
     "#,
         token,
-        subscriber_id
+        subscriber_id,
+        list_id
     )
-    .execute(db_pool)
+    .execute(transaction)
     .await
     .map_err(|e| {
         tracing::info!("Failed to add subscription token [{:?}]", e);
@@ -158,7 +401,37 @@ async fn insert_subscription_token(
     Ok(())
 }
 
-fn generate_subscription_token() -> String {
+/// Keeps track of confirmation emails that never left, so pending subscribers who
+/// could not possibly confirm can be found with `confirmation_email_sent_at IS NULL`.
+#[tracing::instrument(
+    name = "Record the confirmation email outcome",
+    skip(token, outcome, db_pool)
+)]
+pub(crate) async fn record_confirmation_email_outcome(
+    token: &str,
+    outcome: &Result<(), EmailError>,
+    db_pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let (sent_at, error) = match outcome {
+        Ok(()) => (Some(Utc::now()), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    sqlx::query!(
+        r#"
+        UPDATE subscription_tokens
+        SET confirmation_email_sent_at = $2, confirmation_email_error = $3
+        WHERE subscription_token = $1
+        "#,
+        token,
+        sent_at,
+        error
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
+
+pub(crate) fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
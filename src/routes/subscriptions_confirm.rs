@@ -1,73 +1,243 @@
+use actix_web::http::StatusCode;
 use actix_web::{
     web::{Data, Query},
-    HttpResponse,
+    HttpRequest, HttpResponse, ResponseError,
 };
+use anyhow::Context;
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
+use crate::consent::{record_consent_event, ConsentEventKind, ConsentForm, ConsentOrigin};
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_templates::{EmailTemplates, TemplateContext};
+use crate::lists::{confirm_membership, leave_all_lists};
+use crate::startup::SubscriptionTokenTtl;
+use crate::utils::error_chain_fmt;
+
 #[derive(Deserialize)]
 pub struct Parameters {
     subscription_token: String,
 }
 
-#[tracing::instrument(name = "Confirm a subscription", skip(params))]
+#[derive(thiserror::Error)]
+pub enum ConfirmationError {
+    #[error("There is no subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error("The confirmation link has expired, please subscribe again to receive a new one.")]
+    ExpiredToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ConfirmationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmationError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmationError::ExpiredToken => StatusCode::GONE,
+            ConfirmationError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ConfirmationError::UnknownToken => HttpResponse::Unauthorized().body(self.to_string()),
+            ConfirmationError::ExpiredToken => HttpResponse::Gone().body(self.to_string()),
+            ConfirmationError::UnexpectedError(_) => {
+                tracing::error!("Failed to confirm subscription cause: {:?}", self);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+struct StoredToken {
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    issued_at: DateTime<Utc>,
+    consumed_at: Option<DateTime<Utc>>,
+}
+
+struct ConfirmedSubscriber {
+    email: String,
+    name: String,
+    language: Option<String>,
+    previous_status: String,
+}
+
+#[tracing::instrument(
+    name = "Confirm a subscription",
+    skip(params, request, db_pool, token_ttl, email_client, email_templates)
+)]
 pub async fn subscription_confirm(
     params: Query<Parameters>,
+    request: HttpRequest,
     db_pool: Data<PgPool>,
-) -> HttpResponse {
-    println!("{}", params.subscription_token);
+    token_ttl: Data<SubscriptionTokenTtl>,
+    email_client: Data<EmailClient>,
+    email_templates: Data<EmailTemplates>,
+) -> Result<HttpResponse, ConfirmationError> {
     let token = params.subscription_token.as_str();
-    let subscriber_id = match get_subscriber_id_from_token(token, db_pool.as_ref()).await {
-        Ok(query_res) => query_res,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    match subscriber_id {
-        None => return HttpResponse::Unauthorized().finish(),
-        Some(id) => {
-            if confirm_subscriber(id, db_pool.as_ref()).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-        }
+    let mut transaction = db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let stored_token = get_stored_token(token, &mut transaction)
+        .await
+        .context("Failed to retrieve the subscriber id associated with the provided token.")?
+        .ok_or(ConfirmationError::UnknownToken)?;
+    // A token is single-use, a consumed one is as good as an unknown one.
+    if stored_token.consumed_at.is_some() {
+        return Err(ConfirmationError::UnknownToken);
+    }
+    let ttl = chrono::Duration::from_std(token_ttl.0).context("Invalid token ttl.")?;
+    if stored_token.issued_at + ttl < Utc::now() {
+        return Err(ConfirmationError::ExpiredToken);
     }
-    HttpResponse::Ok().finish()
+
+    let subscriber = confirm_subscriber(stored_token.subscriber_id, &mut transaction)
+        .await
+        .context("Failed to update the subscriber status to `confirmed`.")?;
+    // Bounces and complaints stop all mail without touching the memberships, the
+    // confirmation only vouches for the list it was sent for.
+    if matches!(
+        subscriber.previous_status.as_str(),
+        "bounced" | "complained"
+    ) {
+        leave_all_lists(&mut transaction, stored_token.subscriber_id)
+            .await
+            .context("Failed to leave the lists joined before the address stopped working.")?;
+    }
+    confirm_membership(
+        &mut transaction,
+        stored_token.subscriber_id,
+        stored_token.list_id,
+    )
+    .await
+    .context("Failed to confirm the list membership.")?;
+    consume_token(token, &mut transaction)
+        .await
+        .context("Failed to mark the subscription token as consumed.")?;
+    record_consent_event(
+        &mut transaction,
+        stored_token.subscriber_id,
+        stored_token.list_id,
+        ConsentEventKind::Confirmed,
+        token,
+        &ConsentOrigin::from_request(&request),
+        ConsentForm::ConfirmationEmail,
+    )
+    .await
+    .context("Failed to record the confirmation of the consent.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the transaction to confirm a subscriber.")?;
+
+    // The subscription is already confirmed at this point, failing to greet the
+    // subscriber must not turn into an error page.
+    if let Err(e) = send_welcome_email(&email_client, &email_templates, subscriber).await {
+        tracing::warn!("Failed to send the welcome email cause: [{:?}]", e);
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(
+    name = "Send a welcome email to a confirmed subscriber",
+    skip(email_client, email_templates, subscriber)
+)]
+async fn send_welcome_email(
+    email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    subscriber: ConfirmedSubscriber,
+) -> Result<(), anyhow::Error> {
+    let email = SubscriberEmail::parse(subscriber.email).map_err(anyhow::Error::msg)?;
+    let mut context = TemplateContext::new();
+    context.insert("name", &subscriber.name);
+    let language = subscriber.language.as_deref();
+    let subject = email_templates.message(language, "welcome-subject")?;
+    let body = email_templates.render("welcome", language, &context)?;
+    email_client
+        .send_mail(&email, &subject, &body.html, &body.text, None)
+        .await?;
+    Ok(())
 }
 
 #[tracing::instrument(
     name = "Mark subscriber as confirmed"
-    skip(id, db_pool)
+    skip(id, transaction)
 )]
-async fn confirm_subscriber(id: Uuid, db_pool: &PgPool) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
+async fn confirm_subscriber(
+    id: Uuid,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<ConfirmedSubscriber, sqlx::Error> {
+    sqlx::query_as!(
+        ConfirmedSubscriber,
+        r#"
+        UPDATE subscriptions
+        SET status = 'confirmed', unsubscribed_at = NULL
+        FROM (SELECT id, status FROM subscriptions WHERE id = $1 FOR UPDATE) AS previous
+        WHERE subscriptions.id = previous.id
+        RETURNING subscriptions.email, subscriptions.name, subscriptions.language,
+            previous.status AS previous_status
+        "#,
         id
     )
-    .execute(db_pool)
+    .fetch_one(transaction)
     .await
     .map_err(|e| {
         tracing::info!("Failed to confirm subscriber [{:?}]", e);
         e
-    })?;
+    })
+}
+
+#[tracing::instrument(
+    name = "Mark subscription token as consumed"
+    skip(token, transaction)
+)]
+async fn consume_token(
+    token: &str,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscription_tokens SET consumed_at = now() WHERE subscription_token = $1"#,
+        token
+    )
+    .execute(transaction)
+    .await?;
     Ok(())
 }
 
 #[tracing::instrument(
     name = "Get subscriber from token"
-    skip(token, db_pool)
+    skip(token, transaction)
 )]
-async fn get_subscriber_id_from_token(
+async fn get_stored_token(
     token: &str,
-    db_pool: &PgPool,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let query_result = sqlx::query!(
-        "SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1",
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<StoredToken>, sqlx::Error> {
+    sqlx::query_as!(
+        StoredToken,
+        r#"
+        SELECT subscriber_id, list_id, issued_at, consumed_at
+        FROM subscription_tokens
+        WHERE subscription_token = $1
+        FOR UPDATE
+        "#,
         token
     )
-    .fetch_optional(db_pool)
+    .fetch_optional(transaction)
     .await
     .map_err(|e| {
         tracing::info!("could not load subscriber [{:?}]", e);
         e
-    })?;
-    Ok(query_result.map(|r| r.subscriber_id))
+    })
 }
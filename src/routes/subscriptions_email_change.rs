@@ -0,0 +1,424 @@
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Form, Query};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use serde::Deserialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::consent::ConsentOrigin;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_templates::{EmailTemplates, TemplateContext};
+use crate::preferences::record_preference_change;
+use crate::routes::generate_subscription_token;
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::subscriber_links::{LinkPurpose, SubscriberLinkSigner};
+use crate::utils::error_chain_fmt;
+
+/// The short-lived preferences link, see `PreferencesAccessParameters`.
+#[derive(Deserialize)]
+pub struct EmailChangeParameters {
+    subscriber_id: Uuid,
+    /// Unix timestamp in seconds, covered by the token.
+    expires_at: i64,
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailChangeForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailChangeConfirmParameters {
+    token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum EmailChangeError {
+    #[error("The link is not valid.")]
+    InvalidToken,
+    #[error("The link has expired, please ask for a new one.")]
+    ExpiredLink,
+    #[error("We do not store any data about you.")]
+    UnknownSubscriber,
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("The confirmation link has expired, please ask for the change again.")]
+    ExpiredToken,
+    #[error("Another subscription already uses this email address.")]
+    AddressTaken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for EmailChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for EmailChangeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            EmailChangeError::InvalidToken => StatusCode::UNAUTHORIZED,
+            EmailChangeError::ExpiredLink => StatusCode::GONE,
+            EmailChangeError::UnknownSubscriber => StatusCode::NOT_FOUND,
+            EmailChangeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            EmailChangeError::ExpiredToken => StatusCode::GONE,
+            EmailChangeError::AddressTaken => StatusCode::CONFLICT,
+            EmailChangeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            EmailChangeError::UnexpectedError(_) => {
+                tracing::error!("Failed to change an email address cause: {:?}", self);
+                HttpResponse::InternalServerError().finish()
+            }
+            _ => HttpResponse::build(self.status_code()).body(self.to_string()),
+        }
+    }
+}
+
+struct SubscriberContact {
+    email: String,
+    name: String,
+    language: Option<String>,
+}
+
+/// Sends a confirmation link to the new address, the subscription keeps the old one
+/// until the link is followed. A newer request replaces any unconfirmed older one.
+#[tracing::instrument(
+    name = "Request an email address change",
+    skip(params, form, db_pool, email_client, base_url, subscriber_links, email_templates),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn request_email_change(
+    params: Query<EmailChangeParameters>,
+    form: Form<EmailChangeForm>,
+    db_pool: Data<PgPool>,
+    email_client: Data<EmailClient>,
+    base_url: Data<ApplicationBaseUrl>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+    email_templates: Data<EmailTemplates>,
+) -> Result<HttpResponse, EmailChangeError> {
+    if params.expires_at <= Utc::now().timestamp() {
+        return Err(EmailChangeError::ExpiredLink);
+    }
+    if !subscriber_links.verify_expiring(
+        LinkPurpose::PreferencesAccess,
+        params.subscriber_id,
+        params.expires_at,
+        &params.token,
+    ) {
+        return Err(EmailChangeError::InvalidToken);
+    }
+    let new_email =
+        SubscriberEmail::parse(form.0.email).map_err(EmailChangeError::ValidationError)?;
+    let mut transaction = db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let subscriber = get_subscriber_contact(params.subscriber_id, &mut transaction)
+        .await
+        .context("Failed to look up the subscriber changing their email address.")?
+        .ok_or(EmailChangeError::UnknownSubscriber)?;
+    if subscriber.email.eq_ignore_ascii_case(new_email.as_ref()) {
+        return Err(EmailChangeError::ValidationError(
+            "This is already your email address.".into(),
+        ));
+    }
+    let token = generate_subscription_token();
+    insert_email_change_request(
+        &token,
+        params.subscriber_id,
+        new_email.as_ref(),
+        &mut transaction,
+    )
+    .await
+    .context("Failed to store the email change request.")?;
+
+    let confirmation_link = format!("{}/subscriptions/email/confirm?token={}", base_url.0, token);
+    let mut context = TemplateContext::new();
+    context.insert("name", &subscriber.name);
+    context.insert("confirmation_link", &confirmation_link);
+    let language = subscriber.language.as_deref();
+    let subject = email_templates.message(language, "email-change-subject")?;
+    let body = email_templates.render("email_change", language, &context)?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the transaction to store an email change request.")?;
+    email_client
+        .send_mail(&new_email, &subject, &body.html, &body.text, None)
+        .await
+        .context("Failed to send the email change confirmation.")?;
+
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Confirm your new address</title>
+</head>
+<body>
+    <p>We sent a link to your new address, your subscription moves there once you follow it.</p>
+</body>
+</html>"#,
+    ))
+}
+
+struct StoredEmailChange {
+    subscriber_id: Uuid,
+    new_email: String,
+    requested_at: DateTime<Utc>,
+    confirmed_at: Option<DateTime<Utc>>,
+}
+
+/// Moves the subscription to the new address, together with the deliveries still
+/// queued for the old one, and lets the old address know.
+#[tracing::instrument(
+    name = "Confirm an email address change",
+    skip(params, request, db_pool, token_ttl, email_client, email_templates)
+)]
+pub async fn confirm_email_change(
+    params: Query<EmailChangeConfirmParameters>,
+    request: HttpRequest,
+    db_pool: Data<PgPool>,
+    token_ttl: Data<SubscriptionTokenTtl>,
+    email_client: Data<EmailClient>,
+    email_templates: Data<EmailTemplates>,
+) -> Result<HttpResponse, EmailChangeError> {
+    let mut transaction = db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let change = get_email_change_request(&params.token, &mut transaction)
+        .await
+        .context("Failed to look up the email change request.")?
+        .ok_or(EmailChangeError::InvalidToken)?;
+    if change.confirmed_at.is_some() {
+        return Err(EmailChangeError::InvalidToken);
+    }
+    let ttl = chrono::Duration::from_std(token_ttl.0).context("Invalid token ttl.")?;
+    if change.requested_at + ttl < Utc::now() {
+        return Err(EmailChangeError::ExpiredToken);
+    }
+    let subscriber = get_subscriber_contact(change.subscriber_id, &mut transaction)
+        .await
+        .context("Failed to look up the subscriber changing their email address.")?
+        .ok_or(EmailChangeError::UnknownSubscriber)?;
+    if is_email_taken(&change.new_email, change.subscriber_id, &mut transaction)
+        .await
+        .context("Failed to check whether the new email address is in use.")?
+    {
+        return Err(EmailChangeError::AddressTaken);
+    }
+    match update_email(
+        change.subscriber_id,
+        &subscriber.email,
+        &change.new_email,
+        &mut transaction,
+    )
+    .await
+    {
+        Ok(()) => {}
+        // Another subscription took the address since the check above.
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => {
+            return Err(EmailChangeError::AddressTaken)
+        }
+        Err(e) => {
+            return Err(anyhow::Error::new(e)
+                .context("Failed to update the email address of a subscriber.")
+                .into())
+        }
+    }
+    sqlx::query!(
+        r#"UPDATE email_change_requests SET confirmed_at = now() WHERE token = $1"#,
+        params.token
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to mark the email change request as confirmed.")?;
+    record_preference_change(
+        &mut transaction,
+        change.subscriber_id,
+        "email",
+        Some(&subscriber.email),
+        Some(&change.new_email),
+        &ConsentOrigin::from_request(&request),
+    )
+    .await
+    .context("Failed to record the email address change.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the transaction to change an email address.")?;
+
+    // The address has changed at this point, failing to tell the old one about it must
+    // not turn into an error page.
+    if let Err(e) = send_email_changed_notice(
+        &email_client,
+        &email_templates,
+        subscriber,
+        &change.new_email,
+    )
+    .await
+    {
+        tracing::warn!("Failed to notify the old email address cause: [{:?}]", e);
+    }
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Email address changed</title>
+</head>
+<body>
+    <p>Your email address has been changed.</p>
+</body>
+</html>"#,
+    ))
+}
+
+#[tracing::instrument(
+    name = "Notify the old address of an email change",
+    skip(email_client, email_templates, subscriber, new_email)
+)]
+async fn send_email_changed_notice(
+    email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    subscriber: SubscriberContact,
+    new_email: &str,
+) -> Result<(), anyhow::Error> {
+    let old_email = SubscriberEmail::parse(subscriber.email).map_err(anyhow::Error::msg)?;
+    let mut context = TemplateContext::new();
+    context.insert("name", &subscriber.name);
+    context.insert("new_email", new_email);
+    let language = subscriber.language.as_deref();
+    let subject = email_templates.message(language, "email-changed-subject")?;
+    let body = email_templates.render("email_changed", language, &context)?;
+    email_client
+        .send_mail(&old_email, &subject, &body.html, &body.text, None)
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get the contact details of a subscriber", skip(transaction))]
+async fn get_subscriber_contact(
+    subscriber_id: Uuid,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<SubscriberContact>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriberContact,
+        r#"SELECT email, name, language FROM subscriptions WHERE id = $1 FOR UPDATE"#,
+        subscriber_id
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+#[tracing::instrument(
+    name = "Store an email change request",
+    skip(token, new_email, transaction)
+)]
+async fn insert_email_change_request(
+    token: &str,
+    subscriber_id: Uuid,
+    new_email: &str,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM email_change_requests
+        WHERE subscriber_id = $1 AND confirmed_at IS NULL
+        "#,
+        subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO email_change_requests (token, subscriber_id, new_email, requested_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        token,
+        subscriber_id,
+        new_email,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get an email change request", skip(token, transaction))]
+async fn get_email_change_request(
+    token: &str,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<StoredEmailChange>, sqlx::Error> {
+    sqlx::query_as!(
+        StoredEmailChange,
+        r#"
+        SELECT subscriber_id, new_email, requested_at, confirmed_at
+        FROM email_change_requests
+        WHERE token = $1
+        FOR UPDATE
+        "#,
+        token
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+/// Whether another subscription uses the address, in any casing. Changing only the
+/// casing of one's own address is refused before this is ever asked.
+#[tracing::instrument(name = "Check whether an email address is in use", skip_all)]
+async fn is_email_taken(
+    email: &str,
+    subscriber_id: Uuid,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE lower(email) = lower($1) AND id <> $2"#,
+        email,
+        subscriber_id
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(row.is_some())
+}
+
+/// Queued deliveries follow the new address, except those a worker holds a lease on,
+/// which go out to the address they were prepared for.
+#[tracing::instrument(name = "Update the email address of a subscriber", skip_all)]
+async fn update_email(
+    subscriber_id: Uuid,
+    old_email: &str,
+    new_email: &str,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET email = $2 WHERE id = $1"#,
+        subscriber_id,
+        new_email
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue SET subscriber_email = $2
+        WHERE subscriber_email = $1 AND (leased_until IS NULL OR leased_until < now())
+        "#,
+        old_email,
+        new_email
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,307 @@
+use actix_web::http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Query};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Duration;
+use serde::Deserialize;
+use sqlx::types::chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_templates::{EmailTemplates, TemplateContext};
+use crate::personal_data::{erase_personal_data, export_personal_data, SuppressionList};
+use crate::subscriber_links::{LinkPurpose, SubscriberLinkSigner};
+use crate::utils::error_chain_fmt;
+
+/// How long the link we email to the address on file can be used.
+const PERSONAL_DATA_ACCESS_TTL: Duration = Duration::hours(1);
+
+/// The link in our emails, it can only have an access link sent to the address on
+/// file so a forwarded newsletter does not hand out the data.
+#[derive(Deserialize)]
+pub struct PersonalDataParameters {
+    subscriber_id: Uuid,
+    token: String,
+}
+
+/// The short-lived link that lets the subscriber download or erase their data.
+#[derive(Deserialize)]
+pub struct PersonalDataAccessParameters {
+    subscriber_id: Uuid,
+    /// Unix timestamp in seconds, covered by the token.
+    expires_at: i64,
+    token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum PersonalDataError {
+    #[error("The link is not valid.")]
+    InvalidToken,
+    #[error("The link has expired, please ask for a new one.")]
+    ExpiredToken,
+    #[error("We do not store any data about you.")]
+    UnknownSubscriber,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PersonalDataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PersonalDataError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PersonalDataError::InvalidToken => StatusCode::UNAUTHORIZED,
+            PersonalDataError::ExpiredToken => StatusCode::GONE,
+            PersonalDataError::UnknownSubscriber => StatusCode::NOT_FOUND,
+            PersonalDataError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            PersonalDataError::InvalidToken
+            | PersonalDataError::ExpiredToken
+            | PersonalDataError::UnknownSubscriber => {
+                HttpResponse::build(self.status_code()).body(self.to_string())
+            }
+            PersonalDataError::UnexpectedError(_) => {
+                tracing::error!("Failed to handle a personal data request cause: {:?}", self);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+impl PersonalDataParameters {
+    fn verify(&self, subscriber_links: &SubscriberLinkSigner) -> Result<(), PersonalDataError> {
+        if subscriber_links.verify(LinkPurpose::PersonalData, self.subscriber_id, &self.token) {
+            Ok(())
+        } else {
+            Err(PersonalDataError::InvalidToken)
+        }
+    }
+}
+
+impl PersonalDataAccessParameters {
+    fn verify(&self, subscriber_links: &SubscriberLinkSigner) -> Result<(), PersonalDataError> {
+        if self.expires_at <= Utc::now().timestamp() {
+            return Err(PersonalDataError::ExpiredToken);
+        }
+        if subscriber_links.verify_expiring(
+            LinkPurpose::PersonalDataAccess,
+            self.subscriber_id,
+            self.expires_at,
+            &self.token,
+        ) {
+            Ok(())
+        } else {
+            Err(PersonalDataError::InvalidToken)
+        }
+    }
+
+    /// The email address the link was signed for.
+    async fn verified_email(
+        &self,
+        subscriber_links: &SubscriberLinkSigner,
+        db_pool: &PgPool,
+    ) -> Result<String, PersonalDataError> {
+        self.verify(subscriber_links)?;
+        let contact = get_subscriber_contact(db_pool, self.subscriber_id)
+            .await
+            .context("Failed to look up the subscriber of a personal data link.")?
+            .ok_or(PersonalDataError::UnknownSubscriber)?;
+        Ok(contact.email)
+    }
+}
+
+struct SubscriberContact {
+    email: String,
+    name: String,
+    language: Option<String>,
+}
+
+/// The landing page of the link in our emails. It only offers to email a link to
+/// the address on file, a GET never sends or erases anything.
+#[tracing::instrument(
+    name = "Show the personal data page",
+    skip(params, request, subscriber_links),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn personal_data_page(
+    params: Query<PersonalDataParameters>,
+    request: HttpRequest,
+    subscriber_links: Data<SubscriberLinkSigner>,
+) -> Result<HttpResponse, PersonalDataError> {
+    params.verify(&subscriber_links)?;
+    let query = htmlescape::encode_attribute(request.query_string());
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your data</title>
+</head>
+<body>
+    <form action="/subscriptions/data/link?{query}" method="post">
+        <p>We will email you a link to download or erase everything we store about you.</p>
+        <button type="submit">Send me the link</button>
+    </form>
+</body>
+</html>"#,
+        )))
+}
+
+/// Emails a link to download or erase the data to the address on file, the link
+/// expires after `PERSONAL_DATA_ACCESS_TTL`.
+#[tracing::instrument(
+    name = "Send a personal data access link",
+    skip(params, db_pool, email_client, subscriber_links, email_templates),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn request_personal_data_access(
+    params: Query<PersonalDataParameters>,
+    db_pool: Data<PgPool>,
+    email_client: Data<EmailClient>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+    email_templates: Data<EmailTemplates>,
+) -> Result<HttpResponse, PersonalDataError> {
+    params.verify(&subscriber_links)?;
+    let subscriber = get_subscriber_contact(&db_pool, params.subscriber_id)
+        .await
+        .context("Failed to look up the subscriber asking for their personal data.")?
+        .ok_or(PersonalDataError::UnknownSubscriber)?;
+    let email = SubscriberEmail::parse(subscriber.email).map_err(anyhow::Error::msg)?;
+    let access_link = subscriber_links
+        .personal_data_access_link(params.subscriber_id, Utc::now() + PERSONAL_DATA_ACCESS_TTL);
+    let mut context = TemplateContext::new();
+    context.insert("name", &subscriber.name);
+    context.insert("access_link", &access_link);
+    let language = subscriber.language.as_deref();
+    let subject = email_templates.message(language, "personal-data-access-subject")?;
+    let body = email_templates.render("personal_data_access", language, &context)?;
+    email_client
+        .send_mail(&email, &subject, &body.html, &body.text, None)
+        .await
+        .context("Failed to send the personal data access link.")?;
+
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Check your inbox</title>
+</head>
+<body>
+    <p>We sent a link to your email address, it can be used for one hour.</p>
+</body>
+</html>"#,
+    ))
+}
+
+/// The landing page of the emailed access link, it offers the download and the
+/// erasure.
+#[tracing::instrument(
+    name = "Show the personal data access page",
+    skip(params, request, subscriber_links),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn personal_data_access_page(
+    params: Query<PersonalDataAccessParameters>,
+    request: HttpRequest,
+    subscriber_links: Data<SubscriberLinkSigner>,
+) -> Result<HttpResponse, PersonalDataError> {
+    params.verify(&subscriber_links)?;
+    let query = htmlescape::encode_attribute(request.query_string());
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your data</title>
+</head>
+<body>
+    <p><a href="/subscriptions/data/export?{query}">Download everything we store about you</a></p>
+    <form action="/subscriptions/data/erase?{query}" method="post">
+        <p>Erasing your data also ends your subscription and cannot be undone.</p>
+        <button type="submit">Erase my data</button>
+    </form>
+</body>
+</html>"#,
+        )))
+}
+
+#[tracing::instrument(
+    name = "Export personal data for a subscriber",
+    skip(params, db_pool, subscriber_links),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn personal_data_self_export(
+    params: Query<PersonalDataAccessParameters>,
+    db_pool: Data<PgPool>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+) -> Result<HttpResponse, PersonalDataError> {
+    let email = params.verified_email(&subscriber_links, &db_pool).await?;
+    let export = export_personal_data(&db_pool, &email)
+        .await
+        .context("Failed to export the personal data of a subscriber.")?;
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("personal-data.json".into())],
+        })
+        .json(export))
+}
+
+#[tracing::instrument(
+    name = "Erase personal data for a subscriber",
+    skip(params, db_pool, subscriber_links, suppression_list),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn personal_data_self_erasure(
+    params: Query<PersonalDataAccessParameters>,
+    db_pool: Data<PgPool>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+    suppression_list: Data<SuppressionList>,
+) -> Result<HttpResponse, PersonalDataError> {
+    let email = params.verified_email(&subscriber_links, &db_pool).await?;
+    erase_personal_data(&db_pool, &email, &suppression_list)
+        .await
+        .context("Failed to erase the personal data of a subscriber.")?;
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Data erased</title>
+</head>
+<body>
+    <p>Everything we stored about you has been erased.</p>
+</body>
+</html>"#,
+    ))
+}
+
+#[tracing::instrument(name = "Get the contact details of a subscriber", skip(db_pool))]
+async fn get_subscriber_contact(
+    db_pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<SubscriberContact>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriberContact,
+        r#"SELECT email, name, language FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(db_pool)
+    .await
+}
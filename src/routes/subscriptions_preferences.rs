@@ -0,0 +1,624 @@
+use std::collections::HashSet;
+
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Form, Query};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Duration;
+use serde::Deserialize;
+use sqlx::types::chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::consent::{record_consent_event, ConsentEventKind, ConsentForm, ConsentOrigin};
+use crate::domain::{
+    EmailFrequency, SubscriberEmail, SubscriberLanguage, SubscriberName, SubscriberTimezone,
+};
+use crate::email_client::EmailClient;
+use crate::email_templates::{EmailTemplates, TemplateContext};
+use crate::lists::{leave_list, request_membership};
+use crate::preferences::{
+    get_preferences, get_topic_choices, record_preference_change, update_preferences, Preferences,
+    TopicChoice,
+};
+use crate::routes::{
+    confirmation_token, record_confirmation_email_outcome, render_confirmation_email,
+};
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::subscriber_links::{LinkPurpose, SubscriberLinkSigner};
+use crate::utils::error_chain_fmt;
+
+/// How long the link we email to the address on file can be used.
+const PREFERENCES_ACCESS_TTL: Duration = Duration::hours(1);
+
+/// The link in our emails, it can only have an access link sent to the address on
+/// file so a forwarded newsletter does not let anyone change the subscription.
+#[derive(Deserialize)]
+pub struct PreferencesParameters {
+    subscriber_id: Uuid,
+    token: String,
+}
+
+/// The short-lived link that lets the subscriber change their preferences.
+#[derive(Deserialize)]
+pub struct PreferencesAccessParameters {
+    subscriber_id: Uuid,
+    /// Unix timestamp in seconds, covered by the token.
+    expires_at: i64,
+    token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("The link is not valid.")]
+    InvalidToken,
+    #[error("The link has expired, please ask for a new one.")]
+    ExpiredToken,
+    #[error("We do not store any data about you.")]
+    UnknownSubscriber,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PreferencesError::InvalidToken => StatusCode::UNAUTHORIZED,
+            PreferencesError::ExpiredToken => StatusCode::GONE,
+            PreferencesError::UnknownSubscriber => StatusCode::NOT_FOUND,
+            PreferencesError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            PreferencesError::InvalidToken
+            | PreferencesError::ExpiredToken
+            | PreferencesError::UnknownSubscriber => {
+                HttpResponse::build(self.status_code()).body(self.to_string())
+            }
+            PreferencesError::UnexpectedError(_) => {
+                tracing::error!("Failed to handle a preferences request cause: {:?}", self);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+/// The submitted form. Every checked topic repeats the `topic` field, which is why
+/// the body is read as a list of pairs.
+struct PreferencesForm {
+    name: String,
+    language: String,
+    email_frequency: String,
+    timezone: String,
+    topics: HashSet<String>,
+}
+
+impl PreferencesForm {
+    fn from_pairs(pairs: Vec<(String, String)>) -> Self {
+        let mut form = Self {
+            name: String::new(),
+            language: String::new(),
+            email_frequency: String::new(),
+            timezone: String::new(),
+            topics: HashSet::new(),
+        };
+        for (key, value) in pairs {
+            match key.as_str() {
+                "name" => form.name = value,
+                "language" => form.language = value,
+                "email_frequency" => form.email_frequency = value,
+                "timezone" => form.timezone = value,
+                "topic" => {
+                    form.topics.insert(value);
+                }
+                _ => {}
+            }
+        }
+        form
+    }
+}
+
+struct ValidPreferences {
+    name: SubscriberName,
+    language: Option<SubscriberLanguage>,
+    email_frequency: EmailFrequency,
+    timezone: Option<SubscriberTimezone>,
+}
+
+impl TryFrom<&PreferencesForm> for ValidPreferences {
+    type Error = String;
+
+    fn try_from(form: &PreferencesForm) -> Result<Self, Self::Error> {
+        let name = SubscriberName::parse(form.name.clone())?;
+        // An empty language leaves the choice to us again.
+        let language = match form.language.trim() {
+            "" => None,
+            language => Some(SubscriberLanguage::parse(language.into())?),
+        };
+        let email_frequency = EmailFrequency::parse(form.email_frequency.clone())?;
+        // Without a timezone, scheduled issues arrive at their time in UTC.
+        let timezone = match form.timezone.trim() {
+            "" => None,
+            timezone => Some(SubscriberTimezone::parse(timezone.into())?),
+        };
+        Ok(Self {
+            name,
+            language,
+            email_frequency,
+            timezone,
+        })
+    }
+}
+
+impl PreferencesParameters {
+    fn verify(&self, subscriber_links: &SubscriberLinkSigner) -> Result<(), PreferencesError> {
+        if subscriber_links.verify(LinkPurpose::Preferences, self.subscriber_id, &self.token) {
+            Ok(())
+        } else {
+            Err(PreferencesError::InvalidToken)
+        }
+    }
+}
+
+impl PreferencesAccessParameters {
+    fn verify(&self, subscriber_links: &SubscriberLinkSigner) -> Result<(), PreferencesError> {
+        if self.expires_at <= Utc::now().timestamp() {
+            return Err(PreferencesError::ExpiredToken);
+        }
+        if subscriber_links.verify_expiring(
+            LinkPurpose::PreferencesAccess,
+            self.subscriber_id,
+            self.expires_at,
+            &self.token,
+        ) {
+            Ok(())
+        } else {
+            Err(PreferencesError::InvalidToken)
+        }
+    }
+}
+
+struct SubscriberContact {
+    email: String,
+    name: String,
+    language: Option<String>,
+}
+
+/// The landing page of the link in our emails. It only offers to email a link to
+/// the address on file, a GET never sends anything.
+#[tracing::instrument(
+    name = "Show the preference page",
+    skip(params, request, subscriber_links),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn preferences_page(
+    params: Query<PreferencesParameters>,
+    request: HttpRequest,
+    subscriber_links: Data<SubscriberLinkSigner>,
+) -> Result<HttpResponse, PreferencesError> {
+    params.verify(&subscriber_links)?;
+    let query = htmlescape::encode_attribute(request.query_string());
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your preferences</title>
+</head>
+<body>
+    <form action="/subscriptions/preferences/link?{query}" method="post">
+        <p>We will email you a link to change your preferences or your email address.</p>
+        <button type="submit">Send me the link</button>
+    </form>
+</body>
+</html>"#,
+        )))
+}
+
+/// Emails a link to change the preferences to the address on file, the link expires
+/// after `PREFERENCES_ACCESS_TTL`.
+#[tracing::instrument(
+    name = "Send a preferences access link",
+    skip(params, db_pool, email_client, subscriber_links, email_templates),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn request_preferences_access(
+    params: Query<PreferencesParameters>,
+    db_pool: Data<PgPool>,
+    email_client: Data<EmailClient>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+    email_templates: Data<EmailTemplates>,
+) -> Result<HttpResponse, PreferencesError> {
+    params.verify(&subscriber_links)?;
+    let subscriber = get_subscriber_contact(&db_pool, params.subscriber_id)
+        .await
+        .context("Failed to look up the subscriber asking to change their preferences.")?
+        .ok_or(PreferencesError::UnknownSubscriber)?;
+    let email = SubscriberEmail::parse(subscriber.email).map_err(anyhow::Error::msg)?;
+    let access_link = subscriber_links
+        .preferences_access_link(params.subscriber_id, Utc::now() + PREFERENCES_ACCESS_TTL);
+    let mut context = TemplateContext::new();
+    context.insert("name", &subscriber.name);
+    context.insert("access_link", &access_link);
+    let language = subscriber.language.as_deref();
+    let subject = email_templates.message(language, "preferences-access-subject")?;
+    let body = email_templates.render("preferences_access", language, &context)?;
+    email_client
+        .send_mail(&email, &subject, &body.html, &body.text, None)
+        .await
+        .context("Failed to send the preferences access link.")?;
+
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Check your inbox</title>
+</head>
+<body>
+    <p>We sent a link to your email address, it can be used for one hour.</p>
+</body>
+</html>"#,
+    ))
+}
+
+/// The landing page of the emailed access link, it shows the current preferences.
+#[tracing::instrument(
+    name = "Show the preferences access page",
+    skip(params, request, db_pool, subscriber_links),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn preferences_access_page(
+    params: Query<PreferencesAccessParameters>,
+    request: HttpRequest,
+    db_pool: Data<PgPool>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+) -> Result<HttpResponse, PreferencesError> {
+    params.verify(&subscriber_links)?;
+    let mut transaction = db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let preferences = get_preferences(&mut transaction, params.subscriber_id)
+        .await
+        .context("Failed to load the preferences of a subscriber.")?
+        .ok_or(PreferencesError::UnknownSubscriber)?;
+    let topics = get_topic_choices(&mut transaction, params.subscriber_id)
+        .await
+        .context("Failed to load the topics of a subscriber.")?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(preferences_html(&request, &preferences, &topics, None)))
+}
+
+/// Applies the submitted form and records one audit entry per changed field. Picking
+/// a topic only requests the membership: the consent is recorded and the list is
+/// joined once the subscriber follows the confirmation link we email for it, like a
+/// sign-up.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "Save the preferences of a subscriber",
+    skip(
+        params,
+        form,
+        request,
+        db_pool,
+        email_client,
+        base_url,
+        token_ttl,
+        email_templates,
+        subscriber_links
+    ),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn save_preferences(
+    params: Query<PreferencesAccessParameters>,
+    form: Form<Vec<(String, String)>>,
+    request: HttpRequest,
+    db_pool: Data<PgPool>,
+    email_client: Data<EmailClient>,
+    base_url: Data<ApplicationBaseUrl>,
+    token_ttl: Data<SubscriptionTokenTtl>,
+    email_templates: Data<EmailTemplates>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+) -> Result<HttpResponse, PreferencesError> {
+    params.verify(&subscriber_links)?;
+    let subscriber_id = params.subscriber_id;
+    let form = PreferencesForm::from_pairs(form.into_inner());
+    let mut transaction = db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let mut preferences = get_preferences(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to load the preferences of a subscriber.")?
+        .ok_or(PreferencesError::UnknownSubscriber)?;
+    let mut topics = get_topic_choices(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to load the topics of a subscriber.")?;
+    let valid = match ValidPreferences::try_from(&form) {
+        Ok(valid) => valid,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest()
+                .content_type(ContentType::html())
+                .body(preferences_html(&request, &preferences, &topics, Some(&e))));
+        }
+    };
+    let origin = ConsentOrigin::from_request(&request);
+
+    let changes = [
+        (
+            "name",
+            Some(preferences.name.clone()),
+            Some(valid.name.as_ref().to_string()),
+        ),
+        (
+            "language",
+            preferences.language.clone(),
+            valid.language.as_ref().map(|l| l.as_ref().to_string()),
+        ),
+        (
+            "email_frequency",
+            Some(preferences.email_frequency.clone()),
+            Some(valid.email_frequency.as_ref().to_string()),
+        ),
+        (
+            "timezone",
+            preferences.timezone.clone(),
+            valid.timezone.as_ref().map(|t| t.as_ref().to_string()),
+        ),
+    ];
+    let changes: Vec<_> = changes
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .collect();
+    if !changes.is_empty() {
+        update_preferences(
+            &mut transaction,
+            subscriber_id,
+            valid.name.as_ref(),
+            valid.language.as_ref().map(AsRef::as_ref),
+            valid.email_frequency.as_ref(),
+            valid.timezone.as_ref().map(AsRef::as_ref),
+        )
+        .await
+        .context("Failed to update the preferences of a subscriber.")?;
+    }
+    for (field, old, new) in &changes {
+        record_preference_change(
+            &mut transaction,
+            subscriber_id,
+            field,
+            old.as_deref(),
+            new.as_deref(),
+            &origin,
+        )
+        .await
+        .context("Failed to record a preference change.")?;
+    }
+    preferences.name = valid.name.as_ref().into();
+    preferences.language = valid.language.map(|l| l.as_ref().into());
+    preferences.email_frequency = valid.email_frequency.as_ref().into();
+    preferences.timezone = valid.timezone.map(|t| t.as_ref().into());
+
+    let old_topics = subscribed_slugs(&topics);
+    let mut confirmation_tokens = Vec::new();
+    for topic in topics.iter_mut() {
+        let wanted = form.topics.contains(&topic.slug);
+        if wanted && !topic.subscribed {
+            request_membership(&mut transaction, subscriber_id, topic.list_id)
+                .await
+                .context("Failed to request a list membership.")?;
+            let token =
+                confirmation_token(subscriber_id, topic.list_id, &token_ttl, &mut transaction)
+                    .await?;
+            record_consent_event(
+                &mut transaction,
+                subscriber_id,
+                topic.list_id,
+                ConsentEventKind::Subscribed,
+                &token,
+                &origin,
+                ConsentForm::Preferences,
+            )
+            .await
+            .context("Failed to record the consent given by the subscriber.")?;
+            confirmation_tokens.push(token);
+        } else if !wanted && topic.subscribed {
+            leave_list(&mut transaction, subscriber_id, topic.list_id)
+                .await
+                .context("Failed to leave a list.")?;
+            topic.subscribed = false;
+        }
+    }
+    let new_topics = subscribed_slugs(&topics);
+    if old_topics != new_topics {
+        record_preference_change(
+            &mut transaction,
+            subscriber_id,
+            "topics",
+            Some(&old_topics),
+            Some(&new_topics),
+            &origin,
+        )
+        .await
+        .context("Failed to record a preference change.")?;
+    }
+    // Parsed and rendered before committing so that a broken template does not leave
+    // behind memberships nobody can confirm.
+    let email = SubscriberEmail::parse(preferences.email.clone()).map_err(anyhow::Error::msg)?;
+    let confirmation_emails = confirmation_tokens
+        .iter()
+        .map(|token| {
+            render_confirmation_email(
+                &email_templates,
+                &base_url.0,
+                &preferences.name,
+                preferences.language.as_deref(),
+                token,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the transaction to save the preferences.")?;
+
+    for (token, confirmation_email) in confirmation_tokens.iter().zip(&confirmation_emails) {
+        let outcome = email_client
+            .send_mail(
+                &email,
+                &confirmation_email.subject,
+                &confirmation_email.body.html,
+                &confirmation_email.body.text,
+                None,
+            )
+            .await;
+        if let Err(e) = record_confirmation_email_outcome(token, &outcome, &db_pool).await {
+            tracing::warn!(
+                "Failed to record the confirmation email outcome cause: [{:?}]",
+                e
+            );
+        }
+        outcome.context("Failed to send a confirmation email.")?;
+    }
+
+    let notice = if confirmation_tokens.is_empty() {
+        "Your preferences have been saved."
+    } else {
+        "Your preferences have been saved. Please follow the link we emailed you to confirm each new topic."
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(preferences_html(
+            &request,
+            &preferences,
+            &topics,
+            Some(notice),
+        )))
+}
+
+/// The slugs of the lists someone receives, comma separated as stored in the audit
+/// trail.
+fn subscribed_slugs(topics: &[TopicChoice]) -> String {
+    topics
+        .iter()
+        .filter(|topic| topic.subscribed)
+        .map(|topic| topic.slug.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn frequency_label(frequency: EmailFrequency) -> &'static str {
+    match frequency {
+        EmailFrequency::Immediate => "Every issue as soon as it is published",
+        EmailFrequency::WeeklyDigest => "A weekly digest",
+    }
+}
+
+fn preferences_html(
+    request: &HttpRequest,
+    preferences: &Preferences,
+    topics: &[TopicChoice],
+    notice: Option<&str>,
+) -> String {
+    let query = htmlescape::encode_attribute(request.query_string());
+    let notice = notice
+        .map(|notice| format!("<p><i>{}</i></p>", htmlescape::encode_minimal(notice)))
+        .unwrap_or_default();
+    let name = htmlescape::encode_attribute(&preferences.name);
+    let language = htmlescape::encode_attribute(preferences.language.as_deref().unwrap_or(""));
+    let timezone = htmlescape::encode_attribute(preferences.timezone.as_deref().unwrap_or(""));
+    let frequencies: String = EmailFrequency::ALL
+        .iter()
+        .map(|frequency| {
+            let checked = if frequency.as_ref() == preferences.email_frequency {
+                " checked"
+            } else {
+                ""
+            };
+            format!(
+                r#"
+        <label><input type="radio" name="email_frequency" value="{}"{checked}> {}</label><br>"#,
+                frequency.as_ref(),
+                frequency_label(*frequency),
+            )
+        })
+        .collect();
+    let topics: String = topics
+        .iter()
+        .map(|topic| {
+            let checked = if topic.subscribed { " checked" } else { "" };
+            format!(
+                r#"
+        <label><input type="checkbox" name="topic" value="{}"{checked}> {}</label><br>"#,
+                htmlescape::encode_attribute(&topic.slug),
+                htmlescape::encode_minimal(&topic.name),
+            )
+        })
+        .collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your preferences</title>
+</head>
+<body>
+    {notice}
+    <form action="/subscriptions/preferences/manage?{query}" method="post">
+        <label>Name
+            <input type="text" name="name" value="{name}">
+        </label>
+        <br>
+        <label>Language
+            <input type="text" name="language" value="{language}" placeholder="e.g. en or pt-BR">
+        </label>
+        <br>
+        <label>Timezone
+            <input type="text" name="timezone" value="{timezone}" placeholder="e.g. Europe/Berlin">
+        </label>
+        <fieldset>
+            <legend>How often</legend>{frequencies}
+        </fieldset>
+        <fieldset>
+            <legend>Topics</legend>{topics}
+        </fieldset>
+        <button type="submit">Save</button>
+    </form>
+    <form action="/subscriptions/preferences/email?{query}" method="post">
+        <label>New email address
+            <input type="email" name="email">
+        </label>
+        <p>We send a link to the new address, your subscription moves there once you follow it.</p>
+        <button type="submit">Change my email address</button>
+    </form>
+</body>
+</html>"#,
+    )
+}
+
+#[tracing::instrument(name = "Get the contact details of a subscriber", skip(db_pool))]
+async fn get_subscriber_contact(
+    db_pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<SubscriberContact>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriberContact,
+        r#"SELECT email, name, language FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(db_pool)
+    .await
+}
@@ -0,0 +1,218 @@
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Form, Query};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::lists::{get_list, leave_all_lists, leave_list};
+use crate::subscriber_links::{LinkPurpose, SubscriberLinkSigner};
+use crate::utils::error_chain_fmt;
+
+/// Links without a `list_id`, as in the weekly digest, leave every list.
+#[derive(Deserialize)]
+pub struct UnsubscribeParameters {
+    subscriber_id: Uuid,
+    list_id: Option<Uuid>,
+    token: String,
+}
+
+impl UnsubscribeParameters {
+    fn is_signed(&self, subscriber_links: &SubscriberLinkSigner) -> bool {
+        match self.list_id {
+            Some(list_id) => subscriber_links.verify_list(
+                LinkPurpose::Unsubscribe,
+                self.subscriber_id,
+                list_id,
+                &self.token,
+            ),
+            None => {
+                subscriber_links.verify(LinkPurpose::Unsubscribe, self.subscriber_id, &self.token)
+            }
+        }
+    }
+}
+
+/// `lists` is `all` when the subscriber explicitly chose to leave every list.
+#[derive(Deserialize)]
+pub struct UnsubscribeFormData {
+    lists: Option<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("The unsubscribe link is not valid.")]
+    InvalidToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::InvalidToken => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            UnsubscribeError::InvalidToken => HttpResponse::Unauthorized().body(self.to_string()),
+            UnsubscribeError::UnexpectedError(_) => {
+                tracing::error!("Failed to unsubscribe cause: {:?}", self);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+/// Only asks for confirmation: mail scanners follow links in emails, so a plain GET
+/// must never unsubscribe anybody.
+#[tracing::instrument(
+    name = "Show the unsubscribe form",
+    skip(params, request, db_pool, subscriber_links),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn unsubscribe_form(
+    params: Query<UnsubscribeParameters>,
+    request: HttpRequest,
+    db_pool: Data<PgPool>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if !params.is_signed(&subscriber_links) {
+        return Err(UnsubscribeError::InvalidToken);
+    }
+    let action = htmlescape::encode_attribute(&format!(
+        "/subscriptions/unsubscribe?{}",
+        request.query_string()
+    ));
+    let forms = match params.list_id {
+        Some(list_id) => {
+            let list = get_list(&db_pool, list_id)
+                .await
+                .context("Failed to look up the list to leave.")?;
+            let name = htmlescape::encode_minimal(
+                list.as_ref().map_or("this list", |list| list.name.as_str()),
+            );
+            format!(
+                r#"<p>Do you really want to stop receiving {name}?</p>
+    <form action="{action}" method="post">
+        <button type="submit">Unsubscribe from {name}</button>
+    </form>
+    <p>You can also stop receiving every one of our newsletters.</p>
+    <form action="{action}" method="post">
+        <input type="hidden" name="lists" value="all">
+        <button type="submit">Leave every list</button>
+    </form>"#
+            )
+        }
+        None => format!(
+            r#"<p>Do you really want to stop receiving our newsletters?</p>
+    <form action="{action}" method="post">
+        <button type="submit">Unsubscribe</button>
+    </form>"#
+        ),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    {forms}
+</body>
+</html>"#,
+        )))
+}
+
+/// Handles both the form above and one-click unsubscribe requests sent by mail
+/// clients (RFC 8058). A one-click request only leaves the list of the link, every
+/// list is left only when the form says so.
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber",
+    skip(params, form, db_pool, subscriber_links),
+    fields(subscriber_id = %params.subscriber_id)
+)]
+pub async fn unsubscribe(
+    params: Query<UnsubscribeParameters>,
+    form: Option<Form<UnsubscribeFormData>>,
+    db_pool: Data<PgPool>,
+    subscriber_links: Data<SubscriberLinkSigner>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if !params.is_signed(&subscriber_links) {
+        return Err(UnsubscribeError::InvalidToken);
+    }
+    let every_list = matches!(&form, Some(form) if form.lists.as_deref() == Some("all"));
+    let message = match params.list_id {
+        Some(list_id) if !every_list => {
+            leave_one_list(params.subscriber_id, list_id, &db_pool)
+                .await
+                .context("Failed to leave the list.")?;
+            "You have been unsubscribed and will not receive any further issues of this list."
+        }
+        _ => {
+            mark_unsubscribed(params.subscriber_id, &db_pool)
+                .await
+                .context("Failed to update the subscriber status to `unsubscribed`.")?;
+            "You have been unsubscribed and will not receive any further issues."
+        }
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribed</title>
+</head>
+<body>
+    <p>{message}</p>
+</body>
+</html>"#,
+        )))
+}
+
+/// Leaves the other lists and the subscription itself alone.
+#[tracing::instrument(name = "Leave the list of an unsubscribe link", skip(db_pool))]
+async fn leave_one_list(
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    db_pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+    leave_list(&mut transaction, subscriber_id, list_id).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Stops all mail, whichever list the issue was sent to.
+#[tracing::instrument(name = "Mark subscriber as unsubscribed", skip(db_pool))]
+async fn mark_unsubscribed(subscriber_id: Uuid, db_pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET status = 'unsubscribed', unsubscribed_at = now()
+        WHERE id = $1 AND status <> 'unsubscribed'
+        "#,
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    leave_all_lists(&mut transaction, subscriber_id).await?;
+    transaction.commit().await?;
+    Ok(())
+}
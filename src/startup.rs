@@ -2,73 +2,195 @@ use std::io::Error;
 use std::net::TcpListener;
 use std::time::Duration;
 
+use actix_session::SessionMiddleware;
+use actix_web::cookie::{Key, SameSite};
 use actix_web::dev::Server;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpServer};
+use secrecy::{ExposeSecret, Secret};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use tracing_actix_web::TracingLogger;
 
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::authentication::{create_initial_admin, WebhookCredentials};
+use crate::configuration::{DatabaseSettings, SessionSettings, SessionStoreKind, Settings};
+use crate::consent::TrustedProxies;
 use crate::email_client::EmailClient;
-use crate::routes::{health_check, subscription_confirm, subscriptions};
+use crate::email_templates::EmailTemplates;
+use crate::issue_delivery_worker::IssueDeliveryWorker;
+use crate::personal_data::SuppressionList;
+use crate::routes::{
+    admin_dashboard, cancel_newsletter, change_password, change_password_form,
+    confirm_email_change, create_newsletter_draft, create_newsletter_list, edit_newsletter_issue,
+    export_consent_records, export_subscribers, health_check, import_subscribers,
+    list_dead_letters, list_subscribers, log_out, login, login_form, newsletter_issue,
+    newsletter_issue_revisions, newsletter_lists, personal_data_access_page, personal_data_erasure,
+    personal_data_export, personal_data_page, personal_data_self_erasure,
+    personal_data_self_export, postmark_webhook, preferences_access_page, preferences_page,
+    preview_newsletter_issue, publish_newsletter, publish_newsletter_form,
+    publish_newsletter_from_form, request_email_change, request_personal_data_access,
+    request_preferences_access, requeue_dead_letters, reschedule_newsletter, save_preferences,
+    scheduled_newsletters, send_newsletter_issue, send_test_newsletter_issue, subscription_confirm,
+    subscriptions, unsubscribe, unsubscribe_form,
+};
+use crate::session_store::{AppSessionStore, InMemorySessionStore, PostgresSessionStore};
+use crate::subscriber_links::SubscriberLinkSigner;
+use crate::subscription_cleanup_worker::SubscriptionCleanupWorker;
 
 pub struct Application {
     server: Server,
+    delivery_worker: IssueDeliveryWorker,
+    cleanup_worker: SubscriptionCleanupWorker,
     port: u16,
 }
 
 pub struct ApplicationBaseUrl(pub String);
 
+/// How long a subscription confirmation token stays valid after it was issued.
+pub struct SubscriptionTokenTtl(pub Duration);
+
+/// The admin addresses a test of a newsletter issue may be sent to.
+pub struct TestRecipients(pub Vec<String>);
+
+impl TestRecipients {
+    pub fn contains(&self, email: &str) -> bool {
+        self.0
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(email))
+    }
+}
+
 pub fn get_connection_pool(config: &DatabaseSettings) -> PgPool {
     PgPoolOptions::new()
         .acquire_timeout(Duration::from_secs(2))
         .connect_lazy_with(config.with_db())
 }
 
+pub fn get_session_store(config: &SessionSettings, db_pool: &PgPool) -> AppSessionStore {
+    match config.store {
+        SessionStoreKind::Memory => AppSessionStore::InMemory(InMemorySessionStore::default()),
+        SessionStoreKind::Postgres => {
+            AppSessionStore::Postgres(PostgresSessionStore::new(db_pool.clone()))
+        }
+    }
+}
+
 impl Application {
     pub async fn build(config: Settings) -> Result<Self, Error> {
-        let timeout = config.email_client.timeout();
         logs::info!("Config loaded");
         let address = format!("{}:{}", config.application.host, config.application.port);
         logs::info!("bind port {}", address);
         let db_pool = get_connection_pool(&config.database);
-        let email = config
-            .email_client
-            .sender()
-            .expect("Not a valid email address");
-        let email_client = EmailClient::new(
-            config.email_client.base_url,
-            email,
-            config.email_client.auth_token,
-            timeout,
+        if let (Some(username), Some(password_hash)) =
+            (&config.admin.username, &config.admin.password_hash)
+        {
+            if create_initial_admin(username, password_hash, &db_pool)
+                .await
+                .map_err(Error::other)?
+            {
+                logs::info!("Created the initial admin {}", username);
+            }
+        }
+        let email_client = config.email_client.clone().client();
+        let email_templates = config.templates.email_templates().map_err(Error::other)?;
+        let delivery_worker = IssueDeliveryWorker::new(
+            db_pool.clone(),
+            config.email_client.client(),
+            config.issue_delivery.poll_interval(),
+            config.issue_delivery.retry_policy(),
+            SubscriberLinkSigner::new(
+                config.application.base_url.clone(),
+                config.application.hmac_secret.clone(),
+            ),
+            email_templates.clone(),
+        );
+        let cleanup_worker = SubscriptionCleanupWorker::new(
+            db_pool.clone(),
+            config.subscriptions.cleanup_interval(),
+            config.subscriptions.pending_retention(),
+            config.idempotency.retention(),
         );
+        let session_store = get_session_store(&config.session, &db_pool);
         let base_url = config.application.base_url;
         let listener = TcpListener::bind(&address).expect("Failed to bind port");
         let port = listener.local_addr().unwrap().port();
-        let server =
-            Self::run(listener, db_pool, email_client, base_url).expect("Failed to run app");
-        Ok(Application { port, server })
+        let server = Self::run(
+            listener,
+            db_pool,
+            email_client,
+            base_url,
+            config.application.hmac_secret,
+            config.application.suppression_key,
+            session_store,
+            config.session.cookie_secure,
+            config.subscriptions.token_ttl(),
+            email_templates,
+            WebhookCredentials {
+                username: config.webhooks.username,
+                password: config.webhooks.password,
+            },
+            TrustedProxies(config.application.trusted_proxies),
+            TestRecipients(config.admin.test_recipients),
+        )
+        .expect("Failed to run app");
+        Ok(Application {
+            port,
+            server,
+            delivery_worker,
+            cleanup_worker,
+        })
     }
     pub fn port(&self) -> u16 {
         self.port
     }
 
     pub async fn run_until_stopped(self) -> Result<(), Error> {
-        self.server.await
+        tokio::select! {
+            outcome = self.server => outcome,
+            outcome = self.delivery_worker.run_until_stopped() => outcome,
+            outcome = self.cleanup_worker.run_until_stopped() => outcome,
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn run(
         listener: TcpListener,
         dp_pool: PgPool,
         email_client: EmailClient,
         base_url: String,
+        hmac_secret: Secret<String>,
+        suppression_key: Secret<String>,
+        session_store: AppSessionStore,
+        cookie_secure: bool,
+        token_ttl: Duration,
+        email_templates: EmailTemplates,
+        webhook_credentials: WebhookCredentials,
+        trusted_proxies: TrustedProxies,
+        test_recipients: TestRecipients,
     ) -> Result<Server, std::io::Error> {
         let db_pool = Data::new(dp_pool);
         let email_client = Data::new(email_client);
+        let subscriber_links = Data::new(SubscriberLinkSigner::new(
+            base_url.clone(),
+            hmac_secret.clone(),
+        ));
+        let suppression_list = Data::new(SuppressionList::new(suppression_key));
         let base_url = Data::new(ApplicationBaseUrl(base_url));
+        let token_ttl = Data::new(SubscriptionTokenTtl(token_ttl));
+        let email_templates = Data::new(email_templates);
+        let webhook_credentials = Data::new(webhook_credentials);
+        let trusted_proxies = Data::new(trusted_proxies);
+        let test_recipients = Data::new(test_recipients);
+        let secret_key = Key::from(hmac_secret.expose_secret().as_bytes());
         let server = HttpServer::new(move || {
             App::new()
+                .wrap(
+                    SessionMiddleware::builder(session_store.clone(), secret_key.clone())
+                        .cookie_secure(cookie_secure)
+                        .cookie_http_only(true)
+                        .cookie_same_site(SameSite::Strict)
+                        .build(),
+                )
                 .wrap(TracingLogger::default())
                 .route("/health_check", web::get().to(health_check))
                 .route("/subscriptions", web::post().to(subscriptions))
@@ -76,9 +198,128 @@ impl Application {
                     "/subscriptions/confirm",
                     web::get().to(subscription_confirm),
                 )
+                .route(
+                    "/subscriptions/unsubscribe",
+                    web::get().to(unsubscribe_form),
+                )
+                .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+                .route("/subscriptions/data", web::get().to(personal_data_page))
+                .route(
+                    "/subscriptions/data/link",
+                    web::post().to(request_personal_data_access),
+                )
+                .route(
+                    "/subscriptions/data/manage",
+                    web::get().to(personal_data_access_page),
+                )
+                .route(
+                    "/subscriptions/data/export",
+                    web::get().to(personal_data_self_export),
+                )
+                .route(
+                    "/subscriptions/data/erase",
+                    web::post().to(personal_data_self_erasure),
+                )
+                .route(
+                    "/subscriptions/preferences",
+                    web::get().to(preferences_page),
+                )
+                .route(
+                    "/subscriptions/preferences/link",
+                    web::post().to(request_preferences_access),
+                )
+                .route(
+                    "/subscriptions/preferences/manage",
+                    web::get().to(preferences_access_page),
+                )
+                .route(
+                    "/subscriptions/preferences/manage",
+                    web::post().to(save_preferences),
+                )
+                .route(
+                    "/subscriptions/preferences/email",
+                    web::post().to(request_email_change),
+                )
+                .route(
+                    "/subscriptions/email/confirm",
+                    web::get().to(confirm_email_change),
+                )
+                .route("/newsletters", web::post().to(publish_newsletter))
+                .route(
+                    "/newsletters/scheduled",
+                    web::get().to(scheduled_newsletters),
+                )
+                .route(
+                    "/newsletters/drafts",
+                    web::post().to(create_newsletter_draft),
+                )
+                .route("/newsletters/{issue_id}", web::get().to(newsletter_issue))
+                .route(
+                    "/newsletters/{issue_id}",
+                    web::put().to(edit_newsletter_issue),
+                )
+                .route(
+                    "/newsletters/{issue_id}/revisions",
+                    web::get().to(newsletter_issue_revisions),
+                )
+                .route(
+                    "/newsletters/{issue_id}/preview",
+                    web::get().to(preview_newsletter_issue),
+                )
+                .route(
+                    "/newsletters/{issue_id}/test",
+                    web::post().to(send_test_newsletter_issue),
+                )
+                .route(
+                    "/newsletters/{issue_id}/send",
+                    web::post().to(send_newsletter_issue),
+                )
+                .route(
+                    "/newsletters/{issue_id}/schedule",
+                    web::put().to(reschedule_newsletter),
+                )
+                .route(
+                    "/newsletters/{issue_id}/cancel",
+                    web::post().to(cancel_newsletter),
+                )
+                .route("/dead_letters", web::get().to(list_dead_letters))
+                .route(
+                    "/dead_letters/redrive",
+                    web::post().to(requeue_dead_letters),
+                )
+                .route("/webhooks/postmark", web::post().to(postmark_webhook))
+                .route("/personal_data", web::get().to(personal_data_export))
+                .route("/personal_data", web::delete().to(personal_data_erasure))
+                .route("/login", web::get().to(login_form))
+                .route("/login", web::post().to(login))
+                .service(
+                    web::scope("/admin")
+                        .route("/dashboard", web::get().to(admin_dashboard))
+                        .route("/newsletters", web::get().to(publish_newsletter_form))
+                        .route("/newsletters", web::post().to(publish_newsletter_from_form))
+                        .route("/password", web::get().to(change_password_form))
+                        .route("/password", web::post().to(change_password))
+                        .route("/lists", web::get().to(newsletter_lists))
+                        .route("/lists", web::post().to(create_newsletter_list))
+                        .route("/subscribers", web::get().to(list_subscribers))
+                        .route("/subscribers/export", web::get().to(export_subscribers))
+                        .route("/subscribers/import", web::post().to(import_subscribers))
+                        .route(
+                            "/subscribers/{subscriber_id}/consent",
+                            web::get().to(export_consent_records),
+                        )
+                        .route("/logout", web::post().to(log_out)),
+                )
                 .app_data(db_pool.clone())
                 .app_data(email_client.clone())
                 .app_data(base_url.clone())
+                .app_data(token_ttl.clone())
+                .app_data(subscriber_links.clone())
+                .app_data(email_templates.clone())
+                .app_data(webhook_credentials.clone())
+                .app_data(trusted_proxies.clone())
+                .app_data(test_recipients.clone())
+                .app_data(suppression_list.clone())
         })
         .listen(listener)?
         .run();
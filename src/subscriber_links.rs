@@ -0,0 +1,335 @@
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::types::chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// What a signed link lets its holder do, a token for one purpose is rejected for
+/// any other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkPurpose {
+    Unsubscribe,
+    /// Only lets the holder have an access link sent to the address on file.
+    PersonalData,
+    /// Lets the holder download or erase the data, always signed with an expiry.
+    PersonalDataAccess,
+    /// Only lets the holder have an access link sent to the address on file.
+    Preferences,
+    /// Lets the holder change the preferences and the email address, always signed
+    /// with an expiry.
+    PreferencesAccess,
+}
+
+impl LinkPurpose {
+    fn domain(&self) -> &'static [u8] {
+        match self {
+            LinkPurpose::Unsubscribe => b"unsubscribe:",
+            LinkPurpose::PersonalData => b"personal-data:",
+            LinkPurpose::PersonalDataAccess => b"personal-data-access:",
+            LinkPurpose::Preferences => b"preferences:",
+            LinkPurpose::PreferencesAccess => b"preferences-access:",
+        }
+    }
+}
+
+/// Builds and checks the per-subscriber links put into emails. The token is an HMAC of
+/// the subscriber id, so nothing has to be stored and a link cannot be forged for
+/// somebody else's id.
+#[derive(Clone)]
+pub struct SubscriberLinkSigner {
+    base_url: String,
+    hmac_secret: Secret<String>,
+}
+
+impl SubscriberLinkSigner {
+    pub fn new(base_url: String, hmac_secret: Secret<String>) -> Self {
+        Self {
+            base_url,
+            hmac_secret,
+        }
+    }
+
+    /// Lets a subscriber leave `list_id`, the list id is signed so the link cannot be
+    /// turned into one for another list.
+    pub fn unsubscribe_link(&self, subscriber_id: Uuid, list_id: Uuid) -> String {
+        format!(
+            "{}/subscriptions/unsubscribe?subscriber_id={}&list_id={}&token={}",
+            self.base_url,
+            subscriber_id,
+            list_id,
+            self.list_token(LinkPurpose::Unsubscribe, subscriber_id, list_id)
+        )
+    }
+
+    /// Lets a subscriber leave every list, for emails that are not about a single list.
+    pub fn unsubscribe_all_link(&self, subscriber_id: Uuid) -> String {
+        self.link(
+            "/subscriptions/unsubscribe",
+            LinkPurpose::Unsubscribe,
+            subscriber_id,
+        )
+    }
+
+    /// Lets a subscriber ask for a link to download or erase everything we store about
+    /// them, the link is sent to the address on file.
+    pub fn personal_data_link(&self, subscriber_id: Uuid) -> String {
+        self.link(
+            "/subscriptions/data",
+            LinkPurpose::PersonalData,
+            subscriber_id,
+        )
+    }
+
+    /// Lets a subscriber ask for a link to change their preferences, the link is sent
+    /// to the address on file.
+    pub fn preferences_link(&self, subscriber_id: Uuid) -> String {
+        self.link(
+            "/subscriptions/preferences",
+            LinkPurpose::Preferences,
+            subscriber_id,
+        )
+    }
+
+    /// Lets a subscriber download or erase their data until `expires_at`.
+    pub fn personal_data_access_link(
+        &self,
+        subscriber_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> String {
+        self.expiring_link(
+            "/subscriptions/data/manage",
+            LinkPurpose::PersonalDataAccess,
+            subscriber_id,
+            expires_at,
+        )
+    }
+
+    /// Lets a subscriber change their name, language, email frequency, timezone,
+    /// topics and email address until `expires_at`.
+    pub fn preferences_access_link(
+        &self,
+        subscriber_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> String {
+        self.expiring_link(
+            "/subscriptions/preferences/manage",
+            LinkPurpose::PreferencesAccess,
+            subscriber_id,
+            expires_at,
+        )
+    }
+
+    pub fn token(&self, purpose: LinkPurpose, subscriber_id: Uuid) -> String {
+        hex::encode(self.mac(purpose, subscriber_id).finalize().into_bytes())
+    }
+
+    pub fn verify(&self, purpose: LinkPurpose, subscriber_id: Uuid, token: &str) -> bool {
+        match hex::decode(token) {
+            Ok(tag) => self.mac(purpose, subscriber_id).verify_slice(&tag).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// A token that also signs `list_id`.
+    pub fn list_token(&self, purpose: LinkPurpose, subscriber_id: Uuid, list_id: Uuid) -> String {
+        let mut mac = self.mac(purpose, subscriber_id);
+        mac.update(list_id.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Rejects tokens for another list as well as tokens without a list.
+    pub fn verify_list(
+        &self,
+        purpose: LinkPurpose,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+        token: &str,
+    ) -> bool {
+        match hex::decode(token) {
+            Ok(tag) => {
+                let mut mac = self.mac(purpose, subscriber_id);
+                mac.update(list_id.as_bytes());
+                mac.verify_slice(&tag).is_ok()
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// A token that also signs `expires_at`, a Unix timestamp in seconds.
+    pub fn expiring_token(
+        &self,
+        purpose: LinkPurpose,
+        subscriber_id: Uuid,
+        expires_at: i64,
+    ) -> String {
+        let mut mac = self.mac(purpose, subscriber_id);
+        mac.update(&expires_at.to_be_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Rejects tokens past their expiry as well as tokens for another expiry.
+    pub fn verify_expiring(
+        &self,
+        purpose: LinkPurpose,
+        subscriber_id: Uuid,
+        expires_at: i64,
+        token: &str,
+    ) -> bool {
+        if expires_at <= Utc::now().timestamp() {
+            return false;
+        }
+        match hex::decode(token) {
+            Ok(tag) => {
+                let mut mac = self.mac(purpose, subscriber_id);
+                mac.update(&expires_at.to_be_bytes());
+                mac.verify_slice(&tag).is_ok()
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn link(&self, path: &str, purpose: LinkPurpose, subscriber_id: Uuid) -> String {
+        format!(
+            "{}{}?subscriber_id={}&token={}",
+            self.base_url,
+            path,
+            subscriber_id,
+            self.token(purpose, subscriber_id)
+        )
+    }
+
+    fn expiring_link(
+        &self,
+        path: &str,
+        purpose: LinkPurpose,
+        subscriber_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> String {
+        let expires_at = expires_at.timestamp();
+        format!(
+            "{}{}?subscriber_id={}&expires_at={}&token={}",
+            self.base_url,
+            path,
+            subscriber_id,
+            expires_at,
+            self.expiring_token(purpose, subscriber_id, expires_at)
+        )
+    }
+
+    fn mac(&self, purpose: LinkPurpose, subscriber_id: Uuid) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.hmac_secret.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        // Prefixed so the tag cannot be replayed where the same secret signs other data.
+        mac.update(purpose.domain());
+        mac.update(subscriber_id.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+    use sqlx::types::chrono::Utc;
+    use uuid::Uuid;
+
+    use crate::subscriber_links::{LinkPurpose, SubscriberLinkSigner};
+
+    fn signer(secret: &str) -> SubscriberLinkSigner {
+        SubscriberLinkSigner::new("http://127.0.0.1".into(), Secret::new(secret.into()))
+    }
+
+    #[test]
+    fn a_signed_token_is_accepted() {
+        let subscriber_id = Uuid::new_v4();
+        let signer = signer("secret");
+        let token = signer.token(LinkPurpose::Unsubscribe, subscriber_id);
+        assert!(signer.verify(LinkPurpose::Unsubscribe, subscriber_id, &token));
+    }
+
+    #[test]
+    fn a_token_for_another_subscriber_is_rejected() {
+        let signer = signer("secret");
+        let token = signer.token(LinkPurpose::Unsubscribe, Uuid::new_v4());
+        assert!(!signer.verify(LinkPurpose::Unsubscribe, Uuid::new_v4(), &token));
+    }
+
+    #[test]
+    fn a_token_for_another_purpose_is_rejected() {
+        let subscriber_id = Uuid::new_v4();
+        let signer = signer("secret");
+        let token = signer.token(LinkPurpose::Unsubscribe, subscriber_id);
+        assert!(!signer.verify(LinkPurpose::PersonalData, subscriber_id, &token));
+    }
+
+    #[test]
+    fn a_token_signed_with_another_secret_is_rejected() {
+        let subscriber_id = Uuid::new_v4();
+        let token = signer("another-secret").token(LinkPurpose::Unsubscribe, subscriber_id);
+        assert!(!signer("secret").verify(LinkPurpose::Unsubscribe, subscriber_id, &token));
+    }
+
+    #[test]
+    fn a_malformed_token_is_rejected() {
+        assert!(!signer("secret").verify(LinkPurpose::Unsubscribe, Uuid::new_v4(), "not-hex"));
+    }
+
+    #[test]
+    fn an_expiring_token_is_accepted_until_it_expires() {
+        let subscriber_id = Uuid::new_v4();
+        let signer = signer("secret");
+        let in_an_hour = Utc::now().timestamp() + 3600;
+        let an_hour_ago = Utc::now().timestamp() - 3600;
+
+        let valid =
+            signer.expiring_token(LinkPurpose::PersonalDataAccess, subscriber_id, in_an_hour);
+        let expired =
+            signer.expiring_token(LinkPurpose::PersonalDataAccess, subscriber_id, an_hour_ago);
+
+        assert!(signer.verify_expiring(
+            LinkPurpose::PersonalDataAccess,
+            subscriber_id,
+            in_an_hour,
+            &valid
+        ));
+        assert!(!signer.verify_expiring(
+            LinkPurpose::PersonalDataAccess,
+            subscriber_id,
+            an_hour_ago,
+            &expired
+        ));
+    }
+
+    #[test]
+    fn the_expiry_of_a_token_cannot_be_extended() {
+        let subscriber_id = Uuid::new_v4();
+        let signer = signer("secret");
+        let expires_at = Utc::now().timestamp() + 3600;
+        let token =
+            signer.expiring_token(LinkPurpose::PersonalDataAccess, subscriber_id, expires_at);
+
+        assert!(!signer.verify_expiring(
+            LinkPurpose::PersonalDataAccess,
+            subscriber_id,
+            expires_at + 3600,
+            &token
+        ));
+        assert!(!signer.verify(LinkPurpose::PersonalDataAccess, subscriber_id, &token));
+    }
+
+    #[test]
+    fn a_list_token_is_only_accepted_for_its_list() {
+        let subscriber_id = Uuid::new_v4();
+        let list_id = Uuid::new_v4();
+        let signer = signer("secret");
+        let token = signer.list_token(LinkPurpose::Unsubscribe, subscriber_id, list_id);
+
+        assert!(signer.verify_list(LinkPurpose::Unsubscribe, subscriber_id, list_id, &token));
+        assert!(!signer.verify_list(
+            LinkPurpose::Unsubscribe,
+            subscriber_id,
+            Uuid::new_v4(),
+            &token
+        ));
+        assert!(!signer.verify(LinkPurpose::Unsubscribe, subscriber_id, &token));
+    }
+}
@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use sqlx::types::chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::idempotency::purge_expired_idempotency_keys;
+use crate::session_store::purge_expired_sessions;
+
+pub struct SubscriptionCleanupWorker {
+    db_pool: PgPool,
+    cleanup_interval: Duration,
+    pending_retention: Duration,
+    idempotency_retention: Duration,
+}
+
+impl SubscriptionCleanupWorker {
+    pub fn new(
+        db_pool: PgPool,
+        cleanup_interval: Duration,
+        pending_retention: Duration,
+        idempotency_retention: Duration,
+    ) -> Self {
+        Self {
+            db_pool,
+            cleanup_interval,
+            pending_retention,
+            idempotency_retention,
+        }
+    }
+
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        loop {
+            // A failed run is simply picked up again on the next tick.
+            let _ = purge_stale_subscriptions(&self.db_pool, self.pending_retention).await;
+            let _ = purge_expired_sessions(&self.db_pool).await;
+            let _ = purge_expired_idempotency_keys(&self.db_pool, self.idempotency_retention).await;
+            tokio::time::sleep(self.cleanup_interval).await;
+        }
+    }
+}
+
+/// Removes subscribers that never confirmed within `pending_retention` together with
+/// their memberships, tokens, consent records and preference changes, as well as
+/// consumed tokens and email change requests older than the retention period.
+/// Confirmed subscribers who never confirmed joining another list only lose that
+/// pending membership. A sign-up is only stale once its newest confirmation token is
+/// older than the retention period, signing up again starts it over. Returns the
+/// number of purged pending subscriptions.
+#[tracing::instrument(name = "Purge stale pending subscriptions", skip(db_pool), err)]
+pub async fn purge_stale_subscriptions(
+    db_pool: &PgPool,
+    pending_retention: Duration,
+) -> Result<u64, anyhow::Error> {
+    let cutoff = Utc::now() - chrono::Duration::from_std(pending_retention)?;
+    let mut transaction = db_pool.begin().await?;
+    let stale_subscribers = sqlx::query_scalar!(
+        r#"
+        SELECT id FROM subscriptions
+        WHERE status = 'pending_confirmation' AND subscribed_at < $1
+          AND NOT EXISTS (
+              SELECT 1 FROM subscription_tokens
+              WHERE subscription_tokens.subscriber_id = subscriptions.id
+                AND issued_at >= $1
+          )
+        FOR UPDATE
+        "#,
+        cutoff
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let (stale_subscriber_ids, stale_list_ids): (Vec<Uuid>, Vec<Uuid>) = sqlx::query!(
+        r#"
+        SELECT subscriber_id, list_id FROM list_memberships
+        WHERE status = 'pending_confirmation' AND subscribed_at < $1
+          AND NOT EXISTS (
+              SELECT 1 FROM subscription_tokens
+              WHERE subscription_tokens.subscriber_id = list_memberships.subscriber_id
+                AND subscription_tokens.list_id = list_memberships.list_id
+                AND issued_at >= $1
+          )
+        FOR UPDATE
+        "#,
+        cutoff
+    )
+    .fetch_all(&mut transaction)
+    .await?
+    .into_iter()
+    .map(|row| (row.subscriber_id, row.list_id))
+    .unzip();
+    // Consent given in an earlier, since left, membership of the list stays on record.
+    sqlx::query!(
+        r#"
+        DELETE FROM consent_events
+        WHERE subscriber_id = ANY($1)
+           OR subscription_token IN (
+               SELECT subscription_token
+               FROM subscription_tokens
+               WHERE (subscriber_id, list_id) IN (SELECT * FROM UNNEST($2::uuid[], $3::uuid[]))
+                 AND consumed_at IS NULL
+           )
+        "#,
+        &stale_subscribers[..],
+        &stale_subscriber_ids[..],
+        &stale_list_ids[..]
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE consumed_at < $1
+           OR subscriber_id = ANY($2)
+           OR (subscriber_id, list_id) IN (SELECT * FROM UNNEST($3::uuid[], $4::uuid[]))
+        "#,
+        cutoff,
+        &stale_subscribers[..],
+        &stale_subscriber_ids[..],
+        &stale_list_ids[..]
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM email_change_requests
+        WHERE requested_at < $1 OR subscriber_id = ANY($2)
+        "#,
+        cutoff,
+        &stale_subscribers[..]
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM preference_changes WHERE subscriber_id = ANY($1)",
+        &stale_subscribers[..]
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM list_memberships
+        WHERE subscriber_id = ANY($1)
+           OR (subscriber_id, list_id) IN (SELECT * FROM UNNEST($2::uuid[], $3::uuid[]))
+        "#,
+        &stale_subscribers[..],
+        &stale_subscriber_ids[..],
+        &stale_list_ids[..]
+    )
+    .execute(&mut transaction)
+    .await?;
+    let result = sqlx::query!(
+        "DELETE FROM subscriptions WHERE id = ANY($1)",
+        &stale_subscribers[..]
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    tracing::info!(
+        "Purged [{}] stale pending subscriptions",
+        result.rows_affected()
+    );
+    Ok(result.rows_affected())
+}
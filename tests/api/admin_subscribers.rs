@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use uuid::Uuid;
+
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+
+async fn insert_subscriber(
+    app: &TestApp,
+    email: &str,
+    name: &str,
+    status: &str,
+    subscribed_at: DateTime<Utc>,
+) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        id,
+        email,
+        name,
+        subscribed_at,
+        status
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    id
+}
+
+fn emails(page: &serde_json::Value) -> Vec<&str> {
+    page["subscribers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|subscriber| subscriber["email"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_list_subscribers() {
+    let app = spawn_app().await;
+
+    let res = app.get_admin_subscribers(&[("limit", "10")]).await;
+
+    assert_is_redirect_to(&res, "/login");
+}
+
+#[tokio::test]
+async fn subscribers_are_listed_newest_first_with_their_fields() {
+    let app = spawn_app().await;
+    let start = Utc.with_ymd_and_hms(2023, 9, 1, 12, 0, 0).unwrap();
+    insert_subscriber(&app, "old@example.com", "Old", "confirmed", start).await;
+    let id = insert_subscriber(
+        &app,
+        "new@example.com",
+        "New",
+        "pending_confirmation",
+        start + Duration::days(1),
+    )
+    .await;
+    app.test_user.login(&app).await;
+
+    let page: serde_json::Value = app
+        .get_admin_subscribers(&[] as &[(&str, &str)])
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(emails(&page), vec!["new@example.com", "old@example.com"]);
+    let newest = &page["subscribers"][0];
+    assert_eq!(newest["id"], id.to_string());
+    assert_eq!(newest["name"], "New");
+    assert_eq!(newest["status"], "pending_confirmation");
+    assert_eq!(newest["language"], serde_json::Value::Null);
+    assert_eq!(newest["unsubscribed_at"], serde_json::Value::Null);
+    assert_eq!(page["next_cursor"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn pages_cover_every_subscriber_exactly_once() {
+    let app = spawn_app().await;
+    let start = Utc.with_ymd_and_hms(2023, 9, 1, 12, 0, 0).unwrap();
+    for i in 0..7 {
+        // Pairs of subscribers share a sign-up time, the id breaks the tie.
+        insert_subscriber(
+            &app,
+            &format!("subscriber{}@example.com", i),
+            "Subscriber",
+            "confirmed",
+            start + Duration::minutes(i / 2),
+        )
+        .await;
+    }
+    app.test_user.login(&app).await;
+
+    let mut seen = HashSet::new();
+    let mut cursor: Option<String> = None;
+    let mut n_pages = 0;
+    loop {
+        let mut query = vec![("limit", "3".to_string())];
+        if let Some(cursor) = &cursor {
+            query.push(("cursor", cursor.clone()));
+        }
+        let page: serde_json::Value = app
+            .get_admin_subscribers(&query)
+            .await
+            .json()
+            .await
+            .unwrap();
+        n_pages += 1;
+        for email in emails(&page) {
+            assert!(seen.insert(email.to_string()), "{} was listed twice", email);
+        }
+        match page["next_cursor"].as_str() {
+            Some(next) => cursor = Some(next.to_string()),
+            None => break,
+        }
+    }
+
+    assert_eq!(seen.len(), 7);
+    assert_eq!(n_pages, 3);
+}
+
+#[tokio::test]
+async fn subscribers_can_be_filtered_by_status_and_signup_date() {
+    let app = spawn_app().await;
+    let start = Utc.with_ymd_and_hms(2023, 9, 1, 12, 0, 0).unwrap();
+    insert_subscriber(&app, "a@example.com", "A", "confirmed", start).await;
+    insert_subscriber(&app, "b@example.com", "B", "unsubscribed", start).await;
+    insert_subscriber(
+        &app,
+        "c@example.com",
+        "C",
+        "confirmed",
+        start + Duration::days(1),
+    )
+    .await;
+    insert_subscriber(
+        &app,
+        "d@example.com",
+        "D",
+        "confirmed",
+        start + Duration::days(2),
+    )
+    .await;
+    app.test_user.login(&app).await;
+
+    let by_status: serde_json::Value = app
+        .get_admin_subscribers(&[("status", "unsubscribed")])
+        .await
+        .json()
+        .await
+        .unwrap();
+    let by_date: serde_json::Value = app
+        .get_admin_subscribers(&[
+            ("status", "confirmed".to_string()),
+            ("signed_up_after", start.to_rfc3339()),
+            ("signed_up_before", (start + Duration::days(2)).to_rfc3339()),
+        ])
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(emails(&by_status), vec!["b@example.com"]);
+    assert_eq!(emails(&by_date), vec!["c@example.com", "a@example.com"]);
+}
+
+#[tokio::test]
+async fn the_search_matches_email_and_name_ignoring_case() {
+    let app = spawn_app().await;
+    let start = Utc.with_ymd_and_hms(2023, 9, 1, 12, 0, 0).unwrap();
+    insert_subscriber(&app, "ursula@example.com", "Le Guin", "confirmed", start).await;
+    insert_subscriber(
+        &app,
+        "octavia@example.com",
+        "Butler",
+        "confirmed",
+        start + Duration::days(1),
+    )
+    .await;
+    insert_subscriber(
+        &app,
+        "ted@example.com",
+        "Chiang",
+        "confirmed",
+        start + Duration::days(2),
+    )
+    .await;
+    app.test_user.login(&app).await;
+
+    let by_email: serde_json::Value = app
+        .get_admin_subscribers(&[("search", "URSULA")])
+        .await
+        .json()
+        .await
+        .unwrap();
+    let by_name: serde_json::Value = app
+        .get_admin_subscribers(&[("search", "butl")])
+        .await
+        .json()
+        .await
+        .unwrap();
+    let wildcard: serde_json::Value = app
+        .get_admin_subscribers(&[("search", "%")])
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(emails(&by_email), vec!["ursula@example.com"]);
+    assert_eq!(emails(&by_name), vec!["octavia@example.com"]);
+    assert!(emails(&wildcard).is_empty());
+}
+
+#[tokio::test]
+async fn an_invalid_cursor_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let res = app
+        .get_admin_subscribers(&[("cursor", "definitely-not-a-cursor")])
+        .await;
+
+    assert_eq!(res.status().as_u16(), 400);
+}
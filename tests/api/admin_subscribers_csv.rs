@@ -0,0 +1,223 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+
+async fn subscribers(app: &TestApp) -> Vec<(String, String, String, Option<String>)> {
+    sqlx::query!("SELECT email, name, status, language FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| (row.email, row.name, row.status, row.language))
+        .collect()
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_import_or_export_subscribers() {
+    let app = spawn_app().await;
+
+    let import = app
+        .post_subscriber_import("confirmed", "email,name\n".into())
+        .await;
+    let export = app.get_subscriber_export().await;
+
+    assert_is_redirect_to(&import, "/login");
+    assert_is_redirect_to(&export, "/login");
+}
+
+#[tokio::test]
+async fn valid_rows_are_imported_with_the_chosen_status() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let csv = "\
+Name,Email,Language,Source
+Ursula Le Guin,ursula@example.com,de,old tool
+\"Butler, Octavia\",octavia@example.com,,old tool
+"
+    .to_string();
+
+    let res = app.post_subscriber_import("confirmed", csv).await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    let report: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(report["imported"], 2);
+    assert_eq!(report["errors"], serde_json::json!([]));
+    assert_eq!(
+        subscribers(&app).await,
+        vec![
+            (
+                "octavia@example.com".into(),
+                "Butler, Octavia".into(),
+                "confirmed".into(),
+                None
+            ),
+            (
+                "ursula@example.com".into(),
+                "Ursula Le Guin".into(),
+                "confirmed".into(),
+                Some("de".into())
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn rejected_rows_are_reported_and_the_others_imported() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    app.post_subscriber_import(
+        "confirmed",
+        "email,name\nexisting@example.com,Existing\nerased@example.com,Erased\n".into(),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    reqwest::Client::new()
+        .delete(format!("http://{}/personal_data", app.address))
+        .query(&[("email", "erased@example.com")])
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let csv = "\
+email,name
+not-an-email,Nobody
+good@example.com,Good
+nameless@example.com,
+good@example.com,Good again
+existing@example.com,Existing
+erased@example.com,Erased
+"
+    .to_string();
+
+    let res = app.post_subscriber_import("unsubscribed", csv).await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    let report: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(report["imported"], 1);
+    let rows: Vec<u64> = report["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["row"].as_u64().unwrap())
+        .collect();
+    assert_eq!(rows, vec![2, 4, 5, 6, 7]);
+    let statuses: Vec<(String, String)> = subscribers(&app)
+        .await
+        .into_iter()
+        .map(|(email, _, status, _)| (email, status))
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            ("existing@example.com".into(), "confirmed".into()),
+            ("good@example.com".into(), "unsubscribed".into()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn an_import_without_the_required_columns_is_rejected() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let missing_name = app
+        .post_subscriber_import("confirmed", "email\nursula@example.com\n".into())
+        .await;
+    let empty = app.post_subscriber_import("confirmed", "".into()).await;
+    let pending = app
+        .post_subscriber_import("pending_confirmation", "email,name\n".into())
+        .await;
+
+    assert_eq!(missing_name.status().as_u16(), 400);
+    assert_eq!(empty.status().as_u16(), 400);
+    assert_eq!(pending.status().as_u16(), 400);
+    assert!(subscribers(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn a_large_import_round_trips_through_the_export() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let mut csv = String::from("email,name\n");
+    for i in 0..2500 {
+        csv.push_str(&format!(
+            "subscriber{}@example.com,\"Subscriber\n{}\"\n",
+            i, i
+        ));
+    }
+
+    let report: serde_json::Value = app
+        .post_subscriber_import("confirmed", csv)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let res = app.get_subscriber_export().await;
+
+    assert_eq!(report["imported"], 2500);
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(
+        res.headers().get("Content-Type").unwrap(),
+        "text/csv; charset=utf-8"
+    );
+    let export = res.text().await.unwrap();
+    let mut reader = csv::Reader::from_reader(export.as_bytes());
+    assert_eq!(
+        reader.headers().unwrap(),
+        vec![
+            "id",
+            "email",
+            "name",
+            "status",
+            "language",
+            "subscribed_at",
+            "unsubscribed_at"
+        ]
+    );
+    let records: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+    assert_eq!(records.len(), 2500);
+    let first = records
+        .iter()
+        .find(|record| &record[1] == "subscriber0@example.com")
+        .unwrap();
+    assert_eq!(&first[2], "Subscriber\n0");
+    assert_eq!(&first[3], "confirmed");
+}
+
+#[tokio::test]
+async fn a_spreadsheet_upload_with_a_byte_order_mark_is_imported() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let csv = "\u{feff}email,name\nursula@example.com,Ursula\n".to_string();
+
+    let res = app.post_subscriber_import("confirmed", csv).await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    let report: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(report["imported"], 1);
+    assert_eq!(subscribers(&app).await[0].0, "ursula@example.com");
+}
+
+#[tokio::test]
+async fn names_that_look_like_formulas_are_exported_as_text() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    app.post_subscriber_import(
+        "confirmed",
+        "email,name\nursula@example.com,=1+1\noctavia@example.com,Octavia\n".into(),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+
+    let export = app.get_subscriber_export().await.text().await.unwrap();
+
+    let mut reader = csv::Reader::from_reader(export.as_bytes());
+    let names: Vec<String> = reader
+        .records()
+        .map(|record| record.unwrap()[2].to_string())
+        .collect();
+    assert!(names.contains(&"'=1+1".to_string()));
+    assert!(names.contains(&"Octavia".to_string()));
+}
@@ -0,0 +1,129 @@
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{assert_is_redirect_to, spawn_app, spawn_app_with, TestApp};
+
+/// Subscribes with the given `X-Forwarded-For` and returns the recorded address.
+async fn recorded_ip_address(app: &TestApp, forwarded_for: &str) -> Option<String> {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    reqwest::Client::new()
+        .post(format!("http://{}/subscriptions", app.address))
+        .header("X-Forwarded-For", forwarded_for)
+        .form(&[("name", "le guin"), ("email", "ursula_le_guin@gmail.com")])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    sqlx::query!("SELECT ip_address FROM consent_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .ip_address
+}
+
+#[tokio::test]
+async fn subscribing_and_confirming_records_both_consent_events() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    reqwest::Client::new()
+        .post(format!("http://{}/subscriptions", app.address))
+        .header("User-Agent", "consent-test/1.0")
+        .form(&[
+            ("name", "le guin"),
+            ("email", "ursula_le_guin@gmail.com"),
+            // Whatever the client claims, the version is the one of the endpoint.
+            ("form_version", "homepage-v2"),
+        ])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request).html;
+    let token = confirmation_link
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .unwrap()
+        .1
+        .to_string();
+    reqwest::get(confirmation_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+    app.test_user.login(&app).await;
+    let res = app.get_consent_export(subscriber_id).await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    let export: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(export["email"], "ursula_le_guin@gmail.com");
+    let events = export["consent_events"].as_array().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["event_type"], "subscribed");
+    assert_eq!(events[0]["form_version"], "subscribe-v1");
+    assert_eq!(events[0]["user_agent"], "consent-test/1.0");
+    assert_eq!(events[0]["ip_address"], "127.0.0.1");
+    assert_eq!(events[0]["subscription_token"], token.as_str());
+    assert_eq!(events[1]["event_type"], "confirmed");
+    assert_eq!(events[1]["form_version"], "confirmation-email-v1");
+    assert_eq!(events[1]["subscription_token"], token.as_str());
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_export_consent_records() {
+    let app = spawn_app().await;
+
+    let res = app.get_consent_export(Uuid::new_v4()).await;
+
+    assert_is_redirect_to(&res, "/login");
+}
+
+#[tokio::test]
+async fn exporting_the_consent_of_an_unknown_subscriber_returns_404() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let res = app.get_consent_export(Uuid::new_v4()).await;
+
+    assert_eq!(res.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn a_forwarded_address_from_an_untrusted_peer_is_ignored() {
+    let app = spawn_app().await;
+
+    let ip_address = recorded_ip_address(&app, "203.0.113.7").await;
+
+    assert_eq!(ip_address.as_deref(), Some("127.0.0.1"));
+}
+
+#[tokio::test]
+async fn a_trusted_proxy_tells_the_client_address() {
+    let app = spawn_app_with(|config| {
+        config.application.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+    })
+    .await;
+
+    // The client made up the first address, the proxy appended the one it saw.
+    let ip_address = recorded_ip_address(&app, "198.51.100.1, 203.0.113.7").await;
+
+    assert_eq!(ip_address.as_deref(), Some("203.0.113.7"));
+}
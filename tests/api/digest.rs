@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{spawn_app, PostmarkBatchResponder, TestApp};
+use crate::newsletters::create_confirmed_subscriber;
+
+/// A confirmed subscriber who chose the weekly digest, the last one went out
+/// `days_ago` days ago.
+async fn create_digest_subscriber(app: &TestApp, days_ago: i32) {
+    create_confirmed_subscriber(app).await;
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET email_frequency = 'weekly_digest', digest_sent_at = now() - make_interval(days => $1)
+        "#,
+        days_ago
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn mount_email_mocks(app: &TestApp) {
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+}
+
+async fn publish(app: &TestApp, title: &str) {
+    app.post_newsletters(serde_json::json!({
+        "title": title,
+        "content": {
+            "text": format!("{} as plain text", title),
+            "html": format!("<p>{} as HTML</p>", title),
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.wait_for_pending_deliveries().await;
+}
+
+/// Makes the digest due, the previous one went out eight days ago.
+async fn make_digest_due(app: &TestApp) {
+    sqlx::query!("UPDATE subscriptions SET digest_sent_at = now() - interval '8 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+}
+
+/// Waits for the worker to claim the due digest, whether it sent one or not.
+async fn wait_for_digest_run(app: &TestApp) {
+    for _ in 0..100 {
+        let claimed = sqlx::query!(
+            "SELECT digest_sent_at > now() - interval '1 minute' AS \"claimed!\" FROM subscriptions"
+        )
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .claimed;
+        if claimed {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The digest was not sent in time");
+}
+
+async fn digests(app: &TestApp) -> Vec<serde_json::Value> {
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|request| request.url.path() == "/email")
+        .map(|request| serde_json::from_slice::<serde_json::Value>(&request.body).unwrap())
+        .filter(|email| email["Subject"] == "Your weekly digest")
+        .collect()
+}
+
+/// Waits until the email server received `n` digests.
+async fn wait_for_digests(app: &TestApp, n: usize) -> Vec<serde_json::Value> {
+    for _ in 0..100 {
+        let digests = digests(app).await;
+        if digests.len() >= n {
+            return digests;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The digests were not sent in time");
+}
+
+#[tokio::test]
+async fn weekly_digest_subscribers_do_not_get_issues_as_they_are_published() {
+    let app = spawn_app().await;
+    create_digest_subscriber(&app, 1).await;
+    mount_email_mocks(&app).await;
+
+    publish(&app, "Monday issue").await;
+
+    assert!(app.newsletter_recipients().await.is_empty());
+}
+
+#[tokio::test]
+async fn a_due_digest_bundles_the_issues_published_since_the_last_one() {
+    let app = spawn_app().await;
+    create_digest_subscriber(&app, 1).await;
+    mount_email_mocks(&app).await;
+    publish(&app, "Old issue").await;
+    // Went out with the previous digest.
+    sqlx::query!("UPDATE newsletter_issues SET enqueued_at = now() - interval '9 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    publish(&app, "Monday issue").await;
+    publish(&app, "Friday issue").await;
+
+    make_digest_due(&app).await;
+    let digests = wait_for_digests(&app, 1).await;
+
+    assert_eq!(digests.len(), 1);
+    let digest = &digests[0];
+    let html = digest["HtmlBody"].as_str().unwrap();
+    let text = digest["TextBody"].as_str().unwrap();
+    assert!(html.contains("<p>Monday issue as HTML</p>"));
+    assert!(text.find("Monday issue").unwrap() < text.find("Friday issue").unwrap());
+    assert!(!text.contains("Old issue"));
+    // The digest spans lists, its unsubscribe link is not tied to one.
+    assert!(text.contains("/subscriptions/unsubscribe?"));
+    assert!(!text.contains("list_id="));
+}
+
+#[tokio::test]
+async fn a_week_without_issues_sends_no_digest() {
+    let app = spawn_app().await;
+    create_digest_subscriber(&app, 8).await;
+    mount_email_mocks(&app).await;
+
+    wait_for_digest_run(&app).await;
+
+    assert!(digests(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn a_digest_that_fails_to_go_out_is_tried_again() {
+    let app = spawn_app().await;
+    create_digest_subscriber(&app, 1).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .mount(&app.email_server)
+        .await;
+    publish(&app, "Monday issue").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    make_digest_due(&app).await;
+    let digests = wait_for_digests(&app, 2).await;
+
+    assert_eq!(digests.len(), 2);
+    assert_eq!(digests[0], digests[1]);
+}
@@ -0,0 +1,333 @@
+use chrono::{DateTime, Duration, Utc};
+use reqwest::{Response, Url};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::subscriber_links::LinkPurpose;
+
+use crate::helpers::{spawn_app, TestApp};
+use crate::newsletters::{create_confirmed_subscriber, newsletter_request_body};
+
+const NEW_EMAIL: &str = "ursula_le_guin@gmail.com";
+
+async fn subscriber(app: &TestApp) -> (Uuid, String) {
+    let row = sqlx::query!("SELECT id, email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    (row.id, row.email)
+}
+
+async fn post_email_change(
+    app: &TestApp,
+    subscriber_id: Uuid,
+    purpose: LinkPurpose,
+    email: &str,
+) -> Response {
+    post_email_change_until(
+        app,
+        subscriber_id,
+        purpose,
+        Utc::now() + Duration::hours(1),
+        email,
+    )
+    .await
+}
+
+async fn post_email_change_until(
+    app: &TestApp,
+    subscriber_id: Uuid,
+    purpose: LinkPurpose,
+    expires_at: DateTime<Utc>,
+    email: &str,
+) -> Response {
+    let expires_at = expires_at.timestamp();
+    let mut url = Url::parse("http://127.0.0.1/subscriptions/preferences/email").unwrap();
+    url.set_port(Some(app.port)).unwrap();
+    url.query_pairs_mut()
+        .append_pair("subscriber_id", &subscriber_id.to_string())
+        .append_pair("expires_at", &expires_at.to_string())
+        .append_pair(
+            "token",
+            &app.subscriber_links
+                .expiring_token(purpose, subscriber_id, expires_at),
+        );
+    reqwest::Client::new()
+        .post(url)
+        .form(&[("email", email)])
+        .send()
+        .await
+        .expect("Failed to send request")
+}
+
+/// Confirmed subscriber who asked to move to `NEW_EMAIL`, with the link sent there.
+async fn requested_change(app: &TestApp) -> (Uuid, String, Url) {
+    create_confirmed_subscriber(app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let (subscriber_id, old_email) = subscriber(app).await;
+    post_email_change(
+        app,
+        subscriber_id,
+        LinkPurpose::PreferencesAccess,
+        NEW_EMAIL,
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    let email_request = last_email(app).await;
+    (
+        subscriber_id,
+        old_email,
+        app.get_confirmation_links(&email_request).html,
+    )
+}
+
+async fn last_email(app: &TestApp) -> wiremock::Request {
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .rev()
+        .find(|request| request.url.path() == "/email")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn requesting_a_change_only_emails_the_new_address() {
+    let app = spawn_app().await;
+
+    let (_, old_email, _) = requested_change(&app).await;
+
+    let email_request = last_email(&app).await;
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], NEW_EMAIL);
+    assert_eq!(subscriber(&app).await.1, old_email);
+}
+
+#[tokio::test]
+async fn confirming_a_change_moves_the_subscription_and_notifies_the_old_address() {
+    let app = spawn_app().await;
+    let (subscriber_id, old_email, confirmation_link) = requested_change(&app).await;
+
+    let response = reqwest::get(confirmation_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        subscriber(&app).await,
+        (subscriber_id, NEW_EMAIL.to_string())
+    );
+    let notice: serde_json::Value = serde_json::from_slice(&last_email(&app).await.body).unwrap();
+    assert_eq!(notice["To"], old_email.as_str());
+    assert!(notice["TextBody"].as_str().unwrap().contains(NEW_EMAIL));
+    let change = sqlx::query!("SELECT field, old_value, new_value FROM preference_changes")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(change.field, "email");
+    assert_eq!(change.old_value.as_deref(), Some(old_email.as_str()));
+    assert_eq!(change.new_value.as_deref(), Some(NEW_EMAIL));
+}
+
+async fn create_draft(app: &TestApp) -> Uuid {
+    let draft: serde_json::Value = reqwest::Client::new()
+        .post(format!("http://{}/newsletters/drafts", app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    draft["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn confirming_a_change_leaves_deliveries_being_sent_to_the_old_address() {
+    let app = spawn_app().await;
+    let (_, old_email, confirmation_link) = requested_change(&app).await;
+    let leased = create_draft(&app).await;
+    let waiting = create_draft(&app).await;
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue
+            (newsletter_issue_id, subscriber_email, leased_until, execute_after)
+        VALUES ($1, $3, now() + interval '1 hour', now()),
+               ($2, $3, NULL, now() + interval '1 hour')
+        "#,
+        leased,
+        waiting,
+        old_email
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    reqwest::get(confirmation_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let queued = |issue_id: Uuid| {
+        sqlx::query!(
+            "SELECT subscriber_email FROM issue_delivery_queue WHERE newsletter_issue_id = $1",
+            issue_id
+        )
+        .fetch_one(&app.db_pool)
+    };
+    assert_eq!(queued(leased).await.unwrap().subscriber_email, old_email);
+    assert_eq!(queued(waiting).await.unwrap().subscriber_email, NEW_EMAIL);
+}
+
+#[tokio::test]
+async fn a_change_link_works_only_once() {
+    let app = spawn_app().await;
+    let (_, _, confirmation_link) = requested_change(&app).await;
+
+    reqwest::get(confirmation_link.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let second = reqwest::get(confirmation_link).await.unwrap();
+
+    assert_eq!(second.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn an_expired_change_link_is_rejected_with_410() {
+    let app = spawn_app().await;
+    let (_, old_email, confirmation_link) = requested_change(&app).await;
+    sqlx::query!("UPDATE email_change_requests SET requested_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(confirmation_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 410);
+    assert_eq!(subscriber(&app).await.1, old_email);
+}
+
+#[tokio::test]
+async fn a_change_to_an_address_in_use_is_rejected_with_409() {
+    let app = spawn_app().await;
+    let (_, old_email, confirmation_link) = requested_change(&app).await;
+    app.post_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::get(confirmation_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+    let emails: Vec<String> = sqlx::query!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.email)
+        .collect();
+    assert!(emails.contains(&old_email));
+    assert!(emails.contains(&NEW_EMAIL.to_string()));
+}
+
+#[tokio::test]
+async fn a_change_to_an_address_in_use_in_another_casing_is_rejected_with_409() {
+    let app = spawn_app().await;
+    let (subscriber_id, _, confirmation_link) = requested_change(&app).await;
+    app.post_subscription("name=le%20guin&email=Ursula_Le_Guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::get(confirmation_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+    let email = sqlx::query!(
+        "SELECT email FROM subscriptions WHERE id = $1",
+        subscriber_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .email;
+    assert_ne!(email, NEW_EMAIL);
+}
+
+#[tokio::test]
+async fn a_newer_request_replaces_an_unconfirmed_one() {
+    let app = spawn_app().await;
+    let (subscriber_id, _, first_link) = requested_change(&app).await;
+
+    post_email_change(
+        &app,
+        subscriber_id,
+        LinkPurpose::PreferencesAccess,
+        "another_le_guin@gmail.com",
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    let first = reqwest::get(first_link).await.unwrap();
+
+    assert_eq!(first.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn requesting_a_change_needs_a_live_preferences_access_link_and_a_valid_address() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (subscriber_id, old_email) = subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let wrong_link = post_email_change(
+        &app,
+        subscriber_id,
+        LinkPurpose::PersonalDataAccess,
+        NEW_EMAIL,
+    )
+    .await;
+    let expired_link = post_email_change_until(
+        &app,
+        subscriber_id,
+        LinkPurpose::PreferencesAccess,
+        Utc::now() - Duration::minutes(1),
+        NEW_EMAIL,
+    )
+    .await;
+    let invalid = post_email_change(
+        &app,
+        subscriber_id,
+        LinkPurpose::PreferencesAccess,
+        "not-an-email",
+    )
+    .await;
+    let unchanged = post_email_change(
+        &app,
+        subscriber_id,
+        LinkPurpose::PreferencesAccess,
+        &old_email,
+    )
+    .await;
+
+    assert_eq!(wrong_link.status().as_u16(), 401);
+    assert_eq!(expired_link.status().as_u16(), 410);
+    assert_eq!(invalid.status().as_u16(), 400);
+    assert_eq!(unchanged.status().as_u16(), 400);
+}
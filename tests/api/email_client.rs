@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use claim::assert_ok;
+use fake::faker::internet::en::SafeEmail;
+use fake::{Fake, Faker};
+use secrecy::Secret;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::domain::SubscriberEmail;
+use zero2prod::email_client::{AppEmailTransport, EmailClient, OutgoingEmail, PostmarkTransport};
+
+use crate::helpers::PostmarkBatchResponder;
+
+fn email() -> SubscriberEmail {
+    SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+}
+
+fn email_client(base_url: String) -> EmailClient {
+    EmailClient::new(
+        email(),
+        AppEmailTransport::Postmark(PostmarkTransport::new(
+            base_url,
+            Secret::new(Faker.fake()),
+            Duration::from_millis(200),
+        )),
+    )
+}
+
+fn outgoing(recipient: &SubscriberEmail) -> OutgoingEmail<'_> {
+    OutgoingEmail {
+        recipient,
+        subject: "subject",
+        html_body: "html",
+        text_body: "text",
+        unsubscribe_url: None,
+    }
+}
+
+#[tokio::test]
+async fn send_batch_reports_the_outcome_of_every_email() {
+    let mock_server = MockServer::start().await;
+    let recipients: Vec<SubscriberEmail> = (0..3).map(|_| email()).collect();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::failing_for(recipients[1].as_ref()))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let emails: Vec<OutgoingEmail> = recipients.iter().map(outgoing).collect();
+    let results = email_client(mock_server.uri()).send_batch(&emails).await;
+
+    assert_eq!(results.len(), 3);
+    assert_ok!(&results[0]);
+    assert!(!results[1].as_ref().unwrap_err().is_retryable());
+    assert_ok!(&results[2]);
+}
+
+#[tokio::test]
+async fn only_transient_postmark_error_codes_are_retryable() {
+    let cases = [
+        (100, true),
+        (405, true),
+        (429, true),
+        (500, true),
+        (300, false),
+        (406, false),
+    ];
+    for (error_code, retryable) in cases {
+        let mock_server = MockServer::start().await;
+        let recipient = email();
+        Mock::given(path("/email/batch"))
+            .respond_with(PostmarkBatchResponder::failing_with(
+                recipient.as_ref(),
+                error_code,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let results = email_client(mock_server.uri())
+            .send_batch(&[outgoing(&recipient)])
+            .await;
+
+        assert_eq!(
+            results[0].as_ref().unwrap_err().is_retryable(),
+            retryable,
+            "Postmark error code {}",
+            error_code
+        );
+    }
+}
+
+#[tokio::test]
+async fn send_batch_splits_emails_into_requests_of_at_most_500() {
+    let mock_server = MockServer::start().await;
+    let recipient = email();
+
+    Mock::given(path("/email/batch"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+
+    let emails: Vec<OutgoingEmail> = (0..501).map(|_| outgoing(&recipient)).collect();
+    let results = email_client(mock_server.uri()).send_batch(&emails).await;
+
+    assert_eq!(results.len(), 501);
+    assert!(results.iter().all(|r| r.is_ok()));
+}
+
+#[tokio::test]
+async fn a_failed_batch_request_fails_every_email_in_it() {
+    let mock_server = MockServer::start().await;
+    let recipient = email();
+
+    Mock::given(path("/email/batch"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let emails: Vec<OutgoingEmail> = (0..2).map(|_| outgoing(&recipient)).collect();
+    let results = email_client(mock_server.uri()).send_batch(&emails).await;
+
+    assert_eq!(results.len(), 2);
+    assert!(results
+        .iter()
+        .all(|r| r.as_ref().unwrap_err().is_retryable()));
+}
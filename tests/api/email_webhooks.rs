@@ -0,0 +1,210 @@
+use wiremock::matchers::any;
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{spawn_app, TestApp};
+use crate::newsletters::{create_confirmed_subscriber, newsletter_request_body};
+
+async fn subscriber_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status
+}
+
+fn bounce(email: &str, bounce_type: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "ID": 4323372036854775807u64,
+        "Type": bounce_type,
+        "TypeCode": 1,
+        "Email": email,
+        "BouncedAt": "2023-08-20T09:17:33Z",
+        "Inactive": false,
+    })
+}
+
+fn spam_complaint(email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "ID": 42,
+        "Type": "SpamComplaint",
+        "TypeCode": 512,
+        "Email": email,
+        "BouncedAt": "2023-08-20T09:17:33Z",
+        "Inactive": true,
+    })
+}
+
+#[tokio::test]
+async fn webhooks_without_credentials_are_rejected() {
+    let app = spawn_app().await;
+
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/webhooks/postmark", app.address))
+        .json(&bounce("ursula@example.com", "HardBounce"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status().as_u16(), 401);
+    assert_eq!(
+        res.headers()["WWW-Authenticate"],
+        r#"Basic realm="webhooks""#
+    );
+}
+
+#[tokio::test]
+async fn webhooks_with_a_wrong_password_are_rejected() {
+    let app = spawn_app().await;
+
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/webhooks/postmark", app.address))
+        .basic_auth(&app.webhooks.username, Some("not-the-password"))
+        .json(&bounce("ursula@example.com", "HardBounce"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_hard_bounce_marks_the_subscriber_as_bounced_and_is_stored() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let res = app
+        .post_postmark_webhook(&bounce(&email, "HardBounce"))
+        .await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+    let event = sqlx::query!("SELECT record_type, subscriber_email, payload FROM email_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(event.record_type, "Bounce");
+    assert_eq!(event.subscriber_email, email);
+    assert_eq!(event.payload, bounce(&email, "HardBounce"));
+}
+
+#[tokio::test]
+async fn a_soft_bounce_is_stored_without_changing_the_subscriber() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let res = app
+        .post_postmark_webhook(&bounce(&email, "SoftBounce"))
+        .await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+    let n_events = sqlx::query!("SELECT count(*) AS n FROM email_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n;
+    assert_eq!(n_events, Some(1));
+}
+
+#[tokio::test]
+async fn a_spam_complaint_marks_the_subscriber_as_complained() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let res = app.post_postmark_webhook(&spam_complaint(&email)).await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+}
+
+#[tokio::test]
+async fn a_bounce_is_matched_regardless_of_the_casing_of_the_address() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    app.post_postmark_webhook(&bounce(&email.to_uppercase(), "HardBounce"))
+        .await;
+
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn a_bounce_after_a_spam_complaint_keeps_the_complaint() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    app.post_postmark_webhook(&spam_complaint(&email)).await;
+    app.post_postmark_webhook(&bounce(&email, "HardBounce"))
+        .await;
+
+    assert_eq!(subscriber_status(&app).await, "complained");
+}
+
+#[tokio::test]
+async fn other_record_types_are_acknowledged_and_ignored() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let res = app
+        .post_postmark_webhook(&serde_json::json!({
+            "RecordType": "Delivery",
+            "Email": email,
+        }))
+        .await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+    let n_events = sqlx::query!("SELECT count(*) AS n FROM email_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n;
+    assert_eq!(n_events, Some(0));
+}
+
+#[tokio::test]
+async fn a_malformed_payload_is_rejected_with_400() {
+    let app = spawn_app().await;
+
+    let res = app
+        .post_postmark_webhook(&serde_json::json!({"RecordType": "Bounce"}))
+        .await;
+
+    assert_eq!(res.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn bounced_subscribers_do_not_receive_newsletters() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    app.post_postmark_webhook(&bounce(&email, "HardBounce"))
+        .await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.post_newsletters(newsletter_request_body()).await;
+
+    assert_eq!(res.status().as_u16(), 202);
+    app.wait_for_pending_deliveries().await;
+}
@@ -6,7 +6,7 @@ async fn health_check_returns_ok() {
     let client = reqwest::Client::new();
 
     let res = client
-        .get(&format!("http://{}/health_check", &test_app.address))
+        .get(format!("http://{}/health_check", &test_app.address))
         .send()
         .await
         .expect("Failed to send request");
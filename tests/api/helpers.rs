@@ -1,12 +1,22 @@
+use std::time::Duration;
+
 use linkify::LinkKind;
 use once_cell::sync::Lazy;
 use reqwest::{Response, Url};
+use secrecy::{ExposeSecret, Secret};
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
 
-use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use wiremock::{MockServer, Respond, ResponseTemplate};
+use zero2prod::authentication::compute_password_hash;
+use zero2prod::configuration::{
+    get_configuration, DatabaseSettings, SessionStoreKind, Settings, WebhookSettings,
+};
+use zero2prod::email_client::EmailClient;
+use zero2prod::email_templates::EmailTemplates;
+use zero2prod::issue_delivery_worker::RetryPolicy;
 use zero2prod::startup::{get_connection_pool, Application};
+use zero2prod::subscriber_links::SubscriberLinkSigner;
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
 static TRACING: Lazy<()> = Lazy::new(|| {
@@ -23,6 +33,11 @@ static TRACING: Lazy<()> = Lazy::new(|| {
 });
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Like `spawn_app`, `configure` can change the settings before the application starts.
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
     let email_server = MockServer::start().await;
     let config = {
@@ -30,6 +45,12 @@ pub async fn spawn_app() -> TestApp {
         config.database.database_name = Uuid::new_v4().to_string();
         config.application.port = 0;
         config.email_client.base_url = email_server.uri();
+        config.issue_delivery.poll_interval_millis = 50;
+        config.issue_delivery.max_attempts = 3;
+        config.issue_delivery.backoff_base_millis = 20;
+        config.issue_delivery.backoff_max_millis = 100;
+        config.session.store = SessionStoreKind::Memory;
+        configure(&mut config);
         config
     };
     configure_database(&config.database).await;
@@ -38,13 +59,32 @@ pub async fn spawn_app() -> TestApp {
         .expect("Failed to load application");
     let application_port = application.port();
     let address = format!("localhost:{}", application.port());
-    let _ = tokio::spawn(application.run_until_stopped());
-    TestApp {
+    tokio::spawn(application.run_until_stopped());
+    let test_app = TestApp {
         address,
         db_pool: get_connection_pool(&config.database),
         email_server,
         port: application_port,
-    }
+        email_client: config.email_client.client(),
+        retry_policy: config.issue_delivery.retry_policy(),
+        subscriber_links: SubscriberLinkSigner::new(
+            config.application.base_url.clone(),
+            config.application.hmac_secret.clone(),
+        ),
+        email_templates: config
+            .templates
+            .email_templates()
+            .expect("Failed to load the email templates"),
+        webhooks: config.webhooks.clone(),
+        test_user: TestUser::generate(),
+        api_client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .cookie_store(true)
+            .build()
+            .unwrap(),
+    };
+    test_app.test_user.store(&test_app.db_pool).await;
+    test_app
 }
 
 async fn configure_database(settings: &DatabaseSettings) -> PgPool {
@@ -72,6 +112,96 @@ pub struct TestApp {
     pub db_pool: PgPool,
     pub email_server: MockServer,
     pub port: u16,
+    pub email_client: EmailClient,
+    pub retry_policy: RetryPolicy,
+    pub subscriber_links: SubscriberLinkSigner,
+    pub email_templates: EmailTemplates,
+    pub webhooks: WebhookSettings,
+    pub test_user: TestUser,
+    pub api_client: reqwest::Client,
+}
+
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub async fn login(&self, app: &TestApp) {
+        app.post_login(&serde_json::json!({
+            "username": &self.username,
+            "password": &self.password,
+        }))
+        .await;
+    }
+
+    async fn store(&self, db_pool: &PgPool) {
+        let password_hash = compute_password_hash(Secret::new(self.password.clone()))
+            .expect("Failed to hash test user password");
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash.expose_secret(),
+        )
+        .execute(db_pool)
+        .await
+        .expect("Failed to store test user");
+    }
+}
+
+pub struct DeadLetter {
+    pub subscriber_email: String,
+    pub n_attempts: i32,
+    pub last_error: String,
+}
+
+/// Answers a Postmark batch request with a result for each email in it, failing
+/// the ones sent to the recipient given to `failing_for`.
+#[derive(Default)]
+pub struct PostmarkBatchResponder {
+    failing_recipient: Option<(String, i64)>,
+}
+
+impl PostmarkBatchResponder {
+    /// Rejects the recipient as inactive.
+    pub fn failing_for(recipient: &str) -> Self {
+        Self::failing_with(recipient, 406)
+    }
+
+    pub fn failing_with(recipient: &str, error_code: i64) -> Self {
+        Self {
+            failing_recipient: Some((recipient.to_string(), error_code)),
+        }
+    }
+}
+
+impl Respond for PostmarkBatchResponder {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let emails: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<serde_json::Value> = emails
+            .iter()
+            .map(|email| match &self.failing_recipient {
+                Some((failing, error_code)) if email["To"] == failing.as_str() => {
+                    serde_json::json!({
+                        "ErrorCode": error_code,
+                        "Message": "The email was not accepted."
+                    })
+                }
+                _ => serde_json::json!({"ErrorCode": 0, "Message": "OK"}),
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(results)
+    }
 }
 
 pub struct ConfirmationLinks {
@@ -83,7 +213,7 @@ impl TestApp {
     pub async fn post_subscription(&self, body: String) -> Response {
         let client = reqwest::Client::new();
         client
-            .post(&format!("http://{}/subscriptions", self.address))
+            .post(format!("http://{}/subscriptions", self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -91,6 +221,240 @@ impl TestApp {
             .expect("Failed to send request")
     }
 
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> Response {
+        reqwest::Client::new()
+            .post(format!("http://{}/newsletters", self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_dead_letters(&self, newsletter_issue_id: Option<&str>) -> Response {
+        let mut request = reqwest::Client::new()
+            .get(format!("http://{}/dead_letters", self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password));
+        if let Some(newsletter_issue_id) = newsletter_issue_id {
+            request = request.query(&[("newsletter_issue_id", newsletter_issue_id)]);
+        }
+        request.send().await.expect("Failed to send request")
+    }
+
+    pub async fn post_requeue_dead_letters(&self, body: &serde_json::Value) -> Response {
+        reqwest::Client::new()
+            .post(format!("http://{}/dead_letters/redrive", self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: serde_json::Value,
+        idempotency_key: &str,
+    ) -> Response {
+        reqwest::Client::new()
+            .post(format!("http://{}/newsletters", self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn post_postmark_webhook(&self, body: &serde_json::Value) -> Response {
+        reqwest::Client::new()
+            .post(format!("http://{}/webhooks/postmark", self.address))
+            .basic_auth(
+                &self.webhooks.username,
+                Some(self.webhooks.password.expose_secret()),
+            )
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_publish_newsletter(&self) -> Response {
+        self.api_client
+            .get(format!("http://{}/admin/newsletters", self.address))
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_publish_newsletter_html(&self) -> String {
+        self.get_publish_newsletter().await.text().await.unwrap()
+    }
+
+    pub async fn post_publish_newsletter<Body>(&self, body: &Body) -> Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("http://{}/admin/newsletters", self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn post_login<Body>(&self, body: &Body) -> Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("http://{}/login", self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("http://{}/login", self.address))
+            .send()
+            .await
+            .expect("Failed to send request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_admin_dashboard(&self) -> Response {
+        self.api_client
+            .get(format!("http://{}/admin/dashboard", self.address))
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+
+    pub async fn post_list(&self, body: &serde_json::Value) -> Response {
+        self.api_client
+            .post(format!("http://{}/admin/lists", self.address))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_lists(&self) -> Response {
+        self.api_client
+            .get(format!("http://{}/admin/lists", self.address))
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_admin_subscribers<Q>(&self, query: &Q) -> Response
+    where
+        Q: serde::Serialize + ?Sized,
+    {
+        self.api_client
+            .get(format!("http://{}/admin/subscribers", self.address))
+            .query(query)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn post_subscriber_import(&self, status: &str, csv: String) -> Response {
+        self.api_client
+            .post(format!("http://{}/admin/subscribers/import", self.address))
+            .query(&[("status", status)])
+            .header("Content-Type", "text/csv")
+            .body(csv)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_subscriber_export(&self) -> Response {
+        self.api_client
+            .get(format!("http://{}/admin/subscribers/export", self.address))
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_consent_export(&self, subscriber_id: Uuid) -> Response {
+        self.api_client
+            .get(format!(
+                "http://{}/admin/subscribers/{}/consent",
+                self.address, subscriber_id
+            ))
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_change_password(&self) -> Response {
+        self.api_client
+            .get(format!("http://{}/admin/password", self.address))
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn get_change_password_html(&self) -> String {
+        self.get_change_password().await.text().await.unwrap()
+    }
+
+    pub async fn post_change_password<Body>(&self, body: &Body) -> Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("http://{}/admin/password", self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    pub async fn post_logout(&self) -> Response {
+        self.api_client
+            .post(format!("http://{}/admin/logout", self.address))
+            .send()
+            .await
+            .expect("Failed to send request")
+    }
+
+    /// Waits until the background worker has drained the delivery queue.
+    pub async fn wait_for_pending_deliveries(&self) {
+        for _ in 0..100 {
+            let pending =
+                sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+                    .fetch_one(&self.db_pool)
+                    .await
+                    .expect("Failed to count pending deliveries");
+            if pending == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("Delivery queue was not drained in time");
+    }
+
+    pub async fn dead_letters(&self) -> Vec<DeadLetter> {
+        sqlx::query_as!(
+            DeadLetter,
+            "SELECT subscriber_email, n_attempts, last_error FROM issue_delivery_dead_letters"
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .expect("Failed to load dead letters")
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
 
@@ -107,8 +471,54 @@ impl TestApp {
             confirmation_url
         };
 
-        let html = get_link(&body["HtmlBody"].as_str().unwrap());
-        let plain = get_link(&body["TextBody"].as_str().unwrap());
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain = get_link(body["TextBody"].as_str().unwrap());
         ConfirmationLinks { html, plain }
     }
+
+    /// Recipients of all newsletter emails sent through the batch API so far, sorted.
+    pub async fn newsletter_recipients(&self) -> Vec<String> {
+        let mut recipients: Vec<String> = self
+            .email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| request.url.path() == "/email/batch")
+            .flat_map(|request| {
+                let emails: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+                emails
+                    .into_iter()
+                    .map(|email| email["To"].as_str().unwrap().to_string())
+            })
+            .collect();
+        recipients.sort();
+        recipients
+    }
+
+    /// Extracts the one-click unsubscribe link from the `List-Unsubscribe` header of
+    /// the first email in a newsletter batch request.
+    pub fn get_unsubscribe_link(&self, email_request: &wiremock::Request) -> Url {
+        let emails: Vec<serde_json::Value> = serde_json::from_slice(&email_request.body).unwrap();
+        let header = emails[0]["Headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|h| h["Name"] == "List-Unsubscribe")
+            .unwrap();
+        let raw_link = header["Value"]
+            .as_str()
+            .unwrap()
+            .trim_start_matches('<')
+            .trim_end_matches('>');
+        let mut unsubscribe_url = Url::parse(raw_link).unwrap();
+        assert_eq!(unsubscribe_url.host_str().unwrap(), "127.0.0.1");
+        unsubscribe_url.set_port(Some(self.port)).unwrap();
+        unsubscribe_url
+    }
+}
+
+pub fn assert_is_redirect_to(response: &Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
 }
@@ -0,0 +1,352 @@
+use std::time::Duration;
+
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::subscription_cleanup_worker::purge_stale_subscriptions;
+
+use crate::helpers::{assert_is_redirect_to, spawn_app, PostmarkBatchResponder, TestApp};
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+
+async fn create_list(app: &TestApp, slug: &str) {
+    app.post_list(&serde_json::json!({ "slug": slug, "name": "Weekly digest" }))
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+/// Subscribes to `list`, to the default list when `None`.
+async fn subscribe(app: &TestApp, list: Option<&str>) {
+    let mut form = vec![("name", "le guin"), ("email", EMAIL)];
+    if let Some(list) = list {
+        form.push(("list", list));
+    }
+    app.post_subscription(serde_urlencoded::to_string(&form).unwrap())
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+async fn confirm_last_email(app: &TestApp) {
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .rev()
+        .find(|request| request.url.path() == "/email")
+        .unwrap();
+    reqwest::get(app.get_confirmation_links(&email_request).html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+async fn memberships(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"
+        SELECT lists.slug, list_memberships.status
+        FROM list_memberships
+        JOIN lists ON lists.id = list_memberships.list_id
+        ORDER BY lists.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| (row.slug, row.status))
+    .collect()
+}
+
+async fn publish_to(app: &TestApp, list: &str) {
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        },
+        "list": list,
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.wait_for_pending_deliveries().await;
+}
+
+async fn mount_email_mocks(app: &TestApp) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .mount(&app.email_server)
+        .await;
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_manage_lists() {
+    let app = spawn_app().await;
+
+    let get = app.get_lists().await;
+    let post = app
+        .post_list(&serde_json::json!({ "slug": "digest", "name": "Digest" }))
+        .await;
+
+    assert_is_redirect_to(&get, "/login");
+    assert_is_redirect_to(&post, "/login");
+}
+
+#[tokio::test]
+async fn lists_can_be_created_once_per_slug() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let created = app
+        .post_list(&serde_json::json!({ "slug": "digest", "name": "Weekly digest" }))
+        .await;
+    let duplicate = app
+        .post_list(&serde_json::json!({ "slug": "digest", "name": "Another digest" }))
+        .await;
+    let invalid = app
+        .post_list(&serde_json::json!({ "slug": "Not a slug", "name": "Invalid" }))
+        .await;
+
+    assert_eq!(created.status().as_u16(), 201);
+    assert_eq!(duplicate.status().as_u16(), 409);
+    assert_eq!(invalid.status().as_u16(), 400);
+    let lists: serde_json::Value = app.get_lists().await.json().await.unwrap();
+    let slugs: Vec<&str> = lists
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|list| list["slug"].as_str().unwrap())
+        .collect();
+    assert_eq!(slugs, vec!["default", "digest"]);
+}
+
+#[tokio::test]
+async fn subscribing_without_a_list_joins_the_default_list() {
+    let app = spawn_app().await;
+    mount_email_mocks(&app).await;
+
+    subscribe(&app, None).await;
+    confirm_last_email(&app).await;
+
+    assert_eq!(
+        memberships(&app).await,
+        vec![("default".to_string(), "confirmed".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn subscribing_to_an_unknown_list_is_rejected() {
+    let app = spawn_app().await;
+
+    let res = app
+        .post_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com&list=nope".into())
+        .await;
+
+    assert_eq!(res.status().as_u16(), 400);
+    assert!(memberships(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn issues_only_go_to_the_confirmed_members_of_their_list() {
+    let app = spawn_app().await;
+    mount_email_mocks(&app).await;
+    app.test_user.login(&app).await;
+    create_list(&app, "digest").await;
+    subscribe(&app, Some("digest")).await;
+    confirm_last_email(&app).await;
+
+    publish_to(&app, "default").await;
+    assert!(app.newsletter_recipients().await.is_empty());
+    publish_to(&app, "digest").await;
+    assert_eq!(app.newsletter_recipients().await, vec![EMAIL.to_string()]);
+}
+
+#[tokio::test]
+async fn joining_a_second_list_needs_its_own_confirmation() {
+    let app = spawn_app().await;
+    mount_email_mocks(&app).await;
+    app.test_user.login(&app).await;
+    create_list(&app, "digest").await;
+    subscribe(&app, None).await;
+    confirm_last_email(&app).await;
+
+    subscribe(&app, Some("digest")).await;
+    assert_eq!(
+        memberships(&app).await,
+        vec![
+            ("default".to_string(), "confirmed".to_string()),
+            ("digest".to_string(), "pending_confirmation".to_string()),
+        ]
+    );
+    publish_to(&app, "digest").await;
+    assert!(app.newsletter_recipients().await.is_empty());
+
+    confirm_last_email(&app).await;
+    assert_eq!(
+        memberships(&app).await,
+        vec![
+            ("default".to_string(), "confirmed".to_string()),
+            ("digest".to_string(), "confirmed".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn a_stale_pending_membership_is_purged_without_the_subscriber() {
+    let app = spawn_app().await;
+    mount_email_mocks(&app).await;
+    app.test_user.login(&app).await;
+    create_list(&app, "digest").await;
+    subscribe(&app, None).await;
+    confirm_last_email(&app).await;
+    subscribe(&app, Some("digest")).await;
+    sqlx::query!("UPDATE list_memberships SET subscribed_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE subscriptions SET subscribed_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE subscription_tokens SET issued_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let purged = purge_stale_subscriptions(&app.db_pool, Duration::from_secs(7 * 24 * 60 * 60))
+        .await
+        .unwrap();
+
+    assert_eq!(purged, 0);
+    assert_eq!(
+        memberships(&app).await,
+        vec![("default".to_string(), "confirmed".to_string())]
+    );
+    let consent_lists: Vec<String> = sqlx::query!(
+        r#"
+        SELECT lists.slug FROM consent_events
+        JOIN lists ON lists.id = consent_events.list_id
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| row.slug)
+    .collect();
+    assert_eq!(consent_lists, vec!["default", "default"]);
+}
+
+#[tokio::test]
+async fn the_unsubscribe_link_only_leaves_the_list_of_the_issue() {
+    let app = spawn_app().await;
+    mount_email_mocks(&app).await;
+    app.test_user.login(&app).await;
+    create_list(&app, "digest").await;
+    subscribe(&app, None).await;
+    confirm_last_email(&app).await;
+    subscribe(&app, Some("digest")).await;
+    confirm_last_email(&app).await;
+    publish_to(&app, "digest").await;
+    let newsletter = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    reqwest::Client::new()
+        .post(app.get_unsubscribe_link(&newsletter))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(
+        memberships(&app).await,
+        vec![
+            ("default".to_string(), "confirmed".to_string()),
+            ("digest".to_string(), "unsubscribed".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn the_unsubscribe_form_can_leave_every_list() {
+    let app = spawn_app().await;
+    mount_email_mocks(&app).await;
+    app.test_user.login(&app).await;
+    create_list(&app, "digest").await;
+    subscribe(&app, None).await;
+    confirm_last_email(&app).await;
+    subscribe(&app, Some("digest")).await;
+    confirm_last_email(&app).await;
+    publish_to(&app, "digest").await;
+    let newsletter = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    reqwest::Client::new()
+        .post(app.get_unsubscribe_link(&newsletter))
+        .form(&[("lists", "all")])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(
+        memberships(&app).await,
+        vec![
+            ("default".to_string(), "unsubscribed".to_string()),
+            ("digest".to_string(), "unsubscribed".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn confirming_a_list_after_a_bounce_does_not_resume_the_other_lists() {
+    let app = spawn_app().await;
+    mount_email_mocks(&app).await;
+    app.test_user.login(&app).await;
+    create_list(&app, "digest").await;
+    subscribe(&app, None).await;
+    confirm_last_email(&app).await;
+    app.post_postmark_webhook(&serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "HardBounce",
+        "Email": EMAIL,
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    subscribe(&app, Some("digest")).await;
+    confirm_last_email(&app).await;
+
+    assert_eq!(
+        memberships(&app).await,
+        vec![
+            ("default".to_string(), "unsubscribed".to_string()),
+            ("digest".to_string(), "confirmed".to_string()),
+        ]
+    );
+    publish_to(&app, "default").await;
+    assert!(app.newsletter_recipients().await.is_empty());
+}
@@ -0,0 +1,89 @@
+use secrecy::{ExposeSecret, Secret};
+use zero2prod::authentication::compute_password_hash;
+
+use crate::helpers::{assert_is_redirect_to, spawn_app, spawn_app_with};
+
+#[tokio::test]
+async fn an_error_flash_message_is_set_on_failure() {
+    let app = spawn_app().await;
+    let login_body = serde_json::json!({
+        "username": "random-username",
+        "password": "random-password"
+    });
+
+    let res = app.post_login(&login_body).await;
+    assert_is_redirect_to(&res, "/login");
+
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("<p><i>Authentication failed</i></p>"));
+
+    let html_page = app.get_login_html().await;
+    assert!(!html_page.contains("Authentication failed"));
+}
+
+#[tokio::test]
+async fn redirect_to_admin_dashboard_after_login_success() {
+    let app = spawn_app().await;
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+
+    let res = app.post_login(&login_body).await;
+    assert_is_redirect_to(&res, "/admin/dashboard");
+
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+}
+
+#[tokio::test]
+async fn session_cookie_is_http_only_and_same_site() {
+    let app = spawn_app().await;
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+
+    let res = app.post_login(&login_body).await;
+
+    let cookie = res.headers()["Set-Cookie"].to_str().unwrap();
+    assert!(cookie.contains("HttpOnly"));
+    assert!(cookie.contains("SameSite=Strict"));
+}
+
+#[tokio::test]
+async fn the_configured_first_admin_can_log_in() {
+    let password_hash = compute_password_hash(Secret::new("first-admin-password".into())).unwrap();
+    let app = spawn_app_with(|config| {
+        config.admin.username = Some("first-admin".into());
+        config.admin.password_hash = Some(password_hash.clone());
+    })
+    .await;
+
+    let res = app
+        .post_login(&serde_json::json!({
+            "username": "first-admin",
+            "password": "first-admin-password"
+        }))
+        .await;
+
+    assert_is_redirect_to(&res, "/admin/dashboard");
+    let stored = sqlx::query!("SELECT password_hash FROM users WHERE username = 'first-admin'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(stored.password_hash, password_hash.expose_secret().as_str());
+}
+
+#[tokio::test]
+async fn there_is_no_default_admin() {
+    let app = spawn_app().await;
+
+    let users = sqlx::query!("SELECT username FROM users")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].username, app.test_user.username);
+}
@@ -1,4 +1,23 @@
+mod admin_dashboard;
+mod admin_subscribers;
+mod admin_subscribers_csv;
+mod change_password;
+mod consent;
+mod digest;
+mod email_change;
+mod email_client;
+mod email_webhooks;
 mod health_check;
 mod helpers;
+mod lists;
+mod login;
+mod newsletter_drafts;
+mod newsletters;
+mod personal_data;
+mod preferences;
+mod scheduled_newsletters;
+mod session_store;
+mod subscription_cleanup;
 mod subscriptions;
 mod subscriptions_confirm;
+mod unsubscribe;
@@ -0,0 +1,411 @@
+use std::time::Duration;
+
+use reqwest::{Method, RequestBuilder, Response};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+use crate::helpers::{spawn_app, spawn_app_with, PostmarkBatchResponder, TestApp};
+use crate::newsletters::{create_confirmed_subscriber, newsletter_request_body};
+
+fn api_request(app: &TestApp, method: Method, path: &str) -> RequestBuilder {
+    reqwest::Client::new()
+        .request(method, format!("http://{}{}", app.address, path))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+}
+
+/// An app that sends tests of newsletter issues to `editor@example.com` only.
+async fn spawn_app_with_test_recipient() -> TestApp {
+    spawn_app_with(|config| {
+        config.admin.test_recipients = vec!["editor@example.com".into()];
+    })
+    .await
+}
+
+async fn create_draft(app: &TestApp) -> Uuid {
+    let response = api_request(app, Method::POST, "/newsletters/drafts")
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 201);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+async fn edit(app: &TestApp, issue_id: Uuid, title: &str) -> Response {
+    api_request(app, Method::PUT, &format!("/newsletters/{}", issue_id))
+        .json(&serde_json::json!({
+            "title": title,
+            "content": {
+                "text": "Edited body as plain text",
+                "html": "<p>Edited body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request")
+}
+
+async fn get_issue(app: &TestApp, issue_id: Uuid) -> serde_json::Value {
+    api_request(app, Method::GET, &format!("/newsletters/{}", issue_id))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+async fn send_now(app: &TestApp, issue_id: Uuid) -> Response {
+    api_request(
+        app,
+        Method::POST,
+        &format!("/newsletters/{}/send", issue_id),
+    )
+    .send()
+    .await
+    .expect("Failed to send request")
+}
+
+async fn subscriber_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email
+}
+
+async fn wait_for_status(app: &TestApp, issue_id: Uuid, status: &str) {
+    for _ in 0..100 {
+        if get_issue(app, issue_id).await["status"] == status {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The issue did not become {} in time", status);
+}
+
+#[tokio::test]
+async fn drafts_are_not_delivered() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    let issue_id = create_draft(&app).await;
+
+    let issue = get_issue(&app, issue_id).await;
+    assert_eq!(issue["status"], "draft");
+    assert_eq!(issue["revision"], 1);
+    assert_eq!(issue["list"], "default");
+    let queued = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued, 0);
+}
+
+#[tokio::test]
+async fn every_edit_is_kept_as_a_revision() {
+    let app = spawn_app().await;
+    let issue_id = create_draft(&app).await;
+
+    let first = edit(&app, issue_id, "Second title").await;
+    let unchanged = edit(&app, issue_id, "Second title").await;
+    let second = edit(&app, issue_id, "Third title").await;
+
+    assert_eq!(first.status().as_u16(), 204);
+    assert_eq!(unchanged.status().as_u16(), 204);
+    assert_eq!(second.status().as_u16(), 204);
+    let issue = get_issue(&app, issue_id).await;
+    assert_eq!(issue["title"], "Third title");
+    assert_eq!(issue["text_content"], "Edited body as plain text");
+    assert_eq!(issue["revision"], 3);
+    let revisions: Vec<serde_json::Value> = api_request(
+        &app,
+        Method::GET,
+        &format!("/newsletters/{}/revisions", issue_id),
+    )
+    .send()
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    let titles: Vec<&str> = revisions
+        .iter()
+        .map(|revision| revision["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        titles,
+        vec!["Newsletter title", "Second title", "Third title"]
+    );
+    assert_eq!(
+        revisions[0]["text_content"],
+        "Newsletter body as plain text"
+    );
+    assert!(revisions
+        .iter()
+        .all(|revision| revision["edited_by"] == app.test_user.username.as_str()));
+}
+
+#[tokio::test]
+async fn the_preview_matches_the_delivered_email() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    sqlx::query!("UPDATE subscriptions SET language = 'de'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = create_draft(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let preview: serde_json::Value = api_request(
+        &app,
+        Method::GET,
+        &format!("/newsletters/{}/preview", issue_id),
+    )
+    .query(&[("email", &email)])
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    send_now(&app, issue_id).await.error_for_status().unwrap();
+    app.wait_for_pending_deliveries().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let delivered: Vec<serde_json::Value> = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(preview["subject"], delivered[0]["Subject"]);
+    assert_eq!(preview["html"], delivered[0]["HtmlBody"]);
+    assert_eq!(preview["text"], delivered[0]["TextBody"]);
+}
+
+#[tokio::test]
+async fn the_preview_needs_a_known_issue_and_subscriber() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue_id = create_draft(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let unknown_issue = api_request(
+        &app,
+        Method::GET,
+        &format!("/newsletters/{}/preview", Uuid::new_v4()),
+    )
+    .query(&[("email", &email)])
+    .send()
+    .await
+    .unwrap();
+    let unknown_subscriber = api_request(
+        &app,
+        Method::GET,
+        &format!("/newsletters/{}/preview", issue_id),
+    )
+    .query(&[("email", "nobody@example.com")])
+    .send()
+    .await
+    .unwrap();
+
+    assert_eq!(unknown_issue.status().as_u16(), 404);
+    assert_eq!(unknown_subscriber.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn a_test_send_only_reaches_the_given_address() {
+    let app = spawn_app_with_test_recipient().await;
+    create_confirmed_subscriber(&app).await;
+    let issue_id = create_draft(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = api_request(
+        &app,
+        Method::POST,
+        &format!("/newsletters/{}/test", issue_id),
+    )
+    .json(&serde_json::json!({ "email": "editor@example.com" }))
+    .send()
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 204);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "editor@example.com");
+    assert_eq!(body["Subject"], "[Test] Newsletter title");
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("Newsletter body as plain text"));
+    assert_eq!(get_issue(&app, issue_id).await["status"], "draft");
+}
+
+#[tokio::test]
+async fn a_test_send_needs_a_valid_address() {
+    let app = spawn_app_with_test_recipient().await;
+    let issue_id = create_draft(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = api_request(
+        &app,
+        Method::POST,
+        &format!("/newsletters/{}/test", issue_id),
+    )
+    .json(&serde_json::json!({ "email": "not-an-email" }))
+    .send()
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn a_test_send_to_an_address_that_is_not_a_test_recipient_is_rejected() {
+    let app = spawn_app_with_test_recipient().await;
+    let issue_id = create_draft(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = api_request(
+        &app,
+        Method::POST,
+        &format!("/newsletters/{}/test", issue_id),
+    )
+    .json(&serde_json::json!({ "email": "someone-else@example.com" }))
+    .send()
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn issues_move_from_draft_to_sent() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = create_draft(&app).await;
+
+    api_request(
+        &app,
+        Method::PUT,
+        &format!("/newsletters/{}/schedule", issue_id),
+    )
+    .json(&serde_json::json!({ "scheduled_at": "2099-01-01T09:00:00Z" }))
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+    assert_eq!(get_issue(&app, issue_id).await["status"], "scheduled");
+    api_request(
+        &app,
+        Method::POST,
+        &format!("/newsletters/{}/cancel", issue_id),
+    )
+    .send()
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+    let cancelled = get_issue(&app, issue_id).await;
+    assert_eq!(cancelled["status"], "draft");
+    assert!(cancelled["scheduled_at"].is_null());
+
+    let sent = send_now(&app, issue_id).await;
+    assert_eq!(sent.status().as_u16(), 204);
+    app.wait_for_pending_deliveries().await;
+    wait_for_status(&app, issue_id, "sent").await;
+
+    assert_eq!(send_now(&app, issue_id).await.status().as_u16(), 409);
+    assert_eq!(
+        edit(&app, issue_id, "Too late").await.status().as_u16(),
+        409
+    );
+    assert_eq!(get_issue(&app, issue_id).await["title"], "Newsletter title");
+}
+
+#[tokio::test]
+async fn unknown_issues_are_reported_with_404() {
+    let app = spawn_app().await;
+    let issue_id = Uuid::new_v4();
+
+    let get = api_request(&app, Method::GET, &format!("/newsletters/{}", issue_id))
+        .send()
+        .await
+        .unwrap();
+    let edited = edit(&app, issue_id, "Title").await;
+    let revisions = api_request(
+        &app,
+        Method::GET,
+        &format!("/newsletters/{}/revisions", issue_id),
+    )
+    .send()
+    .await
+    .unwrap();
+    let sent = send_now(&app, issue_id).await;
+
+    assert_eq!(get.status().as_u16(), 404);
+    assert_eq!(edited.status().as_u16(), 404);
+    assert_eq!(revisions.status().as_u16(), 404);
+    assert_eq!(sent.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn drafts_need_an_authenticated_editor() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/newsletters/drafts", app.address))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
@@ -0,0 +1,711 @@
+use std::time::Duration;
+
+use fake::faker::internet::en::SafeEmail;
+use fake::faker::name::en::Name;
+use fake::Fake;
+use sqlx::types::chrono::Utc;
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::idempotency::purge_expired_idempotency_keys;
+use zero2prod::issue_delivery_worker::{redrive_dead_letters, try_execute_task, ExecutionOutcome};
+
+use crate::helpers::{
+    assert_is_redirect_to, spawn_app, ConfirmationLinks, PostmarkBatchResponder, TestApp,
+};
+
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    let name: String = Name().fake();
+    let email: String = SafeEmail().fake();
+    let body = serde_urlencoded::to_string(serde_json::json!({
+        "name": name,
+        "email": email
+    }))
+    .unwrap();
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscription(body)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request)
+}
+
+pub async fn create_confirmed_subscriber(app: &TestApp) {
+    let confirmation_link = create_unconfirmed_subscriber(app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+pub fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.post_newsletters(newsletter_request_body()).await;
+
+    assert_eq!(res.status().as_u16(), 202);
+    app.wait_for_pending_deliveries().await;
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_confirmed_subscribers() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.post_newsletters(newsletter_request_body()).await;
+
+    assert_eq!(res.status().as_u16(), 202);
+    app.wait_for_pending_deliveries().await;
+}
+
+#[tokio::test]
+async fn newsletters_are_rendered_in_the_language_of_each_subscriber() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    sqlx::query!("UPDATE subscriptions SET language = 'de'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.wait_for_pending_deliveries().await;
+
+    let batch_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|request| request.url.path() == "/email/batch")
+        .unwrap();
+    let emails: Vec<serde_json::Value> = serde_json::from_slice(&batch_request.body).unwrap();
+    assert!(emails[0]["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("Abmelden: "));
+    assert!(emails[0]["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains(r#"<html lang="de">"#));
+}
+
+#[tokio::test]
+async fn newsletters_skip_confirmed_subscribers_with_invalid_emails() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'definitely-not-an-email', 'broken', $2, 'confirmed')
+        "#,
+        subscriber_id,
+        Utc::now()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO list_memberships (subscriber_id, list_id, status, subscribed_at)
+        SELECT $1, id, 'confirmed', now() FROM lists WHERE slug = 'default'
+        "#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.post_newsletters(newsletter_request_body()).await;
+
+    assert_eq!(res.status().as_u16(), 202);
+    app.wait_for_pending_deliveries().await;
+}
+
+#[tokio::test]
+async fn concurrent_workers_deliver_each_issue_exactly_once() {
+    let app = spawn_app().await;
+    for _ in 0..5 {
+        create_confirmed_subscriber(&app).await;
+    }
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1..)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.post_newsletters(newsletter_request_body()).await;
+    assert_eq!(res.status().as_u16(), 202);
+
+    let drain_queue = || async {
+        while let ExecutionOutcome::TasksProcessed(_) = try_execute_task(
+            &app.db_pool,
+            &app.email_client,
+            &app.retry_policy,
+            &app.subscriber_links,
+            &app.email_templates,
+        )
+        .await
+        .unwrap()
+        {}
+    };
+    tokio::join!(drain_queue(), drain_queue());
+    app.wait_for_pending_deliveries().await;
+
+    let mut recipients = app.newsletter_recipients().await;
+    assert_eq!(recipients.len(), 5);
+    recipients.dedup();
+    assert_eq!(recipients.len(), 5);
+}
+
+#[tokio::test]
+async fn leased_deliveries_are_only_picked_up_again_once_the_lease_runs_out() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let draft: serde_json::Value = reqwest::Client::new()
+        .post(format!("http://{}/newsletters/drafts", app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let issue_id: Uuid = draft["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    // As if a worker died while sending.
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email, leased_until)
+        SELECT $1, email, now() + interval '1 hour' FROM subscriptions
+        "#,
+        issue_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(app.newsletter_recipients().await.is_empty());
+
+    sqlx::query!("UPDATE issue_delivery_queue SET leased_until = now() - interval '1 second'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.wait_for_pending_deliveries().await;
+    assert_eq!(app.newsletter_recipients().await.len(), 1);
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.wait_for_pending_deliveries().await;
+
+    let dead_letters = app.dead_letters().await;
+    assert!(dead_letters.is_empty());
+}
+
+#[tokio::test]
+async fn deliveries_are_dead_lettered_after_max_attempts() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.wait_for_pending_deliveries().await;
+
+    let dead_letters = app.dead_letters().await;
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].n_attempts, 3);
+    assert!(dead_letters[0].last_error.contains("500"));
+}
+
+#[tokio::test]
+async fn rejected_deliveries_are_dead_lettered_without_retry() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.wait_for_pending_deliveries().await;
+
+    let dead_letters = app.dead_letters().await;
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].n_attempts, 1);
+}
+
+#[tokio::test]
+async fn only_the_failed_emails_of_a_batch_are_dead_lettered() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_confirmed_subscriber(&app).await;
+    let failing = sqlx::query!("SELECT email FROM subscriptions LIMIT 1")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::failing_for(&failing))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.wait_for_pending_deliveries().await;
+
+    let dead_letters = app.dead_letters().await;
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].subscriber_email, failing);
+    assert!(dead_letters[0].last_error.contains("406"));
+}
+
+#[tokio::test]
+async fn redriven_dead_letters_are_delivered() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    let rejection = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body()).await;
+    app.wait_for_pending_deliveries().await;
+    drop(rejection);
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let redriven = redrive_dead_letters(&app.db_pool, None).await.unwrap();
+    app.wait_for_pending_deliveries().await;
+
+    assert_eq!(redriven, 1);
+    assert!(app.dead_letters().await.is_empty());
+}
+
+#[tokio::test]
+async fn operators_can_list_and_requeue_the_dead_letters_of_an_issue() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    let rejection = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let response: serde_json::Value = app
+        .post_newsletters(newsletter_request_body())
+        .await
+        .json()
+        .await
+        .unwrap();
+    let issue_id = response["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    app.wait_for_pending_deliveries().await;
+    drop(rejection);
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let listed: Vec<serde_json::Value> = app
+        .get_dead_letters(Some(&issue_id))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let other_issue: Vec<serde_json::Value> = app
+        .get_dead_letters(Some(&Uuid::new_v4().to_string()))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let requeued: serde_json::Value = app
+        .post_requeue_dead_letters(&serde_json::json!({ "newsletter_issue_id": issue_id }))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    app.wait_for_pending_deliveries().await;
+
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0]["newsletter_issue_id"], issue_id.as_str());
+    assert!(listed[0]["last_error"].as_str().unwrap().contains("422"));
+    assert!(other_issue.is_empty());
+    assert_eq!(requeued["redriven"], 1);
+    assert!(app.dead_letters().await.is_empty());
+}
+
+#[tokio::test]
+async fn dead_letters_need_an_authenticated_operator() {
+    let app = spawn_app().await;
+
+    let list = reqwest::Client::new()
+        .get(format!("http://{}/dead_letters", app.address))
+        .send()
+        .await
+        .unwrap();
+    let requeue = reqwest::Client::new()
+        .post(format!("http://{}/dead_letters/redrive", app.address))
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(list.status().as_u16(), 401);
+    assert_eq!(requeue.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn requests_missing_authorization_are_rejected() {
+    let app = spawn_app().await;
+
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/newsletters", app.address))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to send request");
+
+    assert_eq!(401, res.status().as_u16());
+    assert_eq!(r#"Basic realm="admin""#, res.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn non_existing_user_is_rejected() {
+    let app = spawn_app().await;
+    let username = Uuid::new_v4().to_string();
+    let password = Uuid::new_v4().to_string();
+
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/newsletters", app.address))
+        .basic_auth(username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to send request");
+
+    assert_eq!(401, res.status().as_u16());
+    assert_eq!(r#"Basic realm="admin""#, res.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn invalid_password_is_rejected() {
+    let app = spawn_app().await;
+    let password = Uuid::new_v4().to_string();
+    assert_ne!(app.test_user.password, password);
+
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/newsletters", app.address))
+        .basic_auth(&app.test_user.username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to send request");
+
+    assert_eq!(401, res.status().as_u16());
+    assert_eq!(r#"Basic realm="admin""#, res.headers()["WWW-Authenticate"]);
+}
+
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(res.status().as_u16(), 202);
+
+    let res = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(res.status().as_u16(), 202);
+
+    app.wait_for_pending_deliveries().await;
+}
+
+#[tokio::test]
+async fn concurrent_submission_is_handled_gracefully() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let (first, second) = tokio::join!(
+        app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key),
+        app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+    );
+
+    assert_eq!(first.status(), second.status());
+    assert_eq!(first.text().await.unwrap(), second.text().await.unwrap());
+    app.wait_for_pending_deliveries().await;
+}
+
+#[tokio::test]
+async fn expired_idempotency_keys_are_purged() {
+    let app = spawn_app().await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .mount(&app.email_server)
+        .await;
+    for _ in 0..2 {
+        app.post_newsletters_with_idempotency_key(
+            newsletter_request_body(),
+            &Uuid::new_v4().to_string(),
+        )
+        .await
+        .error_for_status()
+        .unwrap();
+    }
+    sqlx::query!(
+        "UPDATE idempotency SET created_at = now() - interval '3 days' WHERE idempotency_key = (SELECT MIN(idempotency_key) FROM idempotency)"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // The cleanup worker of the app may get to the expired key first.
+    purge_expired_idempotency_keys(&app.db_pool, Duration::from_secs(2 * 24 * 60 * 60))
+        .await
+        .unwrap();
+
+    let remaining = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM idempotency"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining, 1);
+}
+
+#[tokio::test]
+async fn invalid_idempotency_keys_are_rejected() {
+    let app = spawn_app().await;
+
+    let res = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &"a".repeat(50))
+        .await;
+
+    assert_eq!(res.status().as_u16(), 400);
+}
+
+fn newsletter_form_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as HTML</p>",
+        "idempotency_key": Uuid::new_v4().to_string()
+    })
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_the_newsletter_form() {
+    let app = spawn_app().await;
+
+    let res = app.get_publish_newsletter().await;
+
+    assert_is_redirect_to(&res, "/login");
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_publish_a_newsletter_from_the_form() {
+    let app = spawn_app().await;
+
+    let res = app.post_publish_newsletter(&newsletter_form_body()).await;
+
+    assert_is_redirect_to(&res, "/login");
+}
+
+#[tokio::test]
+async fn newsletter_form_submission_is_idempotent() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let form_body = newsletter_form_body();
+    let res = app.post_publish_newsletter(&form_body).await;
+    assert_is_redirect_to(&res, "/admin/newsletters");
+
+    let html_page = app.get_publish_newsletter_html().await;
+    assert!(html_page.contains(
+        "<p><i>The newsletter issue has been accepted - emails will go out shortly.</i></p>"
+    ));
+
+    let res = app.post_publish_newsletter(&form_body).await;
+    assert_is_redirect_to(&res, "/admin/newsletters");
+
+    let html_page = app.get_publish_newsletter_html().await;
+    assert!(html_page.contains(
+        "<p><i>The newsletter issue has been accepted - emails will go out shortly.</i></p>"
+    ));
+
+    app.wait_for_pending_deliveries().await;
+}
+
+#[tokio::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }),
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!"}),
+            "missing content",
+        ),
+    ];
+
+    for (invalid_body, error_message) in test_cases {
+        let res = app.post_newsletters(invalid_body).await;
+
+        assert_eq!(
+            400,
+            res.status().as_u16(),
+            "Api did not fail with payload [{}]",
+            error_message
+        );
+    }
+}
@@ -0,0 +1,439 @@
+use chrono::{Duration, Utc};
+use reqwest::{Response, Url};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::subscriber_links::LinkPurpose;
+
+use crate::helpers::{spawn_app, PostmarkBatchResponder, TestApp};
+use crate::newsletters::{create_confirmed_subscriber, newsletter_request_body};
+
+async fn subscriber(app: &TestApp) -> (Uuid, String) {
+    let row = sqlx::query!("SELECT id, email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    (row.id, row.email)
+}
+
+async fn get_personal_data(app: &TestApp, email: &str) -> Response {
+    reqwest::Client::new()
+        .get(format!("http://{}/personal_data", app.address))
+        .query(&[("email", email)])
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to send request")
+}
+
+async fn delete_personal_data(app: &TestApp, email: &str) -> Response {
+    reqwest::Client::new()
+        .delete(format!("http://{}/personal_data", app.address))
+        .query(&[("email", email)])
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to send request")
+}
+
+fn self_service_url(app: &TestApp, path: &str, subscriber_id: Uuid, token: &str) -> Url {
+    let mut url = Url::parse(&format!("http://127.0.0.1{}", path)).unwrap();
+    url.set_port(Some(app.port)).unwrap();
+    url.query_pairs_mut()
+        .append_pair("subscriber_id", &subscriber_id.to_string())
+        .append_pair("token", token);
+    url
+}
+
+fn personal_data_token(app: &TestApp, subscriber_id: Uuid) -> String {
+    app.subscriber_links
+        .token(LinkPurpose::PersonalData, subscriber_id)
+}
+
+fn access_url(app: &TestApp, path: &str, subscriber_id: Uuid, expires_at: i64, token: &str) -> Url {
+    let mut url = self_service_url(app, path, subscriber_id, token);
+    url.query_pairs_mut()
+        .append_pair("expires_at", &expires_at.to_string());
+    url
+}
+
+fn access_token(app: &TestApp, subscriber_id: Uuid, expires_at: i64) -> String {
+    app.subscriber_links
+        .expiring_token(LinkPurpose::PersonalDataAccess, subscriber_id, expires_at)
+}
+
+async fn count(app: &TestApp, table: &str) -> i64 {
+    sqlx::query_scalar::<_, i64>(&format!("SELECT count(*) FROM {}", table))
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn the_personal_data_api_requires_credentials() {
+    let app = spawn_app().await;
+
+    let res = reqwest::Client::new()
+        .get(format!("http://{}/personal_data", app.address))
+        .query(&[("email", "ursula@example.com")])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn the_export_contains_the_subscription_tokens_and_consent_events() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (subscriber_id, email) = subscriber(&app).await;
+
+    let res = get_personal_data(&app, &email).await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    let export: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(export["email"], email.as_str());
+    assert_eq!(export["subscriptions"][0]["id"], subscriber_id.to_string());
+    assert_eq!(export["subscriptions"][0]["status"], "confirmed");
+    assert_eq!(export["subscription_tokens"].as_array().unwrap().len(), 1);
+    assert_eq!(export["consent_events"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn exporting_an_unknown_address_returns_404() {
+    let app = spawn_app().await;
+
+    let res = get_personal_data(&app, "nobody@example.com").await;
+
+    assert_eq!(res.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn erasure_deletes_everything_and_keeps_a_suppression_hash() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (_, email) = subscriber(&app).await;
+    app.post_postmark_webhook(&serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "SoftBounce",
+        "Email": email,
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    let res = delete_personal_data(&app, &email).await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert!(body["erased_rows"].as_u64().unwrap() > 0);
+    for table in [
+        "subscriptions",
+        "subscription_tokens",
+        "consent_events",
+        "email_events",
+    ] {
+        assert_eq!(count(&app, table).await, 0, "{} was not emptied", table);
+    }
+    let suppressed = sqlx::query!("SELECT email_hash FROM suppressed_emails")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(suppressed.len(), 1);
+    assert!(!suppressed[0].email_hash.contains(&email));
+    assert_eq!(get_personal_data(&app, &email).await.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn addresses_are_matched_regardless_of_case() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (subscriber_id, email) = subscriber(&app).await;
+    let shouted = email.to_uppercase();
+
+    let export = get_personal_data(&app, &shouted).await;
+    let erasure = delete_personal_data(&app, &shouted).await;
+
+    assert_eq!(export.status().as_u16(), 200);
+    let export: serde_json::Value = export.json().await.unwrap();
+    assert_eq!(export["subscriptions"][0]["id"], subscriber_id.to_string());
+    let erasure: serde_json::Value = erasure.json().await.unwrap();
+    assert!(erasure["erased_rows"].as_u64().unwrap() > 0);
+    assert_eq!(count(&app, "subscriptions").await, 0);
+}
+
+#[tokio::test]
+async fn erasing_an_unknown_address_reports_that_nothing_was_erased() {
+    let app = spawn_app().await;
+
+    let res = delete_personal_data(&app, "nobody@example.com").await;
+
+    assert_eq!(res.status().as_u16(), 200);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["erased_rows"], 0);
+    assert_eq!(count(&app, "suppressed_emails").await, 1);
+}
+
+#[tokio::test]
+async fn erasure_removes_pending_and_failed_deliveries() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (_, email) = subscriber(&app).await;
+    // Queued but not delivered yet, and a dead letter from a previous issue.
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_dead_letters
+            (newsletter_issue_id, subscriber_email, n_attempts, last_error, failed_at)
+        SELECT newsletter_issue_id, subscriber_email, 3, 'rejected', now()
+        FROM issue_delivery_queue
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    delete_personal_data(&app, &email)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(count(&app, "issue_delivery_queue").await, 0);
+    assert_eq!(count(&app, "issue_delivery_dead_letters").await, 0);
+}
+
+#[tokio::test]
+async fn newsletters_link_to_the_personal_data_page() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (subscriber_id, _) = subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.wait_for_pending_deliveries().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let emails: Vec<serde_json::Value> = serde_json::from_slice(&email_request.body).unwrap();
+    let personal_data_link = app.subscriber_links.personal_data_link(subscriber_id);
+    assert!(emails[0]["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains(&personal_data_link));
+}
+
+#[tokio::test]
+async fn the_personal_data_link_emails_a_short_lived_link_to_the_address_on_file() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (subscriber_id, email) = subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let token = personal_data_token(&app, subscriber_id);
+
+    let page = reqwest::get(self_service_url(
+        &app,
+        "/subscriptions/data",
+        subscriber_id,
+        &token,
+    ))
+    .await
+    .unwrap();
+    let requested = reqwest::Client::new()
+        .post(self_service_url(
+            &app,
+            "/subscriptions/data/link",
+            subscriber_id,
+            &token,
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(page.status().as_u16(), 200);
+    assert!(page
+        .text()
+        .await
+        .unwrap()
+        .contains("/subscriptions/data/link?"));
+    assert_eq!(requested.status().as_u16(), 200);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .rev()
+        .find(|request| request.url.path() == "/email")
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], email.as_str());
+    let mut access_link = app.get_confirmation_links(&email_request).plain;
+    let manage = reqwest::get(access_link.clone()).await.unwrap();
+    assert_eq!(manage.status().as_u16(), 200);
+    assert!(manage
+        .text()
+        .await
+        .unwrap()
+        .contains("/subscriptions/data/erase?"));
+    access_link.set_path("/subscriptions/data/export");
+    let export = reqwest::get(access_link).await.unwrap();
+    assert_eq!(export.status().as_u16(), 200);
+    let export: serde_json::Value = export.json().await.unwrap();
+    assert_eq!(export["email"], email.as_str());
+}
+
+#[tokio::test]
+async fn the_personal_data_link_cannot_export_or_erase_by_itself() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (subscriber_id, _) = subscriber(&app).await;
+    let token = personal_data_token(&app, subscriber_id);
+
+    let export = reqwest::get(self_service_url(
+        &app,
+        "/subscriptions/data/export",
+        subscriber_id,
+        &token,
+    ))
+    .await
+    .unwrap();
+    let erasure = reqwest::Client::new()
+        .post(self_service_url(
+            &app,
+            "/subscriptions/data/erase",
+            subscriber_id,
+            &token,
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(export.status().as_u16(), 400);
+    assert_eq!(erasure.status().as_u16(), 400);
+    assert_eq!(count(&app, "subscriptions").await, 1);
+}
+
+#[tokio::test]
+async fn the_personal_data_link_rejects_other_tokens() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (subscriber_id, _) = subscriber(&app).await;
+    let unsubscribe_token = app
+        .subscriber_links
+        .token(LinkPurpose::Unsubscribe, subscriber_id);
+    let expires_at = (Utc::now() + Duration::hours(1)).timestamp();
+
+    let requested = reqwest::Client::new()
+        .post(self_service_url(
+            &app,
+            "/subscriptions/data/link",
+            subscriber_id,
+            &unsubscribe_token,
+        ))
+        .send()
+        .await
+        .unwrap();
+    let export = reqwest::get(access_url(
+        &app,
+        "/subscriptions/data/export",
+        subscriber_id,
+        expires_at,
+        &unsubscribe_token,
+    ))
+    .await
+    .unwrap();
+    let erasure = reqwest::Client::new()
+        .post(access_url(
+            &app,
+            "/subscriptions/data/erase",
+            subscriber_id,
+            expires_at,
+            &unsubscribe_token,
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(requested.status().as_u16(), 401);
+    assert_eq!(export.status().as_u16(), 401);
+    assert_eq!(erasure.status().as_u16(), 401);
+    assert_eq!(count(&app, "subscriptions").await, 1);
+}
+
+#[tokio::test]
+async fn expired_or_extended_access_links_are_rejected() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (subscriber_id, _) = subscriber(&app).await;
+    let expired_at = (Utc::now() - Duration::minutes(1)).timestamp();
+    let expires_at = (Utc::now() + Duration::hours(1)).timestamp();
+    let expired_token = access_token(&app, subscriber_id, expired_at);
+    let token = access_token(&app, subscriber_id, expires_at);
+
+    let expired = reqwest::get(access_url(
+        &app,
+        "/subscriptions/data/export",
+        subscriber_id,
+        expired_at,
+        &expired_token,
+    ))
+    .await
+    .unwrap();
+    let extended = reqwest::get(access_url(
+        &app,
+        "/subscriptions/data/export",
+        subscriber_id,
+        expires_at + 3600,
+        &token,
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(expired.status().as_u16(), 410);
+    assert_eq!(extended.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn subscribers_can_erase_their_data_with_the_access_link() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let (subscriber_id, _) = subscriber(&app).await;
+    let expires_at = (Utc::now() + Duration::hours(1)).timestamp();
+    let token = access_token(&app, subscriber_id, expires_at);
+    let erase_url = access_url(
+        &app,
+        "/subscriptions/data/erase",
+        subscriber_id,
+        expires_at,
+        &token,
+    );
+
+    let first = reqwest::Client::new()
+        .post(erase_url.clone())
+        .send()
+        .await
+        .unwrap();
+    let second = reqwest::Client::new().post(erase_url).send().await.unwrap();
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 404);
+    assert_eq!(count(&app, "subscriptions").await, 0);
+    assert_eq!(count(&app, "suppressed_emails").await, 1);
+}
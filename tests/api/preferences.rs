@@ -0,0 +1,686 @@
+use chrono::{DateTime, Duration, Utc};
+use reqwest::{Response, Url};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::subscriber_links::LinkPurpose;
+
+use crate::helpers::{spawn_app, PostmarkBatchResponder, TestApp};
+use crate::newsletters::{create_confirmed_subscriber, newsletter_request_body};
+
+async fn subscriber_id(app: &TestApp) -> Uuid {
+    sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id
+}
+
+fn preferences_url(app: &TestApp, subscriber_id: Uuid, purpose: LinkPurpose) -> Url {
+    let mut url = Url::parse("http://127.0.0.1/subscriptions/preferences").unwrap();
+    url.set_port(Some(app.port)).unwrap();
+    url.query_pairs_mut()
+        .append_pair("subscriber_id", &subscriber_id.to_string())
+        .append_pair("token", &app.subscriber_links.token(purpose, subscriber_id));
+    url
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status
+}
+
+/// The short-lived link we email to the address on file.
+fn manage_url(app: &TestApp, subscriber_id: Uuid, expires_at: DateTime<Utc>) -> Url {
+    let mut url = Url::parse(
+        &app.subscriber_links
+            .preferences_access_link(subscriber_id, expires_at),
+    )
+    .unwrap();
+    url.set_port(Some(app.port)).unwrap();
+    url
+}
+
+async fn post_preferences(app: &TestApp, subscriber_id: Uuid, form: &[(&str, &str)]) -> Response {
+    reqwest::Client::new()
+        .post(manage_url(
+            app,
+            subscriber_id,
+            Utc::now() + Duration::hours(1),
+        ))
+        .form(form)
+        .send()
+        .await
+        .expect("Failed to send request")
+}
+
+async fn mount_email_server(app: &TestApp) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+}
+
+/// The emails sent through the single email API so far, oldest first.
+async fn sent_emails(app: &TestApp) -> Vec<wiremock::Request> {
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|request| request.url.path() == "/email")
+        .collect()
+}
+
+/// List slug and type of every recorded consent event.
+async fn consent_events(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"
+        SELECT lists.slug, consent_events.event_type
+        FROM consent_events
+        JOIN lists ON lists.id = consent_events.list_id
+        ORDER BY consent_events.occurred_at
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| (row.slug, row.event_type))
+    .collect()
+}
+
+/// Field, old and new value of every recorded change, oldest first.
+async fn preference_changes(app: &TestApp) -> Vec<(String, Option<String>, Option<String>)> {
+    sqlx::query!("SELECT field, old_value, new_value FROM preference_changes ORDER BY changed_at")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| (row.field, row.old_value, row.new_value))
+        .collect()
+}
+
+async fn memberships(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"
+        SELECT lists.slug, list_memberships.status
+        FROM list_memberships
+        JOIN lists ON lists.id = list_memberships.list_id
+        ORDER BY lists.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| (row.slug, row.status))
+    .collect()
+}
+
+async fn create_list(app: &TestApp, slug: &str) {
+    app.test_user.login(app).await;
+    app.post_list(&serde_json::json!({ "slug": slug, "name": "Weekly digest" }))
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn newsletters_link_to_the_preference_page() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.wait_for_pending_deliveries().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let emails: Vec<serde_json::Value> = serde_json::from_slice(&email_request.body).unwrap();
+    let preferences_link = app.subscriber_links.preferences_link(subscriber_id);
+    assert!(emails[0]["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains(&preferences_link));
+}
+
+#[tokio::test]
+async fn the_preference_link_emails_a_short_lived_link_to_the_address_on_file() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    mount_email_server(&app).await;
+    let email = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email;
+    let mut link_url = preferences_url(&app, subscriber_id, LinkPurpose::Preferences);
+    link_url.set_path("/subscriptions/preferences/link");
+
+    let page = reqwest::get(preferences_url(
+        &app,
+        subscriber_id,
+        LinkPurpose::Preferences,
+    ))
+    .await
+    .unwrap();
+    let requested = reqwest::Client::new().post(link_url).send().await.unwrap();
+
+    assert_eq!(page.status().as_u16(), 200);
+    let html = page.text().await.unwrap();
+    assert!(html.contains("/subscriptions/preferences/link?"));
+    assert!(!html.contains(r#"name="name""#));
+    assert_eq!(requested.status().as_u16(), 200);
+    let email_request = sent_emails(&app).await.pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], email.as_str());
+    let access_link = app.get_confirmation_links(&email_request).plain;
+    assert_eq!(access_link.path(), "/subscriptions/preferences/manage");
+    let manage = reqwest::get(access_link).await.unwrap();
+    assert_eq!(manage.status().as_u16(), 200);
+    assert!(manage
+        .text()
+        .await
+        .unwrap()
+        .contains("/subscriptions/preferences/manage?"));
+}
+
+#[tokio::test]
+async fn the_preference_link_cannot_change_anything_by_itself() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    let mut url = preferences_url(&app, subscriber_id, LinkPurpose::Preferences);
+    url.set_path("/subscriptions/preferences/manage");
+
+    let page = reqwest::get(url.clone()).await.unwrap();
+    let post = reqwest::Client::new()
+        .post(url)
+        .form(&[
+            ("name", "Someone else"),
+            ("language", ""),
+            ("email_frequency", "immediate"),
+        ])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(page.status().as_u16(), 400);
+    assert_eq!(post.status().as_u16(), 400);
+    assert!(preference_changes(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn the_preference_page_rejects_other_tokens() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    let expires_at = (Utc::now() + Duration::hours(1)).timestamp();
+    let mut manage = preferences_url(&app, subscriber_id, LinkPurpose::Preferences);
+    manage.set_path("/subscriptions/preferences/manage");
+    manage
+        .query_pairs_mut()
+        .clear()
+        .append_pair("subscriber_id", &subscriber_id.to_string())
+        .append_pair("expires_at", &expires_at.to_string())
+        .append_pair(
+            "token",
+            &app.subscriber_links.expiring_token(
+                LinkPurpose::PersonalDataAccess,
+                subscriber_id,
+                expires_at,
+            ),
+        );
+
+    let get = reqwest::get(preferences_url(
+        &app,
+        subscriber_id,
+        LinkPurpose::PersonalData,
+    ))
+    .await
+    .unwrap();
+    let post = reqwest::Client::new()
+        .post(manage)
+        .form(&[
+            ("name", "Someone else"),
+            ("language", ""),
+            ("email_frequency", "immediate"),
+        ])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(get.status().as_u16(), 401);
+    assert_eq!(post.status().as_u16(), 401);
+    assert!(preference_changes(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn an_expired_preference_link_is_rejected_with_410() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    let expired = manage_url(&app, subscriber_id, Utc::now() - Duration::minutes(1));
+
+    let page = reqwest::get(expired.clone()).await.unwrap();
+    let post = reqwest::Client::new()
+        .post(expired)
+        .form(&[
+            ("name", "Someone else"),
+            ("language", ""),
+            ("email_frequency", "immediate"),
+        ])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(page.status().as_u16(), 410);
+    assert_eq!(post.status().as_u16(), 410);
+    assert!(preference_changes(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn the_preference_page_shows_the_current_preferences() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    let name = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .name;
+
+    let page = reqwest::get(manage_url(
+        &app,
+        subscriber_id,
+        Utc::now() + Duration::hours(1),
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(page.status().as_u16(), 200);
+    let html = page.text().await.unwrap();
+    assert!(html.contains(&htmlescape::encode_attribute(&name)));
+    assert!(html.contains(r#"value="immediate" checked"#));
+    assert!(html.contains(r#"value="default" checked"#));
+}
+
+#[tokio::test]
+async fn saving_preferences_updates_the_subscriber_and_records_each_change() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+
+    let response = post_preferences(
+        &app,
+        subscriber_id,
+        &[
+            ("name", "Ursula"),
+            ("language", "de"),
+            ("email_frequency", "weekly_digest"),
+            ("timezone", "Europe/Berlin"),
+            ("topic", "default"),
+        ],
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Your preferences have been saved."));
+    let saved = sqlx::query!(
+        "SELECT name, language, email_frequency, timezone, digest_sent_at FROM subscriptions"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.name, "Ursula");
+    assert_eq!(saved.language.as_deref(), Some("de"));
+    assert_eq!(saved.email_frequency, "weekly_digest");
+    assert_eq!(saved.timezone.as_deref(), Some("Europe/Berlin"));
+    // The first digest only covers what is published from now on.
+    assert!(saved.digest_sent_at.is_some());
+    let fields: Vec<String> = preference_changes(&app)
+        .await
+        .into_iter()
+        .map(|(field, _, _)| field)
+        .collect();
+    assert_eq!(
+        fields,
+        vec!["name", "language", "email_frequency", "timezone"]
+    );
+    assert_eq!(
+        preference_changes(&app).await[2],
+        (
+            "email_frequency".to_string(),
+            Some("immediate".to_string()),
+            Some("weekly_digest".to_string())
+        )
+    );
+}
+
+#[tokio::test]
+async fn saving_unchanged_preferences_records_nothing() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    let name = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .name;
+
+    post_preferences(
+        &app,
+        subscriber_id,
+        &[
+            ("name", &name),
+            ("language", ""),
+            ("email_frequency", "immediate"),
+            ("topic", "default"),
+        ],
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+
+    assert!(preference_changes(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn invalid_preferences_are_rejected_without_changes() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    let test_cases = vec![
+        (
+            vec![
+                ("name", "<script>"),
+                ("language", ""),
+                ("email_frequency", "immediate"),
+            ],
+            "an invalid name",
+        ),
+        (
+            vec![
+                ("name", "Ursula"),
+                ("language", "not a language"),
+                ("email_frequency", "immediate"),
+            ],
+            "an invalid language",
+        ),
+        (
+            vec![
+                ("name", "Ursula"),
+                ("language", ""),
+                ("email_frequency", "immediate"),
+                ("timezone", "Mars/Olympus_Mons"),
+            ],
+            "an unknown timezone",
+        ),
+        (
+            vec![
+                ("name", "Ursula"),
+                ("language", ""),
+                ("email_frequency", "hourly"),
+            ],
+            "an unknown frequency",
+        ),
+    ];
+
+    for (form, description) in test_cases {
+        let response = post_preferences(&app, subscriber_id, &form).await;
+
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The preference page did not reject {}.",
+            description
+        );
+    }
+    assert!(preference_changes(&app).await.is_empty());
+    assert_eq!(
+        memberships(&app).await,
+        vec![("default".to_string(), "confirmed".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn topics_are_left_at_once_and_joined_after_a_confirmation() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_list(&app, "digest").await;
+    let subscriber_id = subscriber_id(&app).await;
+    mount_email_server(&app).await;
+    let name = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .name;
+    let consent_before = consent_events(&app).await.len();
+
+    let response = post_preferences(
+        &app,
+        subscriber_id,
+        &[
+            ("name", &name),
+            ("language", ""),
+            ("email_frequency", "immediate"),
+            ("topic", "digest"),
+        ],
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        memberships(&app).await,
+        vec![
+            ("default".to_string(), "unsubscribed".to_string()),
+            ("digest".to_string(), "pending_confirmation".to_string()),
+        ]
+    );
+    assert_eq!(
+        preference_changes(&app).await,
+        vec![(
+            "topics".to_string(),
+            Some("default".to_string()),
+            Some("".to_string())
+        )]
+    );
+    assert_eq!(
+        consent_events(&app).await[consent_before..],
+        [("digest".to_string(), "subscribed".to_string())]
+    );
+
+    let email_request = sent_emails(&app).await.pop().unwrap();
+    let confirmation_link = app.get_confirmation_links(&email_request).html;
+    reqwest::get(confirmation_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(
+        memberships(&app).await,
+        vec![
+            ("default".to_string(), "unsubscribed".to_string()),
+            ("digest".to_string(), "confirmed".to_string()),
+        ]
+    );
+    assert_eq!(
+        consent_events(&app).await[consent_before..],
+        [
+            ("digest".to_string(), "subscribed".to_string()),
+            ("digest".to_string(), "confirmed".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn picking_a_topic_after_unsubscribing_asks_for_a_confirmation() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    mount_email_server(&app).await;
+    let default_list = sqlx::query!("SELECT id FROM lists WHERE slug = 'default'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let mut unsubscribe_link = Url::parse(
+        &app.subscriber_links
+            .unsubscribe_link(subscriber_id, default_list.id),
+    )
+    .unwrap();
+    unsubscribe_link.set_port(Some(app.port)).unwrap();
+    reqwest::Client::new()
+        .post(unsubscribe_link)
+        .form(&[("lists", "all")])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    post_preferences(
+        &app,
+        subscriber_id,
+        &[
+            ("name", "Ursula"),
+            ("language", ""),
+            ("email_frequency", "immediate"),
+            ("topic", "default"),
+        ],
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+
+    assert_eq!(subscriber_status(&app).await, "unsubscribed");
+    assert_eq!(
+        memberships(&app).await,
+        vec![("default".to_string(), "pending_confirmation".to_string())]
+    );
+
+    let email_request = sent_emails(&app).await.pop().unwrap();
+    let confirmation_link = app.get_confirmation_links(&email_request).html;
+    reqwest::get(confirmation_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+    assert_eq!(
+        memberships(&app).await,
+        vec![("default".to_string(), "confirmed".to_string())]
+    );
+    let fields: Vec<String> = preference_changes(&app)
+        .await
+        .into_iter()
+        .map(|(field, _, _)| field)
+        .collect();
+    assert_eq!(fields, vec!["name"]);
+}
+
+#[tokio::test]
+async fn preference_changes_are_exported_and_erased_with_the_personal_data() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    post_preferences(
+        &app,
+        subscriber_id,
+        &[
+            ("name", "Ursula"),
+            ("language", ""),
+            ("email_frequency", "immediate"),
+            ("topic", "default"),
+        ],
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    let email = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email;
+
+    let export: serde_json::Value = reqwest::Client::new()
+        .get(format!("http://{}/personal_data", app.address))
+        .query(&[("email", &email)])
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let erasure = reqwest::Client::new()
+        .delete(format!("http://{}/personal_data", app.address))
+        .query(&[("email", &email)])
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(export["preference_changes"][0]["field"], "name");
+    assert_eq!(export["preference_changes"][0]["new_value"], "Ursula");
+    assert_eq!(erasure.status().as_u16(), 200);
+    assert!(preference_changes(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn subscribers_can_set_their_timezone() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber_id = subscriber_id(&app).await;
+    let name = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .name;
+    let form = |timezone| {
+        [
+            ("name", name.as_str()),
+            ("language", ""),
+            ("email_frequency", "immediate"),
+            ("timezone", timezone),
+            ("topic", "default"),
+        ]
+    };
+
+    let invalid = post_preferences(&app, subscriber_id, &form("Mars/Olympus_Mons")).await;
+    let valid = post_preferences(&app, subscriber_id, &form("Asia/Tokyo")).await;
+
+    assert_eq!(invalid.status().as_u16(), 400);
+    assert_eq!(valid.status().as_u16(), 200);
+    let timezone = sqlx::query!("SELECT timezone FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .timezone;
+    assert_eq!(timezone.as_deref(), Some("Asia/Tokyo"));
+    assert_eq!(
+        preference_changes(&app).await,
+        vec![("timezone".to_string(), None, Some("Asia/Tokyo".to_string()))]
+    );
+}
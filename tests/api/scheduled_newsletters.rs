@@ -0,0 +1,383 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
+use reqwest::Response;
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+use zero2prod::issue_scheduler::enqueue_delivery_tasks;
+
+use crate::helpers::{assert_is_redirect_to, spawn_app, PostmarkBatchResponder, TestApp};
+use crate::newsletters::{create_confirmed_subscriber, newsletter_request_body};
+
+fn scheduled_request_body(scheduled_at: &str, in_subscriber_timezone: bool) -> serde_json::Value {
+    let mut body = newsletter_request_body();
+    body["scheduled_at"] = scheduled_at.into();
+    body["in_subscriber_timezone"] = in_subscriber_timezone.into();
+    body
+}
+
+async fn publish_scheduled(app: &TestApp, scheduled_at: DateTime<Utc>) -> Uuid {
+    let response: serde_json::Value = app
+        .post_newsletters(scheduled_request_body(&scheduled_at.to_rfc3339(), false))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    response["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+async fn queued_deliveries(app: &TestApp) -> i64 {
+    sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+async fn get_scheduled(app: &TestApp) -> Vec<serde_json::Value> {
+    reqwest::Client::new()
+        .get(format!("http://{}/newsletters/scheduled", app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+async fn cancel(app: &TestApp, issue_id: Uuid) -> Response {
+    reqwest::Client::new()
+        .post(format!(
+            "http://{}/newsletters/{}/cancel",
+            app.address, issue_id
+        ))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to send request")
+}
+
+async fn reschedule(app: &TestApp, issue_id: Uuid, scheduled_at: DateTime<Utc>) -> Response {
+    reqwest::Client::new()
+        .put(format!(
+            "http://{}/newsletters/{}/schedule",
+            app.address, issue_id
+        ))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&serde_json::json!({ "scheduled_at": scheduled_at.to_rfc3339() }))
+        .send()
+        .await
+        .expect("Failed to send request")
+}
+
+/// Moves the issue into the past, the background worker then picks it up.
+async fn make_due(app: &TestApp, issue_id: Uuid) {
+    sqlx::query!(
+        "UPDATE newsletter_issues SET scheduled_at = now() - interval '1 minute' WHERE newsletter_issue_id = $1",
+        issue_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn is_enqueued(app: &TestApp, issue_id: Uuid) -> bool {
+    sqlx::query!(
+        "SELECT enqueued_at FROM newsletter_issues WHERE newsletter_issue_id = $1",
+        issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .enqueued_at
+    .is_some()
+}
+
+async fn wait_until_enqueued(app: &TestApp, issue_id: Uuid) {
+    for _ in 0..100 {
+        if is_enqueued(app, issue_id).await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The scheduled issue was not queued in time");
+}
+
+#[tokio::test]
+async fn scheduled_issues_are_delivered_once_due() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let issue_id = publish_scheduled(&app, Utc::now() + ChronoDuration::days(1)).await;
+
+    assert_eq!(queued_deliveries(&app).await, 0);
+    let scheduled = get_scheduled(&app).await;
+    assert_eq!(scheduled.len(), 1);
+    assert_eq!(scheduled[0]["newsletter_issue_id"], issue_id.to_string());
+    assert_eq!(scheduled[0]["list"], "default");
+
+    make_due(&app, issue_id).await;
+    wait_until_enqueued(&app, issue_id).await;
+    app.wait_for_pending_deliveries().await;
+
+    assert!(get_scheduled(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn cancelled_issues_are_never_sent() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue_id = publish_scheduled(&app, Utc::now() + ChronoDuration::days(1)).await;
+
+    let first = cancel(&app, issue_id).await;
+    let second = cancel(&app, issue_id).await;
+    let unknown = cancel(&app, Uuid::new_v4()).await;
+
+    assert_eq!(first.status().as_u16(), 204);
+    assert_eq!(second.status().as_u16(), 409);
+    assert_eq!(unknown.status().as_u16(), 404);
+    assert!(get_scheduled(&app).await.is_empty());
+    let cancelled_at = sqlx::query!(
+        "SELECT cancelled_at FROM newsletter_issues WHERE newsletter_issue_id = $1",
+        issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .cancelled_at;
+    assert!(cancelled_at.is_some());
+    make_due(&app, issue_id).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!is_enqueued(&app, issue_id).await);
+    assert_eq!(queued_deliveries(&app).await, 0);
+}
+
+#[tokio::test]
+async fn issues_can_be_rescheduled_until_they_are_queued() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_scheduled(&app, Utc::now() + ChronoDuration::days(1)).await;
+    let later = Utc.with_ymd_and_hms(2099, 1, 1, 9, 30, 0).unwrap();
+
+    let response = reschedule(&app, issue_id, later).await;
+
+    assert_eq!(response.status().as_u16(), 204);
+    let scheduled = get_scheduled(&app).await;
+    assert_eq!(scheduled[0]["scheduled_at"], "2099-01-01T09:30:00Z");
+
+    make_due(&app, issue_id).await;
+    wait_until_enqueued(&app, issue_id).await;
+    let too_late = reschedule(&app, issue_id, later).await;
+    let cancel_too_late = cancel(&app, issue_id).await;
+
+    assert_eq!(too_late.status().as_u16(), 409);
+    assert_eq!(cancel_too_late.status().as_u16(), 409);
+    app.wait_for_pending_deliveries().await;
+}
+
+#[tokio::test]
+async fn issues_in_subscriber_timezones_wait_for_the_local_time() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_confirmed_subscriber(&app).await;
+    sqlx::query!(
+        "UPDATE subscriptions SET timezone = 'Asia/Tokyo' WHERE id = (SELECT id FROM subscriptions LIMIT 1)"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let response: serde_json::Value = app
+        .post_newsletters(scheduled_request_body("2099-06-01T09:00:00+02:00", true))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let issue_id: Uuid = response["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let mut transaction = app.db_pool.begin().await.unwrap();
+    enqueue_delivery_tasks(&mut transaction, issue_id)
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut send_times: Vec<(Option<String>, DateTime<Utc>)> = sqlx::query!(
+        r#"
+        SELECT subscriptions.timezone, issue_delivery_queue.execute_after
+        FROM issue_delivery_queue
+        JOIN subscriptions ON subscriptions.email = issue_delivery_queue.subscriber_email
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| (row.timezone, row.execute_after))
+    .collect();
+    send_times.sort();
+    assert_eq!(
+        send_times,
+        vec![
+            // Without a timezone the issue goes out at `scheduled_at`.
+            (None, Utc.with_ymd_and_hms(2099, 6, 1, 7, 0, 0).unwrap()),
+            (
+                Some("Asia/Tokyo".to_string()),
+                Utc.with_ymd_and_hms(2099, 6, 1, 0, 0, 0).unwrap()
+            ),
+        ]
+    );
+}
+
+/// Publishes for the wall-clock time three hours from now in the subscribers'
+/// timezones, a subscriber in `timezone` gets it at that time there.
+async fn publish_in_subscriber_timezone(app: &TestApp, timezone: &str) -> Uuid {
+    create_confirmed_subscriber(app).await;
+    sqlx::query!("UPDATE subscriptions SET timezone = $1", timezone)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    let local_send_at = (Utc::now() + ChronoDuration::hours(3)).format("%Y-%m-%dT%H:%M:00+00:00");
+    let response: serde_json::Value = app
+        .post_newsletters(scheduled_request_body(&local_send_at.to_string(), true))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    response["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn issues_in_subscriber_timezones_are_queued_once_due_in_a_recipient_timezone() {
+    let app = spawn_app().await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .mount(&app.email_server)
+        .await;
+
+    // Three hours from now in Tokyo was six hours ago.
+    let issue_id = publish_in_subscriber_timezone(&app, "Asia/Tokyo").await;
+
+    wait_until_enqueued(&app, issue_id).await;
+}
+
+#[tokio::test]
+async fn issues_in_subscriber_timezones_can_be_cancelled_until_due_in_a_recipient_timezone() {
+    let app = spawn_app().await;
+
+    // Three hours from now has come in UTC+14, but not yet in Berlin.
+    let issue_id = publish_in_subscriber_timezone(&app, "Europe/Berlin").await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    assert!(!is_enqueued(&app, issue_id).await);
+    assert_eq!(cancel(&app, issue_id).await.status().as_u16(), 204);
+}
+
+#[tokio::test]
+async fn invalid_schedules_are_rejected_with_400() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let mut without_time = newsletter_request_body();
+    without_time["in_subscriber_timezone"] = true.into();
+    let test_cases = vec![
+        (without_time, "a local time without a scheduled_at"),
+        (
+            scheduled_request_body("next tuesday", false),
+            "an invalid scheduled_at",
+        ),
+        (
+            scheduled_request_body("2099-06-01T09:00:00", false),
+            "a scheduled_at without an offset",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        let response = app.post_newsletters(body).await;
+
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not reject {}.",
+            description
+        );
+    }
+    assert_eq!(queued_deliveries(&app).await, 0);
+}
+
+#[tokio::test]
+async fn the_newsletter_form_schedules_in_the_given_timezone() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    let response = app
+        .post_publish_newsletter(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "scheduled_at": "2099-01-15T08:00",
+            "timezone": "Europe/Berlin",
+            "in_subscriber_timezone": "on",
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    let html_page = app.get_publish_newsletter_html().await;
+    assert!(html_page.contains("<p><i>The newsletter issue has been scheduled.</i></p>"));
+    let scheduled = get_scheduled(&app).await;
+    assert_eq!(scheduled[0]["scheduled_at"], "2099-01-15T07:00:00Z");
+    assert_eq!(scheduled[0]["local_send_at"], "2099-01-15T08:00:00");
+    assert_eq!(queued_deliveries(&app).await, 0);
+}
+
+#[tokio::test]
+async fn the_newsletter_form_rejects_an_unknown_timezone() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let response = app
+        .post_publish_newsletter(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "scheduled_at": "2099-01-15T08:00",
+            "timezone": "Mars/Olympus_Mons",
+            "idempotency_key": Uuid::new_v4().to_string()
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert!(get_scheduled(&app).await.is_empty());
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+use actix_session::storage::SessionStore;
+use actix_web::cookie::time::Duration;
+use zero2prod::session_store::{purge_expired_sessions, PostgresSessionStore};
+
+use crate::helpers::spawn_app;
+
+fn session_state(user_id: &str) -> HashMap<String, String> {
+    HashMap::from([("user_id".to_string(), user_id.to_string())])
+}
+
+#[tokio::test]
+async fn postgres_session_store_round_trips_session_state() {
+    let app = spawn_app().await;
+    let store = PostgresSessionStore::new(app.db_pool.clone());
+
+    let key = store
+        .save(session_state("first"), &Duration::minutes(5))
+        .await
+        .unwrap();
+    assert_eq!(
+        store.load(&key).await.unwrap(),
+        Some(session_state("first"))
+    );
+
+    let key = store
+        .update(key, session_state("second"), &Duration::minutes(5))
+        .await
+        .unwrap();
+    assert_eq!(
+        store.load(&key).await.unwrap(),
+        Some(session_state("second"))
+    );
+
+    store.delete(&key).await.unwrap();
+    assert_eq!(store.load(&key).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn postgres_session_store_does_not_load_expired_sessions() {
+    let app = spawn_app().await;
+    let store = PostgresSessionStore::new(app.db_pool.clone());
+
+    let key = store
+        .save(session_state("expired"), &Duration::seconds(-1))
+        .await
+        .unwrap();
+
+    assert_eq!(store.load(&key).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn expired_sessions_are_purged() {
+    let app = spawn_app().await;
+    let store = PostgresSessionStore::new(app.db_pool.clone());
+    store
+        .save(session_state("expired"), &Duration::seconds(-1))
+        .await
+        .unwrap();
+    let live = store
+        .save(session_state("live"), &Duration::minutes(5))
+        .await
+        .unwrap();
+
+    // The cleanup worker of the app may get to the expired session first.
+    purge_expired_sessions(&app.db_pool).await.unwrap();
+
+    let remaining = sqlx::query!("SELECT session_key FROM sessions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].session_key, live.as_ref());
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::subscription_cleanup_worker::purge_stale_subscriptions;
+
+use crate::helpers::spawn_app;
+
+const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[tokio::test]
+async fn stale_pending_subscriptions_are_purged_with_their_tokens() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    sqlx::query!("UPDATE subscriptions SET subscribed_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE subscription_tokens SET issued_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let purged = purge_stale_subscriptions(&app.db_pool, RETENTION)
+        .await
+        .unwrap();
+
+    assert_eq!(purged, 1);
+    let subscribers = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    let tokens = sqlx::query!("SELECT subscription_token FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    let consent_events = sqlx::query!("SELECT id FROM consent_events")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(subscribers.is_empty());
+    assert!(tokens.is_empty());
+    assert!(consent_events.is_empty());
+}
+
+#[tokio::test]
+async fn recent_pending_and_confirmed_subscriptions_are_kept() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    sqlx::query!("UPDATE subscriptions SET subscribed_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.post_subscription("name=tolkien&email=tolkien%40gmail.com".into())
+        .await;
+
+    let purged = purge_stale_subscriptions(&app.db_pool, RETENTION)
+        .await
+        .unwrap();
+
+    assert_eq!(purged, 0);
+    let subscribers = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.len(), 2);
+}
+
+#[tokio::test]
+async fn signing_up_again_keeps_a_pending_subscription() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    app.post_subscription(body.into()).await;
+    sqlx::query!("UPDATE subscriptions SET subscribed_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE subscription_tokens SET issued_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.post_subscription(body.into()).await;
+
+    let purged = purge_stale_subscriptions(&app.db_pool, RETENTION)
+        .await
+        .unwrap();
+
+    assert_eq!(purged, 0);
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_requests[1]);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
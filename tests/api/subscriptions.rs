@@ -73,6 +73,10 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_empty() {
         ("name=&email=ursula_le_guin%40gmail.com", "empty name"),
         ("name=Ursula&email=", "empty email"),
         ("name=Ursula&email=definitely-not-an-email", "invalid email"),
+        (
+            "name=Ursula&email=ursula_le_guin%40gmail.com&language=not%20a%20language",
+            "invalid language",
+        ),
     ];
     for (body, description) in test_cases {
         // Act
@@ -112,7 +116,344 @@ async fn subscribe_sends_a_confirmation_email_with_link() {
     app.post_subscription(email.into()).await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     assert_eq!(confirmation_links.html, confirmation_links.plain);
 }
+
+#[tokio::test]
+async fn subscribe_returns_the_validation_error_message() {
+    let app = spawn_app().await;
+
+    let res = app
+        .post_subscription("name=Ursula&email=definitely-not-an-email".into())
+        .await;
+
+    assert_eq!(400, res.status().as_u16());
+    assert_eq!(
+        "definitely-not-an-email is not a valid email",
+        res.text().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn subscribe_fails_if_there_is_a_fatal_database_error() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let res = app.post_subscription(body.into()).await;
+
+    assert_eq!(500, res.status().as_u16());
+    assert_eq!(Some(0), res.content_length());
+}
+
+#[tokio::test]
+async fn subscribe_does_not_persist_the_subscriber_if_storing_the_token_fails() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let res = app.post_subscription(body.into()).await;
+    assert_eq!(500, res.status().as_u16());
+
+    let saved = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Could not load from DB");
+    assert!(saved.is_empty());
+}
+
+#[tokio::test]
+async fn subscribe_records_that_the_confirmation_email_was_sent() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription(body.into()).await;
+
+    let saved = sqlx::query!(
+        "SELECT confirmation_email_sent_at, confirmation_email_error FROM subscription_tokens"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Could not load from DB");
+    assert!(saved.confirmation_email_sent_at.is_some());
+    assert!(saved.confirmation_email_error.is_none());
+}
+
+#[tokio::test]
+async fn subscribe_records_a_failed_confirmation_email_and_returns_500() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.post_subscription(body.into()).await;
+    assert_eq!(500, res.status().as_u16());
+
+    let saved = sqlx::query!(
+        "SELECT confirmation_email_sent_at, confirmation_email_error FROM subscription_tokens"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Could not load from DB");
+    assert!(saved.confirmation_email_sent_at.is_none());
+    assert!(saved.confirmation_email_error.is_some());
+}
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_resends_the_same_confirmation_link() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let first = app.post_subscription(body.into()).await;
+    let second = app.post_subscription(body.into()).await;
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(200, second.status().as_u16());
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_link = app.get_confirmation_links(&email_requests[0]).html;
+    let second_link = app.get_confirmation_links(&email_requests[1]).html;
+    assert_eq!(first_link, second_link);
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Could not load from DB");
+    assert_eq!(1, saved.len());
+    assert_eq!("pending_confirmation", saved[0].status);
+}
+
+#[tokio::test]
+async fn subscribing_again_after_confirming_sends_a_notice_instead_of_a_confirmation() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    // The confirmation, the welcome email and the notice for the second sign-up.
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription(body.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let res = app.post_subscription(body.into()).await;
+
+    assert_eq!(200, res.status().as_u16());
+    assert_eq!(Some(0), res.content_length());
+    let notice = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let notice: serde_json::Value = serde_json::from_slice(&notice.body).unwrap();
+    assert_eq!(notice["Subject"], "You are already subscribed");
+    assert!(!notice["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("subscription_token"));
+}
+
+#[tokio::test]
+async fn concurrent_first_sign_ups_with_the_same_email_both_succeed() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let (first, second) = tokio::join!(
+        app.post_subscription(body.into()),
+        app.post_subscription(body.into())
+    );
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    let subscribers = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.len(), 1);
+}
+
+#[tokio::test]
+async fn subscribing_again_in_another_casing_resends_the_same_confirmation_link() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.post_subscription("name=le%20guin&email=Ursula_Le_Guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_link = app.get_confirmation_links(&email_requests[0]).html;
+    let second_link = app.get_confirmation_links(&email_requests[1]).html;
+    assert_eq!(first_link, second_link);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(1, saved.len());
+    assert_eq!("ursula_le_guin@gmail.com", saved[0].email);
+}
+
+#[tokio::test]
+async fn subscribing_again_after_the_token_expired_sends_a_new_confirmation_link() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    // Two confirmation emails and the welcome email once confirmed.
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription(body.into()).await;
+    sqlx::query!("UPDATE subscription_tokens SET issued_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.post_subscription(body.into()).await;
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_link = app.get_confirmation_links(&email_requests[0]).html;
+    let second_link = app.get_confirmation_links(&email_requests[1]).html;
+    assert_ne!(first_link, second_link);
+
+    reqwest::get(second_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn subscribe_sends_the_confirmation_email_in_the_requested_language() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&language=de";
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription(body.into()).await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(email["Subject"], "Willkommen");
+    assert!(email["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("Willkommen bei unserem Newsletter, le guin!"));
+    let saved = query!("SELECT language FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.language.as_deref(), Some("de"));
+}
+
+#[tokio::test]
+async fn subscribe_falls_back_to_the_accept_language_header() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    reqwest::Client::new()
+        .post(format!("http://{}/subscriptions", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept-Language", "fr-CH, fr;q=0.9, en;q=0.8")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(email["Subject"], "Bienvenue");
+    let saved = query!("SELECT language FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.language.as_deref(), Some("fr-CH"));
+}
+
+#[tokio::test]
+async fn subscribe_without_a_language_sends_the_confirmation_email_in_the_default_language() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(email["Subject"], "Welcome");
+    let saved = query!("SELECT language FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.language, None);
+}
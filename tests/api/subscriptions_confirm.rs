@@ -28,7 +28,7 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
     app.post_subscription(email.into()).await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     reqwest::get(confirmation_links.html)
         .await
@@ -45,3 +45,145 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
     assert_eq!(saved.name, "le guin");
     assert_eq!(saved.status, "confirmed");
 }
+
+#[tokio::test]
+async fn confirmation_with_an_unknown_token_is_rejected_with_401() {
+    let app = spawn_app().await;
+    let url = format!(
+        "http://{}/subscriptions/confirm?subscription_token=unknown",
+        &app.address
+    );
+
+    let res = reqwest::get(url).await.unwrap();
+
+    assert_eq!(res.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn confirmation_fails_if_there_is_a_fatal_database_error() {
+    let app = spawn_app().await;
+    let url = format!(
+        "http://{}/subscriptions/confirm?subscription_token=unknown",
+        &app.address
+    );
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscriber_id;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let res = reqwest::get(url).await.unwrap();
+
+    assert_eq!(res.status().as_u16(), 500);
+}
+
+#[tokio::test]
+async fn a_confirmation_link_can_only_be_used_once() {
+    let app = spawn_app().await;
+    let email = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription(email.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    let first = reqwest::get(confirmation_links.html.clone()).await.unwrap();
+    let second = reqwest::get(confirmation_links.html).await.unwrap();
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 401);
+    let saved = sqlx::query!("SELECT consumed_at FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.consumed_at.is_some());
+}
+
+#[tokio::test]
+async fn confirmation_with_an_expired_token_is_rejected_with_410() {
+    let app = spawn_app().await;
+    let email = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription(email.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    sqlx::query!("UPDATE subscription_tokens SET issued_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let res = reqwest::get(confirmation_links.html).await.unwrap();
+
+    assert_eq!(res.status().as_u16(), 410);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn confirming_a_subscription_sends_a_welcome_email() {
+    let app = spawn_app().await;
+    let email = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscription(email.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let welcome_request = &app.email_server.received_requests().await.unwrap()[1];
+    let body: serde_json::Value = serde_json::from_slice(&welcome_request.body).unwrap();
+    assert_eq!(body["To"], "ursula_le_guin@gmail.com");
+    assert!(body["HtmlBody"].as_str().unwrap().contains("le guin"));
+    assert!(body["TextBody"].as_str().unwrap().contains("le guin"));
+}
+
+#[tokio::test]
+async fn a_failing_welcome_email_does_not_fail_the_confirmation() {
+    let app = spawn_app().await;
+    let email = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscription(email.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    drop(_mock_guard);
+
+    Mock::given(path("/email"))
+        .and(method("post"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let res = reqwest::get(confirmation_links.html).await.unwrap();
+
+    assert_eq!(res.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
@@ -0,0 +1,165 @@
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+use zero2prod::subscriber_links::LinkPurpose;
+
+use crate::helpers::{spawn_app, PostmarkBatchResponder, TestApp};
+use crate::newsletters::{create_confirmed_subscriber, newsletter_request_body};
+
+/// Publishes an issue to a single confirmed subscriber and returns the unsubscribe
+/// link of the delivered email.
+async fn receive_a_newsletter(app: &TestApp) -> reqwest::Url {
+    create_confirmed_subscriber(app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::default())
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body()).await;
+    app.wait_for_pending_deliveries().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_unsubscribe_link(&email_request)
+}
+
+#[tokio::test]
+async fn newsletter_emails_carry_one_click_unsubscribe_headers() {
+    let app = spawn_app().await;
+    receive_a_newsletter(&app).await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let emails: Vec<serde_json::Value> = serde_json::from_slice(&email_request.body).unwrap();
+    let body = &emails[0];
+    let headers = body["Headers"].as_array().unwrap();
+    assert!(headers.iter().any(
+        |h| h["Name"] == "List-Unsubscribe-Post" && h["Value"] == "List-Unsubscribe=One-Click"
+    ));
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("/subscriptions/unsubscribe?"));
+}
+
+#[tokio::test]
+async fn following_the_unsubscribe_link_only_shows_a_form() {
+    let app = spawn_app().await;
+    let link = receive_a_newsletter(&app).await;
+
+    let res = reqwest::get(link).await.unwrap();
+
+    assert_eq!(res.status().as_u16(), 200);
+    assert!(res.text().await.unwrap().contains(r#"method="post""#));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_leaves_the_list_of_the_issue() {
+    let app = spawn_app().await;
+    let link = receive_a_newsletter(&app).await;
+
+    let res = app
+        .api_client
+        .post(link)
+        .form(&[("List-Unsubscribe", "One-Click")])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status, unsubscribed_at FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+    assert!(saved.unsubscribed_at.is_some());
+}
+
+#[tokio::test]
+async fn choosing_to_leave_every_list_marks_the_subscriber_as_unsubscribed() {
+    let app = spawn_app().await;
+    let link = receive_a_newsletter(&app).await;
+
+    let form = reqwest::get(link.clone())
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let res = app
+        .api_client
+        .post(link)
+        .form(&[("lists", "all")])
+        .send()
+        .await
+        .unwrap();
+
+    assert!(form.contains(r#"name="lists" value="all""#));
+    assert_eq!(res.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status, unsubscribed_at FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+    assert!(saved.unsubscribed_at.is_some());
+}
+
+#[tokio::test]
+async fn unsubscribed_subscribers_do_not_receive_further_issues() {
+    let app = spawn_app().await;
+    let link = receive_a_newsletter(&app).await;
+    app.api_client.post(link).send().await.unwrap();
+    let delivered = app.email_server.received_requests().await.unwrap().len();
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.wait_for_pending_deliveries().await;
+
+    assert_eq!(
+        app.email_server.received_requests().await.unwrap().len(),
+        delivered
+    );
+}
+
+#[tokio::test]
+async fn a_forged_unsubscribe_token_is_rejected_with_401() {
+    let app = spawn_app().await;
+    let mut link = receive_a_newsletter(&app).await;
+    let subscriber_id = link
+        .query_pairs()
+        .find(|(k, _)| k == "subscriber_id")
+        .unwrap()
+        .1
+        .to_string();
+    link.set_query(Some(&format!(
+        "subscriber_id={}&token={}",
+        subscriber_id,
+        app.subscriber_links
+            .token(LinkPurpose::Unsubscribe, Uuid::new_v4())
+    )));
+
+    let get = reqwest::get(link.clone()).await.unwrap();
+    let post = app.api_client.post(link).send().await.unwrap();
+
+    assert_eq!(get.status().as_u16(), 401);
+    assert_eq!(post.status().as_u16(), 401);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}